@@ -10,6 +10,8 @@ void cor_panic(const char *msg);
 int cor_printk(const char *format, ...);
 void putc(const char c);
 void *tkalloc(size_t sz, const char *what_for, uint64_t align);
+void *tkalloc_page(const char *what_for);
+void tkfree_page(void *page);
 
 #define ALIGN(x,a)              __ALIGN_MASK(x,(__typeof__(x))(a)-1)
 #define __ALIGN_MASK(x,mask)    (((x)+(mask))&~(mask))
@@ -8,11 +8,43 @@ is_return_from_trampoline:
   sti
   jmp trampoline_from_user
 
-isr_dispatcher:
-  # no==0xd (protection fault): bail
-  sub $0xd, %rax
+# A CPU exception in userspace ends the trip to userspace just like a syscall
+# does, see StepResult::Fault. We pass 0x100+vector as the "syscall number",
+# the faulting address (%cr2) and the error code. Exceptions in the kernel
+# are still fatal.
+is_fault:
+  testq $3, 16(%rsp) # CS of the faulting context, after our saved rax and rip
+  jz cor_panic
+  mov %rax, %rdx
+  pop %rax # original rax, lost
+  xor %rcx, %rcx
+  jmp is_user_fault
+
+is_fault_with_errcode:
+  testq $3, 24(%rsp) # same, but the CPU also pushed an error code
   jz cor_panic
-  add $0xd, %rax
+  mov %rax, %rdx
+  pop %rax # original rax, lost
+  pop %rcx # error code
+
+is_user_fault:
+  mov %cr2, %rbx
+  lea 0x100(%rdx), %rax
+  sti
+  jmp trampoline_from_user
+
+isr_dispatcher:
+  # no==0 (divide error), no==6 (invalid opcode)
+  cmp $0, %rax
+  je is_fault
+  cmp $6, %rax
+  je is_fault
+
+  # no==0xd (protection fault), no==0xe (page fault)
+  cmp $0xd, %rax
+  je is_fault_with_errcode
+  cmp $0xe, %rax
+  je is_fault_with_errcode
 
   # no==49 (syscall): return from userspace
   sub $49, %rax
@@ -67,9 +99,55 @@ timer_isr:
   ; mov $0x20, %al # command port of PIC1
   ; outb %al, $0x20
 
+  # A tick in userspace ends the trip there, so that the kernel gets to run
+  # other tasks and deliver signals even if the process never makes a
+  # syscall. See StepResult::Preempted.
+  testq $3, 16(%rsp) # CS of the interrupted context, after our saved rax and rip
+  jnz timer_preempt
+
   pop %rax
   iretq
 
+# Unlike for a syscall, the process expects every register to be as it left
+# them, so save all of them (and its flags) for trampoline_to_user to restore.
+timer_preempt:
+  pop %rax
+  movabs %rax, trampoline_user_regs
+  mov %rbx, %rax
+  movabs %rax, trampoline_user_regs+8
+  mov %rcx, %rax
+  movabs %rax, trampoline_user_regs+16
+  mov %rdx, %rax
+  movabs %rax, trampoline_user_regs+24
+  mov %rsi, %rax
+  movabs %rax, trampoline_user_regs+32
+  mov %rdi, %rax
+  movabs %rax, trampoline_user_regs+40
+  mov %rbp, %rax
+  movabs %rax, trampoline_user_regs+48
+  mov %r8, %rax
+  movabs %rax, trampoline_user_regs+56
+  mov %r9, %rax
+  movabs %rax, trampoline_user_regs+64
+  mov %r10, %rax
+  movabs %rax, trampoline_user_regs+72
+  mov %r11, %rax
+  movabs %rax, trampoline_user_regs+80
+  mov %r12, %rax
+  movabs %rax, trampoline_user_regs+88
+  mov %r13, %rax
+  movabs %rax, trampoline_user_regs+96
+  mov %r14, %rax
+  movabs %rax, trampoline_user_regs+104
+  mov %r15, %rax
+  movabs %rax, trampoline_user_regs+112
+  mov 16(%rsp), %rax # rflags, after rip and cs
+  movabs %rax, trampoline_user_regs+120
+
+  mov $0x200, %rax # not a syscall number, see StepResult::Preempted
+  sti
+  jmp trampoline_from_user
+
 .global asm_eoi
 asm_eoi:
   # set EOI
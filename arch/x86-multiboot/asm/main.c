@@ -3,7 +3,7 @@
 #include "chrdev_serial.h"
 #include "tss.h"
 #include "mm.h"
-#include "pci.h"
+#include "multiboot.h"
 #include "pic.h"
 #include "timer.h"
 #include "interrupt.h"
@@ -85,6 +85,9 @@ void kernel_main(void) {
     );
   cor_printk("XXX fs:70=%x\n",res);
 
+  // Before MM, whose heap might cover GRUB's data.
+  multiboot_init();
+
   cor_printk("Initializing MM.. ");
   mm_init();
   cor_printk("OK.\n");
@@ -1,4 +1,5 @@
 #include "common.h"
+#include "multiboot.h"
 
 #pragma pack(push, 1)
 struct memmap_entry {
@@ -9,6 +10,18 @@ struct memmap_entry {
 };
 #pragma pack(pop)
 
+// The page directory pointer table that boot.s set up for the higher half,
+// and the page directory for its first GiB. Every address space shares them,
+// so whatever we map here shows up in all of them.
+#define KERNEL_PDPT 0x2000
+#define KERNEL_PD 0x3000
+#define PAGE_HUGE (1<<7)
+#define PAGE_NOCACHE ((1<<4) | (1<<3))
+#define HUGE_PAGE_SIZE 0x200000
+
+// How much physical memory the heap may use, see mm_init
+#define HEAP_MAPPED_END 0x1000000
+
 struct region {
   size_t limit;
   void *base;
@@ -37,7 +50,29 @@ void mm_init() {
   // }
 
   largest_base = (void*)0x8000200000;
-  largest_limit =            0xfffff;
+  largest_limit =            0xbfffff;
+
+  // boot.s only maps the first 4 MiB. Map the rest of the heap with 2 MiB
+  // pages in the same page directory, which every address space shares.
+  uint64_t *pd = PTOK(KERNEL_PD);
+  for(uint64_t p = 0x400000; p < HEAP_MAPPED_END; p += HUGE_PAGE_SIZE) {
+    pd[p >> 21] = p | 3 | PAGE_HUGE;
+    __asm__ volatile("invlpg (%0)" : : "r"(PTOK(p)) : "memory");
+  }
+
+  // GRUB loads modules right behind the kernel, which might be where the
+  // heap would go. Move the heap behind them instead, shrinking it if it
+  // doesn't fit into what we've mapped otherwise.
+  uint64_t modules_end = ALIGN(multiboot_modules_end(), 0x1000);
+  if(modules_end > (uint64_t)KTOP(largest_base)) {
+    if(modules_end + 0x10000 > HEAP_MAPPED_END) {
+      cor_panic("Multiboot modules are too big to fit a heap behind them");
+    }
+    if(modules_end + largest_limit + 1 > HEAP_MAPPED_END) {
+      largest_limit = HEAP_MAPPED_END - modules_end - 1;
+    }
+    largest_base = PTOK(modules_end);
+  }
 
   /*
     We do pretty much the best implementation ever: just ignore all segments but the biggest.
@@ -77,3 +112,71 @@ void *tkalloc(size_t s, const char *what_for, uint64_t align) {
 
   return p;
 }
+
+// Whole pages, like user memory and page tables, can be given back. They go
+// on a free list threaded through the pages themselves and are handed out
+// again before we take anything new from the region.
+struct free_page {
+  struct free_page *next;
+};
+static struct free_page *free_pages = 0;
+static size_t free_page_count = 0;
+
+void *tkalloc_page(const char *what_for) {
+  void *p;
+  if(free_pages != 0) {
+    p = free_pages;
+    free_pages = free_pages->next;
+    free_page_count--;
+  } else {
+    p = tkalloc(0x1000, what_for, 0x1000);
+  }
+
+  for(size_t j = 0; j < 0x1000; j++) { // TODO: memzero
+    *((char*)p + j) = 0;
+  }
+  return p;
+}
+
+void tkfree_page(void *p) {
+  struct free_page *f = (struct free_page *)p;
+  f->next = free_pages;
+  free_pages = f;
+  free_page_count++;
+}
+
+// For /proc/meminfo. Only pages on the free list count as free again, the
+// rest of the region is never given back.
+void mm_stats(size_t *limit, size_t *used) {
+  *limit = source_region.limit;
+  *used = source_region.used - free_page_count * 0x1000;
+}
+
+
+// Make device memory, like a PCI BAR, visible at PTOK(phys), uncached. It's
+// mapped in 2 MiB pages, leaving alone whatever is mapped already. Returns 0
+// on success.
+int mm_map_mmio(uint64_t phys, uint64_t len) {
+  if(len == 0 || phys + len < phys || phys + len > 0x0000008000000000) {
+    return -1;
+  }
+
+  for(uint64_t p = phys & ~(uint64_t)(HUGE_PAGE_SIZE-1); p < phys + len; p += HUGE_PAGE_SIZE) {
+    uint64_t *pdpte = (uint64_t*)PTOK(KERNEL_PDPT) + (p >> 30 & 0x1ff);
+    if(*pdpte == 0) {
+      void *pd = tkalloc(0x1000, "kernel page directory", 0x1000);
+      for(size_t j = 0; j < 0x1000; j++) { // TODO: memzero
+        *((char*)pd + j) = 0;
+      }
+      *pdpte = (uint64_t)KTOP(pd) | 3;
+    }
+
+    uint64_t *pde = (uint64_t*)PTOK(*pdpte & ~(uint64_t)0xfff) + (p >> 21 & 0x1ff);
+    if(*pde == 0) {
+      cor_printk("Mapping device memory %p\n", p);
+      *pde = p | 3 | PAGE_HUGE | PAGE_NOCACHE;
+      __asm__ volatile("invlpg (%0)" : : "r"(PTOK(p)) : "memory");
+    }
+  }
+  return 0;
+}
@@ -1 +1,3 @@
 void mm_init(void);
+void mm_stats(size_t *limit, size_t *used);
+int mm_map_mmio(uint64_t phys, uint64_t len);
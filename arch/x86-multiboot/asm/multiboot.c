@@ -0,0 +1,105 @@
+#include "common.h"
+#include "multiboot.h"
+
+// ref: https://www.gnu.org/software/grub/manual/multiboot/multiboot.html
+
+#define MULTIBOOT_BOOTLOADER_MAGIC 0x2BADB002
+#define MULTIBOOT_INFO_CMDLINE (1<<2)
+#define MULTIBOOT_INFO_MODS (1<<3)
+
+// We only keep track of this many modules, the rest are ignored.
+#define MAX_MODULES 8
+// Longer command lines are cut off.
+#define MAX_CMDLINE 256
+
+// The start of the Multiboot information structure, up to the module list.
+struct multiboot_info {
+  uint32_t flags;
+  uint32_t mem_lower;
+  uint32_t mem_upper;
+  uint32_t boot_device;
+  uint32_t cmdline;
+  uint32_t mods_count;
+  uint32_t mods_addr;
+};
+
+struct multiboot_module {
+  uint32_t start; // physical, like everything else in here
+  uint32_t end; // one past the last byte
+  uint32_t string;
+  uint32_t reserved;
+};
+
+// Set by boot.s from what GRUB left in %eax and %ebx. They live in the
+// low-memory part of the image, so they're accessed through PTOK.
+extern uint32_t multiboot_magic;
+extern uint32_t multiboot_info;
+
+struct module {
+  uint64_t start;
+  uint64_t end;
+};
+
+// GRUB puts its structures wherever it likes, possibly where the kernel heap
+// is going to be, so we copy what we need before mm_init.
+static struct module modules[MAX_MODULES];
+static size_t module_count = 0;
+static char cmdline[MAX_CMDLINE];
+
+void multiboot_init(void) {
+  uint32_t magic = *(uint32_t*)PTOK(&multiboot_magic);
+  if(magic != MULTIBOOT_BOOTLOADER_MAGIC) {
+    cor_printk("Not loaded by a Multiboot boot loader (magic %x), no modules.\n", magic);
+    return;
+  }
+
+  struct multiboot_info *info = PTOK(*(uint32_t*)PTOK(&multiboot_info));
+  if(info->flags & MULTIBOOT_INFO_CMDLINE) {
+    const char *s = PTOK(info->cmdline);
+    size_t i;
+    for(i = 0; i < MAX_CMDLINE - 1 && s[i]; i++) {
+      cmdline[i] = s[i];
+    }
+    cmdline[i] = '\0';
+    cor_printk("Kernel command line: %s\n", cmdline);
+  }
+
+  if(!(info->flags & MULTIBOOT_INFO_MODS)) {
+    return;
+  }
+
+  struct multiboot_module *mods = PTOK(info->mods_addr);
+  for(size_t i = 0; i < info->mods_count && i < MAX_MODULES; i++) {
+    modules[i].start = mods[i].start;
+    modules[i].end = mods[i].end;
+    cor_printk("Multiboot module %u at %x-%x\n", i, modules[i].start, modules[i].end);
+    module_count++;
+  }
+}
+
+// Where the last module ends, or 0 if there are none.
+uint64_t multiboot_modules_end(void) {
+  uint64_t end = 0;
+  for(size_t i = 0; i < module_count; i++) {
+    if(modules[i].end > end) {
+      end = modules[i].end;
+    }
+  }
+  return end;
+}
+
+// The physical memory of the i-th module. Returns 0 if there's no such module.
+int multiboot_module(size_t i, uint64_t *start, uint64_t *end) {
+  if(i >= module_count) {
+    return 0;
+  }
+  *start = modules[i].start;
+  *end = modules[i].end;
+  return 1;
+}
+
+// The kernel command line, including the kernel's own path as GRUB passes it,
+// or an empty string if there was none.
+const char *multiboot_cmdline(void) {
+  return cmdline;
+}
@@ -0,0 +1,4 @@
+void multiboot_init(void);
+uint64_t multiboot_modules_end(void);
+int multiboot_module(size_t i, uint64_t *start, uint64_t *end);
+const char *multiboot_cmdline(void);
@@ -1 +0,0 @@
-void pci_init(void);
@@ -1,70 +1,202 @@
 #include "common.h"
 #include "task.h"
 
-struct task_table_entry *the_task; // yes, we can only have 1 right now
-
 void *initial_pagetable = (void*)0x1000;
 
+// Freed tasks keep their top-level page tables and are reused by task_new.
+static struct task_table_entry *free_tasks = 0;
+
 struct task_table_entry *task_new() {
-  the_task = (struct task_table_entry *)tkalloc(sizeof(struct task_table_entry), "new task struct", 1);
-  the_task->brk = 0;
+  struct task_table_entry *t;
+  if(free_tasks != 0) {
+    t = free_tasks;
+    free_tasks = t->next_free;
+  } else {
+    t = (struct task_table_entry *)tkalloc(sizeof(struct task_table_entry), "new task struct", 1);
+    t->page_table_base = tkalloc(0x3000, "task page table", 0x1000);
+  }
+  t->next_free = 0;
 
   // fill page table
-  the_task->page_table_base = tkalloc(0x4000, "task page table", 0x1000);
-
-  for(size_t j = 0; j < 0x4000; j++) { // TODO: memzero
-    char *t = the_task->page_table_base + j;
-    *t = 0;
+  for(size_t j = 0; j < 0x3000; j++) { // TODO: memzero
+    char *c = t->page_table_base + j;
+    *c = 0;
   }
 
-  // copy over the topmost page table level, so that we copy the higher-half kernel mapping
+  // copy over the topmost page table level, so that we copy the higher-half
+  // kernel mapping. Go through the higher half to read it, the lower half
+  // might be some other task's address space by now.
   for(size_t j = 0; j < 0x1000; j++) { // TODO: memcpy
-    char *loadsrc = initial_pagetable + j;
-    char *loadtarget = the_task->page_table_base + j;
+    char *loadsrc = PTOK(initial_pagetable) + j;
+    char *loadtarget = t->page_table_base + j;
     *loadtarget = *loadsrc;
   }
 
-  *(ptr_t*)(the_task->page_table_base+0x0000) = (ptr_t)KTOP((ptr_t)the_task->page_table_base+0x1003)|4;
-  *(ptr_t*)(the_task->page_table_base+0x1000) = (ptr_t)KTOP((ptr_t)the_task->page_table_base+0x2003)|4;
+  *(ptr_t*)(t->page_table_base+0x0000) = (ptr_t)KTOP((ptr_t)t->page_table_base+0x1003)|4;
+  *(ptr_t*)(t->page_table_base+0x1000) = (ptr_t)KTOP((ptr_t)t->page_table_base+0x2003)|4;
 
+  return t;
+}
 
-  return the_task;
+// Give back every user page and page table of `t`, and `t` itself. If it's
+// the address space we're running in, we move to the kernel's own first.
+void task_free(struct task_table_entry *t) {
+  if((task_current_memspace() & ~(uint64_t)0xfff) == (uint64_t)KTOP(t->page_table_base)) {
+    task_restore_memspace((uint64_t)initial_pagetable);
+  }
+
+  uint64_t *pd = (uint64_t *)((ptr_t)t->page_table_base + 0x2000);
+  for(size_t i = 0; i < 512; i++) {
+    if(pd[i] == 0) {
+      continue;
+    }
+    uint64_t *pt = PTOK(pd[i] & ~((uint64_t)0xfff));
+    for(size_t j = 0; j < 512; j++) {
+      if(pt[j] != 0) {
+        tkfree_page(PTOK(pt[j] & ~((uint64_t)0xfff)));
+      }
+    }
+    tkfree_page(pt);
+    pd[i] = 0;
+  }
+
+  t->next_free = free_tasks;
+  free_tasks = t;
 }
 
-int task_addpage(struct task_table_entry *t, void *offset) {
+// Returns the page table entry for the given user address, allocating the
+// innermost page table for its 2MB region on demand. We only ever set up
+// the first PDPT entry, so user addresses have to stay below 1GB.
+static uint64_t *task_pte(struct task_table_entry *t, void *offset, int create) {
+  if((ptr_t)offset >= 0x40000000) {
+    cor_printk("[!] user address %p is outside of the task address space\n", offset);
+    return 0;
+  }
+
   uint16_t whichpage = (ptr_t)offset >> 12 & ((1<<9)-1);
   uint16_t whichtbl = (ptr_t)offset >> 21 & ((1<<9)-1);
-  cor_printk("Mapping page %x in table %x\n", whichpage, whichtbl);
 
   uint64_t *pdloc = (uint64_t *)((ptr_t)t->page_table_base + 0x2000 + whichtbl*8);
-  uint64_t pdentry = (uint64_t)KTOP(t->page_table_base + 0x3000) | 3 | 4;
-  cor_printk("Adding page directory entry %p --> %p\n", pdloc, pdentry);
-  *pdloc = pdentry;
+  if(*pdloc == 0) {
+    if(!create) {
+      return 0;
+    }
+
+    void *pt = tkalloc_page("task page table");
+    uint64_t pdentry = (uint64_t)KTOP(pt) | 3 | 4;
+    debug("Adding page directory entry %p --> %p\n", pdloc, pdentry);
+    *pdloc = pdentry;
+  }
 
-  uint64_t *ptloc = (uint64_t *)((ptr_t)t->page_table_base + 0x3000 + whichpage*8);
+  uint64_t *pt = PTOK(*pdloc & ~((uint64_t)0xfff));
+  return pt + whichpage;
+}
+
+// A new task with a copy of every user page of `src`, mapped with the same
+// flags, for fork.
+struct task_table_entry *task_copy(struct task_table_entry *src) {
+  struct task_table_entry *t = task_new();
+
+  uint64_t *pd = (uint64_t *)((ptr_t)src->page_table_base + 0x2000);
+  for(size_t i = 0; i < 512; i++) {
+    if(pd[i] == 0) {
+      continue;
+    }
+    uint64_t *pt = PTOK(pd[i] & ~((uint64_t)0xfff));
+    for(size_t j = 0; j < 512; j++) {
+      if(pt[j] == 0) {
+        continue;
+      }
+      void *offset = (void*)(i << 21 | j << 12);
+      char *from = PTOK(pt[j] & ~((uint64_t)0xfff));
+      char *to = tkalloc_page("task page");
+      for(size_t k = 0; k < 0x1000; k++) { // TODO: memcpy
+        to[k] = from[k];
+      }
+      *task_pte(t, offset, 1) = (uint64_t)KTOP(to) | (pt[j] & 7);
+    }
+  }
+
+  return t;
+}
+
+static void task_invalidate(void *offset) {
+  __asm__ (
+    "invlpg (%0)"
+    :
+    : "r"(offset)
+    : "memory"
+    );
+}
+
+// `flags` are the page table entry bits besides "present", i.e. 2 for
+// writable and 4 for user-accessible.
+int task_addpage(struct task_table_entry *t, void *offset, uint64_t flags) {
+  debug("Mapping page %p with flags %x\n", offset, flags);
+
+  uint64_t *ptloc = task_pte(t, offset, 1);
+  if(ptloc == 0) {
+    return -1;
+  }
 
   if(*ptloc != 0) {
     // abort if we have already mapped this page.
-    // TODO: is it OK to have the above pointer set before this check?
     cor_printk("[!] tried to remap page, aborting.\n");
     return -1;
   }
 
   // this gives us the page in kernel memory space
-  void *kpage = tkalloc(0x1000, "task page", 0x1000);
+  void *kpage = tkalloc_page("task page");
   // this gives us its physical address
   void *phys = KTOP(kpage);
   // construct the page table entry from this address
-  uint64_t ptentry = (ptr_t)phys | 3 | 4;
-  cor_printk("Adding page table entry %p --> %p\n", ptloc, ptentry);
+  uint64_t ptentry = (ptr_t)phys | 1 | (flags & 6);
+  debug("Adding page table entry %p --> %p\n", ptloc, ptentry);
 
   *ptloc = ptentry;
 
   return 0;
 }
 
+int task_removepage(struct task_table_entry *t, void *offset) {
+  uint64_t *ptloc = task_pte(t, offset, 0);
+  if(ptloc == 0 || *ptloc == 0) {
+    return -1;
+  }
+
+  tkfree_page(PTOK(*ptloc & ~((uint64_t)0xfff)));
+  *ptloc = 0;
+  task_invalidate(offset);
+  return 0;
+}
+
+int task_protectpage(struct task_table_entry *t, void *offset, uint64_t flags) {
+  uint64_t *ptloc = task_pte(t, offset, 0);
+  if(ptloc == 0 || *ptloc == 0) {
+    return -1;
+  }
+
+  *ptloc = (*ptloc & ~((uint64_t)6)) | (flags & 6);
+  task_invalidate(offset);
+  return 0;
+}
+
+// What the scheduler saves and restores around a task switch, so that every
+// kernel task stays in the address space it was running in.
+uint64_t task_current_memspace() {
+  uint64_t cr3;
+  __asm__ volatile("mov %%cr3, %0" : "=r"(cr3));
+  return cr3;
+}
+
+void task_restore_memspace(uint64_t cr3) {
+  if(task_current_memspace() != cr3) {
+    __asm__ volatile("mov %0, %%cr3" : : "r"(cr3) : "memory");
+  }
+}
+
 void task_enter_memspace(struct task_table_entry *t) {
-  cor_printk("Entering task memspace\n");
+  debug("Entering task memspace\n");
   __asm__ (
     "mov %0, %%rax\n"
     "mov %%rax, %%cr3"
@@ -73,21 +205,3 @@ void task_enter_memspace(struct task_table_entry *t) {
     : "rax"
     );
 }
-
-// TODO fix this
-#define MAXALLOC 0x10000
-uint64_t syscall_moremem(uint64_t insize) {
-  cor_printk("syscall_moremem() size=%x\n", insize);
-
-  size_t size = (size_t)insize;
-  if(0 < size && size <= MAXALLOC) {
-    cor_printk("addpage'ing at brk %p\n", the_task->brk);
-    task_addpage(the_task, the_task->brk);
-    void *oldbrk = the_task->brk;
-    the_task->brk += 0x1000;
-    return (uint64_t)oldbrk;
-  } else {
-    cor_printk("invalid size given for syscall_moremem()\n");
-    return -1;
-  }
-}
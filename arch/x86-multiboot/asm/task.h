@@ -4,10 +4,16 @@
 struct task_table_entry {
   void *page_table_base;
   void *page_table_useddir;
-  void *brk;
+  struct task_table_entry *next_free;
 };
 
 // TODO: should the caller alloc this?
 struct task_table_entry *task_new();
-int task_addpage(struct task_table_entry *t, void *page);
+void task_free(struct task_table_entry *t);
+struct task_table_entry *task_copy(struct task_table_entry *src);
+int task_addpage(struct task_table_entry *t, void *page, uint64_t flags);
+int task_removepage(struct task_table_entry *t, void *page);
+int task_protectpage(struct task_table_entry *t, void *page, uint64_t flags);
 void task_enter_memspace(struct task_table_entry *t);
+uint64_t task_current_memspace();
+void task_restore_memspace(uint64_t cr3);
@@ -15,9 +15,29 @@ trampoline_to_user_codeseg:
 trampoline_to_user_raxval:
   .quad 0
 
+# value to load into rdi, i.e. the first argument when entering a signal handler
+.globl trampoline_to_user_rdival
+trampoline_to_user_rdival:
+  .quad 0
+
+# the process's frame pointer, which survives syscalls like on Linux
+.globl trampoline_to_user_rbpval
+trampoline_to_user_rbpval:
+  .quad 0
+
 trampoline_previous_kernel_rsp:
   .quad 0
 
+# Every register of a process that the timer interrupted: rax, rbx, rcx, rdx,
+# rsi, rdi, rbp, r8 to r15, and rflags. If trampoline_to_user_resume is set,
+# all of them are restored on the way back in.
+.globl trampoline_user_regs
+trampoline_user_regs:
+  .fill 16, 8, 0
+.globl trampoline_to_user_resume
+trampoline_to_user_resume:
+  .quad 0
+
 .globl trampoline_to_user
 trampoline_to_user:
     # TODO: what about interrupts in here?
@@ -44,12 +64,54 @@ trampoline_to_user:
     pushq %rcx
     pushq %rax
 
-    # Store syscall ret value
-    #movabs trampoline_to_user_raxval, %rax
+    movabs trampoline_to_user_resume, %rax
+    test %rax, %rax
+    jnz trampoline_resume
+
+    # Store signal handler argument, frame pointer and syscall ret value
+    movabs trampoline_to_user_rdival, %rax
+    mov %rax, %rdi
+    movabs trampoline_to_user_rbpval, %rax
+    mov %rax, %rbp
+    movabs trampoline_to_user_raxval, %rax
 
     # Bye
     iretq
 
+trampoline_resume:
+    movabs trampoline_user_regs+120, %rax
+    mov %rax, 16(%rsp) # rflags, after rip and cs
+    movabs trampoline_user_regs+8, %rax
+    mov %rax, %rbx
+    movabs trampoline_user_regs+16, %rax
+    mov %rax, %rcx
+    movabs trampoline_user_regs+24, %rax
+    mov %rax, %rdx
+    movabs trampoline_user_regs+32, %rax
+    mov %rax, %rsi
+    movabs trampoline_user_regs+40, %rax
+    mov %rax, %rdi
+    movabs trampoline_user_regs+48, %rax
+    mov %rax, %rbp
+    movabs trampoline_user_regs+56, %rax
+    mov %rax, %r8
+    movabs trampoline_user_regs+64, %rax
+    mov %rax, %r9
+    movabs trampoline_user_regs+72, %rax
+    mov %rax, %r10
+    movabs trampoline_user_regs+80, %rax
+    mov %rax, %r11
+    movabs trampoline_user_regs+88, %rax
+    mov %rax, %r12
+    movabs trampoline_user_regs+96, %rax
+    mov %rax, %r13
+    movabs trampoline_user_regs+104, %rax
+    mov %rax, %r14
+    movabs trampoline_user_regs+112, %rax
+    mov %rax, %r15
+    movabs trampoline_user_regs, %rax
+    iretq
+
 
 .globl trampoline_from_user
 trampoline_from_user:
@@ -62,6 +124,12 @@ trampoline_from_user:
     movabs %rax, trampoline_from_user_arg3
     mov %rdx, %rax
     movabs %rax, trampoline_from_user_arg4
+    mov %rsi, %rax
+    movabs %rax, trampoline_from_user_arg5
+    mov %rdi, %rax
+    movabs %rax, trampoline_from_user_arg6
+    mov %r8, %rax
+    movabs %rax, trampoline_from_user_arg7
 
     mov (%rsp), %rax
     movabs %rax, trampoline_from_user_rip
@@ -69,6 +137,8 @@ trampoline_from_user:
     movabs %rax, trampoline_from_user_codeseg
     mov 24(%rsp), %rax
     movabs %rax, trampoline_from_user_rsp
+    mov %rbp, %rax
+    movabs %rax, trampoline_from_user_rbp
 
     movabs trampoline_previous_kernel_rsp, %rax
     mov %rax, %rsp
@@ -94,6 +164,15 @@ trampoline_from_user_arg3:
 .globl trampoline_from_user_arg4
 trampoline_from_user_arg4:
   .quad 0
+.globl trampoline_from_user_arg5
+trampoline_from_user_arg5:
+  .quad 0
+.globl trampoline_from_user_arg6
+trampoline_from_user_arg6:
+  .quad 0
+.globl trampoline_from_user_arg7
+trampoline_from_user_arg7:
+  .quad 0
 
 
 .globl trampoline_from_user_rip
@@ -105,3 +184,6 @@ trampoline_from_user_rsp:
 .globl trampoline_from_user_codeseg
 trampoline_from_user_codeseg:
   .quad 0
+.globl trampoline_from_user_rbp
+trampoline_from_user_rbp:
+  .quad 0
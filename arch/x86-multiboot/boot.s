@@ -25,6 +25,11 @@ _start:
 go:
   cli
 
+  # GRUB tells us about itself in %eax and where the Multiboot information
+  # is in %ebx, which we're about to need for other things. See multiboot.c.
+  movl %eax, multiboot_magic
+  movl %ebx, multiboot_info
+
   lgdt gdt_descriptor
 
   # So, we're going to set up our page tables starting at 0x1000. The control
@@ -200,6 +205,15 @@ broken:
   hlt
 
 
+# Filled in right at the start, see above.
+.align 4
+.global multiboot_magic
+multiboot_magic:
+  .long 0
+.global multiboot_info
+multiboot_info:
+  .long 0
+
 # This is some strange metadata struct that points to the GDT.
 gdt_descriptor:
   .word (3 * 8) - 1 # GDT size in bytes - 1, 3 is the number of entries
@@ -11,3 +11,220 @@ Feature: Getting started
       """
     When I run the machine
     Then I should see "Hello, world from userspace!"
+
+  Scenario: Passing data through a pipe
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+
+      int main() {
+        int fds[2];
+        char buf[17] = {0};
+
+        if (pipe(fds) != 0) {
+          printf("pipe failed\n");
+          return 1;
+        }
+
+        write(fds[1], "through the pipe", 16);
+        close(fds[1]);
+
+        int n = read(fds[0], buf, 16);
+        int eof = read(fds[0], buf + n, 1);
+        printf("read %u: '%s'\n", n, buf);
+        printf("then %u\n", eof);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "read 16: 'through the pipe'"
+    And I should see "then 0"
+
+  Scenario: Two processes blocking on each other through pipes
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <sys/wait.h>
+
+      int main() {
+        int down[2], up[2];
+        static char buf[5000];
+
+        pipe(down);
+        pipe(up);
+
+        int pid = fork();
+        if (pid == 0) {
+          close(down[1]);
+          close(up[0]);
+
+          // Only 4096 bytes fit, so this waits for the parent to read.
+          printf("child wrote %d\n", write(up[1], buf, 5000));
+
+          char ping[5] = {0};
+          printf("child read %d: '%s'\n", read(down[0], ping, 4), ping);
+          return 7;
+        }
+
+        close(down[0]);
+        close(up[1]);
+
+        // Waits for the child to write, until it exits.
+        int n, total = 0;
+        while ((n = read(up[0], buf, sizeof(buf))) > 0) {
+          if (total == 0) {
+            write(down[1], "ping", 4);
+          }
+          total += n;
+        }
+        printf("parent read %d\n", total);
+
+        int status;
+        printf("reaped child: %d\n", waitpid(pid, &status, 0) == pid);
+        printf("exit status: %d\n", WEXITSTATUS(status));
+        printf("no more children: %d\n", wait(&status));
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "child wrote 5000"
+    And I should see "child read 4: 'ping'"
+    And I should see "parent read 5000"
+    And I should see "reaped child: 1"
+    And I should see "exit status: 7"
+    And I should see "no more children: -10"
+
+  Scenario: The console is a terminal
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <termios.h>
+
+      int main() {
+        struct termios t;
+        if (!isatty(0) || tcgetattr(0, &t) != 0) {
+          printf("not a tty\n");
+          return 1;
+        }
+        printf("canonical: %u\n", (t.c_lflag & ICANON) != 0);
+
+        t.c_lflag &= ~(ICANON | ECHO);
+        tcsetattr(0, TCSANOW, &t);
+        tcgetattr(0, &t);
+        printf("raw: %u\n", (t.c_lflag & ICANON) == 0);
+
+        printf("foreground: %u\n", tcgetpgrp(0) == getpgrp());
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "canonical: 1"
+    And I should see "raw: 1"
+    And I should see "foreground: 1"
+
+  Scenario: Booting from an initrd
+    Given GRUB loads the root filesystem as an initrd
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <sys/stat.h>
+
+      int main() {
+        struct stat st;
+        printf("ram0: %u\n", stat("/dev/ram0", &st) == 0);
+        printf("vda: %u\n", stat("/dev/vda", &st) == 0);
+        printf("init on /: %u\n", stat("/init", &st) == 0);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "ram0: 1"
+    And I should see "vda: 0"
+    And I should see "init on /: 1"
+
+  Scenario: Options on the kernel command line
+    Given a second disk with a copy of the root filesystem that also has init as "/sbin/other"
+    And the kernel command line "root=vdb init=/sbin/other loglevel=0 test=hello"
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <sys/stat.h>
+
+      int main(int argc, char **argv, char **envp) {
+        struct stat st;
+        printf("argv[0]: %s\n", argv[0]);
+        printf("second disk: %u\n", stat("/second-disk", &st) == 0);
+        for (char **e = envp; *e; e++) {
+          printf("env: %s\n", *e);
+        }
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "argv[0]: /sbin/other"
+    And I should not have seen "cmdline:" before that
+    And I should see "second disk: 1"
+    And I should see "env: TEST=hello"
+
+  Scenario Outline: How long processes run before giving others a turn
+    Given the kernel command line "sched.timeslice=<timeslice>"
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <sys/wait.h>
+
+      // Both write a letter at a time into the same pipe, so what comes out
+      // shows how often they took turns.
+      int main() {
+        char buf[64];
+        int fds[2], i, n, turns = 0;
+        pipe(fds);
+
+        int pid = fork();
+        char me = pid == 0 ? 'c' : 'p';
+        for (i = 0; i < 20; i++) {
+          write(fds[1], &me, 1);
+        }
+        if (pid == 0) {
+          return 0;
+        }
+
+        waitpid(pid, 0, 0);
+        n = read(fds[0], buf, sizeof(buf));
+        for (i = 1; i < n; i++) {
+          turns += buf[i] != buf[i - 1];
+        }
+        printf("read %d, took turns more than once: %u\n", n, turns > 1);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "read 40, took turns more than once: <interleaved>"
+
+    Examples:
+      | timeslice | interleaved |
+      | 1         | 1           |
+      | 1000      | 0           |
+
+  Scenario Outline: Booting from a <kind>-only virtio disk
+    Given the boot disk is a <kind>-only virtio device
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+
+      int main() {
+        printf("Hello from a <kind> disk!\n");
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "Hello from a <kind> disk!"
+
+    Examples:
+      | kind   |
+      | legacy |
+      | modern |
@@ -0,0 +1,26 @@
+Feature: Device files
+  Scenario: Reading and writing the memory devices
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+
+      int main() {
+        char buf[8] = {1, 1, 1, 1, 1, 1, 1, 1};
+
+        int null = open("/dev/null", 0);
+        int zero = open("/dev/zero", 0);
+        printf("null reads %u\n", read(null, buf, 8));
+        printf("null takes %u\n", write(null, buf, 8));
+        printf("zero reads %u\n", read(zero, buf, 8));
+        printf("zeroes: %u\n", buf[0] == 0 && buf[7] == 0);
+        printf("no such device: %u\n", open("/dev/nonexistent", 0) < 0);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "null reads 0"
+    And I should see "null takes 8"
+    And I should see "zero reads 8"
+    And I should see "zeroes: 1"
+    And I should see "no such device: 1"
@@ -40,3 +40,213 @@ Feature: Disk access
       """
     When I run the machine
     Then I should see "in file: 'hi from file'"
+
+  Scenario: Writing to a block device
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+
+      int main() {
+        char sector[512], again[512];
+        int fd = open("/dev/vda", O_RDONLY);
+        read(fd, sector, 512);
+        close(fd);
+
+        // Write the first sector back as it was, so the root filesystem survives.
+        fd = open("/dev/vda", O_RDWR);
+        printf("wrote %d\n", write(fd, sector, 512));
+        close(fd);
+        sync();
+
+        fd = open("/dev/vda", O_RDONLY);
+        read(fd, again, 512);
+        int same = 1;
+        for(int i = 0; i < 512; i++) {
+          same = same && sector[i] == again[i];
+        }
+        printf("same after writing: %u\n", same);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "wrote 512"
+    And I should see "same after writing: 1"
+
+  Scenario: The initrd is a writable RAM disk
+    Given GRUB loads the root filesystem as an initrd
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+
+      int main() {
+        char sector[512], flipped[512], again[512];
+        int fd = open("/dev/ram0", O_RDWR);
+        read(fd, sector, 512);
+        // The root filesystem is a cpio archive.
+        printf("archive: %u\n", sector[0] == '0' && sector[1] == '7' && sector[2] == '0' && sector[3] == '7');
+
+        for(int i = 0; i < 512; i++) {
+          flipped[i] = sector[i] ^ 0xff;
+        }
+        lseek(fd, 0, SEEK_SET);
+        printf("wrote %d\n", (int)write(fd, flipped, 512));
+        lseek(fd, 0, SEEK_SET);
+        read(fd, again, 512);
+        int same = 1;
+        for(int i = 0; i < 512; i++) {
+          same = same && flipped[i] == again[i];
+        }
+        printf("same after writing: %u\n", same);
+
+        lseek(fd, 0, SEEK_SET);
+        write(fd, sector, 512);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "archive: 1"
+    And I should see "wrote 512"
+    And I should see "same after writing: 1"
+
+  Scenario: Seeking on a block device
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+
+      int main() {
+        char sector[512];
+        int fd = open("/dev/vda", O_RDONLY);
+        long size = lseek(fd, 0, SEEK_END);
+        printf("whole sectors: %u\n", size > 0 && size % 512 == 0);
+        printf("at the end: %d\n", (int)read(fd, sector, 512));
+        printf("past the end: %d\n", (int)lseek(fd, 1, SEEK_END));
+
+        lseek(fd, -512, SEEK_END);
+        printf("last sector: %d\n", (int)read(fd, sector, 512));
+        printf("then: %d\n", (int)read(fd, sector, 512));
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "whole sectors: 1"
+    And I should see "at the end: 0"
+    And I should see "past the end: -22"
+    And I should see "last sector: 512"
+    And I should see "then: 0"
+
+  Scenario: Reading many sectors of a block device at once
+    Given I attach a disk where every sector starts with its number as a second disk
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+
+      static char buf[16384];
+
+      // The number after "sector ".
+      static int number(const char *s) {
+        int n = 0;
+        for (s += 7; *s >= '0' && *s <= '9'; s++)
+          n = n * 10 + *s - '0';
+        return n;
+      }
+
+      int main() {
+        int i, ok = 1;
+        int fd = open("/dev/vdb", O_RDONLY);
+        lseek(fd, 128 * 512, SEEK_SET);
+        printf("read %d\n", (int)read(fd, buf, sizeof(buf)));
+        for (i = 0; i < 32; i++) {
+          char *sector = buf + i * 512;
+          ok = ok && sector[0] == 's' && number(sector) == 128 + i;
+        }
+        printf("sectors 128 to 159 in order: %d\n", ok);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "virtio blockdev: Reading 32 buffer(s) from sector 128"
+    And I should see "read 16384"
+    And I should see "sectors 128 to 159 in order: 1"
+
+  Scenario: Two processes reading a block device at the same time
+    Given I attach a disk where every sector starts with its number as a second disk
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+      #include <sys/wait.h>
+
+      static char buf[4096];
+
+      // The number after "sector ".
+      static int number(const char *s) {
+        int n = 0;
+        for (s += 7; *s >= '0' && *s <= '9'; s++)
+          n = n * 10 + *s - '0';
+        return n;
+      }
+
+      // Reads sectors first..first+512 a few at a time, checking each one.
+      static int check(int first) {
+        int i, j, ok = 1;
+        int fd = open("/dev/vdb", O_RDONLY);
+        lseek(fd, first * 512, SEEK_SET);
+        for (i = 0; i < 512; i += 8) {
+          ok = ok && read(fd, buf, sizeof(buf)) == sizeof(buf);
+          for (j = 0; j < 8; j++)
+            ok = ok && buf[j * 512] == 's' && number(buf + j * 512) == first + i + j;
+        }
+        close(fd);
+        return ok;
+      }
+
+      int main() {
+        int status;
+        int pid = fork();
+        if (pid == 0)
+          return check(1024) ? 0 : 1;
+
+        int ok = check(0);
+        waitpid(pid, &status, 0);
+        printf("child ok: %d\n", WIFEXITED(status) && WEXITSTATUS(status) == 0);
+        printf("parent ok: %d\n", ok);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "child ok: 1"
+    And I should see "parent ok: 1"
+
+  Scenario: Mounting a partition next to the root filesystem
+    Given the boot disk is partitioned, with an ext2 partition containing "hi from the data partition"
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+      #include <sys/mount.h>
+
+      int main() {
+        char buf[64];
+        int n, fd;
+
+        printf("mounted: %d\n", mount("/dev/vda2", "/mnt", "ext2"));
+        fd = open("/mnt/hello.txt", O_RDONLY);
+        n = read(fd, buf, sizeof(buf) - 1);
+        buf[n > 0 ? n : 0] = 0;
+        printf("in file: '%s'\n", buf);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "mounted: 0"
+    And I should see "in file: 'hi from the data partition'"
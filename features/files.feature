@@ -0,0 +1,227 @@
+Feature: Files and directories
+  Scenario: Listing and walking the root filesystem
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+      #include <dirent.h>
+      #include <sys/stat.h>
+
+      int main(int argc, char **argv) {
+        printf("argv[0] is %s\n", argv[0]);
+
+        DIR *d = opendir("/");
+        struct dirent *e;
+        while((e = readdir(d))) {
+          if(e->d_type == DT_DIR) {
+            printf("dir %s\n", e->d_name);
+          }
+        }
+        closedir(d);
+
+        struct stat st;
+        stat("/init", &st);
+        printf("init is a file: %u\n", S_ISREG(st.st_mode));
+        stat("/dev/null", &st);
+        printf("null is a device: %u\n", S_ISCHR(st.st_mode));
+
+        char cwd[64];
+        chdir("/dev");
+        printf("cwd %s\n", getcwd(cwd, sizeof(cwd)));
+        printf("found init from /dev: %u\n", open("../init", O_RDONLY) >= 0);
+        printf("no such file: %u\n", open("/nonexistent", O_RDONLY) < 0);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "argv[0] is /init"
+    And I should see "dir proc"
+    And I should see "init is a file: 1"
+    And I should see "null is a device: 1"
+    And I should see "cwd /dev"
+    And I should see "found init from /dev: 1"
+    And I should see "no such file: 1"
+
+  Scenario: Directories in the root archive have their own entries
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <dirent.h>
+      #include <sys/stat.h>
+
+      int main() {
+        int n = 0;
+        DIR *d = opendir("/mnt");
+        while(readdir(d)) {
+          n++;
+        }
+        printf("/mnt has %u entries\n", n);
+
+        struct stat st;
+        printf("no /mnt/init: %u\n", stat("/mnt/init", &st) < 0);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "/mnt has 2 entries"
+    And I should see "no /mnt/init: 1"
+
+  Scenario: Scratch files in /tmp
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+      #include <sys/stat.h>
+
+      int main() {
+        char buf[16] = {0};
+        struct stat st;
+
+        printf("mkdir: %d\n", mkdir("/tmp/dir", 0755));
+        int fd = open("/tmp/dir/a", O_WRONLY | O_CREAT, 0644);
+        printf("wrote %d\n", write(fd, "scratch", 7));
+        close(fd);
+
+        printf("rename: %d\n", rename("/tmp/dir/a", "/tmp/b"));
+        fd = open("/tmp/b", O_RDONLY);
+        read(fd, buf, 15);
+        printf("read back %s\n", buf);
+        close(fd);
+
+        printf("rmdir: %d\n", rmdir("/tmp/dir"));
+        printf("unlink: %d\n", unlink("/tmp/b"));
+        printf("gone: %u\n", stat("/tmp/b", &st) < 0);
+        printf("read-only root: %u\n", open("/new", O_WRONLY | O_CREAT, 0644) < 0);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "mkdir: 0"
+    And I should see "wrote 7"
+    And I should see "rename: 0"
+    And I should see "read back scratch"
+    And I should see "rmdir: 0"
+    And I should see "unlink: 0"
+    And I should see "gone: 1"
+    And I should see "read-only root: 1"
+
+  Scenario: Unlinked files in /tmp stay open, and a full /tmp fails with ENOSPC
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+
+      static char chunk[65536];
+
+      int main() {
+        char buf[16] = {0};
+        int fd = open("/tmp/a", O_RDWR | O_CREAT, 0644);
+        write(fd, "still here", 10);
+        printf("unlink: %d\n", unlink("/tmp/a"));
+        lseek(fd, 0, SEEK_SET);
+        read(fd, buf, 15);
+        printf("read back %s\n", buf);
+        close(fd);
+
+        fd = open("/tmp/big", O_WRONLY | O_CREAT, 0644);
+        long n, total = 0;
+        while ((n = write(fd, chunk, sizeof(chunk))) > 0) {
+          total += n;
+        }
+        printf("full after %ld KiB: %ld\n", total / 1024, n);
+        close(fd);
+        printf("unlink: %d\n", unlink("/tmp/big"));
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "unlink: 0"
+    And I should see "read back still here"
+    And I should see "full after 1024 KiB: -28"
+    And I should see "unlink: 0"
+
+  Scenario: Mounting a filesystem from a block device
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <sys/stat.h>
+      #include <sys/mount.h>
+
+      int main() {
+        struct stat st;
+        printf("not ext2: %u\n", mount("/dev/vda", "/mnt", "ext2") < 0);
+        printf("not a block device: %u\n", mount("/dev/null", "/mnt", "cpio") < 0);
+        printf("cpio: %d\n", mount("/dev/vda", "/mnt", "cpio"));
+        printf("init in /mnt: %u\n", stat("/mnt/init", &st) == 0);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "not ext2: 1"
+    And I should see "not a block device: 1"
+    And I should see "cpio: 0"
+    And I should see "init in /mnt: 1"
+
+  Scenario: A crc cpio archive with a corrupted file
+    Given I attach a crc cpio archive with a corrupted "hello.txt" as a second disk
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <sys/stat.h>
+      #include <sys/mount.h>
+
+      int main() {
+        struct stat st;
+        mount("/dev/vdb", "/mnt", "cpio");
+        printf("hello.txt in /mnt: %u\n", stat("/mnt/hello.txt", &st) == 0);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "cpio: bad checksum for hello.txt"
+    And I should see "hello.txt in /mnt: 0"
+
+  Scenario: Reading and writing a vfat filesystem made on the host
+    Given I attach a vfat image with "A long file name.txt" containing "hello from the host" as a second disk
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+      #include <sys/stat.h>
+      #include <sys/mount.h>
+
+      int main() {
+        char buf[32] = {0};
+        struct stat before, after;
+        printf("mount: %d\n", mount("/dev/vdb", "/mnt", "vfat"));
+
+        int fd = open("/mnt/A long file name.txt", O_RDONLY);
+        read(fd, buf, sizeof(buf) - 1);
+        printf("read: %s\n", buf);
+        close(fd);
+
+        fd = open("/mnt/new.txt", O_WRONLY | O_CREAT, 0644);
+        fstat(fd, &before);
+        printf("rename: %d\n", rename("/mnt/new.txt", "/mnt/Written by cor.txt"));
+        stat("/mnt/Written by cor.txt", &after);
+        printf("same inode: %u\n", before.st_ino == after.st_ino);
+        // Still the same file for the descriptor from before the rename
+        printf("wrote %d\n", write(fd, "hello from cor", 14));
+        close(fd);
+        sync();
+        printf("synced\n");
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "mount: 0"
+    And I should see "read: hello from the host"
+    And I should see "rename: 0"
+    And I should see "same inode: 1"
+    And I should see "wrote 14"
+    And I should see "synced"
+    And the vfat image passes fsck and has "Written by cor.txt" containing "hello from cor"
@@ -0,0 +1,67 @@
+Feature: Journalling
+  In order not to lose my files when the power goes out
+  As a kernel user
+  I want the filesystem to be consistent after a crash at any point
+
+  Scenario: An ext3 filesystem survives crashes at random points
+    Given an empty ext3 disk image
+    And the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+      #include <sys/stat.h>
+      #include <sys/mount.h>
+
+      // Typing "check" on the console just mounts the disk, which replays
+      // the journal, and syncs. Anything else writes to it until killed.
+
+      static char *name(char *buf, const char *prefix, unsigned n) {
+        char *p = buf;
+        while(*prefix) {
+          *p++ = *prefix++;
+        }
+        *p++ = 'a' + n % 26;
+        *p++ = 'a' + n / 26 % 26;
+        *p = 0;
+        return buf;
+      }
+
+      int main() {
+        char a[32], b[32], buf[4096];
+        unsigned i;
+
+        printf("mode?\n");
+        read(0, buf, sizeof(buf));
+        if(mount("/dev/vdb", "/mnt", "ext2") < 0) {
+          printf("mount failed\n");
+          return 1;
+        }
+        if(buf[0] == 'c') {
+          sync();
+          printf("synced\n");
+          return 0;
+        }
+        mkdir("/mnt/d", 0755);
+        for(i = 0; i < sizeof(buf); i++) {
+          buf[i] = 'x';
+        }
+        printf("hammering\n");
+
+        for(i = 0; ; i++) {
+          int fd = open(name(a, "/mnt/f", i % 50), O_WRONLY | O_CREAT, 0644);
+          write(fd, buf, (i * 37) % sizeof(buf));
+          ftruncate(fd, (i * 13) % 3000);
+          close(fd);
+          rename(a, name(b, "/mnt/d/g", i % 7));
+          if(i % 3 == 0) {
+            unlink(b);
+          }
+          if(i % 11 == 0 && mkdir(name(a, "/mnt/d/sub", i % 5), 0755) < 0) {
+            rmdir(a);
+          }
+        }
+      }
+      """
+    When I crash the machine at 10 random points while it writes
+    Then the disk image should be consistent after every crash
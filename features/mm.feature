@@ -66,3 +66,54 @@ Feature: Userland memory management
       """
     When I run the machine
     Then I should see "The number is ->25<-"
+
+  Scenario: Growing the heap with sbrk
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+
+      int main() {
+        char *a = sbrk(0);
+        char *b = sbrk(0x3000);
+        char *c = sbrk(0);
+
+        b[0x2fff] = 42;
+
+        if (a == b && c == b + 0x3000) {
+          printf("brk moved by ->%x<-\n", (unsigned int)(c - a));
+        }
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "brk moved by ->0x3000<-"
+
+  Scenario: Anonymous memory mappings
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <sys/mman.h>
+
+      int main() {
+        unsigned int *p = mmap(0, 0x2000, PROT_READ|PROT_WRITE, MAP_PRIVATE|MAP_ANONYMOUS, -1, 0);
+        if (p == MAP_FAILED) {
+          printf("mmap failed\n");
+          return 1;
+        }
+
+        printf("fresh page is ->%u<-\n", p[0x1000/4]);
+        p[0] = 1234;
+        printf("stored ->%u<-\n", p[0]);
+
+        if (mprotect(p, 0x1000, PROT_READ) != 0 || munmap(p, 0x2000) != 0) {
+          printf("mprotect/munmap failed\n");
+          return 1;
+        }
+        printf("unmapped\n");
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "fresh page is ->0<-"
+    And I should see "stored ->1234<-"
+    And I should see "unmapped"
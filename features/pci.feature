@@ -11,3 +11,17 @@ Feature: PCI device detection
     Given I attach a virtio network interface to the machine
     When I run the machine
     Then I should see "this is a virtio NIC"
+
+  Scenario: Binding drivers to what the scan found
+    When I run the machine
+    Then I should see "is driven by virtio-blk"
+
+  Scenario Outline: Negotiating features with a <kind>-only virtio disk
+    Given the boot disk is a <kind>-only virtio device
+    When I run the machine
+    Then I should see a line matching "The device offered feature bits 0x[0-9a-f]+, we took <features>$"
+
+    Examples:
+      | kind   | features    |
+      | legacy | 0x30000200  |
+      | modern | 0x130000200 |
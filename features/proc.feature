@@ -0,0 +1,38 @@
+Feature: /proc
+  Scenario: Looking at a process and the machine through /proc
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <unistd.h>
+      #include <fcntl.h>
+
+      char buf[1024];
+
+      void show(const char *path) {
+        int fd = open(path, O_RDONLY);
+        int n = read(fd, buf, sizeof(buf) - 1);
+        buf[n < 0 ? 0 : n] = 0;
+        printf("%s:\n%s", path, buf);
+        close(fd);
+      }
+
+      int main() {
+        show("/proc/1/status");
+        show("/proc/1/maps");
+        show("/proc/devices");
+        show("/proc/interrupts");
+
+        long n = readlink("/proc/1/fd/0", buf, sizeof(buf) - 1);
+        buf[n < 0 ? 0 : n] = 0;
+        printf("fd 0 is %s\n", buf);
+        printf("no pid 2: %u\n", open("/proc/2/status", O_RDONLY) < 0);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "VmSize:"
+    And I should see "[stack]"
+    And I should see a line matching "virtio mem 0x[0-9a-f]+ irq 0x[0-9a-f]+ block"
+    And I should see "IRQ       COUNT  HANDLERS"
+    And I should see "fd 0 is /dev/console"
+    And I should see "no pid 2: 1"
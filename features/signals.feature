@@ -0,0 +1,155 @@
+Feature: Signals
+  Scenario: Catching a signal
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <signal.h>
+
+      static int caught = 0;
+
+      void handler(int sig) {
+        caught = sig;
+      }
+
+      int main() {
+        signal(SIGUSR1, handler);
+        raise(SIGUSR1);
+        printf("caught signal %u\n", caught);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "caught signal 10"
+
+  Scenario: Faulting processes get SIGSEGV
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+
+      int main() {
+        printf("about to fault\n");
+        *(volatile int *)0x8 = 1;
+        printf("still alive\n");
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "pid 1 was killed by signal 11"
+
+  Scenario: Ctrl-C interrupts a busy foreground process
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <signal.h>
+
+      static volatile int caught = 0;
+
+      void handler(int sig) {
+        caught = sig;
+      }
+
+      int main() {
+        signal(SIGINT, handler);
+        printf("busy\n");
+        while (!caught) {}
+        printf("interrupted with %u\n", caught);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "busy"
+    When I press Ctrl-C
+    Then I should see "interrupted with 2"
+
+  Scenario: A read interrupted by Ctrl-C fails or restarts depending on SA_RESTART
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <signal.h>
+      #include <unistd.h>
+
+      static volatile int caught = 0;
+
+      void handler(int sig) {
+        caught = sig;
+      }
+
+      int main() {
+        char buf[16];
+        struct sigaction act = {0};
+        act.sa_handler = handler;
+        sigaction(SIGINT, &act, 0);
+        printf("reading\n");
+        printf("read: %d after %d\n", (int)read(0, buf, sizeof(buf)), caught);
+
+        caught = 0;
+        signal(SIGINT, handler);
+        printf("reading again\n");
+        printf("read: %d after %d\n", (int)read(0, buf, sizeof(buf)), caught);
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "reading"
+    When I press Ctrl-C
+    Then I should see "read: -4 after 2"
+    And I should see "reading again"
+    When I press Ctrl-C
+    And I type "hello"
+    Then I should see "read: 6 after 2"
+
+  Scenario: Ctrl-Z stops a foreground job and it carries on once continued
+    Given the following code for /sbin/init:
+      """
+      #include <stdio.h>
+      #include <signal.h>
+      #include <unistd.h>
+      #include <sys/wait.h>
+
+      int main() {
+        int go[2];
+        pipe(go);
+
+        int pid = fork();
+        if (pid == 0) {
+          char buf[16] = {0};
+          setpgid(0, 0);
+          close(go[1]);
+          // Wait until the parent has made us the foreground job.
+          read(go[0], buf, 1);
+          printf("job reading\n");
+          int n = read(0, buf, sizeof(buf) - 1);
+          printf("job read %d: %s", n, buf);
+          return 3;
+        }
+
+        // Like a shell, put the job in its own group from both sides.
+        setpgid(pid, pid);
+        tcsetpgrp(0, pid);
+        printf("foreground: %u\n", tcgetpgrp(0) == pid);
+        write(go[1], "g", 1);
+
+        int status;
+        waitpid(pid, &status, WUNTRACED);
+        tcsetpgrp(0, getpgrp());
+        printf("stopped by %u: %u\n", WSTOPSIG(status), WIFSTOPPED(status));
+
+        // fg
+        tcsetpgrp(0, pid);
+        kill(-pid, SIGCONT);
+        printf("continued\n");
+        waitpid(pid, &status, WUNTRACED);
+        tcsetpgrp(0, getpgrp());
+        printf("job exited with %u\n", WEXITSTATUS(status));
+        return 0;
+      }
+      """
+    When I run the machine
+    Then I should see "foreground: 1"
+    And I should see "job reading"
+    When I press Ctrl-Z
+    Then I should see "stopped by 20: 1"
+    And I should see "continued"
+    When I type "hello"
+    Then I should see "job read 6: hello"
+    And I should see "job exited with 3"
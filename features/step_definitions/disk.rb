@@ -1,3 +1,6 @@
+require 'fileutils'
+require 'subprocess'
+
 Given(/^I have a disk image with a sector full of "(.*?)"$/) do |marker|
   File.write("cucumberdisk.bin", "DISK"+marker*1000)
 end
@@ -5,3 +8,94 @@ end
 Given(/^I attach this image as a virtio block device$/) do
   ENV["QEMUOPT"] = "-drive file=cucumberdisk.bin,if=virtio"
 end
+
+# Puts the root filesystem into the first partition of an MBR-partitioned
+# disk, and an ext2 filesystem with a file "hello.txt" into the second one.
+# The image is only put together once userspace/rootfs.bin has been built.
+Given(/^the boot disk is partitioned, with an ext2 partition containing "(.*?)"$/) do |contents|
+  @rootdisk = lambda do
+    FileUtils.rm_rf("partdata")
+    FileUtils.mkdir_p("partdata")
+    File.write("partdata/hello.txt", contents)
+    File.delete("partdata.img") if File.exist?("partdata.img")
+    Subprocess.check_call(%w(mke2fs -q -F -t ext2 -b 1024 -d partdata partdata.img 2048))
+
+    root = File.binread("userspace/rootfs.bin")
+    root << "\0" * (-root.size % 512)
+    data = File.binread("partdata.img")
+    first = 2048
+    second = first + root.size / 512
+
+    entry = lambda { |kind, start, size| [0, 0, 0, 0, kind, 0, 0, 0, start, size].pack("C8VV") }
+    mbr = ("\0" * 446).b + entry.call(0x83, first, root.size / 512) + entry.call(0x83, second, data.size / 512) + ("\0" * 32).b + "\x55\xaa".b
+
+    disk = mbr + ("\0" * ((first - 1) * 512)).b + root + data
+    File.binwrite("partdisk.img", disk)
+    "partdisk.img"
+  end
+end
+
+# A cpio archive in the crc format (newc with checksums) holding `name`,
+# with one byte of the file's contents flipped after the checksum was taken.
+Given(/^I attach a crc cpio archive with a corrupted "(.*?)" as a second disk$/) do |name|
+  FileUtils.rm_rf("cpiodata")
+  FileUtils.mkdir_p("cpiodata")
+  File.write("cpiodata/#{name}", "hello, checksums\n")
+  cpio = Subprocess.popen(%w(cpio --create --quiet -H crc), cwd: "cpiodata", stdin: Subprocess::PIPE, stdout: Subprocess::PIPE)
+  archive = cpio.communicate("#{name}\n")[0].b
+  cpio.wait
+  at = archive.index("hello, checksums")
+  archive[at] = "j"
+  File.binwrite("badcpio.bin", archive)
+  (@extra_disks ||= []) << "badcpio.bin"
+end
+
+# A FAT32 image made by the host's mkfs.vfat, holding a file with a long
+# name. mtools would rather not check the geometry of a plain image.
+Given(/^I attach a vfat image with "(.*?)" containing "(.*?)" as a second disk$/) do |name, contents|
+  ENV["MTOOLS_SKIP_CHECK"] = "1"
+  File.delete("fatdisk.img") if File.exist?("fatdisk.img")
+  File.write("fatfile.txt", contents)
+  Subprocess.check_call(%w(mkfs.vfat -F 32 -s 1 -C fatdisk.img 34000), stdout: Subprocess::PIPE)
+  Subprocess.check_call(["mcopy", "-i", "fatdisk.img", "fatfile.txt", "::#{name}"])
+  (@extra_disks ||= []) << "fatdisk.img"
+end
+
+# Once the machine is gone, the host's tools have to be happy with what it
+# wrote.
+Then(/^the vfat image passes fsck and has "(.*?)" containing "(.*?)"$/) do |name, contents|
+  @process.terminate
+  @process.wait
+  @process = nil
+  Subprocess.check_call(%w(fsck.vfat -n fatdisk.img), stdout: Subprocess::PIPE)
+  listing = Subprocess.check_output(%w(mdir -i fatdisk.img -b ::))
+  assert listing.include?(name), "expected to find \"#{name}\" in \"#{listing}\""
+  assert_equal contents, Subprocess.check_output(["mtype", "-i", "fatdisk.img", "::#{name}"])
+end
+
+# 2048 sectors, each starting with "sector <n>", so that userspace can tell
+# which sectors a read handed back.
+Given(/^I attach a disk where every sector starts with its number as a second disk$/) do
+  disk = (0...2048).map { |i| "sector #{i}".ljust(512, "\0") }.join
+  File.binwrite("numbered.img", disk)
+  (@extra_disks ||= []) << "numbered.img"
+end
+
+# A crc cpio archive like the root filesystem, but with init at `path` as
+# well, and a file "/second-disk" to tell it apart. It's only put together
+# once userspace/rootfs has been built.
+Given(/^a second disk with a copy of the root filesystem that also has init as "(.*?)"$/) do |path|
+  (@extra_disks ||= []) << lambda do
+    FileUtils.rm_rf("rootcopy")
+    FileUtils.cp_r("userspace/rootfs", "rootcopy")
+    FileUtils.mkdir_p(File.dirname("rootcopy#{path}"))
+    FileUtils.cp("rootcopy/init", "rootcopy#{path}")
+    File.write("rootcopy/second-disk", "")
+    files = Dir.chdir("rootcopy") { Dir.glob("**/*") }
+    cpio = Subprocess.popen(%w(cpio --create --quiet -H crc), cwd: "rootcopy", stdin: Subprocess::PIPE, stdout: Subprocess::PIPE)
+    archive = cpio.communicate(files.join("\n") + "\n")[0].b
+    cpio.wait
+    File.binwrite("rootcopy.bin", archive)
+    "rootcopy.bin"
+  end
+end
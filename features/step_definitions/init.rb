@@ -12,15 +12,58 @@ Given(/^I use the following linker script for init:$/) do |string|
   Subprocess.check_call(["touch", "userspace/init.ld"]) # to trigger make
 end
 
+Given(/^GRUB loads the root filesystem as an initrd$/) do
+  @initrd = true
+end
+
+Given(/^the boot disk is a (legacy|modern)-only virtio device$/) do |kind|
+  @diskopts = kind == "legacy" ? "disable-modern=on" : "disable-legacy=on"
+end
+
+Given(/^the kernel command line "([^"]*)"$/) do |cmdline|
+  @cmdline = cmdline
+end
+
 When(/^I run the machine$/) do
   mk = Subprocess.check_output(%w(uname)).chomp == "Darwin" ? "vagrant ssh -- cd /vagrant && make" : "make"
-  Subprocess.check_call(mk.split(" "))
+  mk += " INITRD=userspace/rootfs.bin" if @initrd
+  mk = mk.split(" ")
+  mk << "CMDLINE=#{@cmdline}" if @cmdline
+  Subprocess.check_call(mk)
   if @process
     @process.terminate
     @process.wait
   end
-  q = "qemu-system-x86_64 -s -nographic -serial stdio -monitor null -cdrom cor.iso #{ENV["QEMUOPT"]} -drive file=userspace/rootfs.bin,if=virtio"
-  @process = Subprocess.popen(q.split(" "), stdin: nil, stdout: Subprocess::PIPE, stderr: Subprocess::PIPE)
+  rootdisk = @rootdisk ? @rootdisk.call : "userspace/rootfs.bin"
+  drive = if @initrd
+    ""
+  elsif @diskopts
+    "-drive file=#{rootdisk},if=none,format=raw,id=root -device virtio-blk-pci,drive=root,#{@diskopts}"
+  else
+    "-drive file=#{rootdisk},if=virtio,format=raw"
+  end
+  # Extra disks come after the boot disk, so that they're vdb and so on.
+  # Those that need userspace built are lambdas that make the image.
+  extra = (@extra_disks || []).map { |f| f.respond_to?(:call) ? f.call : f }
+  extra = extra.map { |f| "-drive file=#{f},if=virtio,format=raw" }.join(" ")
+  q = "qemu-system-x86_64 -s -nographic -serial stdio -monitor null -cdrom cor.iso #{ENV["QEMUOPT"]} #{drive} #{extra}"
+  @process = Subprocess.popen(q.split(" "), stdin: Subprocess::PIPE, stdout: Subprocess::PIPE, stderr: Subprocess::PIPE)
+end
+
+# Typed on the serial console, which is QEMU's stdin
+When(/^I press Ctrl-C$/) do
+  @process.stdin.write("\x03")
+  @process.stdin.flush
+end
+
+When(/^I press Ctrl-Z$/) do
+  @process.stdin.write("\x1a")
+  @process.stdin.flush
+end
+
+When(/^I type "(.*?)"$/) do |line|
+  @process.stdin.write("#{line}\n")
+  @process.stdin.flush
 end
 
 Then(/^I should see "([^"]*?)"$/) do |needle|
@@ -54,3 +97,30 @@ After do
     @process.wait
   end
 end
+
+# Like "I should see", for output that changes from machine to machine.
+Then(/^I should see a line matching "([^"]*?)"$/) do |pattern|
+  re = Regexp.new(pattern)
+  @out = ""
+  catch :bye do
+    begin
+      Timeout.timeout(5) do
+        loop do
+          l = @process.stdout.gets
+          @out << l
+          if re.match(l)
+            throw :bye
+          end
+        end
+      end
+    rescue Timeout::Error
+      @out = @out.force_encoding('ASCII-8BIT')
+      assert re.match(@out), "expected a line matching /#{pattern}/ in \"#{@out}\""
+    end
+  end
+end
+
+# Everything up to what the last "I should see" was waiting for.
+Then(/^I should not have seen "([^"]*?)" before that$/) do |needle|
+  assert !@out.include?(needle), "didn't expect \"#{needle}\" in \"#{@out}\""
+end
@@ -0,0 +1,65 @@
+require 'subprocess'
+require 'timeout'
+require 'fileutils'
+require 'minitest'
+
+Given(/^an empty ext3 disk image$/) do
+  File.delete("crashdisk.img") if File.exist?("crashdisk.img")
+  Subprocess.check_call(%w(mke2fs -q -F -t ext3 -b 1024 crashdisk.img 8192))
+end
+
+# Boots cor with the crash disk as vdb, tells init what to do by typing
+# `mode` on the console when it asks, and waits until it prints `ready`.
+def boot_crashdisk(mode, ready)
+  q = "qemu-system-x86_64 -nographic -no-reboot -serial stdio -monitor null -cdrom cor.iso -drive file=userspace/rootfs.bin,if=virtio -drive file=crashdisk.img,if=virtio,format=raw"
+  process = Subprocess.popen(q.split(" "), stdin: Subprocess::PIPE, stdout: Subprocess::PIPE, stderr: Subprocess::PIPE)
+  out = ""
+  Timeout.timeout(10) do
+    [["mode?", mode], [ready, nil]].each do |wanted, reply|
+      until out.include?(wanted)
+        out << process.stdout.gets.to_s
+      end
+      if reply
+        process.stdin.write("#{reply}\n")
+        process.stdin.flush
+      end
+    end
+  end
+  [process, out]
+end
+
+# Kills QEMU at a random time after init said it's started writing. Then
+# boots cor again on the crashed image, so that cor's own mount replays the
+# journal, and only checks the result on the host, without letting e2fsck
+# replay anything itself.
+When(/^I crash the machine at (\d+) random points while it writes$/) do |times|
+  Subprocess.check_call((ENV["MAKE"]||"make").split(" "))
+  @fsck_failures = []
+  times.to_i.times do |i|
+    process, _ = boot_crashdisk("hammer", "hammering")
+    sleep(rand * 2)
+    process.send_signal("KILL")
+    process.wait
+
+    process, out = boot_crashdisk("check", "synced")
+    process.send_signal("KILL")
+    process.wait
+    unless out.include?("ext2: using the journal")
+      @fsck_failures << "crash #{i}: cor didn't mount the image:\n#{out}"
+      next
+    end
+
+    # cor leaves the journal empty once it has replayed and checkpointed it.
+    dump = Subprocess.check_output(%w(dumpe2fs -h crashdisk.img), stderr: Subprocess::STDOUT)
+    unless dump =~ /^Journal start:\s+0$/
+      @fsck_failures << "crash #{i}: the journal wasn't replayed:\n#{dump}"
+    end
+    fsck = Subprocess.popen(%w(e2fsck -fn crashdisk.img), stdout: Subprocess::PIPE, stderr: Subprocess::STDOUT)
+    report = fsck.communicate[0]
+    @fsck_failures << "crash #{i}:\n#{report}" unless fsck.wait.success?
+  end
+end
+
+Then(/^the disk image should be consistent after every crash$/) do
+  assert @fsck_failures.empty?, @fsck_failures.join("\n")
+end
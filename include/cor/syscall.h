@@ -2,4 +2,39 @@
 #define SYSCALL_WRITE 2
 #define SYSCALL_READ 3
 #define SYSCALL_OPEN 4
-#define SYSCALL_MOREMEM 5
+#define SYSCALL_BRK 5
+#define SYSCALL_MMAP 6
+#define SYSCALL_MUNMAP 7
+#define SYSCALL_MPROTECT 8
+#define SYSCALL_PIPE 9
+#define SYSCALL_CLOSE 10
+#define SYSCALL_DUP2 11
+#define SYSCALL_KILL 12
+#define SYSCALL_SIGACTION 13
+#define SYSCALL_SIGPROCMASK 14
+#define SYSCALL_SIGRETURN 15
+#define SYSCALL_GETPID 16
+#define SYSCALL_IOCTL 17
+#define SYSCALL_SETPGID 18
+#define SYSCALL_GETPGID 19
+#define SYSCALL_SETSID 20
+#define SYSCALL_STAT 21
+#define SYSCALL_LSTAT 22
+#define SYSCALL_FSTAT 23
+#define SYSCALL_LSEEK 24
+#define SYSCALL_GETDENTS 25
+#define SYSCALL_CHDIR 26
+#define SYSCALL_GETCWD 27
+#define SYSCALL_READLINK 28
+#define SYSCALL_EXECVE 29
+#define SYSCALL_MKDIR 30
+#define SYSCALL_RMDIR 31
+#define SYSCALL_UNLINK 32
+#define SYSCALL_RENAME 33
+#define SYSCALL_SYMLINK 34
+#define SYSCALL_FTRUNCATE 35
+#define SYSCALL_MOUNT 36
+#define SYSCALL_SYNC 37
+#define SYSCALL_FORK 38
+#define SYSCALL_WAIT4 39
+#define SYSCALL_GETPPID 40
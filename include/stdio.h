@@ -1 +1,2 @@
 int printf(const char *fmt, ...);
+int rename(const char *from, const char *to);
@@ -1,3 +1,5 @@
 // http://pubs.opengroup.org/onlinepubs/009695399/basedefs/stddef.h.html
 
 void *malloc(size_t);
+void *brk(void *addr);
+void *sbrk(long increment);
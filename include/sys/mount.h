@@ -0,0 +1,2 @@
+// Without flags or data: fstype is one of "tmpfs", "ext2", "vfat" and "cpio".
+int mount(const char *source, const char *target, const char *fstype);
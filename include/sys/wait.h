@@ -0,0 +1,16 @@
+// http://pubs.opengroup.org/onlinepubs/009695399/basedefs/sys/wait.h.html
+
+#define WNOHANG 1
+#define WUNTRACED 2
+
+// The status encoding is Linux's: the exit code in bits 8-15, or the
+// terminating signal in bits 0-6, or 0x7f with the stop signal in bits 8-15.
+#define WEXITSTATUS(s) (((s) >> 8) & 0xff)
+#define WTERMSIG(s) ((s) & 0x7f)
+#define WSTOPSIG(s) WEXITSTATUS(s)
+#define WIFEXITED(s) (WTERMSIG(s) == 0)
+#define WIFSTOPPED(s) (((s) & 0xff) == 0x7f)
+#define WIFSIGNALED(s) (!WIFEXITED(s) && !WIFSTOPPED(s))
+
+int wait(int *status);
+int waitpid(int pid, int *status, int options);
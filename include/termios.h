@@ -0,0 +1,60 @@
+// http://pubs.opengroup.org/onlinepubs/009695399/basedefs/termios.h.html
+
+typedef unsigned char cc_t;
+typedef unsigned int speed_t;
+typedef unsigned int tcflag_t;
+
+#define NCCS 19
+
+// Same layout as the kernel's, which is what TCGETS/TCSETS use.
+struct termios {
+  tcflag_t c_iflag;
+  tcflag_t c_oflag;
+  tcflag_t c_cflag;
+  tcflag_t c_lflag;
+  cc_t c_line;
+  cc_t c_cc[NCCS];
+};
+
+// c_cc indices
+#define VINTR 0
+#define VQUIT 1
+#define VERASE 2
+#define VKILL 3
+#define VEOF 4
+#define VTIME 5
+#define VMIN 6
+#define VSUSP 10
+#define VEOL 11
+#define VWERASE 14
+
+// c_iflag
+#define INLCR 0000100
+#define IGNCR 0000200
+#define ICRNL 0000400
+
+// c_oflag
+#define OPOST 0000001
+#define ONLCR 0000004
+
+// c_lflag
+#define ISIG 0000001
+#define ICANON 0000002
+#define ECHO 0000010
+#define ECHOE 0000020
+#define ECHOK 0000040
+#define ECHONL 0000100
+#define NOFLSH 0000200
+#define ECHOCTL 0001000
+#define ECHOKE 0004000
+#define IEXTEN 0100000
+
+// tcsetattr actions
+#define TCSANOW 0
+#define TCSADRAIN 1
+#define TCSAFLUSH 2
+
+int tcgetattr(int fd, struct termios *t);
+int tcsetattr(int fd, int action, const struct termios *t);
+int tcgetpgrp(int fd);
+int tcsetpgrp(int fd, int pgrp);
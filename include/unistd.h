@@ -0,0 +1,33 @@
+// http://pubs.opengroup.org/onlinepubs/009695399/basedefs/unistd.h.html
+
+int read(int fd, const void *buf, size_t count);
+int write(int fd, const void *buf, size_t count);
+int close(int fd);
+int pipe(int fildes[2]);
+int dup2(int oldfd, int newfd);
+int getpid(void);
+int getppid(void);
+int fork(void);
+void _exit(int status);
+int getpgid(int pid);
+int getpgrp(void);
+int setpgid(int pid, int pgid);
+int setpgrp(void);
+int setsid(void);
+int isatty(int fd);
+int open(const char *path, int flags, ...);
+long lseek(int fd, long offset, int whence);
+int chdir(const char *path);
+int rmdir(const char *path);
+int unlink(const char *path);
+int symlink(const char *target, const char *path);
+int ftruncate(int fd, long length);
+void sync();
+char *getcwd(char *buf, size_t size);
+long readlink(const char *path, char *buf, size_t size);
+int execve(const char *path, char *const argv[], char *const envp[]);
+int execl(const char *path, const char *arg, ...);
+
+#define SEEK_SET 0
+#define SEEK_CUR 1
+#define SEEK_END 2
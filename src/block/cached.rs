@@ -1,9 +1,19 @@
 use prelude::*;
+use core::cmp;
 
-use super::{Client, Error};
+use super::{Client, Error, Pending};
 
+// A sector's contents, shared with whichever cache handed it out. Dropping
+// the checkout gives up our reference, so caches can tell which buffers are
+// still in use.
 pub struct SectorCheckout {
-  _data: Box<[u8]>,
+  _data: Arc<Box<[u8]>>,
+}
+
+impl SectorCheckout {
+  pub fn new(data: Arc<Box<[u8]>>) -> Self {
+    SectorCheckout { _data: data }
+  }
 }
 
 impl Deref for SectorCheckout {
@@ -13,20 +23,45 @@ impl Deref for SectorCheckout {
   }
 }
 
-//impl Drop for SectorCheckout -> return buffer to cache .. maybe just an Arc?
-
 // idea: page cache returns Page objects that contain a SleepingRWLock on the page memory?
 // TODO: Cache should be Clone
-pub trait Cache: Sync + fmt::Debug {
+pub trait Cache: Send + Sync + fmt::Debug {
   // Read the specified sector from the page cache.
   // Will block the current process on a cache miss.
   // The returned Sector acts like a [u8; 512]. When the Sector is dropped,
   // the buffer is returned to the cache.
   fn get(&self, sector: u64) -> Result<SectorCheckout, Error>;
+
+  // The size of the underlying device, in sectors.
+  fn sectors(&self) -> u64;
+
+  // Read the sectors starting at `sector` straight into `buf`, which is a
+  // multiple of 512 bytes long. Caches that can should fetch whatever they
+  // don't have in as few requests as possible.
+  fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Error> {
+    for (i, chunk) in buf.chunks_mut(512).enumerate() {
+      chunk.clone_from_slice(&try!(self.get(sector + i as u64)));
+    }
+    Ok(())
+  }
+
+  // Replace the contents of a sector (`data` is 512 bytes).
+  fn write(&self, _sector: u64, _data: &[u8]) -> Result<(), Error> {
+    Err(Error::ReadOnly)
+  }
+
+  // Make sure everything written so far is on the device.
+  fn flush(&self) -> Result<(), Error> {
+    Ok(())
+  }
 }
 
 
-use sync::global_mutex::GlobalMutex;
+use sync::global_mutex::{GlobalMutex,GlobalMutexGuard};
+
+// The most NoopCache reads in one go. Buffers are never freed, so this is
+// what a read costs in kernel heap.
+const MAX_REQUEST: usize = 0x8000;
 
 #[derive(Debug)]
 pub struct NoopCache<C: Client + Send> {
@@ -37,9 +72,49 @@ impl<C: Client + Send> Cache for NoopCache<C> {
   fn get(&self, sector: u64) -> Result<SectorCheckout, Error> {
     // just dumb read & block immediately
     let b = vec![0u8;512].into_boxed_slice();
-    let tok = self.blockdev.lock().read_dispatch(sector as u64, b).unwrap();
-    let buf = self.blockdev.lock().read_await(tok).unwrap();
-    Ok(SectorCheckout{ _data: buf })
+    let tok = try!(self.device().read_dispatch(sector as u64, vec![b]));
+    tok.wait();
+    let mut bufs = try!(self.device().read_await(tok));
+    Ok(SectorCheckout::new(Arc::new(bufs.remove(0))))
+  }
+
+  // A chunk at a time, into one buffer that goes back and forth.
+  fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Error> {
+    let mut bounce = vec![0u8; cmp::min(buf.len(), MAX_REQUEST)].into_boxed_slice();
+    for (i, chunk) in buf.chunks_mut(MAX_REQUEST).enumerate() {
+      if chunk.len() != bounce.len() {
+        bounce = vec![0u8; chunk.len()].into_boxed_slice();
+      }
+      let at = sector + (i * MAX_REQUEST / 512) as u64;
+      let tok = try!(self.device().read_dispatch(at, vec![bounce]));
+      tok.wait();
+      bounce = try!(self.device().read_await(tok)).remove(0);
+      chunk.clone_from_slice(&bounce);
+    }
+    Ok(())
+  }
+
+  fn sectors(&self) -> u64 {
+    self.device().sectors()
+  }
+
+  // Straight to the device, and wait for it.
+  fn write(&self, sector: u64, data: &[u8]) -> Result<(), Error> {
+    let mut b = vec![0u8;512].into_boxed_slice();
+    b.clone_from_slice(data);
+    let tok = try!(self.device().write_dispatch(sector, vec![b]));
+    tok.wait();
+    self.device().write_await(tok).map(|_| ())
+  }
+
+  fn flush(&self) -> Result<(), Error> {
+    let tok = try!(self.device().flush_dispatch());
+    tok.wait();
+    match self.device().write_await(tok) {
+      // Devices that can't flush write through anyway.
+      Err(Error::Unsupported) => Ok(()),
+      r => r.map(|_| ()),
+    }
   }
 }
 
@@ -48,4 +123,8 @@ impl<C: Client + Send> NoopCache<C> {
   pub fn new(c: C) -> Self {
     NoopCache{ blockdev: Arc::new(GlobalMutex::new(c)) }
   }
+
+  fn device(&self) -> GlobalMutexGuard<C> {
+    super::lock(&self.blockdev)
+  }
 }
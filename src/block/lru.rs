@@ -0,0 +1,418 @@
+use prelude::*;
+
+use collections::btree_map::BTreeMap;
+use mem;
+use sched;
+use sched::blocking::{self,SignalToken};
+use sync::global_mutex::{GlobalMutex,GlobalMutexGuard};
+use super::{Client, Error, Pending};
+use super::cached::{Cache, SectorCheckout};
+
+// Below this much free kernel heap, the cache stops growing once it has
+// MIN_SECTORS. It can't shrink: the heap doesn't take memory back, so the
+// cache keeps the buffers it has and reuses them for other sectors.
+const LOW_WATER: usize = 0x10000;
+const MIN_SECTORS: usize = 16;
+
+// How many times the write-back task yields after it's woken up, so that
+// more writes can pile up. There are no timers yet, so this is the closest
+// we get to a delay.
+const WRITEBACK_ROUNDS: usize = 64;
+
+#[derive(Debug)]
+struct Entry {
+  data: Arc<Box<[u8]>>,
+  dirty: bool,
+  // When the sector was last used, the key into Lru::order
+  stamp: u64,
+}
+
+#[derive(Debug)]
+struct Lru {
+  entries: BTreeMap<u64, Entry>,
+  // Sectors by the time they were last used, oldest first
+  order: BTreeMap<u64, u64>,
+  clock: u64,
+  // Buffers of evicted sectors, and of finished write-backs
+  spare: Vec<Box<[u8]>>,
+  // Every buffer the cache made and still owns: cached, spare or with the
+  // device
+  allocated: usize,
+  // Wakes up the write-back task while it's waiting for dirty sectors
+  kick: Option<SignalToken>,
+}
+
+impl Lru {
+  fn touch(&mut self, sector: u64) {
+    self.clock += 1;
+    let stamp = self.clock;
+    if let Some(e) = self.entries.get_mut(&sector) {
+      self.order.remove(&e.stamp);
+      e.stamp = stamp;
+    }
+    self.order.insert(stamp, sector);
+  }
+
+  // Take back the buffer of a sector that's no longer cached. If it's still
+  // checked out, it's the checkout's now.
+  fn recycle(&mut self, data: Arc<Box<[u8]>>) {
+    match Arc::try_unwrap(data) {
+      Ok(buf) => self.spare.push(buf),
+      Err(_) => self.allocated -= 1,
+    }
+  }
+
+  fn insert(&mut self, sector: u64, data: Arc<Box<[u8]>>, dirty: bool) {
+    if let Some(e) = self.entries.remove(&sector) {
+      self.order.remove(&e.stamp);
+      self.recycle(e.data);
+    }
+    self.entries.insert(sector, Entry { data: data, dirty: dirty, stamp: 0 });
+    self.touch(sector);
+  }
+
+  // Drop the least recently used sector that doesn't need writing back.
+  fn evict(&mut self) -> bool {
+    let victim = self.order.iter()
+      .map(|(&stamp, &sector)| (stamp, sector))
+      .find(|&(_, sector)| !self.entries[&sector].dirty);
+    match victim {
+      Some((stamp, sector)) => {
+        self.order.remove(&stamp);
+        let e = self.entries.remove(&sector).unwrap();
+        self.recycle(e.data);
+        true
+      },
+      None => false,
+    }
+  }
+
+  fn any_dirty(&self) -> bool {
+    self.entries.values().any(|e| e.dirty)
+  }
+}
+
+#[derive(Debug)]
+struct Shared<C: Client + Send> {
+  blockdev: GlobalMutex<C>,
+  lru: GlobalMutex<Lru>,
+  capacity: usize,
+}
+
+// A write-back cache that keeps the most recently used sectors around.
+//
+// Writes only change the cached sector and mark it dirty; a task that
+// sleeps while there's nothing to do puts dirty sectors on the device every
+// now and then, and flush() does so right away. Dirty sectors are never
+// evicted, so if everything is dirty, making room means writing back first.
+//
+// A checkout shares its buffer with the cache. Writing a sector that's
+// checked out gives the cache another buffer, so checkouts never change.
+// Otherwise, buffers are reused for other sectors once they're evicted.
+#[derive(Debug)]
+pub struct LruCache<C: Client + Send> {
+  shared: Arc<Shared<C>>,
+}
+
+impl<C: Client + Send + 'static> LruCache<C> {
+  // `capacity` is how many sectors to keep at most. Starts the write-back
+  // task, which stays around for as long as the cache.
+  pub fn new(c: C, capacity: usize) -> Self {
+    let lru = Lru { entries: BTreeMap::new(), order: BTreeMap::new(), clock: 0, spare: vec![], allocated: 0, kick: None };
+    let shared = Arc::new(Shared { blockdev: GlobalMutex::new(c), lru: GlobalMutex::new(lru), capacity: capacity });
+    let flusher = shared.clone();
+    sched::add_task(move || writeback_task(flusher), "block write-back");
+    LruCache { shared: shared }
+  }
+}
+
+fn same(a: &Arc<Box<[u8]>>, b: &Arc<Box<[u8]>>) -> bool {
+  a.as_ptr() == b.as_ptr()
+}
+
+impl<C: Client + Send + 'static> Shared<C> {
+  fn device(&self) -> GlobalMutexGuard<C> {
+    super::lock(&self.blockdev)
+  }
+
+  // Read `count` consecutive sectors from the device, one buffer each.
+  fn read_through(&self, sector: u64, count: usize) -> Result<Vec<Box<[u8]>>, Error> {
+    let mut bufs = Vec::with_capacity(count);
+    for _ in 0..count {
+      bufs.push(try!(self.buffer()));
+    }
+    let dispatched = self.device().read_dispatch(sector, bufs);
+    let r = dispatched.and_then(|tok| {
+      tok.wait();
+      self.device().read_await(tok)
+    });
+    if r.is_err() {
+      // The buffers went down with the request.
+      self.lru.lock().allocated -= count;
+    }
+    r
+  }
+
+  // Write consecutive sectors, starting at `sector`, in one request. The
+  // device gets copies, since the cached buffers may be written meanwhile.
+  fn write_through(&self, sector: u64, data: &[Arc<Box<[u8]>>]) -> Result<(), Error> {
+    let bufs = data.iter().map(|d| {
+      let mut buf = self.scratch();
+      buf.clone_from_slice(&d[..]);
+      buf
+    }).collect();
+    let dispatched = self.device().write_dispatch(sector, bufs);
+    let r = dispatched.and_then(|tok| {
+      tok.wait();
+      self.device().write_await(tok)
+    });
+    let mut lru = self.lru.lock();
+    match r {
+      Ok(bufs) => {
+        lru.spare.extend(bufs);
+        Ok(())
+      },
+      Err(e) => {
+        lru.allocated -= data.len();
+        Err(e)
+      },
+    }
+  }
+
+  fn max_segments(&self) -> usize {
+    self.device().max_segments()
+  }
+
+  // Whether the cache may make another buffer
+  fn may_grow(&self, allocated: usize) -> bool {
+    if allocated >= self.capacity {
+      return false;
+    }
+    let usage = mem::usage();
+    allocated < MIN_SECTORS || usage.total - usage.used >= LOW_WATER
+  }
+
+  // A buffer for one more sector: a spare one, a new one while the cache may
+  // still grow, or else that of the least recently used clean sector. If
+  // everything is dirty, that means writing back first. If all buffers are
+  // with the device or checked out, the cache has to grow anyway.
+  fn buffer(&self) -> Result<Box<[u8]>, Error> {
+    loop {
+      {
+        let mut lru = self.lru.lock();
+        if let Some(buf) = lru.spare.pop() {
+          return Ok(buf);
+        }
+        if self.may_grow(lru.allocated) {
+          lru.allocated += 1;
+          return Ok(vec![0u8; 512].into_boxed_slice());
+        }
+        if lru.evict() {
+          continue;
+        }
+        if !lru.any_dirty() {
+          lru.allocated += 1;
+          return Ok(vec![0u8; 512].into_boxed_slice());
+        }
+      }
+      try!(self.write_back());
+    }
+  }
+
+  // A buffer to hand a copy to the device in. This can't wait for a
+  // write-back, as it's needed for one, so the cache may end up with one
+  // request's worth of buffers more than its capacity.
+  fn scratch(&self) -> Box<[u8]> {
+    let mut lru = self.lru.lock();
+    match lru.spare.pop() {
+      Some(buf) => buf,
+      None => {
+        lru.allocated += 1;
+        vec![0u8; 512].into_boxed_slice()
+      },
+    }
+  }
+
+  // Put every dirty sector on the device, runs of consecutive ones in a
+  // single request each. A sector only counts as clean again if nobody wrote
+  // it while we were at it.
+  fn write_back(&self) -> Result<(), Error> {
+    let dirty: Vec<(u64, Arc<Box<[u8]>>)> = self.lru.lock().entries.iter()
+      .filter(|&(_, e)| e.dirty)
+      .map(|(&sector, e)| (sector, e.data.clone()))
+      .collect();
+
+    let max = self.max_segments();
+    let mut start = 0;
+    while start < dirty.len() {
+      let mut end = start + 1;
+      while end < dirty.len() && end - start < max && dirty[end].0 == dirty[end-1].0 + 1 {
+        end += 1;
+      }
+      let run = &dirty[start..end];
+      let data: Vec<Arc<Box<[u8]>>> = run.iter().map(|&(_, ref d)| d.clone()).collect();
+      try!(self.write_through(run[0].0, &data[..]));
+
+      let mut lru = self.lru.lock();
+      for &(sector, ref data) in run.iter() {
+        if let Some(e) = lru.entries.get_mut(&sector) {
+          if same(&e.data, data) {
+            e.dirty = false;
+          }
+        }
+      }
+      start = end;
+    }
+    Ok(())
+  }
+
+  // Add a sector that was just read, unless somebody else read or wrote it
+  // while we were waiting. Either way, returns what's cached now.
+  fn fill(&self, sector: u64, data: Box<[u8]>) -> Arc<Box<[u8]>> {
+    let mut lru = self.lru.lock();
+    let cached = lru.entries.get(&sector).map(|e| e.data.clone());
+    match cached {
+      Some(newer) => {
+        lru.touch(sector);
+        lru.spare.push(data);
+        newer
+      },
+      None => {
+        let data = Arc::new(data);
+        lru.insert(sector, data.clone(), false);
+        data
+      },
+    }
+  }
+
+  // The cached sector, if there is one.
+  fn lookup(&self, sector: u64) -> Option<Arc<Box<[u8]>>> {
+    let mut lru = self.lru.lock();
+    let hit = lru.entries.get(&sector).map(|e| e.data.clone());
+    if hit.is_some() {
+      lru.touch(sector);
+    }
+    hit
+  }
+}
+
+// Sleeps until a write makes a sector dirty, then writes back.
+fn writeback_task<C: Client + Send + 'static>(shared: Arc<Shared<C>>) {
+  loop {
+    let idle = {
+      let mut lru = shared.lru.lock();
+      if lru.any_dirty() {
+        None
+      } else {
+        let (wait, kick) = blocking::tokens(String::from("block write-back"));
+        lru.kick = Some(kick);
+        Some(wait)
+      }
+    };
+    if let Some(wait) = idle {
+      wait.wait();
+    }
+
+    for _ in 0..WRITEBACK_ROUNDS {
+      sched::kyield();
+    }
+    if let Err(e) = shared.write_back() {
+      println!("block: write-back failed: {:?}", e);
+    }
+  }
+}
+
+impl<C: Client + Send + 'static> Cache for LruCache<C> {
+  fn get(&self, sector: u64) -> Result<SectorCheckout, Error> {
+    let s = &self.shared;
+    if let Some(data) = s.lookup(sector) {
+      return Ok(SectorCheckout::new(data));
+    }
+
+    let data = try!(s.read_through(sector, 1)).remove(0);
+    Ok(SectorCheckout::new(s.fill(sector, data)))
+  }
+
+  // Hits are copied from the cache; every run of misses is fetched with one
+  // request, as long as the device takes that many buffers.
+  fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Error> {
+    let s = &self.shared;
+    let count = buf.len() / 512;
+    let max = s.max_segments();
+    let mut i = 0;
+    while i < count {
+      if let Some(data) = s.lookup(sector + i as u64) {
+        buf[i*512..(i+1)*512].clone_from_slice(&data[..]);
+        i += 1;
+        continue;
+      }
+
+      let mut end = i + 1;
+      while end < count && end - i < max && !s.lru.lock().entries.contains_key(&(sector + end as u64)) {
+        end += 1;
+      }
+      let bufs = try!(s.read_through(sector + i as u64, end - i));
+      for (j, data) in bufs.into_iter().enumerate() {
+        let at = i + j;
+        let data = s.fill(sector + at as u64, data);
+        buf[at*512..(at+1)*512].clone_from_slice(&data[..]);
+      }
+      i = end;
+    }
+    Ok(())
+  }
+
+  fn sectors(&self) -> u64 {
+    self.shared.device().sectors()
+  }
+
+  // In place if nobody else has the cached sector, in another buffer
+  // otherwise.
+  fn write(&self, sector: u64, data: &[u8]) -> Result<(), Error> {
+    let s = &self.shared;
+    if sector >= self.sectors() {
+      return Err(Error::OutOfRange);
+    }
+
+    let in_place = {
+      let mut lru = s.lru.lock();
+      let written = match lru.entries.get_mut(&sector) {
+        Some(e) => match Arc::get_mut(&mut e.data) {
+          Some(buf) => {
+            buf.clone_from_slice(data);
+            e.dirty = true;
+            true
+          },
+          None => false,
+        },
+        None => false,
+      };
+      if written {
+        lru.touch(sector);
+      }
+      written
+    };
+    if !in_place {
+      let mut buf = try!(s.buffer());
+      buf.clone_from_slice(data);
+      s.lru.lock().insert(sector, Arc::new(buf), true);
+    }
+
+    let kick = s.lru.lock().kick.take();
+    if let Some(kick) = kick {
+      kick.signal();
+    }
+    Ok(())
+  }
+
+  fn flush(&self) -> Result<(), Error> {
+    try!(self.shared.write_back());
+    let tok = try!(self.shared.device().flush_dispatch());
+    tok.wait();
+    match self.shared.device().write_await(tok) {
+      // Devices that can't flush write through anyway.
+      Err(Error::Unsupported) => Ok(()),
+      r => r.map(|_| ()),
+    }
+  }
+}
@@ -1,26 +1,80 @@
-//pub mod ramdev;
+pub mod ramdev;
 pub mod cached;
+pub mod lru;
+pub mod partition;
 
 use core::fmt;
 use alloc::boxed::Box;
+use collections::vec::Vec;
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum Error {
   InternalError,
   Unknown,
+  ReadOnly,
+  // The device reported that it failed to carry out the request
+  IoError,
+  // The device doesn't know the request, e.g. flushes on a write-through disk
+  Unsupported,
+  // The request reaches past the end of the device (or partition)
+  OutOfRange,
 }
 
 pub use self::cached::Cache;
 
+use sched;
+use sync::global_mutex::{GlobalMutex,GlobalMutexGuard};
+
+// A request that was handed to a device.
+pub trait Pending {
+  // Block until the device is done with the request. This doesn't need the
+  // device, so other tasks can keep submitting requests in the meantime.
+  fn wait(&self);
+}
+
 pub trait Client: fmt::Debug {
-  type Tag;
+  type Tag: Pending;
 
-  // Submits a sequest to read the specified sector.
+  // Submits a sequest to read the sectors starting at `sector` into `bufs`.
+  // Every buffer must be a multiple of 512 bytes long; they are filled one
+  // after the other, so the whole request covers as many consecutive sectors
+  // as they add up to. There can be at most max_segments() buffers.
   // Returns a token that can be used to block until the read is completed.
-  fn read_dispatch(&mut self, sector: u64, buf: Box<[u8]>) -> Result<Self::Tag, Error>;
+  fn read_dispatch(&mut self, sector: u64, bufs: Vec<Box<[u8]>>) -> Result<Self::Tag, Error>;
+
+  // Block until the read identified by the token is completed, then hands
+  // back the buffers it was given, filled with the data. To not keep the
+  // device busy while waiting, call tok.wait() first.
+  fn read_await(&mut self, tok: Self::Tag) -> Result<Vec<Box<[u8]>>, Error>;
+
+  // Submits a request to write `bufs` (laid out as for read_dispatch) to the
+  // sectors starting at `sector`. The write may sit in the device's cache
+  // until the next flush.
+  fn write_dispatch(&mut self, sector: u64, bufs: Vec<Box<[u8]>>) -> Result<Self::Tag, Error>;
+
+  // Submits a request to make every write that completed before it durable.
+  fn flush_dispatch(&mut self) -> Result<Self::Tag, Error>;
+
+  // Block until the write or flush identified by the token is completed
+  // (see read_await). Hands back the buffers that were written, so that
+  // they can be used again; a flush has none.
+  fn write_await(&mut self, tok: Self::Tag) -> Result<Vec<Box<[u8]>>, Error>;
+
+  // The size of the device, in 512-byte sectors.
+  fn sectors(&self) -> u64;
+
+  // How many buffers a single read or write can take.
+  fn max_segments(&self) -> usize;
+}
 
-  // Block until the read identified by the token is completed, then writes the read data
-  // into `buf` (which must be of size 512).
-  // TODO: This is actually just a badly-designed Future! We could probably just call it .wait() on the Tag?
-  fn read_await(&mut self, tok: Self::Tag) -> Result<Box<[u8]>, Error>;
+// Take the lock on a device that's shared between tasks. Whoever holds it
+// might be waiting for the device, so spinning would keep them from ever
+// getting to run again.
+fn lock<C>(dev: &GlobalMutex<C>) -> GlobalMutexGuard<C> {
+  loop {
+    if let Some(d) = dev.try_lock() {
+      return d;
+    }
+    sched::kyield();
+  }
 }
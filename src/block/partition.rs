@@ -0,0 +1,294 @@
+use prelude::*;
+
+use byteorder::{ByteOrder,LittleEndian};
+use sync::global_mutex::GlobalMutex;
+use super::{Cache, Client, Error, Pending};
+use super::cached::SectorCheckout;
+
+const SECTOR_SIZE: usize = 512;
+
+// MBR partition types that hold a chain of extended boot records instead of
+// a filesystem.
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+// The single partition of a protective MBR, which says "look at the GPT".
+const MBR_PROTECTIVE: u8 = 0xee;
+// Logical partitions are numbered from here, like on Linux.
+const FIRST_LOGICAL: usize = 5;
+// A corrupt chain of extended boot records could go round in circles.
+const MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &'static [u8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+// Anything bigger is taken to be garbage: that's 128 sectors of entries.
+const GPT_MAX_ENTRIES_SIZE: usize = 0x10000;
+
+// A range of sectors on a device, used like a device of its own. The whole
+// device is a range too, and all ranges on it share it.
+#[derive(Debug)]
+pub struct Partition<C: Client> {
+  dev: Arc<GlobalMutex<C>>,
+  start: u64,
+  sectors: u64,
+  // 1 for vda1 and so on, 0 for the whole device
+  number: usize,
+}
+
+impl<C: Client> Partition<C> {
+  // The whole device.
+  pub fn whole(dev: C) -> Self {
+    let sectors = dev.sectors();
+    Partition { dev: Arc::new(GlobalMutex::new(dev)), start: 0, sectors: sectors, number: 0 }
+  }
+
+  pub fn number(&self) -> usize {
+    self.number
+  }
+
+  // Where the partition starts on the device, in sectors.
+  pub fn start(&self) -> u64 {
+    self.start
+  }
+
+  // The partitions in the device's partition table, from a GPT if there is
+  // one and from the MBR otherwise. No table means no partitions.
+  pub fn scan(&self) -> Result<Vec<Partition<C>>, Error> {
+    let mbr = try!(self.read(0, 1));
+    if mbr[510] != 0x55 || mbr[511] != 0xaa {
+      return Ok(vec![]);
+    }
+
+    let primary: Vec<(u8, u64, u64)> = (0..4).map(|i| {
+      let e = &mbr[446 + 16*i..446 + 16*(i+1)];
+      (e[4], LittleEndian::read_u32(&e[8..]) as u64, LittleEndian::read_u32(&e[12..]) as u64)
+    }).collect();
+
+    if primary.iter().any(|&(kind, _, _)| kind == MBR_PROTECTIVE) {
+      return self.scan_gpt();
+    }
+
+    let mut found = vec![];
+    for (i, &(kind, start, sectors)) in primary.iter().enumerate() {
+      if kind == 0 || sectors == 0 {
+        continue;
+      }
+      if MBR_EXTENDED.contains(&kind) {
+        try!(self.scan_extended(start, &mut found));
+      } else {
+        self.add(&mut found, i + 1, start, sectors);
+      }
+    }
+    Ok(found)
+  }
+
+  // Logical partitions: every extended boot record describes one of them,
+  // relative to itself, and where the next record is, relative to the start
+  // of the extended partition.
+  fn scan_extended(&self, base: u64, found: &mut Vec<Partition<C>>) -> Result<(), Error> {
+    let mut ebr = base;
+    for n in 0..MAX_LOGICAL {
+      if ebr >= self.sectors {
+        break;
+      }
+      let sector = try!(self.read(ebr, 1));
+      if sector[510] != 0x55 || sector[511] != 0xaa {
+        break;
+      }
+      let (logical, next) = (&sector[446..462], &sector[462..478]);
+      if logical[4] != 0 {
+        let start = ebr + LittleEndian::read_u32(&logical[8..]) as u64;
+        self.add(found, FIRST_LOGICAL + n, start, LittleEndian::read_u32(&logical[12..]) as u64);
+      }
+      if next[4] == 0 {
+        break;
+      }
+      ebr = base + LittleEndian::read_u32(&next[8..]) as u64;
+    }
+    Ok(())
+  }
+
+  // The primary header is right after the MBR, the backup one in the very
+  // last sector. Whichever checks out first wins.
+  fn scan_gpt(&self) -> Result<Vec<Partition<C>>, Error> {
+    for &lba in [1, self.sectors - 1].iter() {
+      if let Some(found) = try!(self.read_gpt(lba)) {
+        return Ok(found);
+      }
+    }
+    println!("block: no valid GPT header found");
+    Ok(vec![])
+  }
+
+  fn read_gpt(&self, lba: u64) -> Result<Option<Vec<Partition<C>>>, Error> {
+    let mut hdr = try!(self.read(lba, 1));
+    let size = LittleEndian::read_u32(&hdr[12..]) as usize;
+    if &hdr[..8] != GPT_SIGNATURE || size < GPT_HEADER_SIZE || size > SECTOR_SIZE {
+      return Ok(None);
+    }
+    // The checksum is over the header with the checksum field zeroed.
+    let crc = LittleEndian::read_u32(&hdr[16..]);
+    LittleEndian::write_u32(&mut hdr[16..], 0);
+    if crc32(&hdr[..size]) != crc {
+      return Ok(None);
+    }
+
+    let entries_lba = LittleEndian::read_u64(&hdr[72..]);
+    let count = LittleEndian::read_u32(&hdr[80..]) as usize;
+    let entry_size = LittleEndian::read_u32(&hdr[84..]) as usize;
+    let bytes = count * entry_size;
+    if entry_size < 128 || bytes > GPT_MAX_ENTRIES_SIZE {
+      return Ok(None);
+    }
+    // A header can check out and still point past the end of the device,
+    // in which case the other one gets its chance.
+    if self.check(entries_lba, bytes).is_err() {
+      return Ok(None);
+    }
+    let entries = try!(self.read(entries_lba, (bytes + SECTOR_SIZE - 1) / SECTOR_SIZE));
+    if crc32(&entries[..bytes]) != LittleEndian::read_u32(&hdr[88..]) {
+      return Ok(None);
+    }
+
+    let mut found = vec![];
+    for (i, e) in entries[..bytes].chunks(entry_size).enumerate() {
+      // An all-zero type GUID marks an unused entry.
+      if e[..16].iter().all(|&b| b == 0) {
+        continue;
+      }
+      let first = LittleEndian::read_u64(&e[32..]);
+      let last = LittleEndian::read_u64(&e[40..]);
+      if last >= first {
+        self.add(&mut found, i + 1, first, last - first + 1);
+      }
+    }
+    Ok(Some(found))
+  }
+
+  // Add a partition at `start` (relative to this one), if it fits.
+  fn add(&self, found: &mut Vec<Partition<C>>, number: usize, start: u64, sectors: u64) {
+    if start == 0 || start >= self.sectors || sectors > self.sectors - start {
+      println!("block: partition {} ({} sectors at {}) doesn't fit on the device, ignoring it", number, sectors, start);
+      return;
+    }
+    found.push(Partition { dev: self.dev.clone(), start: self.start + start, sectors: sectors, number: number });
+  }
+
+  // Read `count` sectors (relative to this partition) and wait for them.
+  fn read(&self, sector: u64, count: usize) -> Result<Box<[u8]>, Error> {
+    try!(self.check(sector, count * SECTOR_SIZE));
+    let buf = vec![0u8; count * SECTOR_SIZE].into_boxed_slice();
+    let tok = try!(super::lock(&self.dev).read_dispatch(self.start + sector, vec![buf]));
+    tok.wait();
+    let mut bufs = try!(super::lock(&self.dev).read_await(tok));
+    Ok(bufs.remove(0))
+  }
+
+  // Whether `bytes` starting at `sector` are all within the partition.
+  fn check(&self, sector: u64, bytes: usize) -> Result<(), Error> {
+    let count = ((bytes + SECTOR_SIZE - 1) / SECTOR_SIZE) as u64;
+    if sector > self.sectors || count > self.sectors - sector {
+      return Err(Error::OutOfRange);
+    }
+    Ok(())
+  }
+}
+
+fn total(bufs: &[Box<[u8]>]) -> usize {
+  bufs.iter().fold(0, |n, b| n + b.len())
+}
+
+impl<C: Client> Client for Partition<C> {
+  type Tag = C::Tag;
+
+  fn read_dispatch(&mut self, sector: u64, bufs: Vec<Box<[u8]>>) -> Result<Self::Tag, Error> {
+    try!(self.check(sector, total(&bufs)));
+    super::lock(&self.dev).read_dispatch(self.start + sector, bufs)
+  }
+
+  fn read_await(&mut self, tok: Self::Tag) -> Result<Vec<Box<[u8]>>, Error> {
+    tok.wait();
+    super::lock(&self.dev).read_await(tok)
+  }
+
+  fn write_dispatch(&mut self, sector: u64, bufs: Vec<Box<[u8]>>) -> Result<Self::Tag, Error> {
+    try!(self.check(sector, total(&bufs)));
+    super::lock(&self.dev).write_dispatch(self.start + sector, bufs)
+  }
+
+  // Flushes are for the whole device, there's no flushing only part of it.
+  fn flush_dispatch(&mut self) -> Result<Self::Tag, Error> {
+    super::lock(&self.dev).flush_dispatch()
+  }
+
+  fn write_await(&mut self, tok: Self::Tag) -> Result<Vec<Box<[u8]>>, Error> {
+    tok.wait();
+    super::lock(&self.dev).write_await(tok)
+  }
+
+  fn sectors(&self) -> u64 {
+    self.sectors
+  }
+
+  fn max_segments(&self) -> usize {
+    super::lock(&self.dev).max_segments()
+  }
+}
+
+// A partition as seen through the cache of the whole device, so that the
+// device and its partitions share one set of buffers.
+#[derive(Debug)]
+pub struct CachedPartition {
+  cache: Arc<Cache>,
+  start: u64,
+  sectors: u64,
+}
+
+impl CachedPartition {
+  pub fn new<C: Client>(cache: Arc<Cache>, p: &Partition<C>) -> Self {
+    CachedPartition { cache: cache, start: p.start(), sectors: p.sectors }
+  }
+
+  fn check(&self, sector: u64, bytes: usize) -> Result<(), Error> {
+    let count = ((bytes + SECTOR_SIZE - 1) / SECTOR_SIZE) as u64;
+    if sector > self.sectors || count > self.sectors - sector {
+      return Err(Error::OutOfRange);
+    }
+    Ok(())
+  }
+}
+
+impl Cache for CachedPartition {
+  fn get(&self, sector: u64) -> Result<SectorCheckout, Error> {
+    try!(self.check(sector, SECTOR_SIZE));
+    self.cache.get(self.start + sector)
+  }
+
+  fn sectors(&self) -> u64 {
+    self.sectors
+  }
+
+  fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Error> {
+    try!(self.check(sector, buf.len()));
+    self.cache.read(self.start + sector, buf)
+  }
+
+  fn write(&self, sector: u64, data: &[u8]) -> Result<(), Error> {
+    try!(self.check(sector, data.len()));
+    self.cache.write(self.start + sector, data)
+  }
+
+  fn flush(&self) -> Result<(), Error> {
+    self.cache.flush()
+  }
+}
+
+// The crc32 that GPT (and zlib, and Ethernet) uses.
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for &b in data {
+    crc ^= b as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+    }
+  }
+  !crc
+}
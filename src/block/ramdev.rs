@@ -1,48 +1,140 @@
-use super::{Error,Client,ReadWaitToken};
-use alloc::boxed::Box;
-use collections::vec::Vec;
-use sync::global_mutex::GlobalMutex;
-use core::slice::bytes::copy_memory;
-use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use prelude::*;
+use core::{cmp,usize};
 
-use collections::btree_map::BTreeMap;
+use super::{Client, Error, Pending};
+
+const SECTOR_SIZE: usize = 512;
 
-// this could be SleepingMutex as well.
 #[derive(Debug)]
-pub struct Ramdev(GlobalMutex<Vec<u8>>, GlobalMutex<BTreeMap<ReadWaitToken,Box<[u8]>>>, AtomicUsize);
+enum Memory {
+  ReadWrite(&'static mut [u8]),
+  // Blobs built into the kernel image
+  ReadOnly(&'static [u8]),
+}
 
+// A block device in memory. If the memory isn't a whole number of sectors,
+// the last sector is padded with zeroes, and writes to the padding are lost.
+#[derive(Debug)]
+pub struct Ramdev {
+  mem: Memory,
+}
 
-// TODO: bounds check (sector vs. length)
-impl Client for Ramdev {
-  fn read_dispatch(&self, sector: u64, mut buf: Box<[u8]>) -> Result<ReadWaitToken, Error> {
-    assert_eq!(buf.len(), 512);
+// Everything happens right away, at dispatch, so the tag just holds on to
+// the result until it's collected.
+#[derive(Debug)]
+pub struct Done(Result<Vec<Box<[u8]>>, Error>);
+
+impl Pending for Done {
+  fn wait(&self) {}
+}
+
+impl Ramdev {
+  // A fresh disk, all zeroes.
+  pub fn new(sectors: usize) -> Ramdev {
+    Ramdev::from_vec(vec![0u8; sectors * SECTOR_SIZE])
+  }
 
-    assert_eq!(buf.len(), 512);
-    let offs = ((sector as u64) * 512) as usize;
-    let mem = self.0.lock();
-    copy_memory(&mem[offs..offs+512], &mut buf); // this could be a short read
+  // Every sector is filled with its number, modulo 256.
+  pub fn new_striped(sectors: usize) -> Ramdev {
+    let mut mem = Vec::with_capacity(sectors * SECTOR_SIZE);
+    for i in 0..sectors {
+      mem.extend([(i % 256) as u8; SECTOR_SIZE].iter().cloned());
+    }
+    Ramdev::from_vec(mem)
+  }
+
+  // The device keeps the memory for as long as the kernel runs.
+  pub fn from_vec(mem: Vec<u8>) -> Ramdev {
+    let mem = unsafe { &mut *Box::into_raw(mem.into_boxed_slice()) };
+    Ramdev { mem: Memory::ReadWrite(mem) }
+  }
 
-    let mut map = self.1.lock();
-    let tok = self.2.fetch_add(1, Ordering::SeqCst) as ReadWaitToken;
-    map.insert(tok, buf);
+  // A read-only disk, e.g. with a filesystem image built into the kernel.
+  pub fn from_static(mem: &'static [u8]) -> Ramdev {
+    Ramdev { mem: Memory::ReadOnly(mem) }
+  }
 
-    Ok(tok)
+  // Memory that nobody else uses, like a Multiboot module.
+  pub fn from_memory(mem: &'static mut [u8]) -> Ramdev {
+    Ramdev { mem: Memory::ReadWrite(mem) }
   }
 
-  fn read_await(&self, tok: ReadWaitToken) -> Result<Box<[u8]>, Error> {
-    let mut map = self.1.lock();
-    let buf = map.remove(&tok).unwrap(); // panic on invalid, for now
-    Ok(buf)
+  fn bytes(&self) -> &[u8] {
+    match self.mem {
+      Memory::ReadWrite(ref m) => &m[..],
+      Memory::ReadOnly(m) => m,
+    }
+  }
+
+  fn check(&self, sector: u64, bufs: &[Box<[u8]>]) -> Result<(), Error> {
+    if bufs.len() == 0 || bufs.iter().any(|b| b.len() == 0 || b.len() % SECTOR_SIZE != 0) {
+      return Err(Error::InternalError);
+    }
+    let count = bufs.iter().fold(0, |n, b| n + b.len() / SECTOR_SIZE) as u64;
+    if sector > self.sectors() || count > self.sectors() - sector {
+      return Err(Error::OutOfRange);
+    }
+    Ok(())
   }
 }
 
-impl Ramdev {
-  pub fn new_striped(n_sectors: usize) -> Ramdev {
-    let mut mem = Vec::with_capacity(n_sectors*512);
-    for i in 0..n_sectors {
-      let data = [(i % 256) as u8; 512];
-      copy_memory(&data, &mut mem[(i*512)..(i*512)+512]);
+// The part of `len` bytes at `pos` that isn't padding, as (start, length).
+fn clamp(mem: &[u8], pos: usize, len: usize) -> (usize, usize) {
+  let start = cmp::min(pos, mem.len());
+  (start, cmp::min(len, mem.len() - start))
+}
+
+impl Client for Ramdev {
+  type Tag = Done;
+
+  fn read_dispatch(&mut self, sector: u64, mut bufs: Vec<Box<[u8]>>) -> Result<Self::Tag, Error> {
+    try!(self.check(sector, &bufs));
+    let mem = self.bytes();
+    let mut pos = sector as usize * SECTOR_SIZE;
+    for buf in bufs.iter_mut() {
+      let (start, n) = clamp(mem, pos, buf.len());
+      buf[..n].clone_from_slice(&mem[start..start+n]);
+      for b in buf[n..].iter_mut() {
+        *b = 0;
+      }
+      pos += buf.len();
     }
-    Ramdev(GlobalMutex::new(mem), GlobalMutex::new(BTreeMap::new()), ATOMIC_USIZE_INIT)
+    Ok(Done(Ok(bufs)))
+  }
+
+  fn read_await(&mut self, tok: Self::Tag) -> Result<Vec<Box<[u8]>>, Error> {
+    tok.0
+  }
+
+  fn write_dispatch(&mut self, sector: u64, bufs: Vec<Box<[u8]>>) -> Result<Self::Tag, Error> {
+    try!(self.check(sector, &bufs));
+    let mem = match self.mem {
+      Memory::ReadWrite(ref mut m) => &mut m[..],
+      Memory::ReadOnly(_) => return Err(Error::ReadOnly),
+    };
+    let mut pos = sector as usize * SECTOR_SIZE;
+    for buf in bufs.iter() {
+      let (start, n) = clamp(mem, pos, buf.len());
+      mem[start..start+n].clone_from_slice(&buf[..n]);
+      pos += buf.len();
+    }
+    Ok(Done(Ok(bufs)))
+  }
+
+  // Nothing to flush, memory is as durable as it gets.
+  fn flush_dispatch(&mut self) -> Result<Self::Tag, Error> {
+    Ok(Done(Ok(vec![])))
+  }
+
+  fn write_await(&mut self, tok: Self::Tag) -> Result<Vec<Box<[u8]>>, Error> {
+    tok.0
+  }
+
+  fn sectors(&self) -> u64 {
+    ((self.bytes().len() + SECTOR_SIZE - 1) / SECTOR_SIZE) as u64
+  }
+
+  fn max_segments(&self) -> usize {
+    usize::MAX
   }
 }
@@ -0,0 +1,106 @@
+use prelude::*;
+use core::str;
+
+use print;
+
+extern {
+  fn multiboot_cmdline() -> *const u8;
+}
+
+// What the kernel command line says, e.g.
+//
+//   /boot/cor.elf root=vda2 init=/bin/sh loglevel=0 console=ttyS0
+//
+// Anything that's not mentioned keeps its default.
+#[derive(Debug)]
+pub struct Options {
+  // The disk with the root filesystem, e.g. "vda2". By default it's the
+  // first disk that has one.
+  pub root: Option<String>,
+  // The program to start as the first process.
+  pub init: String,
+  // 0 silences println!, 2 adds debug!. Panics are always shown.
+  pub loglevel: usize,
+  // The device in /dev that init's stdin, stdout and stderr go to.
+  pub console: String,
+  // How many steps a user process runs before yielding to other tasks.
+  pub timeslice: usize,
+  // Handed to init as TEST=..., for the feature tests.
+  pub test: Option<String>,
+  // Words we didn't understand.
+  ignored: Vec<String>,
+}
+
+unsafe_lazy_static! {
+  pub static ref OPTIONS: Options = Options::parse(raw());
+}
+
+// Parse the command line and apply the options nobody asks for themselves.
+// This has to come before any other task looks at OPTIONS.
+pub fn init() {
+  print::set_loglevel(OPTIONS.loglevel);
+  for word in OPTIONS.ignored.iter() {
+    println!("cmdline: ignoring {:?}", word);
+  }
+  println!("cmdline: {:?}", *OPTIONS);
+}
+
+// The command line as GRUB passed it (see multiboot.c).
+fn raw() -> &'static str {
+  unsafe {
+    let p = multiboot_cmdline();
+    let mut len = 0;
+    while *p.offset(len as isize) != 0 {
+      len += 1;
+    }
+    str::from_utf8(slice::from_raw_parts(p, len)).unwrap_or("")
+  }
+}
+
+// Devices can be named with or without the /dev/ in front.
+fn device(value: &str) -> String {
+  String::from(if value.starts_with("/dev/") { &value[5..] } else { value })
+}
+
+impl Options {
+  fn parse(line: &str) -> Options {
+    let mut o = Options {
+      root: None,
+      init: String::from("/init"),
+      loglevel: 1,
+      console: String::from("console"),
+      timeslice: 1,
+      test: None,
+      ignored: vec![],
+    };
+
+    let mut words = line.split(' ').filter(|w| !w.is_empty()).peekable();
+    // GRUB puts the kernel's own path first.
+    if words.peek().map_or(false, |w| w.starts_with('/')) {
+      words.next();
+    }
+
+    for word in words {
+      let (key, value) = match word.find('=') {
+        Some(i) => (&word[..i], &word[i+1..]),
+        None => (word, ""),
+      };
+      let understood = match key {
+        "root" if value.len() > 0 => { o.root = Some(device(value)); true },
+        "init" if value.starts_with('/') => { o.init = String::from(value); true },
+        "loglevel" => value.parse::<usize>().map(|n| o.loglevel = n).is_ok(),
+        "console" if value.len() > 0 => { o.console = device(value); true },
+        "sched.timeslice" => match value.parse::<usize>() {
+          Ok(n) if n > 0 => { o.timeslice = n; true },
+          _ => false,
+        },
+        "test" => { o.test = Some(String::from(value)); true },
+        _ => false,
+      };
+      if !understood {
+        o.ignored.push(String::from(word));
+      }
+    }
+    o
+  }
+}
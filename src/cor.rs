@@ -14,39 +14,39 @@ extern crate collections;
 
 mod prelude;
 
-#[macro_use] // For `print!` and `println!`, writing to the kernel console
+#[macro_use] // For `print!`, `println!` and `debug!`, writing to the kernel console
 mod print;
 
+#[macro_use] // For `unsafe_lazy_static!`
+mod lazy_static;
+
 mod byteorder;
 
 mod usertask;
 mod cpuio;
+mod mmio;
 mod drivers;
 mod fs;
 mod kbuf;
 mod mem;
+mod multiboot;
+mod cmdline;
 mod sched;
 mod sync;
 mod block;
 
 extern "C" {
-  fn pci_init();
   fn asm_idle();
 }
 
-#[no_mangle]
-pub fn virtio_init() {
-  println!("virtio_init() called!");
-}
-
 #[no_mangle]
 pub extern "C" fn handle_irq(irq: u8) {
   sched::irq::handle_irq(irq);
 }
 
 fn explore_pci() {
-  unsafe { pci_init(); }
-  println!("c-land pci_init exited");
+  drivers::probe();
+  println!("PCI scan done");
 }
 
 fn idle_task() {
@@ -60,6 +60,8 @@ fn idle_task() {
 
 #[no_mangle]
 pub fn rs_sched_exec() {
+  cmdline::init();
+
   sched::add_task(idle_task, "idle");
 
   // Okay, now that we have the scheduler set up, we can start doing things
@@ -68,6 +70,8 @@ pub fn rs_sched_exec() {
   // have something to say to us.
   sched::add_task(explore_pci, "PCI task");
 
+  // Probing doesn't yield, so the PCI task is done by the time init starts
+  // and takes the devices the drivers found.
   sched::add_task(usertask::exec_init, "init task");
 
   // Finally, we can pass control to all the tasks that have been set up by
@@ -84,7 +88,6 @@ extern {
 #[lang = "stack_exhausted"] extern fn stack_exhausted() {}
 #[lang = "eh_personality"] extern fn eh_personality() {}
 
-use collections::string::String;
 use core::fmt::Write;
 use print::Kio;
 
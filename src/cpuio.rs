@@ -20,6 +20,10 @@ pub unsafe fn alloc(base: u16, width: u16, mask: &'static str) -> Result<IoPort,
 
 // these should probably just be debug_assert!'s
 impl IoPort {
+  pub fn base(&self) -> u16 {
+    self.base
+  }
+
   // Given two byte masks containing 'X' and '-', split the given Port into two
   // non-overlapping masked ports.
   // Panics if the given masks are illegal.
@@ -72,7 +76,14 @@ impl IoPort {
     assert_eq!(self.mask.char_at((offset+1) as usize), 'X');
     unsafe { read16(self.base+offset) }
   }
-  //n read32(&self, offset: u16) { unsafe { read32(self.base+offset) } }
+  pub fn read32(&mut self, offset: u16) -> u32 {
+    assert!(offset+3 < self.width);
+    assert_eq!(self.mask.char_at((offset)   as usize), 'X');
+    assert_eq!(self.mask.char_at((offset+1) as usize), 'X');
+    assert_eq!(self.mask.char_at((offset+2) as usize), 'X');
+    assert_eq!(self.mask.char_at((offset+3) as usize), 'X');
+    unsafe { read32(self.base+offset) }
+  }
 }
 
 pub type Port = u16;
@@ -119,6 +130,18 @@ pub unsafe fn read16(port: Port) -> u16 {
   x
 }
 
+pub unsafe fn read32(port: Port) -> u32 {
+  let mut x : u32 = 0;
+  asm! (
+    "inl %dx, %eax"
+    : "={eax}" (x)
+    : "{dx}" (port as u16)
+    :
+    : "volatile"
+  );
+  x
+}
+
 pub unsafe fn read8(port: Port) -> u8 {
   let mut x : u8 = 0;
   asm! (
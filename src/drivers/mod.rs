@@ -1 +1,34 @@
+use prelude::*;
+use sync::global_mutex::GlobalMutex;
+
+pub mod pci;
 pub mod virtio;
+pub mod uart;
+
+// Something that was found on a bus, for /proc/devices.
+#[derive(Debug,Clone)]
+pub struct Device {
+  pub bus: &'static str,
+  // Where on the bus, e.g. "00:03" for PCI
+  pub address: String,
+  pub description: String,
+}
+
+unsafe_lazy_static! {
+  static ref DEVICES: GlobalMutex<Vec<Device>> = { GlobalMutex::new(vec![]) };
+}
+
+pub fn register(bus: &'static str, address: String, description: String) {
+  DEVICES.lock().push(Device { bus: bus, address: address, description: description });
+}
+
+// In the order they were found.
+pub fn list() -> Vec<Device> {
+  DEVICES.lock().clone()
+}
+
+// Let every driver we have register itself, then go looking for devices.
+pub fn probe() {
+  virtio::register_drivers();
+  pci::scan();
+}
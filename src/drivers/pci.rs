@@ -0,0 +1,358 @@
+use prelude::*;
+
+use cpuio;
+use drivers;
+use sync::global_mutex::GlobalMutex;
+
+// ref: http://wiki.osdev.org/PCI
+//
+// We talk to the configuration space through the two legacy I/O ports. Only
+// the PCI task and driver probes touch them, and neither yields halfway
+// through an access, so nothing else can get between the address and the
+// data.
+const CONFIG_ADDRESS: cpuio::Port = 0xcf8;
+const CONFIG_DATA: cpuio::Port = 0xcfc;
+
+// Where pic_init (in main.c) puts the legacy interrupt lines.
+const IRQ_BASE: u8 = 0x20;
+
+// The configuration space header, as far as we need it
+const VENDOR: u8 = 0x00;
+const DEVICE: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+const STATUS: u8 = 0x06;
+const REVISION: u8 = 0x08;
+const PROG_IF: u8 = 0x09;
+const SUBCLASS: u8 = 0x0a;
+const CLASS: u8 = 0x0b;
+const HEADER_TYPE: u8 = 0x0e;
+const BAR0: u8 = 0x10;
+const SECONDARY_BUS: u8 = 0x19;
+const SUBSYSTEM_VENDOR: u8 = 0x2c;
+const SUBSYSTEM: u8 = 0x2e;
+const CAPABILITIES: u8 = 0x34;
+const INTERRUPT_LINE: u8 = 0x3c;
+const INTERRUPT_PIN: u8 = 0x3d;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_BRIDGE: u8 = 0x01;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+// Capability IDs
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_MSIX: u8 = 0x11;
+
+// 256 bytes of configuration space, minus the header, fit this many.
+const MAX_CAPABILITIES: usize = 48;
+
+// Where a function sits in the configuration space.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Address {
+  pub bus: u8,
+  pub slot: u8,
+  pub function: u8,
+}
+
+impl fmt::Display for Address {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:02x}:{:02x}.{}", self.bus, self.slot, self.function)
+  }
+}
+
+impl Address {
+  fn select(&self, offset: u8) {
+    let address = 0x80000000 | (self.bus as u32) << 16 | (self.slot as u32) << 11 |
+      (self.function as u32) << 8 | (offset & 0xfc) as u32;
+    unsafe { cpuio::write32(CONFIG_ADDRESS, address); }
+  }
+
+  pub fn read8(&self, offset: u8) -> u8 {
+    self.select(offset);
+    unsafe { cpuio::read8(CONFIG_DATA + (offset & 3) as u16) }
+  }
+
+  pub fn read16(&self, offset: u8) -> u16 {
+    self.select(offset);
+    unsafe { cpuio::read16(CONFIG_DATA + (offset & 2) as u16) }
+  }
+
+  pub fn read32(&self, offset: u8) -> u32 {
+    self.select(offset);
+    unsafe { cpuio::read32(CONFIG_DATA) }
+  }
+
+  pub fn write8(&self, offset: u8, val: u8) {
+    self.select(offset);
+    unsafe { cpuio::write8(CONFIG_DATA + (offset & 3) as u16, val) }
+  }
+
+  pub fn write16(&self, offset: u8, val: u16) {
+    self.select(offset);
+    unsafe { cpuio::write16(CONFIG_DATA + (offset & 2) as u16, val) }
+  }
+
+  pub fn write32(&self, offset: u8, val: u32) {
+    self.select(offset);
+    unsafe { cpuio::write32(CONFIG_DATA, val) }
+  }
+
+  // Nothing answers for functions that aren't there, so reads come back as
+  // all ones; no vendor has the ID 0xffff.
+  fn exists(&self) -> bool {
+    self.read16(VENDOR) != 0xffff
+  }
+}
+
+// A base address register: where the device wants to be talked to.
+#[derive(Debug,Clone,Copy)]
+pub enum Bar {
+  Io { port: u16, size: u16 },
+  Memory { address: u64, size: u64, prefetchable: bool },
+}
+
+// An entry in the capability list. Its contents are in the configuration
+// space at `offset`, after the ID and the pointer to the next one.
+#[derive(Debug,Clone,Copy)]
+pub struct Capability {
+  pub id: u8,
+  pub offset: u8,
+}
+
+// A function on the bus, with everything a driver needs to set it up.
+#[derive(Debug,Clone)]
+pub struct Device {
+  pub address: Address,
+  pub vendor: u16,
+  pub device: u16,
+  pub class: u8,
+  pub subclass: u8,
+  pub prog_if: u8,
+  pub revision: u8,
+  // Zero for bridges, which don't have any
+  pub subsystem_vendor: u16,
+  pub subsystem: u16,
+  // By BAR number. The upper half of a 64-bit BAR, and BARs the device
+  // doesn't implement, are None.
+  pub bars: Vec<Option<Bar>>,
+  pub capabilities: Vec<Capability>,
+  // The interrupt vector of its legacy interrupt line, if it has one
+  pub irq: Option<u8>,
+}
+
+impl Device {
+  fn read(address: Address) -> Device {
+    let header = address.read8(HEADER_TYPE) & !HEADER_MULTIFUNCTION;
+    let (bars, subsystem_vendor, subsystem) = match header {
+      0 => (read_bars(address, 6), address.read16(SUBSYSTEM_VENDOR), address.read16(SUBSYSTEM)),
+      HEADER_BRIDGE => (read_bars(address, 2), 0, 0),
+      _ => (vec![], 0, 0),
+    };
+    let line = address.read8(INTERRUPT_LINE);
+    let irq = if address.read8(INTERRUPT_PIN) != 0 && line < 16 { Some(IRQ_BASE + line) } else { None };
+
+    Device {
+      address: address,
+      vendor: address.read16(VENDOR),
+      device: address.read16(DEVICE),
+      class: address.read8(CLASS),
+      subclass: address.read8(SUBCLASS),
+      prog_if: address.read8(PROG_IF),
+      revision: address.read8(REVISION),
+      subsystem_vendor: subsystem_vendor,
+      subsystem: subsystem,
+      bars: bars,
+      capabilities: read_capabilities(address),
+      irq: irq,
+    }
+  }
+
+  pub fn bar(&self, i: usize) -> Option<Bar> {
+    self.bars.get(i).and_then(|b| *b)
+  }
+
+  // Let the device answer to its BARs and do DMA.
+  pub fn enable(&self) {
+    let command = self.address.read16(COMMAND);
+    self.address.write16(COMMAND, command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+  }
+
+  fn is_bridge(&self) -> bool {
+    self.class == CLASS_BRIDGE && self.subclass == SUBCLASS_PCI_BRIDGE
+  }
+}
+
+// Sizing a BAR means writing all ones to it and seeing which bits stick, so
+// the device mustn't decode addresses while we do it.
+fn read_bars(address: Address, count: u8) -> Vec<Option<Bar>> {
+  let command = address.read16(COMMAND);
+  address.write16(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+  let probe = |offset: u8| {
+    let val = address.read32(offset);
+    address.write32(offset, !0);
+    let mask = address.read32(offset);
+    address.write32(offset, val);
+    (val, mask)
+  };
+
+  let mut bars = vec![];
+  let mut i = 0;
+  while i < count {
+    let (val, mask) = probe(BAR0 + 4*i);
+    i += 1;
+    if mask == 0 {
+      bars.push(None);
+    } else if val & 1 == 1 {
+      // I/O ports only go up to 0xffff, the upper half may read as zeroes.
+      let mask = (mask & !0x3) as u16;
+      bars.push(Some(Bar::Io { port: (val & !0x3) as u16, size: (!mask).wrapping_add(1) }));
+    } else {
+      let mut base = (val & !0xf) as u64;
+      let mut mask = (mask & !0xf) as u64 | 0xffffffff00000000;
+      let wide = (val >> 1) & 3 == 2;
+      if wide && i < count {
+        let (high, high_mask) = probe(BAR0 + 4*i);
+        i += 1;
+        base |= (high as u64) << 32;
+        mask = (mask & 0xffffffff) | (high_mask as u64) << 32;
+      }
+      bars.push(Some(Bar::Memory { address: base, size: (!mask).wrapping_add(1), prefetchable: val & 0x8 != 0 }));
+      if wide {
+        bars.push(None);
+      }
+    }
+  }
+
+  address.write16(COMMAND, command);
+  bars
+}
+
+fn read_capabilities(address: Address) -> Vec<Capability> {
+  let mut caps = vec![];
+  if address.read16(STATUS) & STATUS_CAPABILITIES == 0 {
+    return caps;
+  }
+  let mut offset = address.read8(CAPABILITIES) & !0x3;
+  // A broken list could go round in circles.
+  while offset != 0 && caps.len() < MAX_CAPABILITIES {
+    caps.push(Capability { id: address.read8(offset), offset: offset });
+    offset = address.read8(offset + 1) & !0x3;
+  }
+  caps
+}
+
+// Which devices a driver wants. Whatever is None matches anything.
+#[derive(Debug,Clone,Copy)]
+pub struct Id {
+  pub vendor: Option<u16>,
+  pub device: Option<u16>,
+  pub class: Option<(u8, u8)>,
+}
+
+impl Id {
+  pub fn device(vendor: u16, device: u16) -> Id {
+    Id { vendor: Some(vendor), device: Some(device), class: None }
+  }
+
+  pub fn class(class: u8, subclass: u8) -> Id {
+    Id { vendor: None, device: None, class: Some((class, subclass)) }
+  }
+
+  fn matches(&self, dev: &Device) -> bool {
+    self.vendor.map_or(true, |v| v == dev.vendor) &&
+      self.device.map_or(true, |d| d == dev.device) &&
+      self.class.map_or(true, |c| c == (dev.class, dev.subclass))
+  }
+}
+
+// Sets up a device the driver said it wants. It's the driver's business what
+// becomes of the device afterwards.
+pub type Probe = fn(&Device) -> Result<(), &'static str>;
+
+struct Driver {
+  name: &'static str,
+  ids: Vec<Id>,
+  probe: Probe,
+}
+
+unsafe_lazy_static! {
+  static ref DRIVERS: GlobalMutex<Vec<Driver>> = { GlobalMutex::new(vec![]) };
+}
+
+// Drivers have to be registered before the scan, they're not probed for
+// devices found earlier.
+pub fn register_driver(name: &'static str, ids: Vec<Id>, probe: Probe) {
+  DRIVERS.lock().push(Driver { name: name, ids: ids, probe: probe });
+}
+
+// Find every function on every bus behind the host bridge, list it in
+// /proc/devices, and hand it to the first driver that wants it.
+pub fn scan() {
+  let mut found = vec![];
+  let host = Address { bus: 0, slot: 0, function: 0 };
+  if host.read8(HEADER_TYPE) & HEADER_MULTIFUNCTION == 0 {
+    scan_bus(0, &mut found);
+  } else {
+    // Several host bridges, function n is responsible for bus n.
+    for function in 0..8 {
+      if (Address { function: function, ..host }).exists() {
+        scan_bus(function, &mut found);
+      }
+    }
+  }
+
+  for dev in found.iter() {
+    let mut description = String::new();
+    let _ = write!(description, "{:04x}:{:04x} class {:02x}{:02x}", dev.vendor, dev.device, dev.class, dev.subclass);
+    println!("pci: {} {}", dev.address, description);
+    let mut address = String::new();
+    let _ = write!(address, "{}", dev.address);
+    drivers::register("pci", address, description);
+
+    // Don't hold the lock while probing, drivers might look at the list too.
+    let probe = DRIVERS.lock().iter().find(|d| d.ids.iter().any(|id| id.matches(dev))).map(|d| (d.name, d.probe));
+    if let Some((name, probe)) = probe {
+      match probe(dev) {
+        Ok(()) => println!("pci: {} is driven by {}", dev.address, name),
+        Err(e) => println!("pci: {} couldn't set up {}: {}", name, dev.address, e),
+      }
+    }
+  }
+}
+
+fn scan_bus(bus: u8, found: &mut Vec<Device>) {
+  for slot in 0..32 {
+    let address = Address { bus: bus, slot: slot, function: 0 };
+    if !address.exists() {
+      continue;
+    }
+    let functions = if address.read8(HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 { 8 } else { 1 };
+    for function in 0..functions {
+      let address = Address { function: function, ..address };
+      if address.exists() {
+        scan_function(address, found);
+      }
+    }
+  }
+}
+
+fn scan_function(address: Address, found: &mut Vec<Device>) {
+  let dev = Device::read(address);
+  // Firmware numbers the buses behind bridges; a bus that's numbered lower
+  // than the one it hangs off would have us going in circles.
+  let secondary = if dev.is_bridge() { Some(address.read8(SECONDARY_BUS)) } else { None };
+  found.push(dev);
+  if let Some(bus) = secondary {
+    if bus > address.bus {
+      scan_bus(bus, found);
+    }
+  }
+}
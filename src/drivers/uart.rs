@@ -0,0 +1,54 @@
+use cpuio;
+use sched;
+
+// The 16550 on COM1, which boot code (chrdev_serial.c) has already set up
+// for the kernel log. We only poll it: its IRQ isn't routed anywhere.
+pub const COM1: cpuio::Port = 0x3f8;
+
+const DATA: cpuio::Port = 0;
+const LINE_STATUS: cpuio::Port = 5;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+
+#[derive(Debug)]
+pub struct Uart {
+  base: cpuio::Port,
+}
+
+impl Uart {
+  pub fn new(base: cpuio::Port) -> Self {
+    Uart { base: base }
+  }
+
+  fn status(&self) -> u8 {
+    unsafe { cpuio::read8(self.base + LINE_STATUS) }
+  }
+
+  pub fn write(&self, data: &[u8]) {
+    for c in data {
+      while self.status() & LSR_THR_EMPTY == 0 {}
+      unsafe { cpuio::write8(self.base + DATA, *c); }
+    }
+  }
+
+  // Returns None if no input is waiting, otherwise drains the FIFO into buf.
+  pub fn try_read(&self, buf: &mut [u8]) -> Option<usize> {
+    let mut n = 0;
+    while n < buf.len() && self.status() & LSR_DATA_READY != 0 {
+      buf[n] = unsafe { cpuio::read8(self.base + DATA) };
+      n += 1;
+    }
+    if n > 0 { Some(n) } else { None }
+  }
+
+  // Blocks until there is some input.
+  pub fn read(&self, buf: &mut [u8]) -> usize {
+    loop {
+      if let Some(n) = self.try_read(buf) {
+        return n;
+      }
+      sched::kyield();
+    }
+  }
+}
@@ -3,9 +3,10 @@ use prelude::*;
 use super::virtq;
 use super::pci;
 
-use cpuio;
-use sched::blocking::{WaitToken,SignalToken};
-use block::{Client,Error};
+use drivers::pci::Device;
+use sched;
+use sched::blocking::{self,WaitToken,SignalToken};
+use block::{Client,Error,Pending};
 
 use collections::btree_map::BTreeMap;
 use sync::global_mutex::GlobalMutex;
@@ -13,62 +14,159 @@ use byteorder::{ByteOrder,NativeEndian};
 
 #[derive(Debug)]
 pub struct Blockdev {
-  port: cpuio::IoPort,
+  transport: pci::Transport,
   q: virtq::Virtq,
 
-  completed_requests: Arc<GlobalMutex<BTreeMap<u16,virtq::Buf>>>,
+  // Requests in flight, by the descriptor their chain starts with
+  inflight: Arc<GlobalMutex<BTreeMap<u16,Completion>>>,
+  capacity: u64,
+}
+
+// Where the IRQ handler puts a finished request, and whom it wakes up.
+#[derive(Debug)]
+struct Completion {
+  buf: Arc<GlobalMutex<Option<virtq::Buf>>>,
+  signal: SignalToken,
+}
+
+// The tag for a request. Descriptors go back to the queue as soon as the
+// device is done, so the head descriptor can be in use by another request by
+// the time this one is collected; the tag carries its own slot instead.
+#[derive(Debug)]
+pub struct Request {
+  buf: Arc<GlobalMutex<Option<virtq::Buf>>>,
+  done: WaitToken,
+}
+
+impl Pending for Request {
+  fn wait(&self) {
+    self.done.wait();
+  }
+}
+
+// The device has a write cache that VIRTIO_BLK_T_FLUSH writes back.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types, from the header's first field
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+// What the device writes into the status byte at the end of every request
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+fn header(kind: u32, sector: u64) -> Box<[u8]> {
+  let mut hdr = box [0u8; 16];
+  NativeEndian::write_u32(&mut hdr[0..], kind);
+  NativeEndian::write_u32(&mut hdr[4..], 1); // ioprio
+  NativeEndian::write_u64(&mut hdr[8..], sector);
+  hdr
+}
+
+// Data buffers have to be whole sectors, and there has to be at least one.
+fn check_bufs(bufs: &[Box<[u8]>]) -> Result<(), Error> {
+  if bufs.len() == 0 || bufs.iter().any(|b| b.len() == 0 || b.len() % 512 != 0) {
+    return Err(Error::InternalError);
+  }
+  Ok(())
 }
 
 impl Client for Blockdev {
-  type Tag = u16;
+  type Tag = Request;
+
+  fn read_dispatch(&mut self, sector: u64, bufs: Vec<Box<[u8]>>) -> Result<Self::Tag, Error> {
+    try!(check_bufs(&bufs));
+
+    println!("virtio blockdev: Reading {} buffer(s) from sector {}", bufs.len(), sector);
+
+    let hdr = header(VIRTIO_BLK_T_IN, sector);
+    let done = box [17u8; 1]; // != 0 for checking that it was set by the host
+
+    self.submit(hdr, bufs, true, done)
+  }
+
+  fn read_await(&mut self, req: Self::Tag) -> Result<Vec<Box<[u8]>>, Error> {
+    self.finish(req)
+  }
+
+  fn write_dispatch(&mut self, sector: u64, bufs: Vec<Box<[u8]>>) -> Result<Self::Tag, Error> {
+    try!(check_bufs(&bufs));
 
-  fn read_dispatch(&mut self, sector: u64, mut buf: Box<[u8]>) -> Result<Self::Tag, Error> {
-    assert_eq!(512, buf.len());
+    println!("virtio blockdev: Writing {} buffer(s) to sector {}", bufs.len(), sector);
 
-    println!("virtio blockdev: Reading sector {}", sector);
+    let hdr = header(VIRTIO_BLK_T_OUT, sector);
+    let done = box [17u8; 1];
 
-    let mut hdr = box [0u8; 16];
-    NativeEndian::write_u32(&mut hdr[0..], 0); // kind: 0=read
-    NativeEndian::write_u32(&mut hdr[4..], 1); // ioprio
-    NativeEndian::write_u64(&mut hdr[8..], sector as u64);
-    let mut done = box [17u8; 1]; // != 0 for checking that it was set by the host
+    self.submit(hdr, bufs, false, done)
+  }
+
+  fn flush_dispatch(&mut self) -> Result<Self::Tag, Error> {
+    let hdr = header(VIRTIO_BLK_T_FLUSH, 0);
+    let done = box [17u8; 1];
+
+    self.submit(hdr, vec![], false, done)
+  }
 
-    let tag = self.q.register_rww(hdr, buf, done);
+  fn write_await(&mut self, req: Self::Tag) -> Result<Vec<Box<[u8]>>, Error> {
+    self.finish(req)
+  }
 
-    self.q.send_rww(tag, &mut self.port);
+  fn sectors(&self) -> u64 {
+    self.capacity
+  }
 
-    Ok(tag as Self::Tag)
+  // The header and the status byte take a descriptor each.
+  fn max_segments(&self) -> usize {
+    self.q.size() - 2
+  }
+}
+
+impl Blockdev {
+  fn submit(&mut self, hdr: Box<[u8]>, bufs: Vec<Box<[u8]>>, writable: bool, done: Box<[u8]>) -> Result<Request, Error> {
+    if bufs.len() > self.max_segments() {
+      return Err(Error::InternalError);
+    }
+    // Other requests might be holding the descriptors we need. The IRQ
+    // handler gives them back, so all we have to do is wait.
+    while self.q.available() < self.q.descriptors_for(bufs.len() + 2) {
+      sched::kyield();
+    }
+    let head = try!(self.q.register_request(hdr, bufs, writable, done).ok_or(Error::InternalError));
+
+    let (wait, signal) = blocking::tokens(String::from("virtio-blk request"));
+    let slot = Arc::new(GlobalMutex::new(None));
+    // Before the device gets to see it, so the IRQ handler finds it.
+    assert!(self.inflight.lock().insert(head, Completion { buf: slot.clone(), signal: signal }).is_none());
+    self.q.send_chain(head, &mut self.transport);
+    Ok(Request { buf: slot, done: wait })
   }
 
-  fn read_await(&mut self, tag: Self::Tag) -> Result<Box<[u8]>, Error> {
-    // FIXME: make sure that we're not holding any borrows or locks before we go to sleep?
-    // TODO loop / condition check macro
-    self.q.device_activity.clone().multiwait();
+  // Wait for the given request and check the status the device reported.
+  // The data buffers come back too.
+  fn finish(&mut self, req: Request) -> Result<Vec<Box<[u8]>>, Error> {
+    req.wait();
 
-    match self.completed_requests.lock().remove(&tag) {
+    let (data, done) = match req.buf.lock().take() {
       // drop hdr
-      Some(virtq::Buf::Rww(id1, _, id2, data, id3, done)) => {
-        {
-          self.q.free_descriptors.push_back(id1);
-          self.q.free_descriptors.push_back(id2);
-          self.q.free_descriptors.push_back(id3);
-        }
-
-        println!("Virtio call completed, retval={}", done[0]);
-        if done[0] != 0 { // retval of 0 indicates success
-          println!("Virtio retval is {} != 0", done[0]);
-          return Err(Error::InternalError)
-        }
-        return Ok(data)
-      }
+      Some(virtq::Buf::Request { data, done, .. }) => (data, done),
       x => { panic!("wut! unexpected buffer type {:?}",x) }
+    };
+
+    println!("Virtio call completed, retval={}", done[0]);
+    match done[0] {
+      VIRTIO_BLK_S_OK => Ok(data),
+      VIRTIO_BLK_S_IOERR => Err(Error::IoError),
+      VIRTIO_BLK_S_UNSUPP => Err(Error::Unsupported),
+      _ => Err(Error::InternalError),
     }
-
-    Err(Error::InternalError)
   }
 }
 
-
+// Indirect descriptors let a request of any size take up a single slot in the
+// queue, so more of them fit in at once.
+const FEATURES: u64 = VIRTIO_BLK_F_FLUSH | virtq::VIRTIO_RING_F_INDIRECT_DESC | virtq::VIRTIO_RING_F_EVENT_IDX;
 
 #[derive(Debug)]
 pub enum InitError {
@@ -78,34 +176,56 @@ pub enum InitError {
 }
 
 impl Blockdev {
-  pub fn new(mut port: cpuio::IoPort) -> Result<Self, InitError> {
-    let completed = Arc::new(GlobalMutex::new(BTreeMap::new()));
-    let completed_irqside = completed.clone();
+  pub fn new(dev: &Device) -> Result<Self, InitError> {
+    let inflight = Arc::new(GlobalMutex::new(BTreeMap::new()));
+    let inflight_irqside = inflight.clone();
 
     let request_completion_handler = (box move |used, free| {
-      let ref mut completed = completed_irqside.lock();
-
-      for (buf, written) in used.drain(..) {
-        assert_eq!(513, written);
-        match buf {
-          virtq::Buf::Rww(id, hdr, datadesc, data, donedesc, done) => {
-            println!("Request with tag {} is completed", id);
-            assert!(completed.insert(id, virtq::Buf::Rww(id, hdr, datadesc, data, donedesc, done)).is_none());
-          }
-          _ => {
-            panic!("unexpected buffer type");
-          }
-        }
+      let ref mut inflight = inflight_irqside.lock();
+
+      // Reads write the data and the status byte, everything else just the
+      // status byte.
+      for (buf, _) in used.drain(..) {
+        let id = buf.head();
+        println!("Request with tag {} is completed", id);
+        let c = inflight.remove(&id).unwrap();
+        *c.buf.lock() = Some(buf);
+        c.signal.signal();
       }
     }) as virtq::Handler;
 
     let handlers = vec![(0, request_completion_handler)];
-    let (mut qs, mut txport) = pci::init(port, 0x2a, handlers);
+    // The device-specific configuration starts with the capacity in sectors.
+    let (mut qs, transport, mut config) = try!(pci::init(dev, "block", 8, FEATURES, handlers).map_err(|e| {
+      println!("virtio blockdev: {}", e);
+      InitError::VirtioHandshakeFailure
+    }));
+
+    let capacity = (config.read32(0) as u64) | ((config.read32(4) as u64) << 32);
+    println!("virtio blockdev: {} sectors", capacity);
 
     Ok(Blockdev {
-      port: txport,
+      transport: transport,
       q: qs.remove(0),
-      completed_requests: completed,
+      inflight: inflight,
+      capacity: capacity,
     })
   }
 }
+
+unsafe_lazy_static! {
+  static ref FOUND: GlobalMutex<Vec<Blockdev>> = { GlobalMutex::new(vec![]) };
+}
+
+// Called by the PCI scan for every virtio block device.
+pub fn probe(dev: &Device) -> Result<(), &'static str> {
+  let blockdev = try!(Blockdev::new(dev).map_err(|_| "handshake failed"));
+  println!("result of blockdevice init: {:?}", blockdev);
+  FOUND.lock().push(blockdev);
+  Ok(())
+}
+
+// The disks the PCI scan found that nobody has taken yet, in bus order.
+pub fn take_all() -> Vec<Blockdev> {
+  core::mem::replace(&mut *FOUND.lock(), vec![])
+}
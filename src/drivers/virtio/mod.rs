@@ -1,8 +1,12 @@
-// We can now talk to the actual virtio device
-// via the CPU's I/O pins directly. A couple of helpful references:
+// We can now talk to the actual virtio device, either via the CPU's I/O
+// pins (the legacy interface) or via memory-mapped registers (virtio 1.0).
+// A couple of helpful references:
 //
 // http://ozlabs.org/~rusty/virtio-spec/virtio-0.9.5.pdf
-//     This is the actual virtio spec.
+//     This is the actual virtio spec, or at least the legacy one.
+//
+// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html
+//     This is the virtio 1.0 spec, see "Virtio Over PCI Bus".
 //
 // http://ozlabs.org/~rusty/virtio-spec/virtio-paper.pdf
 //     This is an academic paper describing the virtio design and architecture,
@@ -19,4 +23,29 @@ pub mod block;
 
 mod virtq;
 mod vring;
-mod pci;
+pub mod pci;
+
+use drivers::pci as bus;
+use drivers::pci::Id;
+
+// PCI IDs of virtio devices: transitional ones, which also speak the legacy
+// interface, and modern-only ones, which are 0x1040 plus the device type.
+const VENDOR: u16 = 0x1af4;
+const DEVICE_NET: [u16; 2] = [0x1000, 0x1041];
+const DEVICE_BLOCK: [u16; 2] = [0x1001, 0x1042];
+const DEVICE_CONSOLE: [u16; 2] = [0x1003, 0x1043];
+
+fn ids(devices: &[u16]) -> Vec<Id> {
+  devices.iter().map(|&d| Id::device(VENDOR, d)).collect()
+}
+
+pub fn register_drivers() {
+  bus::register_driver("virtio-blk", ids(&DEVICE_BLOCK), block::probe);
+  bus::register_driver("virtio-console", ids(&DEVICE_CONSOLE), serial::probe);
+  bus::register_driver("virtio-net", ids(&DEVICE_NET), probe_net);
+}
+
+fn probe_net(_: &bus::Device) -> Result<(), &'static str> {
+  println!("this is a virtio NIC, but there's no driver for it yet");
+  Err("not supported")
+}
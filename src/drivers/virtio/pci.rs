@@ -1,62 +1,336 @@
 use prelude::*;
 use cpuio;
+use mmio::Mmio;
 use super::virtq;
-use sync::global_mutex::GlobalMutex;
 use sched;
+use drivers;
+use drivers::pci::{Bar,Device,CAP_VENDOR};
 
-use core::iter::Map;
+use collections::btree_map::BTreeMap;
 
 const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1;
 const VIRTIO_STATUS_DRIVER: u8 = 2;
 const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
+const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
 const VIRTIO_STATUS_FAILED: u8 = 128;
 
-pub fn init(mut port: cpuio::IoPort, irqnum: u8, rxhandlers: Vec<(u16, virtq::Handler)>) -> (Vec<virtq::Virtq>, cpuio::IoPort) {
-  println!("Initializing virtio device on ioport {:?}..", port);
+// The device speaks virtio 1.0 rather than the legacy interface. Modern
+// devices refuse drivers that don't take it, so we always do.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
 
-  let (mut configport, mut operationsport) = port.split_at_masks(
-    "XXXXXXXX----------X-XXXX",  // feature negotiation flags, device status, MSI-X fields
-    "--------XXXXXXXXXX-X----"); // queue address, size, select, notify, ISR status
+// Without MSI-X, the legacy header is 20 bytes long and the device-specific
+// configuration follows right after it.
+pub const HEADER_SIZE: u16 = 20;
 
-  let (mut rxport, mut txport) = operationsport.split_at_masks(
-    "-------------------X----",  // ISR status
-    "--------XXXXXXXXXX------"); // queue address, size, select, notify
+// The virtio 1.0 structures are found through vendor-specific PCI
+// capabilities (struct virtio_pci_cap), one for each type.
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_ISR: u8 = 3;
+const CAP_DEVICE: u8 = 4;
+// Fields of the capability, relative to where it starts
+const CAP_TYPE: u8 = 3;
+const CAP_BAR: u8 = 4;
+const CAP_OFFSET: u8 = 8;
+const CAP_LENGTH: u8 = 12;
+const CAP_NOTIFY_MULTIPLIER: u8 = 16;
+
+// struct virtio_pci_common_cfg
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0;
+const COMMON_DEVICE_FEATURE: usize = 4;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 8;
+const COMMON_DRIVER_FEATURE: usize = 12;
+const COMMON_DEVICE_STATUS: usize = 20;
+const COMMON_QUEUE_SELECT: usize = 22;
+const COMMON_QUEUE_SIZE: usize = 24;
+const COMMON_QUEUE_ENABLE: usize = 28;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 30;
+const COMMON_QUEUE_DESC: usize = 32;
+const COMMON_QUEUE_DRIVER: usize = 40;
+const COMMON_QUEUE_DEVICE: usize = 48;
+
+// How we get at a device's registers, for setting it up and for telling it
+// about new buffers in its queues.
+#[derive(Debug)]
+pub enum Transport {
+  // Everything but the ISR status in the legacy I/O port header
+  Legacy(cpuio::IoPort),
+  // The common configuration, and the notification registers: every queue
+  // has its own, at its notify offset times the multiplier.
+  Modern { common: Mmio, notify: Mmio, multiplier: u32, offsets: BTreeMap<u16, u16> },
+}
+
+impl Transport {
+  fn status(&mut self) -> u8 {
+    match *self {
+      Transport::Legacy(ref mut port) => port.read8(18),
+      Transport::Modern { ref common, .. } => common.read8(COMMON_DEVICE_STATUS),
+    }
+  }
+
+  fn set_status(&mut self, status: u8) {
+    match *self {
+      Transport::Legacy(ref mut port) => port.write8(18, status),
+      Transport::Modern { ref mut common, .. } => common.write8(COMMON_DEVICE_STATUS, status),
+    }
+  }
+
+  // Agree on features with the device, given the status so far and the
+  // features the driver supports: we take whatever both sides do. Returns the
+  // new status and the negotiated features, or None if the device won't have
+  // it.
+  fn negotiate(&mut self, state: u8, supported: u64) -> Option<(u8, u64)> {
+    match *self {
+      Transport::Legacy(ref mut port) => {
+        // The legacy interface only has the first 32 feature bits.
+        let offered = port.read32(0) as u64;
+        let negotiated = offered & supported & 0xffffffff;
+        println!("The device offered feature bits {:#x}, we took {:#x}", offered, negotiated);
+        port.write32(4, negotiated as u32);
+        Some((state, negotiated))
+      },
+      Transport::Modern { ref mut common, .. } => {
+        common.write32(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let mut offered = common.read32(COMMON_DEVICE_FEATURE) as u64;
+        common.write32(COMMON_DEVICE_FEATURE_SELECT, 1);
+        offered = offered | ((common.read32(COMMON_DEVICE_FEATURE) as u64) << 32);
+        if offered & VIRTIO_F_VERSION_1 == 0 {
+          return None;
+        }
+        let negotiated = offered & (supported | VIRTIO_F_VERSION_1);
+        println!("The device offered feature bits {:#x}, we took {:#x}", offered, negotiated);
+        common.write32(COMMON_DRIVER_FEATURE_SELECT, 0);
+        common.write32(COMMON_DRIVER_FEATURE, negotiated as u32);
+        common.write32(COMMON_DRIVER_FEATURE_SELECT, 1);
+        common.write32(COMMON_DRIVER_FEATURE, (negotiated >> 32) as u32);
+
+        // The device gets to say no by not keeping FEATURES_OK set.
+        let state = state | VIRTIO_STATUS_FEATURES_OK;
+        common.write8(COMMON_DEVICE_STATUS, state);
+        if common.read8(COMMON_DEVICE_STATUS) & VIRTIO_STATUS_FEATURES_OK == 0 {
+          return None;
+        }
+        Some((state, negotiated))
+      },
+    }
+  }
+
+  // How many descriptors the given queue has; 0 if there's no such queue.
+  pub fn queue_size(&mut self, index: u16) -> u16 {
+    match *self {
+      Transport::Legacy(ref mut port) => {
+        port.write16(14, index);
+        port.read16(12)
+      },
+      Transport::Modern { ref mut common, .. } => {
+        common.write16(COMMON_QUEUE_SELECT, index);
+        common.read16(COMMON_QUEUE_SIZE)
+      },
+    }
+  }
+
+  // Tell the device where the parts of a queue are, as physical addresses,
+  // and start using it.
+  pub fn activate_queue(&mut self, index: u16, desc: u64, avail: u64, used: u64) {
+    match *self {
+      Transport::Legacy(ref mut port) => {
+        // The legacy layout is fixed (see vring::setup): the device works
+        // out where the rings are from the page the descriptors start at.
+        port.write16(14, index);
+        port.write32(8, (desc >> 12) as u32);
+      },
+      Transport::Modern { ref mut common, ref mut offsets, .. } => {
+        common.write16(COMMON_QUEUE_SELECT, index);
+        common.write64(COMMON_QUEUE_DESC, desc);
+        common.write64(COMMON_QUEUE_DRIVER, avail);
+        common.write64(COMMON_QUEUE_DEVICE, used);
+        offsets.insert(index, common.read16(COMMON_QUEUE_NOTIFY_OFF));
+        common.write16(COMMON_QUEUE_ENABLE, 1);
+      },
+    }
+  }
+
+  // There are new buffers in the given queue.
+  pub fn notify(&mut self, index: u16) {
+    match *self {
+      Transport::Legacy(ref mut port) => port.write16(16, index),
+      Transport::Modern { ref mut notify, multiplier, ref offsets, .. } => {
+        let offset = offsets[&index] as usize * multiplier as usize;
+        notify.write16(offset, index);
+      },
+    }
+  }
+}
+
+// Where the device says why it interrupted us.
+#[derive(Debug)]
+pub enum Isr {
+  Legacy(cpuio::IoPort),
+  Modern(Mmio),
+}
+
+impl Isr {
+  // Reading the status also resets it.
+  pub fn read(&mut self) -> u8 {
+    match *self {
+      Isr::Legacy(ref mut port) => port.read8(19),
+      Isr::Modern(ref mmio) => mmio.read8(0),
+    }
+  }
+}
+
+// The device-specific configuration, like a disk's capacity.
+#[derive(Debug)]
+pub enum DeviceConfig {
+  Legacy(cpuio::IoPort),
+  Modern(Mmio),
+  // The device doesn't have any, and the driver doesn't need any.
+  None,
+}
+
+impl DeviceConfig {
+  // Modern devices could change the configuration while we read it, which
+  // config_generation would tell us about. Nothing we read ever changes.
+  pub fn read32(&mut self, offset: u16) -> u32 {
+    match *self {
+      DeviceConfig::Legacy(ref mut port) => port.read32(offset),
+      DeviceConfig::Modern(ref mmio) => mmio.read32(offset as usize),
+      DeviceConfig::None => panic!("virtio: the device has no configuration"),
+    }
+  }
+}
+
+// Where a capability says a structure is: BAR, offset, length.
+type Region = (u8, u32, u32);
+
+fn map(dev: &Device, (bar, offset, length): Region) -> Result<(Mmio, u64), &'static str> {
+  match dev.bar(bar as usize) {
+    Some(Bar::Memory { address, size, .. }) if offset as u64 + length as u64 <= size => {
+      let physical = address + offset as u64;
+      // Every device is probed once, so nobody else has these.
+      let mmio = try!(unsafe { Mmio::map(physical, length as usize) }.map_err(|_| "can't map the registers"));
+      Ok((mmio, physical))
+    },
+    _ => Err("a capability points outside of the memory BARs"),
+  }
+}
+
+// The virtio 1.0 registers, if the device has them.
+fn modern(dev: &Device, config_size: u16) -> Result<Option<(Transport, Isr, DeviceConfig, String)>, &'static str> {
+  let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+  // If there are several of a type, the first one is the one to use.
+  for cap in dev.capabilities.iter().filter(|c| c.id == CAP_VENDOR) {
+    let (a, o) = (dev.address, cap.offset);
+    let region = (a.read8(o + CAP_BAR), a.read32(o + CAP_OFFSET), a.read32(o + CAP_LENGTH));
+    match a.read8(o + CAP_TYPE) {
+      CAP_COMMON if common.is_none() => common = Some(region),
+      CAP_NOTIFY if notify.is_none() => notify = Some((region, a.read32(o + CAP_NOTIFY_MULTIPLIER))),
+      CAP_ISR if isr.is_none() => isr = Some(region),
+      CAP_DEVICE if device.is_none() => device = Some(region),
+      _ => {},
+    }
+  }
+
+  let (common, (notify, multiplier), isr) = match (common, notify, isr) {
+    (Some(c), Some(n), Some(i)) => (c, n, i),
+    _ => return Ok(None),
+  };
+  let (common, physical) = try!(map(dev, common));
+  let (notify, _) = try!(map(dev, notify));
+  let (isr, _) = try!(map(dev, isr));
+  let config = match device {
+    Some(region) => DeviceConfig::Modern(try!(map(dev, region)).0),
+    None if config_size == 0 => DeviceConfig::None,
+    None => return Err("no device configuration"),
+  };
+
+  let mut address = String::new();
+  let _ = write!(address, "mem {:#x}", physical);
+  let transport = Transport::Modern { common: common, notify: notify, multiplier: multiplier, offsets: BTreeMap::new() };
+  Ok(Some((transport, Isr::Modern(isr), config, address)))
+}
+
+// The legacy header and, right after it, `config_size` bytes of
+// device-specific configuration, from the I/O BAR.
+fn legacy(dev: &Device, config_size: u16) -> Result<(Transport, Isr, DeviceConfig, String), &'static str> {
+  let base = match dev.bar(0) {
+    Some(Bar::Io { port, size }) if size >= HEADER_SIZE + config_size => port,
+    _ => return Err("BAR0 isn't a big enough I/O port range"),
+  };
+  // Every device is probed once, so nobody else has these.
+  let (port, config) = unsafe {
+    let port = try!(cpuio::alloc(base, HEADER_SIZE, "XXXXXXXXXXXXXXXXXXXX").map_err(|_| "can't allocate the header ports"));
+    let config = try!(cpuio::alloc(base + HEADER_SIZE, config_size, &"XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX"[..config_size as usize])
+      .map_err(|_| "can't allocate the config ports"));
+    (port, config)
+  };
+
+  let (isr, port) = port.split_at_masks(
+    "-------------------X",  // ISR status
+    "XXXXXXXXXXXXXXXXXXX-"); // features, queue address, size, select, notify, device status
+
+  let mut address = String::new();
+  let _ = write!(address, "io {:#x}", base);
+  Ok((Transport::Legacy(port), Isr::Legacy(isr), DeviceConfig::Legacy(config), address))
+}
+
+// Set up a virtio device that the PCI scan found, through the virtio 1.0
+// registers if it has them and the legacy ones otherwise. `kind` is what the
+// device shows up as in /proc/devices, `config_size` how much of the
+// device-specific configuration the driver needs, `features` the feature bits
+// the driver supports (device-specific ones as well as the virtq ones).
+pub fn init(dev: &Device, kind: &str, config_size: u16, features: u64, rxhandlers: Vec<(u16, virtq::Handler)>) -> Result<(Vec<virtq::Virtq>, Transport, DeviceConfig), &'static str> {
+  let irqnum = try!(dev.irq.ok_or("no interrupt line"));
+  dev.enable();
+  let (mut transport, isr, config, mut address) = match try!(modern(dev, config_size)) {
+    Some(t) => t,
+    None => try!(legacy(dev, config_size)),
+  };
+  println!("Initializing virtio device at {}..", address);
+
+  let _ = write!(address, " irq {:#x}", irqnum);
+  drivers::register("virtio", address, String::from(kind));
 
   let mut state = 0u8;
-  configport.write8(18, state);
+  transport.set_status(state);
 
   state = state | VIRTIO_STATUS_ACKNOWLEDGE;
-  configport.write8(18, state);
+  transport.set_status(state);
 
   state = state | VIRTIO_STATUS_DRIVER;
-  configport.write8(18, state);
+  transport.set_status(state);
 
-  // Feature negotiation
-  let offered_featureflags = configport.read16(0);
-  println!("The device offered us these feature bits: {:?}", offered_featureflags);
-  // In theory, we'd do `negotiated = offered & supported`; we don't actually
-  // support any flags, so we can just set 0.
-  configport.write16(4, 0);
+  let (negotiated_state, features) = match transport.negotiate(state, features) {
+    Some(n) => n,
+    None => {
+      transport.set_status(state | VIRTIO_STATUS_FAILED);
+      return Err("feature negotiation failed");
+    },
+  };
+  state = negotiated_state;
 
   let mut rxs = vec![];
   let mut qs = vec![];
 
   for (queue_index, handler) in rxhandlers {
-    let (q, rx) = virtq::Virtq::new(queue_index, &mut txport, handler);
+    let (q, rx) = virtq::Virtq::new(queue_index, &mut transport, features, handler);
     qs.push(q);
     rxs.push(rx);
   }
 
   let handler = virtq::RxHandler {
     rings: rxs,
-    isr_status_port: rxport,
+    isr: isr,
   };
 
   sched::irq::add_handler(irqnum, box handler);
 
   // Tell the device we're done setting it up
   state = state | VIRTIO_STATUS_DRIVER_OK;
-  configport.write8(18, state);
+  transport.set_status(state);
+  if transport.status() & VIRTIO_STATUS_FAILED != 0 {
+    return Err("the device gave up on us");
+  }
 
-  (qs, txport)
+  Ok((qs, transport, config))
 }
@@ -1,61 +1,79 @@
 use prelude::*;
 
-use cpuio;
+use drivers::pci::Device;
 use super::virtq;
 use super::pci;
 use mem::*;
 use sched;
+use sync::global_mutex::GlobalMutex;
 
+// Size of each receive buffer, and thus the most a single read can return.
+pub const RX_BUF_SIZE: usize = 20;
+
+// All queues sit behind their own lock, so that a Serialdev can be shared
+// (e.g. between file descriptors). We never hold any of them while sleeping.
+#[derive(Debug)]
 pub struct Serialdev {
-  port: cpuio::IoPort,
+  transport: GlobalMutex<pci::Transport>,
 
-  rxq: virtq::Virtq,
-  txq: virtq::Virtq,
+  rxq: GlobalMutex<virtq::Virtq>,
+  txq: GlobalMutex<virtq::Virtq>,
 }
 
 impl Serialdev {
-  pub fn putc(&mut self, c: char) {
+  pub fn putc(&self, c: char) {
     let mut b = [0u8; 1];
     b[0] = c as u8;
-    let n = self.txq.send(&b[..], &mut self.port);
+    let mut transport = self.transport.lock();
+    let n = self.txq.lock().send(&b[..], &mut *transport);
 
     println!("serial send done");
   }
 
-  pub fn read(&mut self, buf: &mut[u8]) -> usize {
-    let mut n;
+  pub fn write(&self, data: &[u8]) {
+    for c in data {
+      self.putc(*c as char);
+    }
+  }
+
+  // Blocks until the device hands us some input.
+  pub fn read(&self, buf: &mut[u8]) -> usize {
     loop {
-      // Make sure that we don't keep the lock held when we possibly call kyield()
-      // TODO: Enforce this using the type systems (sleep tokens that downgrade to spinlock tokens)
-      let r = {
-        let mut lock = self.rxq.used_buffers.lock();
-        lock.pop_front()
-      };
-
-      if let Some((b, count)) = r {
-        match b {
-          virtq::Buf::Simple(desc, data) => {
-            n = count;
-
-            buf.clone_from_slice(&data[0..n]);
-
-            // enqueue the buffer again for the next read
-            self.rxq.free_buffers.lock().push_back(virtq::Buf::Simple(desc, data));
-            self.rxq.send(&[0u8; 20], &mut self.port);
-
-            break;
-          },
-          _ => { panic!("unexpected buffer type"); }
-        }
+      if let Some(n) = self.try_read(buf) {
+        return n;
       }
-
       sched::kyield();
     }
+  }
 
-    n
+  // Returns None if no input is waiting.
+  pub fn try_read(&self, buf: &mut[u8]) -> Option<usize> {
+    // Make sure that we don't keep the lock held when we possibly call kyield()
+    // TODO: Enforce this using the type systems (sleep tokens that downgrade to spinlock tokens)
+    let r = {
+      let q = self.rxq.lock();
+      let mut lock = q.used_buffers.lock();
+      lock.pop_front()
+    };
+
+    match r {
+      Some((virtq::Buf::Simple(desc, data), count)) => {
+        buf.clone_from_slice(&data[0..count]);
+
+        // enqueue the buffer again for the next read
+        let mut transport = self.transport.lock();
+        let mut q = self.rxq.lock();
+        q.free_buffers.lock().push_back(virtq::Buf::Simple(desc, data));
+        q.send(&[0u8; RX_BUF_SIZE], &mut *transport);
+
+        Some(count)
+      },
+      Some(_) => { panic!("unexpected buffer type"); }
+      None => None,
+    }
   }
 
-  pub fn new(mut port: cpuio::IoPort) -> Result<Self, ()> {
+  pub fn new(dev: &Device) -> Result<Self, ()> {
     let rxhandler = (box move |used, free| {
       println!("serialrx processing used buffers: {:?}", used);
     }) as virtq::Handler;
@@ -70,20 +88,41 @@ impl Serialdev {
     }) as virtq::Handler;
 
     let handlers = vec![(0, rxhandler), (1, txhandler)];
-    let (mut qs, mut txport) = pci::init(port, 0x2b, handlers);
+    // Every buffer is a single descriptor, so indirect ones wouldn't help.
+    let features = virtq::VIRTIO_RING_F_EVENT_IDX;
+    let (mut qs, mut transport, _) = try!(pci::init(dev, "console", 0, features, handlers).map_err(|e| {
+      println!("virtio console: {}", e);
+    }));
 
     let mut txq = qs.remove(1);
     let mut rxq = qs.remove(0);
 
     for _ in 0..1 {
-      rxq.register(box ['X' as u8; 20], true); // writable by them
-      rxq.send(&[0u8; 20], &mut txport);
+      rxq.register(box [b'X'; RX_BUF_SIZE], true); // writable by them
+      rxq.send(&[0u8; RX_BUF_SIZE], &mut transport);
     }
 
     for _ in 0..10 {
       txq.register(box ['X' as u8; 1], false); // not writable by them
     }
 
-    Ok(Serialdev { port: txport, rxq: rxq, txq: txq })
+    Ok(Serialdev { transport: GlobalMutex::new(transport), rxq: GlobalMutex::new(rxq), txq: GlobalMutex::new(txq) })
   }
 }
+
+unsafe_lazy_static! {
+  static ref FOUND: GlobalMutex<Vec<Serialdev>> = { GlobalMutex::new(vec![]) };
+}
+
+// Called by the PCI scan for every virtio console.
+pub fn probe(dev: &Device) -> Result<(), &'static str> {
+  let serdev = try!(Serialdev::new(dev).map_err(|_| "handshake failed"));
+  FOUND.lock().push(serdev);
+  Ok(())
+}
+
+// The first console the PCI scan found, unless somebody took it already.
+pub fn take() -> Option<Serialdev> {
+  let mut found = FOUND.lock();
+  if found.len() > 0 { Some(found.remove(0)) } else { None }
+}
@@ -1,8 +1,7 @@
 use prelude::*;
 use mem::*;
 
-use cpuio;
-
+use super::pci::{Isr,Transport};
 use super::vring;
 use super::vring::Descriptor;
 
@@ -16,21 +15,44 @@ use sync::global_mutex::GlobalMutex;
 
 const VRING_DESC_F_NEXT: u16 = 1; /* This marks a buffer as continuing via the next field. */
 const VRING_DESC_F_WRITE: u16 = 2; /* This marks a buffer as write-only (otherwise read-only). */
+const VRING_DESC_F_INDIRECT: u16 = 4; /* This means the buffer contains a table of descriptors. */
+
+// Feature bits that are about the queues rather than any kind of device.
+// With INDIRECT_DESC, a request takes up a single descriptor in the queue,
+// which points to a table with the actual ones. With EVENT_IDX, both sides
+// say how far the other one can get before it has to interrupt or notify.
+pub const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
 
 #[derive(Debug)]
 pub enum Buf {
   Simple(u16, Box<[u8]>),
-  Rww(u16, Box<[u8]>, u16, Box<[u8]>, u16, Box<[u8]>),
+  // A header for the device, any number of data buffers that the device
+  // either reads or (if `writable`) writes, and a status byte from the
+  // device. `ids` are the descriptors of all of them, in order, unless they
+  // are in the indirect `table`; then it's just the one pointing there.
+  Request { ids: Vec<u16>, table: Option<Box<[u8]>>, hdr: Box<[u8]>, data: Vec<Box<[u8]>>, writable: bool, done: Box<[u8]> },
+}
+
+impl Buf {
+  // The descriptor that the buffer starts at, which is what the device
+  // reports back once it's done with it.
+  pub fn head(&self) -> u16 {
+    match *self {
+      Buf::Simple(id, _) => id,
+      Buf::Request { ref ids, .. } => ids[0],
+    }
+  }
 }
 
-type RwwTag = u16;
+type ChainTag = u16;
 
 pub type CondvarWait = sched::blocking::WaitToken;
 type CondvarSignal = sched::blocking::SignalToken;
 
 // Handles receive notifications for a virtio device.
 pub struct RxHandler {
-  pub isr_status_port: cpuio::IoPort,
+  pub isr: Isr,
 
   // The rings to receive on
   pub rings: Vec<Rx>,
@@ -48,8 +70,8 @@ impl Debug for RxHandler {
 
 impl sched::irq::InterruptHandler for RxHandler {
   fn critical(&mut self) {
-    // The virtio IRQ status is reset by **reading** from this port
-    if self.isr_status_port.read8(19) & 1 == 0  {
+    // The virtio IRQ status is reset by **reading** it
+    if self.isr.read() & 1 == 0  {
       println!("ISR==0, this interrupt likely wasn't for us.");
       return;
     }
@@ -62,7 +84,7 @@ impl sched::irq::InterruptHandler for RxHandler {
   }
 
   fn noncritical(&self) {
-    println!("NONcritical self: {:?}", self);
+    debug!("NONcritical self: {:?}", self);
   }
 }
 
@@ -77,6 +99,12 @@ pub struct Rx {
   // I don't *really* want this here, but otherwise you can't access the Virtq
   // from the callback handler.
   free_buffers: Arc<GlobalMutex<VecDeque<Buf>>>,
+  // Requests give their descriptors back as soon as the device is done with
+  // them, so new ones can be submitted before the old ones are collected.
+  free_descriptors: Arc<GlobalMutex<VecDeque<u16>>>,
+
+  // Whether we tell the device which buffer to interrupt us for next
+  event_idx: bool,
 
   // Do something with the `used` vec, after some things have been added to it.
   process_used: Box<FnMut(&mut VecDeque<(Buf, usize)>, &GlobalMutex<VecDeque<Buf>>) -> () + Send>,
@@ -87,24 +115,38 @@ pub struct Rx {
 
 impl Rx {
   fn check(&mut self) {
-    println!("Checking some ring.");
+    debug!("Checking some ring.");
     let mut any = false;
     let mut used = self.used_buffers.lock();
-    println!("Got teh spinlock");
-
-    while let Some((ref descid, ref written)) = self.used.take_from_ring() {
-      any = true;
-      println!("Took buffer {:?} with {} written", descid, written);
-      let buf = self.inflight_buffers.lock().remove(descid).unwrap();
-      used.push_back((buf,*written));
+    debug!("Got teh spinlock");
+
+    loop {
+      while let Some((ref descid, ref written)) = self.used.take_from_ring() {
+        any = true;
+        debug!("Took buffer {:?} with {} written", descid, written);
+        let buf = self.inflight_buffers.lock().remove(descid).unwrap();
+        if let Buf::Request { ref ids, .. } = buf {
+          self.free_descriptors.lock().extend(ids.iter().cloned());
+        }
+        used.push_back((buf,*written));
+      }
+      if !self.event_idx {
+        break;
+      }
+      // The device won't interrupt us for anything it hands back before it
+      // sees the new used_event, so look again after setting it.
+      self.used.request_interrupt();
+      if !self.used.pending() {
+        break;
+      }
     }
 
     if any {
-      println!("Took some buffers, calling process_used()..");
+      debug!("Took some buffers, calling process_used()..");
       self.process_used.call_mut((&mut *used,&*self.free_buffers));
       self.device_activity.signal(); // TODO: notify_all instead of notify_one
     } else {
-      println!("Had no buffers to take.");
+      debug!("Had no buffers to take.");
     }
   }
 }
@@ -120,13 +162,18 @@ pub struct Virtq {
   inflight_buffers: Arc<GlobalMutex<BTreeMap<u16, Buf>>>,
 
   pub free_buffers: Arc<GlobalMutex<VecDeque<Buf>>>,
-  pub free_descriptors: VecDeque<u16>,
+  free_descriptors: Arc<GlobalMutex<VecDeque<u16>>>,
 
   index: u16,
+  size: u16,
+
+  // Which of the VIRTIO_RING_F_* features were negotiated
+  indirect: bool,
+  event_idx: bool,
 }
 
 impl Virtq {
-  pub fn send(&mut self, data: &[u8], port: &mut cpuio::IoPort) -> Option<usize> {
+  pub fn send(&mut self, data: &[u8], transport: &mut Transport) -> Option<usize> {
     // panic when either no buffer is available at all, or the available one isn't Simple
     match self.free_buffers.lock().pop_front() {
       Some(Buf::Simple(descriptor_id, mut buf)) => {
@@ -136,9 +183,7 @@ impl Virtq {
         // Also, make sure that we're not overriding any other in-flight entry.
         assert!(self.inflight_buffers.lock().insert(descriptor_id, Buf::Simple(descriptor_id, buf)).is_none());
         self.avail.add_to_ring(descriptor_id);
-
-        // Notify, TODO: make this optional
-        port.write16(16, self.index);
+        self.notify(transport);
 
         Some(n)
       },
@@ -147,7 +192,7 @@ impl Virtq {
   }
 
   pub fn register(&mut self, mem: Box<[u8]>, device_writable: bool) {
-    let i = self.free_descriptors.pop_front().unwrap();
+    let i = self.free_descriptors.lock().pop_front().unwrap();
     let flags = if device_writable { VRING_DESC_F_WRITE } else { 0 };
     self.avail.write_descriptor_at(i as usize, Descriptor {
       addr: physical_from_kernel((mem[..]).as_ptr() as usize) as u64,
@@ -158,62 +203,116 @@ impl Virtq {
     self.free_buffers.lock().push_back(Buf::Simple(i, mem));
   }
 
-  pub fn register_rww(&mut self, hdr: Box<[u8]>, data: Box<[u8]>, done: Box<[u8]>) -> RwwTag {
-    let i1 = self.free_descriptors.pop_front().unwrap();
-    let i2 = self.free_descriptors.pop_front().unwrap();
-    let i3 = self.free_descriptors.pop_front().unwrap();
+  // Write a chain of descriptors for the given buffers, each either readable
+  // or writable (true) by the device. Returns the descriptor ids in order.
+  fn chain(&mut self, parts: &[(&[u8], bool)]) -> Vec<u16> {
+    let ids: Vec<u16> = {
+      let mut free = self.free_descriptors.lock();
+      parts.iter().map(|_| free.pop_front().unwrap()).collect()
+    };
 
-    self.avail.write_descriptor_at(i1 as usize, Descriptor {
-      addr: physical_from_kernel(hdr.as_ptr() as usize) as u64,
-      len: hdr.len() as u32,
-      flags: VRING_DESC_F_NEXT,
-      next: i2,
-    });
+    for (i, &(mem, device_writable)) in parts.iter().enumerate() {
+      self.avail.write_descriptor_at(ids[i] as usize, descriptor(mem, device_writable, ids.get(i + 1).cloned()));
+    }
+    ids
+  }
 
-    self.avail.write_descriptor_at(i2 as usize, Descriptor {
-      addr: physical_from_kernel(data.as_ptr() as usize) as u64,
-      len: data.len() as u32,
-      flags: VRING_DESC_F_NEXT | VRING_DESC_F_WRITE,
-      next: i3,
-    });
+  // Like chain, but into a table of its own, with a single descriptor in the
+  // queue pointing to it. Returns that descriptor's id and the table, which
+  // has to stay around until the device is done with it.
+  fn chain_indirect(&mut self, parts: &[(&[u8], bool)]) -> (u16, Box<[u8]>) {
+    let mut table = vec![0u8; parts.len() * core::mem::size_of::<Descriptor>()].into_boxed_slice();
+    for (i, &(mem, device_writable)) in parts.iter().enumerate() {
+      let next = if i + 1 < parts.len() { Some((i + 1) as u16) } else { None };
+      vring::write_descriptor(&mut table, i, descriptor(mem, device_writable, next));
+    }
 
-    self.avail.write_descriptor_at(i3 as usize, Descriptor {
-      addr: physical_from_kernel(done.as_ptr() as usize) as u64,
-      len: done.len() as u32,
-      flags: VRING_DESC_F_WRITE,
+    let id = self.free_descriptors.lock().pop_front().unwrap();
+    self.avail.write_descriptor_at(id as usize, Descriptor {
+      addr: physical_from_kernel(table.as_ptr() as usize) as u64,
+      len: table.len() as u32,
+      flags: VRING_DESC_F_INDIRECT,
       next: 0,
     });
+    (id, table)
+  }
 
-    let buf = Buf::Rww(i1, hdr, i2, data, i3, done);
-
+  fn add_inflight(&mut self, buf: Buf) -> ChainTag {
+    let head = buf.head();
     // no overwrite, and add to inflight before adding to ring
-    assert!(self.inflight_buffers.lock().insert(i1, buf).is_none());
+    assert!(self.inflight_buffers.lock().insert(head, buf).is_none());
 
     // TODO: need a better encapsulation for the tag
-    i1 as RwwTag
+    head as ChainTag
   }
 
-  pub fn send_rww(&mut self, i1: RwwTag, port: &mut cpuio::IoPort)  {
-    self.avail.add_to_ring(i1);
+  // Set up a Buf::Request, see there. None if there aren't enough free
+  // descriptors for it right now.
+  pub fn register_request(&mut self, hdr: Box<[u8]>, data: Vec<Box<[u8]>>, writable: bool, done: Box<[u8]>) -> Option<ChainTag> {
+    if self.descriptors_for(data.len() + 2) > self.available() {
+      return None;
+    }
+    let (ids, table) = {
+      let mut parts = vec![(&hdr[..], false)];
+      for d in data.iter() {
+        parts.push((&d[..], writable));
+      }
+      parts.push((&done[..], true));
+      if self.indirect {
+        let (id, table) = self.chain_indirect(&parts[..]);
+        (vec![id], Some(table))
+      } else {
+        (self.chain(&parts[..]), None)
+      }
+    };
+    Some(self.add_inflight(Buf::Request { ids: ids, table: table, hdr: hdr, data: data, writable: writable, done: done }))
+  }
+
+  // How many descriptors in the queue a request with this many buffers
+  // (including the header and status) takes up.
+  pub fn descriptors_for(&self, buffers: usize) -> usize {
+    if self.indirect { 1 } else { buffers }
+  }
 
-    // Notify, TODO: make this optional
-    port.write16(16, self.index);
+  // How many descriptors the queue has, i.e. how many buffers can be in
+  // flight at once.
+  pub fn size(&self) -> usize {
+    self.size as usize
   }
 
-  // queue_index is the index on the virtio device to initialize
-  pub fn new(queue_index: u16, port: &mut cpuio::IoPort, process: Box<FnMut(&mut VecDeque<(Buf, usize)>, &GlobalMutex<VecDeque<Buf>>,) -> () + Send>) -> (Self, Rx) {
-    // Set queue_select
-    port.write16(14, queue_index);
+  // How many descriptors aren't in use right now.
+  pub fn available(&self) -> usize {
+    self.free_descriptors.lock().len()
+  }
+
+  // Hand a chain from one of the register_* functions to the device.
+  pub fn send_chain(&mut self, i1: ChainTag, transport: &mut Transport)  {
+    self.avail.add_to_ring(i1);
+    self.notify(transport);
+  }
+
+  // Tell the device about new buffers, unless it said it doesn't need to know.
+  fn notify(&mut self, transport: &mut Transport) {
+    if self.avail.should_notify(self.event_idx) {
+      transport.notify(self.index);
+    }
+  }
 
+  // queue_index is the index on the virtio device to initialize, features
+  // what was negotiated with it.
+  pub fn new(queue_index: u16, transport: &mut Transport, features: u64, process: Box<FnMut(&mut VecDeque<(Buf, usize)>, &GlobalMutex<VecDeque<Buf>>,) -> () + Send>) -> (Self, Rx) {
     // Determine how many descriptors the queue has, and allocate memory for the
     // descriptor table and the ring arrays.
-    let length = port.read16(12);
+    let length = transport.queue_size(queue_index);
     assert!(length > 0);
 
     let (address, mut availring, mut usedring) = vring::setup(length);
 
-    let physical_32 = physical_from_kernel(address as usize) as u32; // FIXME: not really a safe cast
-    port.write32(8, physical_32 >> 12);
+    // The avail ring comes right after the descriptors.
+    let desc = physical_from_kernel(address as usize) as u64;
+    let avail = desc + length as u64 * core::mem::size_of::<Descriptor>() as u64;
+    let used = physical_from_kernel(usedring.mem.as_ptr() as usize) as u64;
+    transport.activate_queue(queue_index, desc, avail, used);
 
     let (wait, signal) = sched::blocking::tokens(String::new());
 
@@ -221,6 +320,15 @@ impl Virtq {
     let free = Arc::new(GlobalMutex::new(VecDeque::new()));
     let inf = Arc::new(GlobalMutex::new(BTreeMap::new()));
 
+    // set initial free descriptor list
+    let mut descs = VecDeque::with_capacity(length as usize);
+    for i in 0..length {
+      descs.push_back(i);
+    }
+    let descs = Arc::new(GlobalMutex::new(descs));
+
+    let event_idx = features & VIRTIO_RING_F_EVENT_IDX != 0;
+
     let rx = Rx {
       used: usedring,
       used_buffers: used.clone(),
@@ -229,14 +337,10 @@ impl Virtq {
 
       process_used: process,
       free_buffers: free.clone(),
+      free_descriptors: descs.clone(),
+      event_idx: event_idx,
     };
 
-    // set initial free descriptor list
-    let mut descs = VecDeque::with_capacity(length as usize);
-    for i in 0..length {
-      descs.push_back(i);
-    }
-
     (Virtq {
       avail: availring,
       device_activity: wait,
@@ -246,6 +350,23 @@ impl Virtq {
       free_descriptors: descs,
       inflight_buffers: inf,
       index: queue_index,
+      size: length,
+      indirect: features & VIRTIO_RING_F_INDIRECT_DESC != 0,
+      event_idx: event_idx,
     }, rx)
   }
 }
+
+// A descriptor for the given buffer, continuing at `next` if there is one.
+fn descriptor(mem: &[u8], device_writable: bool, next: Option<u16>) -> Descriptor {
+  let mut flags = if device_writable { VRING_DESC_F_WRITE } else { 0 };
+  if next.is_some() {
+    flags = flags | VRING_DESC_F_NEXT;
+  }
+  Descriptor {
+    addr: physical_from_kernel(mem.as_ptr() as usize) as u64,
+    len: mem.len() as u32,
+    flags: flags,
+    next: next.unwrap_or(0),
+  }
+}
@@ -6,17 +6,35 @@ use byteorder::{ByteOrder,NativeEndian};
 const VRING_DESC_F_NEXT: u16 = 1; /* This marks a buffer as continuing via the next field. */
 const VRING_DESC_F_WRITE: u16 = 2; /* This marks a buffer as write-only (otherwise read-only). */
 
+const VRING_USED_F_NO_NOTIFY: u16 = 1; /* The device doesn't want to be told about new buffers right now. */
+
 pub fn setup(length: u16) -> (*const u8, Avail, Used) {
   let (writesize, readsize) = size(length);
   let (writebuf, readbuf) = alloc_pagealigned(writesize, readsize);
   let address = writebuf.as_ptr();
+  let qsz = length as usize;
+
+  // With VIRTIO_RING_F_EVENT_IDX, each side says in the other side's ring up
+  // to where it wants to hear about progress: the driver in used_event, at the
+  // end of the avail ring, and the device in avail_event, at the end of the
+  // used ring.
+  let used_event = writebuf.as_ptr() as usize + qsz*16 + 4 + qsz*2;
+  let used_flags = readbuf.as_ptr() as usize;
+  let avail_event = readbuf.as_ptr() as usize + 4 + qsz*8;
 
-  let avail = Avail{ mem: writebuf, qsz: length as usize };
-  let used = Used{ mem: readbuf, qsz: length as usize, last_taken_index: None };
+  let avail = Avail{ mem: writebuf, qsz: qsz, notified: 0, used_flags: used_flags, avail_event: avail_event };
+  let used = Used{ mem: readbuf, qsz: qsz, last_taken_index: None, used_event: used_event };
 
   (address, avail, used)
 }
 
+// Whether the other side wants to hear about the ring going from `old` to
+// `new`, given that it asked to be told once it reaches `event`. This is
+// vring_need_event from the spec; all of it wraps around at 2^16.
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+  new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
 // avail is: flags: u16, index u16, ring: [u16, length], used_event: u16
 // used is: flags: u16, index u16, ring: [u64, length], avail_event: u16
 fn size(length: u16) -> (usize, usize) {
@@ -56,9 +74,22 @@ pub struct Descriptor {
   pub next: u16,
 }
 
+// Write a descriptor into a table, either the queue's own or an indirect one.
+pub fn write_descriptor(table: &mut [u8], pos: usize, d: Descriptor) {
+  NativeEndian::write_u64(&mut table[pos*16..], d.addr);
+  NativeEndian::write_u32(&mut table[pos*16+8..], d.len);
+  NativeEndian::write_u16(&mut table[pos*16+12..], d.flags);
+  NativeEndian::write_u16(&mut table[pos*16+14..], d.next);
+}
+
 pub struct Avail {
   pub mem: Box<[u8]>,
   qsz: usize,
+  // The avail index as of the last time we notified the device
+  notified: u16,
+  // Where the device's flags and avail_event are, see setup
+  used_flags: usize,
+  avail_event: usize,
 }
 
 impl Debug for Avail {
@@ -73,10 +104,25 @@ impl Debug for Avail {
 impl Avail {
   pub fn write_descriptor_at(&mut self, pos: usize, d: Descriptor) {
     assert!(pos < self.qsz);
-    NativeEndian::write_u64(&mut self.mem[pos*16..], d.addr);
-    NativeEndian::write_u32(&mut self.mem[pos*16+8..], d.len);
-    NativeEndian::write_u16(&mut self.mem[pos*16+12..], d.flags);
-    NativeEndian::write_u16(&mut self.mem[pos*16+14..], d.next);
+    write_descriptor(&mut self.mem, pos, d);
+  }
+
+  // Whether the device needs to be told about the buffers added since the
+  // last time it was. Without `event_idx`, it can only ask us not to bother
+  // it at all for a while.
+  pub fn should_notify(&mut self, event_idx: bool) -> bool {
+    // The device has to see the new head before we look at what it wants.
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+
+    let new = NativeEndian::read_u16(&self.mem[self.qsz*16+2..]);
+    let old = core::mem::replace(&mut self.notified, new);
+    unsafe {
+      if event_idx {
+        need_event(core::intrinsics::volatile_load(self.avail_event as *const u16), new, old)
+      } else {
+        core::intrinsics::volatile_load(self.used_flags as *const u16) & VRING_USED_F_NO_NOTIFY == 0
+      }
+    }
   }
 
   pub fn add_to_ring(&mut self, idx: u16) {
@@ -90,7 +136,7 @@ impl Avail {
     // Now, place a memory barrier so the above write is seen for sure.. is that enough?
     core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
 
-    println!("Next buffer avail is: {}",current_head);
+    debug!("Next buffer avail is: {}",current_head);
 
     // "Publish" the new buffer head position
     NativeEndian::write_u16(&mut self.mem[self.qsz*16+2..], current_head);
@@ -101,6 +147,8 @@ pub struct Used {
   pub mem: Box<[u8]>,
   pub qsz: usize,
   last_taken_index: Option<u16>,
+  // Where our used_event is, see setup
+  used_event: usize,
 }
 
 impl Debug for Used {
@@ -110,6 +158,24 @@ impl Debug for Used {
 }
 
 impl Used {
+  // The used index of the next buffer we'll take.
+  fn next_index(&self) -> u16 {
+    self.last_taken_index.map_or(0, |last| last.wrapping_add(1))
+  }
+
+  // Whether the device has handed back buffers we haven't taken yet.
+  pub fn pending(&self) -> bool {
+    NativeEndian::read_u16(&self.mem[2..]) != self.next_index()
+  }
+
+  // With VIRTIO_RING_F_EVENT_IDX: interrupt us for the next buffer we haven't
+  // taken, but not for any after it until we've caught up.
+  pub fn request_interrupt(&mut self) {
+    let next = self.next_index();
+    unsafe { core::intrinsics::volatile_store(self.used_event as *mut u16, next); }
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+  }
+
   // TODO: not sure if this is correct
   // Return the descriptor index of the taken buffer, if any, and how many bytes were written by the device in that buffer.
   pub fn take_from_ring(&mut self) -> Option<(u16, usize)> {
@@ -137,7 +203,7 @@ impl Used {
       Some(i) => {
         let descid = NativeEndian::read_u32(&self.mem[4+8*(i as usize)..]) as u16; // downcast, see virtio spec
         let len = NativeEndian::read_u32(&self.mem[4+4+8*(i as usize)..]);
-        println!("Taking buffer {} (written={}) from index {}", descid, len, i);
+        debug!("Taking buffer {} (written={}) from index {}", descid, len, i);
         Some((descid, len as usize))
       },
       None => {
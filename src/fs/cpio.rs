@@ -1,8 +1,10 @@
-use super::Error;
-use collections::string::String;
-use core::str;
+use prelude::*;
+use core::{cmp,str};
+use collections::btree_map::BTreeMap;
 
-// header format:
+use super::{DirEntry, Error, FileType, Filesystem, Ino, Stat};
+
+// Old binary header (070707), 26 bytes, in 16-bit little-endian words:
 // 2 magic
 // 2 dev
 // 2 ino
@@ -14,81 +16,447 @@ use core::str;
 // 4 mtime
 // 2 namesize
 // 4 filesize
+//
+// The name and body are padded to 2 bytes.
+//
+// newc header (070701, or 070702 with checksums), 110 bytes: the 6-byte ASCII
+// magic followed by 13 fields of 8 hex digits each:
+// ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+// rdevmajor, rdevminor, namesize, check
+//
+// The header plus name and the body are padded to 4 bytes. For 070702,
+// `check` is the sum of all body bytes; it's 0 otherwise.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Format {
+  Binary,
+  Newc,
+  Crc,
+}
 
 #[derive(Debug)]
 pub struct Entry {
+  pub format: Format,
   pub name: String,
+  pub mode: u32,
+  pub nlink: u32,
+  pub uid: u32,
+  pub gid: u32,
+  pub rdev: u64,
+  pub mtime: u64,
   pub size: usize,
-  pub header_pos: (usize, usize),
-  pub body_pos: (usize, usize),
+  // Byte offsets into the archive
+  pub header_pos: usize,
+  pub body_pos: usize,
 }
 
 use block;
-use alloc::arc::Arc;
 
-pub struct Cursor {
+const BINARY_HEADER: usize = 26;
+const NEWC_HEADER: usize = 110;
+
+// Longer names than this are taken to be garbage.
+const MAX_NAMESIZE: usize = 4096;
+
+const SECTOR_SIZE: usize = 512;
+
+// Copy the bytes at `pos` into buf, across as many sectors as it takes.
+// Reading past the end of the device is a format error: the archive said
+// there would be more.
+fn read_at(dev: &block::Cache, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+  let size = dev.sectors() as usize * SECTOR_SIZE;
+  if pos > size || buf.len() > size - pos {
+    return Err(Error::InvalidDiskFormat);
+  }
+
+  super::read_bytes(dev, pos as u64, buf).map_err(Error::ReadFailed)
+}
+
+// The archive as a stream of bytes.
+struct Stream {
   dev: Arc<block::Cache>,
+  pos: usize,
+  size: usize,
+}
 
-  // as (sector, offset)
-  next_header: (usize, usize),
+impl Stream {
+  fn read(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+    try!(read_at(&*self.dev, self.pos, buf));
+    self.pos += buf.len();
+    Ok(())
+  }
+
+  // Move forward by n bytes, which all have to be on the device.
+  fn skip(&mut self, n: usize) -> Result<(), Error> {
+    if n > self.size - self.pos {
+      return Err(Error::InvalidDiskFormat);
+    }
+    self.pos += n;
+    Ok(())
+  }
+
+  fn align(&mut self, to: usize) -> Result<(), Error> {
+    let n = align(self.pos, to) - self.pos;
+    self.skip(n)
+  }
+}
+
+pub struct Cursor {
+  stream: Stream,
+  // Set after the trailer or an error, there's no way to go on from either.
+  done: bool,
 }
 
 impl Cursor {
   pub fn new(dev: Arc<block::Cache>) -> Cursor {
-    Cursor { dev: dev, next_header: (0,0) }
+    let size = dev.sectors() as usize * SECTOR_SIZE;
+    Cursor { stream: Stream { dev: dev, pos: 0, size: size }, done: false }
   }
 }
 
-use collections::string::ToString;
+// The fields of a header that we care about, whatever the format
+struct Header {
+  format: Format,
+  header_len: usize,
+  align: usize,
+  mode: u32,
+  uid: u32,
+  gid: u32,
+  nlink: u32,
+  rdev: u64,
+  mtime: u64,
+  namesize: usize,
+  filesize: usize,
+  check: u32,
+}
+
+fn parse_binary(entry: &[u8]) -> Header {
+  let u16_at = |i: usize| (entry[i] as u16) | ((entry[i+1] as u16)<<8);
+  // nice byte order, bro..
+  let u32_at = |i: usize| ((u16_at(i) as u32)<<16) | (u16_at(i+2) as u32);
 
-use core::slice::bytes::copy_memory;
+  Header {
+    format: Format::Binary,
+    header_len: BINARY_HEADER,
+    align: 2,
+    mode: u16_at(6) as u32,
+    uid: u16_at(8) as u32,
+    gid: u16_at(10) as u32,
+    nlink: u16_at(12) as u32,
+    rdev: u16_at(14) as u64,
+    mtime: u32_at(16) as u64,
+    namesize: u16_at(20) as usize,
+    filesize: u32_at(22) as usize,
+    check: 0,
+  }
+}
+
+fn parse_newc(entry: &[u8], format: Format) -> Result<Header, Error> {
+  let mut fields = [0u32; 13];
+  for (i, field) in fields.iter_mut().enumerate() {
+    let hex = &entry[6 + 8*i..6 + 8*(i+1)];
+    let text = try!(str::from_utf8(hex).map_err(|_| Error::InvalidDiskFormat));
+    *field = try!(u32::from_str_radix(text, 16).map_err(|_| Error::InvalidDiskFormat));
+  }
+
+  Ok(Header {
+    format: format,
+    header_len: NEWC_HEADER,
+    align: 4,
+    mode: fields[1],
+    uid: fields[2],
+    gid: fields[3],
+    nlink: fields[4],
+    mtime: fields[5] as u64,
+    filesize: fields[6] as usize,
+    // Linux's old 8:8 encoding is what the binary format uses, too.
+    rdev: ((fields[9] as u64) << 8) | fields[10] as u64,
+    namesize: fields[11] as usize,
+    check: fields[12],
+  })
+}
+
+fn align(pos: usize, to: usize) -> usize {
+  (pos + to - 1) & !(to - 1)
+}
+
+impl Cursor {
+  fn header(&mut self) -> Result<Header, Error> {
+    let mut buf = [0u8; NEWC_HEADER];
+    try!(self.stream.read(&mut buf[..6]));
+    if (buf[0] as u16) | ((buf[1] as u16)<<8) == 0o70707 {
+      try!(self.stream.read(&mut buf[6..BINARY_HEADER]));
+      return Ok(parse_binary(&buf[..BINARY_HEADER]));
+    }
+    let format = if &buf[..6] == &b"070701"[..] {
+      Format::Newc
+    } else if &buf[..6] == &b"070702"[..] {
+      Format::Crc
+    } else {
+      return Err(Error::InvalidDiskFormat);
+    };
+    try!(self.stream.read(&mut buf[6..]));
+    parse_newc(&buf, format)
+  }
+
+  // Add up all bytes of the body, for the crc format.
+  fn checksum(&self, pos: usize, size: usize) -> Result<u32, Error> {
+    let mut buf = [0u8; SECTOR_SIZE];
+    let mut sum = 0u32;
+    let mut done = 0;
+    while done < size {
+      let n = cmp::min(size - done, SECTOR_SIZE);
+      try!(read_at(&*self.stream.dev, pos + done, &mut buf[..n]));
+      for b in buf[..n].iter() {
+        sum = sum.wrapping_add(*b as u32);
+      }
+      done += n;
+    }
+    Ok(sum)
+  }
+
+  fn entry(&mut self) -> Result<Option<Entry>, Error> {
+    let header_pos = self.stream.pos;
+    let h = try!(self.header());
+
+    // The name includes its NUL terminator.
+    if h.namesize < 2 || h.namesize > MAX_NAMESIZE {
+      return Err(Error::InvalidDiskFormat);
+    }
+    let mut namebuf = vec![0u8; h.namesize];
+    try!(self.stream.read(&mut namebuf));
+    if namebuf.pop() != Some(0) {
+      return Err(Error::InvalidDiskFormat);
+    }
+    let name = try!(String::from_utf8(namebuf).map_err(|_| Error::InvalidDiskFormat));
+
+    // Stop at the end marker.
+    if name == "TRAILER!!!" {
+      return Ok(None);
+    }
+
+    // The name and body blobs are padded.
+    try!(self.stream.align(h.align));
+    let body_pos = self.stream.pos;
+    try!(self.stream.skip(h.filesize));
+    // The last entry's padding may be cut off.
+    if self.stream.align(h.align).is_err() {
+      self.stream.pos = self.stream.size;
+    }
+
+    if h.format == Format::Crc {
+      let sum = try!(self.checksum(body_pos, h.filesize));
+      if sum != h.check {
+        println!("cpio: bad checksum for {}: {:x} instead of {:x}", name, sum, h.check);
+        return Err(Error::InvalidDiskFormat);
+      }
+    }
+
+    Ok(Some(Entry{
+      format: h.format,
+      body_pos: body_pos,
+      header_pos: header_pos,
+      mode: h.mode,
+      nlink: h.nlink,
+      uid: h.uid,
+      gid: h.gid,
+      rdev: h.rdev,
+      mtime: h.mtime,
+      size: h.filesize,
+      name: name,
+    }))
+  }
+}
 
 impl Iterator for Cursor {
-  // On error, you can retry or break.
+  // Once there's an error, the iteration is over.
   type Item = Result<Entry, Error>;
 
   fn next(&mut self) -> Option<Result<Entry, Error>> {
-    println!("Trying to read header at {:?}", self.next_header);
-    let (sector, offset) = self.next_header;
+    if self.done {
+      return None;
+    }
+    match self.entry() {
+      Ok(Some(e)) => Some(Ok(e)),
+      Ok(None) => {
+        self.done = true;
+        None
+      },
+      Err(e) => {
+        println!("cpio: bad entry at byte {}: {:?}", self.stream.pos, e);
+        self.done = true;
+        Some(Err(e))
+      },
+    }
+  }
+}
 
-    let sectorbuf = self.dev.get(sector as u64).unwrap();
+// The archive's root directory isn't an entry of its own.
+const ROOT: Ino = 1;
 
-    let entry = &sectorbuf[offset..];
-    //println!("entry: {:?}", &entry);
-    let magic = (entry[0] as u16) | ((entry[1] as u16)<<8);
-    if magic != 0o70707 {
-      return Some(Err(Error::InvalidDiskFormat));
+// A directory tree node. Directories that only show up as part of other
+// names ("bin" in "bin/sh") get a node without an entry.
+#[derive(Debug)]
+struct Node {
+  parent: Ino,
+  entry: Option<usize>,
+  children: BTreeMap<String, Ino>,
+}
+
+// A read-only filesystem on top of a cpio archive. We read the whole archive
+// once at mount time and build the directory tree from the entries' names;
+// node i gets inode number i+1, the root being node 0.
+#[derive(Debug)]
+pub struct Cpiofs {
+  dev: Arc<block::Cache>,
+  entries: Vec<Entry>,
+  nodes: Vec<Node>,
+}
+
+impl Cpiofs {
+  pub fn new(dev: Arc<block::Cache>) -> Result<Self, Error> {
+    let mut fs = Cpiofs { dev: dev.clone(), entries: vec![], nodes: vec![Node { parent: ROOT, entry: None, children: BTreeMap::new() }] };
+    for e in Cursor::new(dev) {
+      let e = try!(e);
+      let i = fs.entries.len();
+      fs.add(&e.name.clone(), i);
+      fs.entries.push(e);
     }
+    Ok(fs)
+  }
 
-    let mut namelength = ((entry[20] as u16) | ((entry[21] as u16)<<8)) as usize;
+  // Hook entry i into the tree under its full name, making up any missing
+  // parent directories on the way. Names can come as "a/b", "./a/b" or "/a/b".
+  fn add(&mut self, name: &str, i: usize) {
+    let components: Vec<&str> = name.split('/').filter(|c| c.len() > 0 && *c != ".").collect();
+    if components.len() == 0 {
+      // The archive's own "." entry: use its metadata for the root.
+      self.nodes[0].entry = Some(i);
+      return;
+    }
 
-    // nice byte order, bro..
-    let size = (((entry[22] as u32)<<16) | ((entry[23] as u32)<<24) | (entry[24] as u32) | ((entry[25] as u32)<<8)) as usize;
+    let mut dir = ROOT;
+    for (n, c) in components.iter().enumerate() {
+      let last = n + 1 == components.len();
+      let existing = self.node(dir).children.get(*c).cloned();
+      dir = match existing {
+        Some(ino) => ino,
+        None => {
+          self.nodes.push(Node { parent: dir, entry: None, children: BTreeMap::new() });
+          let ino = self.nodes.len() as Ino;
+          self.nodes[dir as usize - 1].children.insert(String::from(*c), ino);
+          ino
+        },
+      };
+      if last {
+        // A later entry with the same name wins, like when extracting.
+        self.nodes[dir as usize - 1].entry = Some(i);
+      }
+    }
+  }
 
-    // we'll panic here if we hit a sector boundary
-    let name = match str::from_utf8(&&entry[26..(26+namelength-1)]) {
-        Ok(v) => v,
-        Err(e) => return Some(Err(Error::Unknown))
-    };
+  fn node(&self, ino: Ino) -> &Node {
+    &self.nodes[ino as usize - 1]
+  }
 
-    // Break if we found the end marker.
-    if name.as_bytes() == "TRAILER!!!".as_bytes() {
-      return None;
+  fn get(&self, ino: Ino) -> Result<&Node, Error> {
+    if ino < 1 || ino as usize > self.nodes.len() {
+      return Err(Error::NotFound);
+    }
+    Ok(self.node(ino))
+  }
+
+  fn kind(&self, n: &Node) -> FileType {
+    if n.children.len() > 0 {
+      return FileType::Directory;
+    }
+    match n.entry {
+      Some(i) => super::file_type(self.entries[i].mode).unwrap_or(FileType::Regular),
+      None => FileType::Directory,
     }
+  }
 
-    // The name and body blobs are u16-padded.
-    let mut next_offset = offset + 26 + namelength;
-    if next_offset & 1 != 0 {
-      next_offset += 1;
+  // Copy the entry's data starting at `offset` into buf.
+  fn read_body(&self, e: &Entry, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+    if offset >= e.size {
+      return Ok(0);
     }
-    let body_pos = next_offset;
-    next_offset += size;
-    if next_offset & 1 != 0 {
-      next_offset += 1;
+    let len = cmp::min(buf.len(), e.size - offset);
+    try!(read_at(&*self.dev, e.body_pos + offset, &mut buf[..len]));
+    Ok(len)
+  }
+
+  fn dir(&self, ino: Ino) -> Result<&Node, Error> {
+    let n = try!(self.get(ino));
+    if self.kind(n) != FileType::Directory {
+      return Err(Error::NotADirectory);
     }
+    Ok(n)
+  }
+}
 
-    self.next_header = (sector + next_offset / 512, next_offset % 512);
+impl Filesystem for Cpiofs {
+  fn root(&self) -> Ino {
+    ROOT
+  }
 
-    Some(Ok(Entry{ body_pos: (sector, body_pos), header_pos: (sector, offset), size: size, name: name.to_string() }))
+  fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, Error> {
+    let n = try!(self.dir(dir));
+    match name {
+      "." => Ok(dir),
+      ".." => Ok(n.parent),
+      _ => n.children.get(name).cloned().ok_or(Error::NotFound),
+    }
+  }
+
+  fn stat(&self, ino: Ino) -> Result<Stat, Error> {
+    let n = try!(self.get(ino));
+    let kind = self.kind(n);
+    let nlink = if kind == FileType::Directory { 2 } else { 1 };
+    Ok(match n.entry {
+      Some(i) => {
+        let e = &self.entries[i];
+        Stat {
+          ino: ino,
+          kind: kind,
+          mode: e.mode & 0o7777,
+          nlink: if e.nlink > 0 { e.nlink } else { nlink },
+          uid: e.uid,
+          gid: e.gid,
+          size: if kind == FileType::Directory { 0 } else { e.size as u64 },
+          rdev: e.rdev,
+          mtime: e.mtime,
+        }
+      },
+      None => Stat { ino: ino, kind: kind, mode: 0o755, nlink: nlink, uid: 0, gid: 0, size: 0, rdev: 0, mtime: 0 },
+    })
+  }
+
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    let n = try!(self.get(ino));
+    if self.kind(n) == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    match n.entry {
+      Some(i) => self.read_body(&self.entries[i], offset as usize, buf),
+      None => Ok(0),
+    }
+  }
+
+  fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, Error> {
+    let n = try!(self.dir(dir));
+    Ok(n.children.iter().map(|(name, &ino)| DirEntry { name: name.clone(), ino: ino, kind: self.kind(self.node(ino)) }).collect())
+  }
+
+  // A symlink's target is its body.
+  fn readlink(&self, ino: Ino) -> Result<String, Error> {
+    let n = try!(self.get(ino));
+    let e = match n.entry {
+      Some(i) if self.kind(n) == FileType::Symlink => &self.entries[i],
+      _ => return Err(Error::InvalidArgument),
+    };
+    let mut buf = vec![0u8; e.size];
+    try!(self.read_body(e, 0, &mut buf));
+    String::from_utf8(buf).map_err(|_| Error::InvalidDiskFormat)
   }
 }
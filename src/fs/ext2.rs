@@ -0,0 +1,1243 @@
+use prelude::*;
+use core::{cmp,mem};
+use core::cell::RefCell;
+use collections::btree_map::BTreeMap;
+use collections::btree_set::BTreeSet;
+
+use block;
+use byteorder::{ByteOrder,LittleEndian};
+use sync::global_mutex::GlobalMutex;
+use super::{io_error, DirEntry, Error, FileType, Filesystem, Ino, Stat, SECTOR_SIZE};
+use super::journal::Journal;
+
+// See http://www.nongnu.org/ext2-doc/ext2.html for the on-disk format.
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT: Ino = 2;
+
+// Revision 0 filesystems have fixed values for these
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+
+const GROUP_DESC_SIZE: u64 = 32;
+
+// Slots in i_block: 12 direct blocks, then single, double and triple indirect
+const DIRECT_BLOCKS: u64 = 12;
+const IND_BLOCK: usize = 12;
+const DIND_BLOCK: usize = 13;
+const TIND_BLOCK: usize = 14;
+
+// Symlinks shorter than this keep their target in i_block.
+const FAST_SYMLINK_MAX: usize = 60;
+
+const MAX_NAME: usize = 255;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+// The journal may have something to replay. Set while we have it mounted.
+const INCOMPAT_RECOVER: u32 = 0x4;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_RECOVER;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+// Hashed directory index. We don't maintain the index, so directories we
+// change lose the flag and are just linear again, which is allowed.
+const INDEX_FL: u32 = 0x1000;
+
+// The most file data a single journalled write puts in one transaction, in
+// blocks. The metadata that goes with it has to fit in the journal.
+const WRITE_CHUNK_BLOCKS: u64 = 256;
+
+fn dirent_type(kind: FileType) -> u8 {
+  match kind {
+    FileType::Regular => 1,
+    FileType::Directory => 2,
+    FileType::CharDevice => 3,
+    FileType::BlockDevice => 4,
+    FileType::Fifo => 5,
+    FileType::Socket => 6,
+    FileType::Symlink => 7,
+  }
+}
+
+fn dirent_kind(t: u8) -> Option<FileType> {
+  match t {
+    1 => Some(FileType::Regular),
+    2 => Some(FileType::Directory),
+    3 => Some(FileType::CharDevice),
+    4 => Some(FileType::BlockDevice),
+    5 => Some(FileType::Fifo),
+    6 => Some(FileType::Socket),
+    7 => Some(FileType::Symlink),
+    _ => None,
+  }
+}
+
+// The space a directory entry with a name this long needs
+fn dirent_len(name_len: usize) -> usize {
+  (8 + name_len + 3) & !3
+}
+
+#[derive(Debug)]
+struct Group {
+  block_bitmap: u32,
+  inode_bitmap: u32,
+  inode_table: u32,
+  free_blocks: u16,
+  free_inodes: u16,
+  used_dirs: u16,
+}
+
+// An inode as it is on disk. We keep all of its bytes around so that fields
+// we don't know about survive a write.
+#[derive(Debug)]
+struct Inode {
+  ino: u32,
+  raw: Vec<u8>,
+}
+
+impl Inode {
+  fn u16_at(&self, i: usize) -> u16 { LittleEndian::read_u16(&self.raw[i..]) }
+  fn u32_at(&self, i: usize) -> u32 { LittleEndian::read_u32(&self.raw[i..]) }
+  fn set_u16(&mut self, i: usize, v: u16) { LittleEndian::write_u16(&mut self.raw[i..], v) }
+  fn set_u32(&mut self, i: usize, v: u32) { LittleEndian::write_u32(&mut self.raw[i..], v) }
+
+  fn mode(&self) -> u32 { self.u16_at(0) as u32 }
+  fn set_mode(&mut self, mode: u32) { self.set_u16(0, mode as u16) }
+  fn kind(&self) -> FileType { super::file_type(self.mode()).unwrap_or(FileType::Regular) }
+  fn uid(&self) -> u32 { self.u16_at(2) as u32 | (self.u16_at(120) as u32) << 16 }
+  fn gid(&self) -> u32 { self.u16_at(24) as u32 | (self.u16_at(122) as u32) << 16 }
+  fn mtime(&self) -> u64 { self.u32_at(16) as u64 }
+  fn links(&self) -> u16 { self.u16_at(26) }
+  fn set_links(&mut self, n: u16) { self.set_u16(26, n) }
+  // In 512-byte units, whatever the block size
+  fn sectors(&self) -> u32 { self.u32_at(28) }
+  fn set_sectors(&mut self, n: u32) { self.set_u32(28, n) }
+  fn flags(&self) -> u32 { self.u32_at(32) }
+  fn set_flags(&mut self, f: u32) { self.set_u32(32, f) }
+  fn block(&self, i: usize) -> u32 { self.u32_at(40 + 4*i) }
+  fn set_block(&mut self, i: usize, b: u32) { self.set_u32(40 + 4*i, b) }
+
+  // Regular files keep the upper half of their size in i_dir_acl.
+  fn size(&self) -> u64 {
+    let hi = if self.kind() == FileType::Regular { self.u32_at(108) as u64 } else { 0 };
+    self.u32_at(4) as u64 | hi << 32
+  }
+
+  fn set_size(&mut self, size: u64) {
+    self.set_u32(4, size as u32);
+    if self.kind() == FileType::Regular {
+      self.set_u32(108, (size >> 32) as u32);
+    }
+  }
+
+  fn is_fast_symlink(&self) -> bool {
+    self.kind() == FileType::Symlink && self.size() < FAST_SYMLINK_MAX as u64 && self.sectors() == 0
+  }
+
+  // Device numbers are in i_block, in the old 8:8 encoding if it fits.
+  fn rdev(&self) -> u64 {
+    match self.kind() {
+      FileType::CharDevice | FileType::BlockDevice if self.block(0) != 0 => self.block(0) as u64,
+      FileType::CharDevice | FileType::BlockDevice => self.block(1) as u64,
+      _ => 0,
+    }
+  }
+}
+
+// A directory entry together with where it is on disk.
+#[derive(Debug)]
+struct Dirent {
+  // Byte position on the device
+  pos: u64,
+  // Of the entry before it in the same block, which absorbs it on removal
+  prev: Option<u64>,
+  ino: u32,
+  rec_len: usize,
+  file_type: u8,
+  name: String,
+}
+
+// Metadata changes since the last commit, when there's a journal
+#[derive(Debug,Default)]
+struct Transaction {
+  // Whole blocks, as they'll be once committed
+  blocks: BTreeMap<u32, Vec<u8>>,
+  // Blocks freed so far. Until the commit, their old contents still count.
+  freed: BTreeSet<u32>,
+}
+
+#[derive(Debug)]
+struct Inner {
+  dev: Arc<block::Cache>,
+  block_size: u64,
+  blocks_count: u32,
+  inodes_count: u32,
+  first_data_block: u32,
+  blocks_per_group: u32,
+  inodes_per_group: u32,
+  inode_size: u64,
+  first_ino: u32,
+  filetype: bool,
+  writable: bool,
+  free_blocks: u32,
+  free_inodes: u32,
+  groups: Vec<Group>,
+  journal: Option<Journal>,
+  txn: RefCell<Transaction>,
+}
+
+// A read-write ext2 filesystem on a block device, or ext3 if it has a
+// journal.
+//
+// Without a journal, every change goes straight to the block cache, in an
+// order that at worst leaks blocks or inodes on a crash. With one, each
+// operation is a transaction: its metadata changes stay in memory until
+// they're committed to the journal as a whole, at the end of the operation.
+// File contents aren't journalled, but they're written before the metadata
+// pointing at them is committed, like ext3's "ordered" mode.
+//
+// The backup superblocks and group descriptors are never updated; e2fsck
+// knows to take the primary ones.
+//
+// The whole filesystem is behind one lock, which is held across I/O.
+#[derive(Debug)]
+pub struct Ext2 {
+  inner: GlobalMutex<Inner>,
+}
+
+impl Inner {
+  // Sees the changes of the running transaction.
+  fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+    try!(super::read_bytes(&*self.dev, pos, buf).map_err(io_error));
+    let txn = self.txn.borrow();
+    if txn.blocks.len() == 0 || buf.len() == 0 {
+      return Ok(());
+    }
+    let end = pos + buf.len() as u64;
+    for block in (pos / self.block_size) as u32..((end - 1) / self.block_size) as u32 + 1 {
+      if let Some(data) = txn.blocks.get(&block) {
+        let start = cmp::max(pos, self.block_pos(block));
+        let stop = cmp::min(end, self.block_pos(block + 1));
+        let from = (start - self.block_pos(block)) as usize;
+        buf[(start - pos) as usize..(stop - pos) as usize].clone_from_slice(&data[from..from + (stop - start) as usize]);
+      }
+    }
+    Ok(())
+  }
+
+  // For metadata. With a journal, this only changes the running transaction.
+  fn write_at(&self, pos: u64, data: &[u8]) -> Result<(), Error> {
+    if !self.writable {
+      return Err(Error::ReadOnly);
+    }
+    if self.journal.is_none() {
+      return super::write_bytes(&*self.dev, pos, data).map_err(io_error);
+    }
+
+    let mut txn = self.txn.borrow_mut();
+    let mut done = 0;
+    while done < data.len() {
+      let at = pos + done as u64;
+      let block = (at / self.block_size) as u32;
+      let start = (at % self.block_size) as usize;
+      let n = cmp::min(data.len() - done, self.block_size as usize - start);
+      if !txn.blocks.contains_key(&block) {
+        let mut copy = vec![0u8; self.block_size as usize];
+        try!(super::read_bytes(&*self.dev, self.block_pos(block), &mut copy).map_err(io_error));
+        txn.blocks.insert(block, copy);
+      }
+      txn.blocks.get_mut(&block).unwrap()[start..start+n].clone_from_slice(&data[done..done+n]);
+      done += n;
+    }
+    Ok(())
+  }
+
+  // For file contents, which skip the journal. Blocks that the running
+  // transaction has touched are the exception: their logged copy would
+  // overwrite the data at the commit, and a block freed in it may still be
+  // metadata if we crash first.
+  fn write_data_at(&self, pos: u64, data: &[u8]) -> Result<(), Error> {
+    let journalled = {
+      let txn = self.txn.borrow();
+      let block = (pos / self.block_size) as u32;
+      txn.blocks.contains_key(&block) || txn.freed.contains(&block)
+    };
+    if self.journal.is_none() || journalled {
+      return self.write_at(pos, data);
+    }
+    if !self.writable {
+      return Err(Error::ReadOnly);
+    }
+    super::write_bytes(&*self.dev, pos, data).map_err(io_error)
+  }
+
+  // End the running transaction, so that its changes get to the device.
+  fn commit(&mut self) -> Result<(), Error> {
+    let txn = mem::replace(&mut *self.txn.borrow_mut(), Transaction::default());
+    match self.journal {
+      Some(ref mut journal) => journal.commit(&txn.blocks),
+      None => Ok(()),
+    }
+  }
+
+  fn read_u32(&self, pos: u64) -> Result<u32, Error> {
+    let mut b = [0u8; 4];
+    try!(self.read_at(pos, &mut b));
+    Ok(LittleEndian::read_u32(&b))
+  }
+
+  fn write_u32(&self, pos: u64, v: u32) -> Result<(), Error> {
+    let mut b = [0u8; 4];
+    LittleEndian::write_u32(&mut b, v);
+    self.write_at(pos, &b)
+  }
+
+  fn block_pos(&self, block: u32) -> u64 {
+    block as u64 * self.block_size
+  }
+
+  fn read_block(&self, block: u32) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; self.block_size as usize];
+    try!(self.read_at(self.block_pos(block), &mut buf));
+    Ok(buf)
+  }
+
+  // Block numbers per indirect block
+  fn per_block(&self) -> u64 {
+    self.block_size / 4
+  }
+
+  // Write back the free counts of the superblock and a group descriptor.
+  fn write_counts(&self, group: usize) -> Result<(), Error> {
+    let mut sb = [0u8; 8];
+    LittleEndian::write_u32(&mut sb[0..], self.free_blocks);
+    LittleEndian::write_u32(&mut sb[4..], self.free_inodes);
+    try!(self.write_at(SUPERBLOCK_OFFSET + 12, &sb));
+
+    let g = &self.groups[group];
+    let mut gd = [0u8; 6];
+    LittleEndian::write_u16(&mut gd[0..], g.free_blocks);
+    LittleEndian::write_u16(&mut gd[2..], g.free_inodes);
+    LittleEndian::write_u16(&mut gd[4..], g.used_dirs);
+    let table = self.block_pos(self.first_data_block + 1);
+    self.write_at(table + group as u64 * GROUP_DESC_SIZE + 12, &gd)
+  }
+
+  // Find a clear bit in a bitmap block, set it and return its index.
+  fn take_bit(&self, bitmap: u32, start: usize, count: usize) -> Result<Option<usize>, Error> {
+    let mut map = try!(self.read_block(bitmap));
+    for i in start..count {
+      if map[i / 8] & (1 << (i % 8)) == 0 {
+        map[i / 8] |= 1 << (i % 8);
+        try!(self.write_at(self.block_pos(bitmap) + (i / 8) as u64, &map[i/8..i/8+1]));
+        return Ok(Some(i));
+      }
+    }
+    Ok(None)
+  }
+
+  fn clear_bit(&self, bitmap: u32, i: usize) -> Result<(), Error> {
+    let pos = self.block_pos(bitmap) + (i / 8) as u64;
+    let mut b = [0u8; 1];
+    try!(self.read_at(pos, &mut b));
+    if b[0] & (1 << (i % 8)) == 0 {
+      println!("ext2: freeing bit {} in bitmap {}, which is already free", i, bitmap);
+      return Err(Error::InvalidDiskFormat);
+    }
+    b[0] &= !(1 << (i % 8));
+    self.write_at(pos, &b)
+  }
+
+  // Groups to try allocating from, starting with the preferred one
+  fn groups_from(&self, goal: usize) -> Vec<usize> {
+    let n = self.groups.len();
+    (0..n).map(|i| (goal + i) % n).collect()
+  }
+
+  // Allocate a zeroed block, near `goal` if possible. `data` says whether it
+  // will hold file contents.
+  fn alloc_block(&mut self, goal: usize, data: bool) -> Result<u32, Error> {
+    if !self.writable {
+      return Err(Error::ReadOnly);
+    }
+    for g in self.groups_from(goal) {
+      if self.groups[g].free_blocks == 0 {
+        continue;
+      }
+      let first = self.first_data_block + g as u32 * self.blocks_per_group;
+      let count = cmp::min(self.blocks_per_group, self.blocks_count - first) as usize;
+      if let Some(i) = try!(self.take_bit(self.groups[g].block_bitmap, 0, count)) {
+        self.groups[g].free_blocks -= 1;
+        self.free_blocks -= 1;
+        try!(self.write_counts(g));
+        let block = first + i as u32;
+        let zeroes = vec![0u8; self.block_size as usize];
+        if data {
+          try!(self.write_data_at(self.block_pos(block), &zeroes));
+        } else {
+          try!(self.write_at(self.block_pos(block), &zeroes));
+        }
+        return Ok(block);
+      }
+    }
+    Err(Error::NoSpace)
+  }
+
+  fn free_block(&mut self, block: u32) -> Result<(), Error> {
+    if block < self.first_data_block || block >= self.blocks_count {
+      return Err(Error::InvalidDiskFormat);
+    }
+    let g = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+    let i = ((block - self.first_data_block) % self.blocks_per_group) as usize;
+    try!(self.clear_bit(self.groups[g].block_bitmap, i));
+    if self.journal.is_some() {
+      self.txn.borrow_mut().freed.insert(block);
+    }
+    self.groups[g].free_blocks += 1;
+    self.free_blocks += 1;
+    self.write_counts(g)
+  }
+
+  fn alloc_inode(&mut self, goal: usize, dir: bool) -> Result<u32, Error> {
+    if !self.writable {
+      return Err(Error::ReadOnly);
+    }
+    for g in self.groups_from(goal) {
+      if self.groups[g].free_inodes == 0 {
+        continue;
+      }
+      // The first few inodes are reserved.
+      let start = if g == 0 { self.first_ino as usize - 1 } else { 0 };
+      if let Some(i) = try!(self.take_bit(self.groups[g].inode_bitmap, start, self.inodes_per_group as usize)) {
+        self.groups[g].free_inodes -= 1;
+        if dir {
+          self.groups[g].used_dirs += 1;
+        }
+        self.free_inodes -= 1;
+        try!(self.write_counts(g));
+        return Ok(g as u32 * self.inodes_per_group + i as u32 + 1);
+      }
+    }
+    Err(Error::NoSpace)
+  }
+
+  fn free_inode(&mut self, ino: u32, dir: bool) -> Result<(), Error> {
+    if ino == 0 || ino > self.inodes_count {
+      return Err(Error::InvalidDiskFormat);
+    }
+    let g = self.group_of(ino);
+    // A directory count that's already zero means the group descriptor is
+    // wrong; check before touching the bitmap so that we fail cleanly.
+    if dir && self.groups[g].used_dirs == 0 {
+      println!("ext2: freeing directory inode {}, but group {} has no directories", ino, g);
+      return Err(Error::InvalidDiskFormat);
+    }
+    try!(self.clear_bit(self.groups[g].inode_bitmap, ((ino - 1) % self.inodes_per_group) as usize));
+    self.groups[g].free_inodes += 1;
+    if dir {
+      self.groups[g].used_dirs -= 1;
+    }
+    self.free_inodes += 1;
+    self.write_counts(g)
+  }
+
+  fn group_of(&self, ino: u32) -> usize {
+    ((ino - 1) / self.inodes_per_group) as usize
+  }
+
+  fn inode_pos(&self, ino: u32) -> Result<u64, Error> {
+    let g = self.group_of(ino);
+    if ino == 0 || g >= self.groups.len() {
+      return Err(Error::NotFound);
+    }
+    let index = ((ino - 1) % self.inodes_per_group) as u64;
+    Ok(self.block_pos(self.groups[g].inode_table) + index * self.inode_size)
+  }
+
+  fn inode(&self, ino: Ino) -> Result<Inode, Error> {
+    if ino > u32::max_value() as Ino {
+      return Err(Error::NotFound);
+    }
+    let ino = ino as u32;
+    let mut raw = vec![0u8; self.inode_size as usize];
+    try!(self.read_at(try!(self.inode_pos(ino)), &mut raw));
+    let inode = Inode { ino: ino, raw: raw };
+    if inode.links() == 0 {
+      return Err(Error::NotFound);
+    }
+    Ok(inode)
+  }
+
+  fn write_inode(&self, inode: &Inode) -> Result<(), Error> {
+    self.write_at(try!(self.inode_pos(inode.ino)), &inode.raw)
+  }
+
+  fn dir(&self, ino: Ino) -> Result<Inode, Error> {
+    let inode = try!(self.inode(ino));
+    if inode.kind() != FileType::Directory {
+      return Err(Error::NotADirectory);
+    }
+    Ok(inode)
+  }
+
+  // Where file block `n` hangs in the block tree: the i_block slot, then
+  // the index into each level of indirect blocks.
+  fn block_path(&self, n: u64) -> Result<(usize, Vec<u64>), Error> {
+    let per = self.per_block();
+    if n < DIRECT_BLOCKS {
+      return Ok((n as usize, vec![]));
+    }
+    let n = n - DIRECT_BLOCKS;
+    if n < per {
+      return Ok((IND_BLOCK, vec![n]));
+    }
+    let n = n - per;
+    if n < per * per {
+      return Ok((DIND_BLOCK, vec![n / per, n % per]));
+    }
+    let n = n - per * per;
+    if n < per * per * per {
+      return Ok((TIND_BLOCK, vec![n / (per * per), (n / per) % per, n % per]));
+    }
+    Err(Error::NoSpace)
+  }
+
+  // The block holding file block `n`, or 0 for a hole.
+  fn bmap(&self, inode: &Inode, n: u64) -> Result<u32, Error> {
+    let (slot, path) = try!(self.block_path(n));
+    let mut b = inode.block(slot);
+    for i in path {
+      if b == 0 {
+        return Ok(0);
+      }
+      b = try!(self.read_u32(self.block_pos(b) + i * 4));
+    }
+    Ok(b)
+  }
+
+  // Like bmap, but fills holes (and missing indirect blocks) on the way.
+  fn bmap_alloc(&mut self, inode: &mut Inode, n: u64) -> Result<u32, Error> {
+    let (slot, path) = try!(self.block_path(n));
+    let goal = self.group_of(inode.ino);
+    let sectors_per_block = (self.block_size / SECTOR_SIZE) as u32;
+    // Directory and symlink blocks are metadata, too.
+    let regular = inode.kind() == FileType::Regular;
+    let depth = path.len();
+
+    let mut b = inode.block(slot);
+    if b == 0 {
+      b = try!(self.alloc_block(goal, regular && depth == 0));
+      inode.set_block(slot, b);
+      let s = inode.sectors();
+      inode.set_sectors(s + sectors_per_block);
+    }
+    for (level, i) in path.into_iter().enumerate() {
+      let pos = self.block_pos(b) + i * 4;
+      let mut next = try!(self.read_u32(pos));
+      if next == 0 {
+        next = try!(self.alloc_block(goal, regular && level == depth - 1));
+        try!(self.write_u32(pos, next));
+        let s = inode.sectors();
+        inode.set_sectors(s + sectors_per_block);
+      }
+      b = next;
+    }
+    Ok(b)
+  }
+
+  // Free the blocks of the tree under `block` that hold file blocks from
+  // `first` on. The tree starts at file block `base`, and each of its
+  // entries covers `span` file blocks. Returns whether `block` itself is
+  // gone now.
+  fn free_tree(&mut self, inode: &mut Inode, block: u32, depth: u32, base: u64, span: u64, first: u64) -> Result<bool, Error> {
+    if depth > 0 {
+      let per = self.per_block();
+      let mut entries = try!(self.read_block(block));
+      let mut changed = false;
+      let mut any_left = false;
+      for i in 0..per {
+        let child = LittleEndian::read_u32(&entries[i as usize * 4..]);
+        if child == 0 {
+          continue;
+        }
+        let child_base = base + i * span;
+        if child_base + span <= first {
+          any_left = true;
+          continue;
+        }
+        if try!(self.free_tree(inode, child, depth - 1, child_base, span / per, first)) {
+          LittleEndian::write_u32(&mut entries[i as usize * 4..], 0);
+          changed = true;
+        } else {
+          any_left = true;
+        }
+      }
+      if any_left {
+        if changed {
+          try!(self.write_at(self.block_pos(block), &entries));
+        }
+        return Ok(false);
+      }
+    } else if base < first {
+      return Ok(false);
+    }
+
+    try!(self.free_block(block));
+    let s = inode.sectors();
+    inode.set_sectors(s - (self.block_size / SECTOR_SIZE) as u32);
+    Ok(true)
+  }
+
+  // Free all blocks past the first `keep` file blocks.
+  fn free_blocks_from(&mut self, inode: &mut Inode, keep: u64) -> Result<(), Error> {
+    if inode.is_fast_symlink() {
+      return Ok(());
+    }
+    let per = self.per_block();
+    let trees = [
+      (IND_BLOCK, 1, DIRECT_BLOCKS, 1),
+      (DIND_BLOCK, 2, DIRECT_BLOCKS + per, per),
+      (TIND_BLOCK, 3, DIRECT_BLOCKS + per + per * per, per * per),
+    ];
+    for slot in 0..DIRECT_BLOCKS as usize {
+      let b = inode.block(slot);
+      if b != 0 && try!(self.free_tree(inode, b, 0, slot as u64, 1, keep)) {
+        inode.set_block(slot, 0);
+      }
+    }
+    for &(slot, depth, base, span) in trees.iter() {
+      let b = inode.block(slot);
+      if b != 0 && try!(self.free_tree(inode, b, depth, base, span, keep)) {
+        inode.set_block(slot, 0);
+      }
+    }
+    Ok(())
+  }
+
+  fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    let size = inode.size();
+    if offset >= size {
+      return Ok(0);
+    }
+    let len = cmp::min(buf.len() as u64, size - offset) as usize;
+    let mut done = 0;
+    while done < len {
+      let pos = offset + done as u64;
+      let in_block = pos % self.block_size;
+      let n = cmp::min((self.block_size - in_block) as usize, len - done);
+      match try!(self.bmap(inode, pos / self.block_size)) {
+        0 => for b in buf[done..done+n].iter_mut() { *b = 0; },
+        block => try!(self.read_at(self.block_pos(block) + in_block, &mut buf[done..done+n])),
+      }
+      done += n;
+    }
+    Ok(len)
+  }
+
+  // Doesn't write the inode back.
+  fn write_data(&mut self, inode: &mut Inode, offset: u64, data: &[u8]) -> Result<usize, Error> {
+    let mut done = 0;
+    while done < data.len() {
+      let pos = offset + done as u64;
+      let in_block = pos % self.block_size;
+      let n = cmp::min((self.block_size - in_block) as usize, data.len() - done);
+      let block = try!(self.bmap_alloc(inode, pos / self.block_size));
+      try!(self.write_data_at(self.block_pos(block) + in_block, &data[done..done+n]));
+      done += n;
+    }
+    if offset + done as u64 > inode.size() {
+      inode.set_size(offset + done as u64);
+    }
+    Ok(done)
+  }
+
+  fn set_size(&mut self, inode: &mut Inode, size: u64) -> Result<(), Error> {
+    let old = inode.size();
+    if size < old {
+      let keep = (size + self.block_size - 1) / self.block_size;
+      try!(self.free_blocks_from(inode, keep));
+      // The rest of the last block has to read as zeroes if the file grows again.
+      let tail = size % self.block_size;
+      if tail != 0 {
+        let block = try!(self.bmap(inode, size / self.block_size));
+        if block != 0 {
+          try!(self.write_data_at(self.block_pos(block) + tail, &vec![0u8; (self.block_size - tail) as usize]));
+        }
+      }
+    }
+    inode.set_size(size);
+    Ok(())
+  }
+
+  fn entries(&self, dir: &Inode) -> Result<Vec<Dirent>, Error> {
+    let mut v = vec![];
+    let blocks = dir.size() / self.block_size;
+    for n in 0..blocks {
+      let block = try!(self.bmap(dir, n));
+      if block == 0 {
+        continue;
+      }
+      let data = try!(self.read_block(block));
+      let mut pos = 0;
+      let mut prev = None;
+      while pos + 8 <= data.len() {
+        let ino = LittleEndian::read_u32(&data[pos..]);
+        let rec_len = LittleEndian::read_u16(&data[pos+4..]) as usize;
+        let name_len = data[pos+6] as usize;
+        if rec_len < 8 || pos + rec_len > data.len() || name_len + 8 > rec_len {
+          println!("ext2: bad directory entry in inode {}, block {}", dir.ino, block);
+          return Err(Error::InvalidDiskFormat);
+        }
+        let here = self.block_pos(block) + pos as u64;
+        let name = String::from_utf8_lossy(&data[pos+8..pos+8+name_len]).into_owned();
+        v.push(Dirent { pos: here, prev: prev, ino: ino, rec_len: rec_len, file_type: data[pos+7], name: name });
+        prev = Some(here);
+        pos += rec_len;
+      }
+    }
+    Ok(v)
+  }
+
+  fn find(&self, dir: &Inode, name: &str) -> Result<Dirent, Error> {
+    let entries = try!(self.entries(dir));
+    entries.into_iter().find(|e| e.ino != 0 && e.name == name).ok_or(Error::NotFound)
+  }
+
+  fn put_dirent(&self, pos: u64, ino: u32, rec_len: usize, name: &str, kind: FileType) -> Result<(), Error> {
+    let mut buf = vec![0u8; 8 + name.len()];
+    LittleEndian::write_u32(&mut buf[0..], ino);
+    LittleEndian::write_u16(&mut buf[4..], rec_len as u16);
+    buf[6] = name.len() as u8;
+    buf[7] = if self.filetype { dirent_type(kind) } else { 0 };
+    buf[8..].clone_from_slice(name.as_bytes());
+    self.write_at(pos, &buf)
+  }
+
+  // Doesn't check whether `name` exists already.
+  fn add_entry(&mut self, dir: &mut Inode, name: &str, ino: u32, kind: FileType) -> Result<(), Error> {
+    if name.len() > MAX_NAME {
+      return Err(Error::NameTooLong);
+    }
+    let needed = dirent_len(name.len());
+
+    if dir.flags() & INDEX_FL != 0 {
+      let f = dir.flags();
+      dir.set_flags(f & !INDEX_FL);
+      try!(self.write_inode(dir));
+    }
+
+    for e in try!(self.entries(dir)) {
+      let used = if e.ino == 0 { 0 } else { dirent_len(e.name.len()) };
+      if e.rec_len < used + needed {
+        continue;
+      }
+      if e.ino == 0 {
+        return self.put_dirent(e.pos, ino, e.rec_len, name, kind);
+      }
+      // Split the entry, the new one gets its slack.
+      let mut shrunk = [0u8; 2];
+      LittleEndian::write_u16(&mut shrunk, used as u16);
+      try!(self.write_at(e.pos + 4, &shrunk));
+      return self.put_dirent(e.pos + used as u64, ino, e.rec_len - used, name, kind);
+    }
+
+    // No room anywhere, append a block.
+    let n = dir.size() / self.block_size;
+    let block = try!(self.bmap_alloc(dir, n));
+    let size = (n + 1) * self.block_size;
+    dir.set_size(size);
+    try!(self.write_inode(dir));
+    self.put_dirent(self.block_pos(block), ino, self.block_size as usize, name, kind)
+  }
+
+  fn remove_entry(&self, e: &Dirent) -> Result<(), Error> {
+    match e.prev {
+      Some(prev) => {
+        let mut len = [0u8; 2];
+        try!(self.read_at(prev + 4, &mut len));
+        let merged = LittleEndian::read_u16(&len) as usize + e.rec_len;
+        LittleEndian::write_u16(&mut len, merged as u16);
+        self.write_at(prev + 4, &len)
+      },
+      // The first entry in a block can't be merged, so it's just cleared.
+      None => self.write_u32(e.pos, 0),
+    }
+  }
+
+  fn new_inode(&mut self, parent: &Inode, kind: FileType, mode: u32) -> Result<Inode, Error> {
+    let goal = self.group_of(parent.ino);
+    let ino = try!(self.alloc_inode(goal, kind == FileType::Directory));
+    let mut inode = Inode { ino: ino, raw: vec![0u8; self.inode_size as usize] };
+    inode.set_mode(super::type_bits(kind) | (mode & 0o7777));
+    inode.set_links(1);
+    // Extra fields past the first 128 bytes, if any
+    if self.inode_size > GOOD_OLD_INODE_SIZE {
+      let extra = cmp::min(self.inode_size - GOOD_OLD_INODE_SIZE, 32) as u16;
+      inode.set_u16(GOOD_OLD_INODE_SIZE as usize, extra);
+    }
+    Ok(inode)
+  }
+
+  // Drop one link to `inode`, and free it if that was the last one.
+  fn unref(&mut self, inode: &mut Inode) -> Result<(), Error> {
+    let dir = inode.kind() == FileType::Directory;
+    let links = inode.links();
+    // A directory's "." counts, too.
+    let gone = links <= 1 || (dir && links <= 2);
+    if !gone {
+      inode.set_links(links - 1);
+      return self.write_inode(inode);
+    }
+
+    try!(self.free_blocks_from(inode, 0));
+    inode.set_size(0);
+    inode.set_links(0);
+    inode.set_u32(20, 1); // dtime, it only has to be non-zero
+    try!(self.write_inode(inode));
+    self.free_inode(inode.ino, dir)
+  }
+
+  fn is_empty(&self, dir: &Inode) -> Result<bool, Error> {
+    Ok(try!(self.entries(dir)).iter().all(|e| e.ino == 0 || e.name == "." || e.name == ".."))
+  }
+
+  // Whether directory `ino` is `ancestor` or somewhere below it.
+  fn is_within(&self, mut ino: u32, ancestor: u32) -> Result<bool, Error> {
+    loop {
+      if ino == ancestor {
+        return Ok(true);
+      }
+      if ino as Ino == ROOT {
+        return Ok(false);
+      }
+      let dir = try!(self.dir(ino as Ino));
+      ino = try!(self.find(&dir, "..")).ino;
+    }
+  }
+
+  // Remove the entry `e` from `dir`, and the directory it names (which has to
+  // be empty) with it.
+  fn remove_dir(&mut self, dir: &mut Inode, e: &Dirent) -> Result<(), Error> {
+    let mut victim = try!(self.dir(e.ino as Ino));
+    if !try!(self.is_empty(&victim)) {
+      return Err(Error::NotEmpty);
+    }
+    try!(self.remove_entry(e));
+    try!(self.unref(&mut victim));
+    // For the ".." that's gone now
+    let links = dir.links();
+    dir.set_links(links - 1);
+    self.write_inode(dir)
+  }
+}
+
+impl Inner {
+  // Read the free counts and group descriptors.
+  fn load_groups(&mut self) -> Result<(), Error> {
+    self.free_blocks = try!(self.read_u32(SUPERBLOCK_OFFSET + 12));
+    self.free_inodes = try!(self.read_u32(SUPERBLOCK_OFFSET + 16));
+    self.groups.clear();
+    let groups = (self.blocks_count - self.first_data_block + self.blocks_per_group - 1) / self.blocks_per_group;
+    let table = self.block_pos(self.first_data_block + 1);
+    for g in 0..groups as u64 {
+      let mut gd = [0u8; 32];
+      try!(self.read_at(table + g * GROUP_DESC_SIZE, &mut gd));
+      self.groups.push(Group {
+        block_bitmap: LittleEndian::read_u32(&gd[0..]),
+        inode_bitmap: LittleEndian::read_u32(&gd[4..]),
+        inode_table: LittleEndian::read_u32(&gd[8..]),
+        free_blocks: LittleEndian::read_u16(&gd[12..]),
+        free_inodes: LittleEndian::read_u16(&gd[14..]),
+        used_dirs: LittleEndian::read_u16(&gd[16..]),
+      });
+    }
+    Ok(())
+  }
+
+  // Replay the journal in inode `ino` if it needs it, and use it from now on.
+  // It's only needed for writing, but a read-only mount that can't replay it
+  // would see a half-done change, so that refuses.
+  fn load_journal(&mut self, ino: u32) -> Result<(), Error> {
+    let inode = try!(self.inode(ino as Ino));
+    let mut map = vec![];
+    for n in 0..inode.size() / self.block_size {
+      match try!(self.bmap(&inode, n)) {
+        0 => return Err(Error::InvalidDiskFormat),
+        b => map.push(b),
+      }
+    }
+    let mut journal = try!(Journal::load(self.dev.clone(), self.block_size, map));
+
+    if !self.writable {
+      if journal.needs_recovery() {
+        println!("ext2: the journal needs recovery, which a read-only mount can't do");
+        return Err(Error::Unsupported);
+      }
+      return Ok(());
+    }
+    if journal.needs_recovery() {
+      try!(journal.recover());
+      try!(self.load_groups());
+    }
+    self.journal = Some(journal);
+
+    // Like ext3, say that there may be a journal to replay for as long as
+    // we're mounted.
+    let incompat = try!(self.read_u32(SUPERBLOCK_OFFSET + 96));
+    if incompat & INCOMPAT_RECOVER == 0 {
+      try!(self.write_u32(SUPERBLOCK_OFFSET + 96, incompat | INCOMPAT_RECOVER));
+      try!(self.commit());
+    }
+    println!("ext2: using the journal in inode {}", ino);
+    Ok(())
+  }
+}
+
+impl Ext2 {
+  // Run `f` as one transaction, committing whatever it changed even if it
+  // fails halfway, since the in-memory counts already include those changes.
+  fn transaction<T, F: FnOnce(&mut Inner) -> Result<T, Error>>(&self, f: F) -> Result<T, Error> {
+    let mut fs = self.inner.lock();
+    let result = f(&mut fs);
+    try!(fs.commit());
+    result
+  }
+
+  pub fn new(dev: Arc<block::Cache>) -> Result<Self, Error> {
+    let mut inner = Inner {
+      dev: dev, block_size: 1024, blocks_count: 0, inodes_count: 0, first_data_block: 0, blocks_per_group: 0, inodes_per_group: 0,
+      inode_size: GOOD_OLD_INODE_SIZE, first_ino: GOOD_OLD_FIRST_INO, filetype: false, writable: false,
+      free_blocks: 0, free_inodes: 0, groups: vec![], journal: None, txn: RefCell::new(Transaction::default()),
+    };
+
+    let mut sb = [0u8; 1024];
+    try!(inner.read_at(SUPERBLOCK_OFFSET, &mut sb));
+    if LittleEndian::read_u16(&sb[56..]) != MAGIC {
+      return Err(Error::InvalidDiskFormat);
+    }
+
+    let log_block_size = LittleEndian::read_u32(&sb[24..]);
+    if log_block_size > 6 {
+      return Err(Error::InvalidDiskFormat);
+    }
+    inner.block_size = 1024 << log_block_size;
+    inner.inodes_count = LittleEndian::read_u32(&sb[0..]);
+    inner.blocks_count = LittleEndian::read_u32(&sb[4..]);
+    inner.first_data_block = LittleEndian::read_u32(&sb[20..]);
+    inner.blocks_per_group = LittleEndian::read_u32(&sb[32..]);
+    inner.inodes_per_group = LittleEndian::read_u32(&sb[40..]);
+    if inner.blocks_per_group == 0 || inner.inodes_per_group == 0 || inner.first_data_block >= inner.blocks_count {
+      return Err(Error::InvalidDiskFormat);
+    }
+
+    let mut compat = 0;
+    let mut incompat = 0;
+    let mut ro_compat = 0;
+    if LittleEndian::read_u32(&sb[76..]) >= 1 {
+      inner.first_ino = LittleEndian::read_u32(&sb[84..]);
+      inner.inode_size = LittleEndian::read_u16(&sb[88..]) as u64;
+      compat = LittleEndian::read_u32(&sb[92..]);
+      incompat = LittleEndian::read_u32(&sb[96..]);
+      ro_compat = LittleEndian::read_u32(&sb[100..]);
+    }
+    if inner.inode_size < GOOD_OLD_INODE_SIZE || inner.inode_size > inner.block_size || inner.first_ino < 2 {
+      return Err(Error::InvalidDiskFormat);
+    }
+    if incompat & !SUPPORTED_INCOMPAT != 0 {
+      println!("ext2: unsupported incompatible features {:x}", incompat & !SUPPORTED_INCOMPAT);
+      return Err(Error::Unsupported);
+    }
+    inner.filetype = incompat & INCOMPAT_FILETYPE != 0;
+    inner.writable = ro_compat & !SUPPORTED_RO_COMPAT == 0;
+    if !inner.writable {
+      println!("ext2: unsupported features {:x}, mounting read-only", ro_compat & !SUPPORTED_RO_COMPAT);
+    }
+
+    try!(inner.load_groups());
+    if inner.inodes_count as u64 > inner.groups.len() as u64 * inner.inodes_per_group as u64 {
+      return Err(Error::InvalidDiskFormat);
+    }
+    if compat & COMPAT_HAS_JOURNAL != 0 {
+      try!(inner.load_journal(LittleEndian::read_u32(&sb[224..])));
+    }
+
+    println!("ext2: {} blocks of {} bytes in {} groups, {} free", inner.blocks_count, inner.block_size, inner.groups.len(), inner.free_blocks);
+    try!(inner.dir(ROOT));
+    Ok(Ext2 { inner: GlobalMutex::new(inner) })
+  }
+}
+
+impl Filesystem for Ext2 {
+  fn root(&self) -> Ino {
+    ROOT
+  }
+
+  fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, Error> {
+    let fs = self.inner.lock();
+    let dir = try!(fs.dir(dir));
+    Ok(try!(fs.find(&dir, name)).ino as Ino)
+  }
+
+  fn stat(&self, ino: Ino) -> Result<Stat, Error> {
+    let i = try!(self.inner.lock().inode(ino));
+    Ok(Stat {
+      ino: ino,
+      kind: i.kind(),
+      mode: i.mode() & 0o7777,
+      nlink: i.links() as u32,
+      uid: i.uid(),
+      gid: i.gid(),
+      size: i.size(),
+      rdev: i.rdev(),
+      mtime: i.mtime(),
+    })
+  }
+
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    let fs = self.inner.lock();
+    let inode = try!(fs.inode(ino));
+    if inode.kind() == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    fs.read_data(&inode, offset, buf)
+  }
+
+  fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, Error> {
+    let fs = self.inner.lock();
+    let dir = try!(fs.dir(dir));
+    let mut v = vec![];
+    for e in try!(fs.entries(&dir)) {
+      if e.ino == 0 || e.name == "." || e.name == ".." {
+        continue;
+      }
+      let kind = match dirent_kind(e.file_type) {
+        Some(k) if fs.filetype => k,
+        _ => try!(fs.inode(e.ino as Ino)).kind(),
+      };
+      v.push(DirEntry { name: e.name, ino: e.ino as Ino, kind: kind });
+    }
+    Ok(v)
+  }
+
+  fn readlink(&self, ino: Ino) -> Result<String, Error> {
+    let fs = self.inner.lock();
+    let inode = try!(fs.inode(ino));
+    if inode.kind() != FileType::Symlink {
+      return Err(Error::InvalidArgument);
+    }
+    let target = if inode.is_fast_symlink() {
+      inode.raw[40..40 + inode.size() as usize].to_vec()
+    } else {
+      let mut buf = vec![0u8; inode.size() as usize];
+      try!(fs.read_data(&inode, 0, &mut buf));
+      buf
+    };
+    String::from_utf8(target).map_err(|_| Error::InvalidDiskFormat)
+  }
+
+  fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+    // A transaction per chunk, so that big writes fit in the journal
+    let chunk = (WRITE_CHUNK_BLOCKS * self.inner.lock().block_size) as usize;
+    let mut done = 0;
+    loop {
+      let at = offset + done as u64;
+      let data = &buf[done..cmp::min(done + chunk, buf.len())];
+      let result = self.transaction(|fs| {
+        let mut inode = try!(fs.inode(ino));
+        if inode.kind() == FileType::Directory {
+          return Err(Error::IsADirectory);
+        }
+        // Write the inode back even on errors, it may have new blocks by then.
+        let result = fs.write_data(&mut inode, at, data);
+        try!(fs.write_inode(&inode));
+        result
+      });
+      match result {
+        Ok(n) => done += n,
+        Err(e) if done == 0 => return Err(e),
+        Err(_) => break,
+      }
+      if done == buf.len() {
+        break;
+      }
+    }
+    Ok(done)
+  }
+
+  fn truncate(&self, ino: Ino, size: u64) -> Result<(), Error> {
+    self.transaction(|fs| {
+      let mut inode = try!(fs.inode(ino));
+      if inode.kind() != FileType::Regular {
+        return Err(Error::InvalidArgument);
+      }
+      let result = fs.set_size(&mut inode, size);
+      try!(fs.write_inode(&inode));
+      result
+    })
+  }
+
+  fn create(&self, dir: Ino, name: &str, kind: FileType, mode: u32, rdev: u64) -> Result<Ino, Error> {
+    if kind == FileType::Symlink {
+      return Err(Error::InvalidArgument);
+    }
+    self.transaction(|fs| {
+      let mut parent = try!(fs.dir(dir));
+      match fs.find(&parent, name) {
+        Ok(_) => return Err(Error::Exists),
+        Err(Error::NotFound) => {},
+        Err(e) => return Err(e),
+      }
+      if name.len() > MAX_NAME {
+        return Err(Error::NameTooLong);
+      }
+
+      let mut inode = try!(fs.new_inode(&parent, kind, mode));
+      match kind {
+        FileType::Directory => {
+          let block = try!(fs.bmap_alloc(&mut inode, 0));
+          let size = fs.block_size;
+          inode.set_size(size);
+          inode.set_links(2);
+          let pos = fs.block_pos(block);
+          try!(fs.put_dirent(pos, inode.ino, dirent_len(1), ".", FileType::Directory));
+          try!(fs.put_dirent(pos + dirent_len(1) as u64, parent.ino, size as usize - dirent_len(1), "..", FileType::Directory));
+          let links = parent.links();
+          parent.set_links(links + 1);
+          try!(fs.write_inode(&parent));
+        },
+        FileType::CharDevice | FileType::BlockDevice => inode.set_block(0, rdev as u32),
+        _ => {},
+      }
+      try!(fs.write_inode(&inode));
+      try!(fs.add_entry(&mut parent, name, inode.ino, kind));
+      Ok(inode.ino as Ino)
+    })
+  }
+
+  fn symlink(&self, dir: Ino, name: &str, target: &str) -> Result<Ino, Error> {
+    self.transaction(|fs| {
+      let mut parent = try!(fs.dir(dir));
+      if fs.find(&parent, name).is_ok() {
+        return Err(Error::Exists);
+      }
+      let mut inode = try!(fs.new_inode(&parent, FileType::Symlink, 0o777));
+      if target.len() < FAST_SYMLINK_MAX {
+        inode.raw[40..40 + target.len()].clone_from_slice(target.as_bytes());
+        inode.set_size(target.len() as u64);
+      } else {
+        try!(fs.write_data(&mut inode, 0, target.as_bytes()));
+      }
+      try!(fs.write_inode(&inode));
+      try!(fs.add_entry(&mut parent, name, inode.ino, FileType::Symlink));
+      Ok(inode.ino as Ino)
+    })
+  }
+
+  fn link(&self, dir: Ino, name: &str, ino: Ino) -> Result<(), Error> {
+    self.transaction(|fs| {
+      let mut parent = try!(fs.dir(dir));
+      let mut inode = try!(fs.inode(ino));
+      if inode.kind() == FileType::Directory {
+        return Err(Error::IsADirectory);
+      }
+      if fs.find(&parent, name).is_ok() {
+        return Err(Error::Exists);
+      }
+      try!(fs.add_entry(&mut parent, name, inode.ino, inode.kind()));
+      let links = inode.links();
+      inode.set_links(links + 1);
+      fs.write_inode(&inode)
+    })
+  }
+
+  fn unlink(&self, dir: Ino, name: &str) -> Result<(), Error> {
+    self.transaction(|fs| {
+      let parent = try!(fs.dir(dir));
+      let e = try!(fs.find(&parent, name));
+      let mut inode = try!(fs.inode(e.ino as Ino));
+      if inode.kind() == FileType::Directory {
+        return Err(Error::IsADirectory);
+      }
+      try!(fs.remove_entry(&e));
+      fs.unref(&mut inode)
+    })
+  }
+
+  fn rmdir(&self, dir: Ino, name: &str) -> Result<(), Error> {
+    self.transaction(|fs| {
+      let mut parent = try!(fs.dir(dir));
+      let e = try!(fs.find(&parent, name));
+      fs.remove_dir(&mut parent, &e)
+    })
+  }
+
+  fn rename(&self, from_dir: Ino, from: &str, to_dir: Ino, to: &str) -> Result<(), Error> {
+    self.transaction(|fs| {
+      let mut src_dir = try!(fs.dir(from_dir));
+      let e = try!(fs.find(&src_dir, from));
+      let inode = try!(fs.inode(e.ino as Ino));
+      let is_dir = inode.kind() == FileType::Directory;
+      let mut dst_dir = try!(fs.dir(to_dir));
+
+      if is_dir && try!(fs.is_within(dst_dir.ino, inode.ino)) {
+        return Err(Error::InvalidArgument);
+      }
+
+      match fs.find(&dst_dir, to) {
+        Ok(ref existing) if existing.ino == inode.ino => return Ok(()),
+        Ok(existing) => {
+          let mut target = try!(fs.inode(existing.ino as Ino));
+          match (is_dir, target.kind() == FileType::Directory) {
+            (true, false) => return Err(Error::NotADirectory),
+            (false, true) => return Err(Error::IsADirectory),
+            (true, true) => try!(fs.remove_dir(&mut dst_dir, &existing)),
+            (false, false) => {
+              try!(fs.remove_entry(&existing));
+              try!(fs.unref(&mut target));
+            },
+          }
+        },
+        Err(Error::NotFound) => {},
+        Err(e) => return Err(e),
+      }
+
+      // Re-read both, the removals above may have changed them.
+      dst_dir = try!(fs.dir(to_dir));
+      try!(fs.add_entry(&mut dst_dir, to, inode.ino, inode.kind()));
+      src_dir = try!(fs.dir(from_dir));
+      // The entry may have moved if both are in the same directory.
+      let e = try!(fs.find(&src_dir, from));
+      try!(fs.remove_entry(&e));
+
+      if is_dir && from_dir != to_dir {
+        let dotdot = try!(fs.find(&inode, ".."));
+        try!(fs.write_u32(dotdot.pos, dst_dir.ino));
+        let links = src_dir.links();
+        src_dir.set_links(links - 1);
+        try!(fs.write_inode(&src_dir));
+        dst_dir = try!(fs.dir(to_dir));
+        let links = dst_dir.links();
+        dst_dir.set_links(links + 1);
+        try!(fs.write_inode(&dst_dir));
+      }
+      Ok(())
+    })
+  }
+
+  fn set_mode(&self, ino: Ino, mode: u32) -> Result<(), Error> {
+    self.transaction(|fs| {
+      let mut inode = try!(fs.inode(ino));
+      let m = inode.mode();
+      inode.set_mode((m & super::S_IFMT) | (mode & 0o7777));
+      fs.write_inode(&inode)
+    })
+  }
+
+  fn sync(&self) -> Result<(), Error> {
+    let fs = self.inner.lock();
+    fs.dev.flush().map_err(io_error)
+  }
+}
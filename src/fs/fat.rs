@@ -0,0 +1,920 @@
+use prelude::*;
+use core::cmp;
+use collections::btree_map::BTreeMap;
+
+use block;
+use byteorder::{ByteOrder,LittleEndian};
+use sync::global_mutex::GlobalMutex;
+use super::{io_error, DirEntry, Error, FileType, Filesystem, Ino, Stat, SECTOR_SIZE};
+
+// FAT32 as in Microsoft's "FAT: General Overview of On-Disk Format", with
+// long file names (VFAT).
+//
+// FAT has no inodes, so we hand out inode numbers as we come across files
+// and remember where each one's short directory entry is. Positions and
+// first clusters both change (on rename, and when an empty file gets its
+// first cluster), the numbers don't.
+
+const ROOT: Ino = 1;
+
+const DIRENT_SIZE: u64 = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+const LAST_LONG_ENTRY: u8 = 0x40;
+const CHARS_PER_LONG_ENTRY: usize = 13;
+// Where the 13 UCS-2 characters of a long name entry are
+const LONG_NAME_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+const DELETED: u8 = 0xe5;
+
+// NTRes bits: the base name or extension of a short name is lowercase
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXT: u8 = 0x10;
+
+const CLUSTER_MASK: u32 = 0x0fffffff;
+const END_OF_CHAIN: u32 = 0x0ffffff8;
+const BAD_CLUSTER: u32 = 0x0ffffff7;
+
+const MAX_NAME: usize = 255;
+
+// The checksum of a short name that its long name entries carry
+fn short_checksum(short: &[u8]) -> u8 {
+  short.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+fn to_upper(c: u8) -> u8 {
+  if c >= b'a' && c <= b'z' { c - b'a' + b'A' } else { c }
+}
+
+fn to_lower(c: u8) -> u8 {
+  if c >= b'A' && c <= b'Z' { c - b'A' + b'a' } else { c }
+}
+
+// "README  TXT" -> "README.TXT", or lowercase if the entry says so
+fn short_to_string(short: &[u8], ntres: u8) -> String {
+  let mut base: Vec<u8> = short[..8].iter().cloned().collect();
+  let mut ext: Vec<u8> = short[8..11].iter().cloned().collect();
+  if base[0] == 0x05 {
+    base[0] = DELETED;
+  }
+  while base.last() == Some(&b' ') { base.pop(); }
+  while ext.last() == Some(&b' ') { ext.pop(); }
+  if ntres & LOWERCASE_BASE != 0 {
+    base = base.into_iter().map(to_lower).collect();
+  }
+  if ntres & LOWERCASE_EXT != 0 {
+    ext = ext.into_iter().map(to_lower).collect();
+  }
+  if ext.len() > 0 {
+    base.push(b'.');
+    base.extend(ext);
+  }
+  String::from_utf8_lossy(&base).into_owned()
+}
+
+// Upper case letters, digits and a few symbols
+fn valid_short_char(c: u8) -> bool {
+  (c >= b'A' && c <= b'Z') || (c >= b'0' && c <= b'9') || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+// If `name` can be a short name as it is, the short name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+  let (base, ext) = match name.rfind('.') {
+    Some(i) => (&name[..i], &name[i+1..]),
+    None => (name, ""),
+  };
+  if base.len() == 0 || base.len() > 8 || ext.len() > 3 || !base.bytes().chain(ext.bytes()).all(valid_short_char) {
+    return None;
+  }
+  let mut short = [b' '; 11];
+  short[..base.len()].clone_from_slice(base.as_bytes());
+  short[8..8+ext.len()].clone_from_slice(ext.as_bytes());
+  Some(short)
+}
+
+// The "LONGNA~1.TXT" style short name for a long name, with tail `n`.
+fn numbered_short_name(name: &str, n: usize) -> [u8; 11] {
+  let (base, ext) = match name.rfind('.') {
+    Some(i) if i > 0 => (&name[..i], &name[i+1..]),
+    _ => (name, ""),
+  };
+  // Anything that isn't ASCII is one '_', however many bytes it takes.
+  let clean = |s: &str, max: usize| -> Vec<u8> {
+    s.chars().filter(|&c| c != ' ' && c != '.')
+      .map(|c| if (c as u32) < 0x80 && valid_short_char(to_upper(c as u8)) { to_upper(c as u8) } else { b'_' })
+      .take(max).collect()
+  };
+
+  let mut tail = vec![];
+  let mut n = n;
+  while n > 0 {
+    tail.insert(0, b'0' + (n % 10) as u8);
+    n /= 10;
+  }
+  tail.insert(0, b'~');
+  let base = clean(base, 8 - tail.len());
+  let ext = clean(ext, 3);
+
+  let mut short = [b' '; 11];
+  short[..base.len()].clone_from_slice(&base);
+  short[base.len()..base.len()+tail.len()].clone_from_slice(&tail);
+  short[8..8+ext.len()].clone_from_slice(&ext);
+  short
+}
+
+// DOS date and time to seconds since the epoch
+fn dos_time(date: u16, time: u16) -> u64 {
+  if date == 0 {
+    return 0;
+  }
+  let year = 1980 + (date >> 9) as i64;
+  let month = ((date >> 5) & 0xf) as i64;
+  let day = (date & 0x1f) as i64;
+  // Days since 1970-01-01, from Howard Hinnant's days_from_civil
+  let y = if month <= 2 { year - 1 } else { year };
+  let era = y / 400;
+  let yoe = y - era * 400;
+  let mp = (month + 9) % 12;
+  let doy = (153 * mp + 2) / 5 + day - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  let days = era * 146097 + doe - 719468;
+
+  let secs = ((time >> 11) as i64) * 3600 + (((time >> 5) & 0x3f) as i64) * 60 + ((time & 0x1f) as i64) * 2;
+  (days * 86400 + secs) as u64
+}
+
+// A file or directory as its directory lists it
+#[derive(Debug,Clone)]
+struct Entry {
+  // Of the short entry
+  pos: u64,
+  // Of the first long name entry, if there are any
+  first_pos: u64,
+  name: String,
+  short: [u8; 11],
+  attr: u8,
+  cluster: u32,
+  size: u32,
+  date: u16,
+  time: u16,
+}
+
+impl Entry {
+  fn kind(&self) -> FileType {
+    if self.attr & ATTR_DIRECTORY != 0 { FileType::Directory } else { FileType::Regular }
+  }
+}
+
+#[derive(Debug)]
+struct Inner {
+  dev: Arc<block::Cache>,
+  // Everything in bytes
+  cluster_size: u64,
+  fat_start: u64,
+  fat_size: u64,
+  fats: u64,
+  data_start: u64,
+  clusters: u32,
+  root_cluster: u32,
+  fsinfo: u64,
+  // Where to start looking for free clusters
+  next_free: u32,
+  // The free count in FSInfo is wrong as soon as we allocate anything.
+  fsinfo_valid: bool,
+  // Inode number to short entry position, and back. Numbers are only
+  // forgotten when their file is removed.
+  inos: BTreeMap<Ino, u64>,
+  positions: BTreeMap<u64, Ino>,
+  next_ino: Ino,
+}
+
+// A FAT32 filesystem on a block device. Like ext2, it's all behind one lock.
+#[derive(Debug)]
+pub struct Fat {
+  inner: GlobalMutex<Inner>,
+}
+
+impl Inner {
+  fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), Error> {
+    super::read_bytes(&*self.dev, pos, buf).map_err(io_error)
+  }
+
+  fn write_at(&self, pos: u64, data: &[u8]) -> Result<(), Error> {
+    super::write_bytes(&*self.dev, pos, data).map_err(io_error)
+  }
+
+  fn cluster_pos(&self, cluster: u32) -> u64 {
+    self.data_start + (cluster as u64 - 2) * self.cluster_size
+  }
+
+  fn valid_cluster(&self, cluster: u32) -> bool {
+    cluster >= 2 && cluster < self.clusters + 2
+  }
+
+  fn fat_entry(&self, cluster: u32) -> Result<u32, Error> {
+    let mut b = [0u8; 4];
+    try!(self.read_at(self.fat_start + cluster as u64 * 4, &mut b));
+    Ok(LittleEndian::read_u32(&b) & CLUSTER_MASK)
+  }
+
+  // Every copy of the FAT gets the change. The top 4 bits are reserved and
+  // have to be kept.
+  fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error> {
+    if self.fsinfo_valid {
+      // "Unknown", so that nobody trusts the stale counts
+      try!(self.write_at(self.fsinfo + 488, &[0xff; 4]));
+      self.fsinfo_valid = false;
+    }
+    let old = {
+      let mut b = [0u8; 4];
+      try!(self.read_at(self.fat_start + cluster as u64 * 4, &mut b));
+      LittleEndian::read_u32(&b)
+    };
+    let mut b = [0u8; 4];
+    LittleEndian::write_u32(&mut b, (old & !CLUSTER_MASK) | (value & CLUSTER_MASK));
+    for i in 0..self.fats {
+      try!(self.write_at(self.fat_start + i * self.fat_size + cluster as u64 * 4, &b));
+    }
+    Ok(())
+  }
+
+  // The clusters of a chain, in order.
+  fn chain(&self, first: u32) -> Result<Vec<u32>, Error> {
+    let mut v = vec![];
+    let mut c = first;
+    while c != 0 && c < END_OF_CHAIN {
+      if !self.valid_cluster(c) || c == BAD_CLUSTER || v.len() > self.clusters as usize {
+        println!("fat: broken cluster chain starting at {}", first);
+        return Err(Error::InvalidDiskFormat);
+      }
+      v.push(c);
+      c = try!(self.fat_entry(c));
+    }
+    Ok(v)
+  }
+
+  // A zeroed cluster, appended to the chain ending in `last` (if any).
+  fn alloc_cluster(&mut self, last: Option<u32>) -> Result<u32, Error> {
+    for i in 0..self.clusters {
+      let c = (self.next_free - 2 + i) % self.clusters + 2;
+      if try!(self.fat_entry(c)) != 0 {
+        continue;
+      }
+      try!(self.set_fat_entry(c, CLUSTER_MASK));
+      if let Some(prev) = last {
+        try!(self.set_fat_entry(prev, c));
+      }
+      self.next_free = c;
+      try!(self.write_at(self.cluster_pos(c), &vec![0u8; self.cluster_size as usize]));
+      return Ok(c);
+    }
+    Err(Error::NoSpace)
+  }
+
+  fn free_chain(&mut self, first: u32) -> Result<(), Error> {
+    for c in try!(self.chain(first)) {
+      try!(self.set_fat_entry(c, 0));
+    }
+    Ok(())
+  }
+
+  fn root_entry(&self) -> Entry {
+    Entry { pos: ROOT, first_pos: ROOT, name: String::from("/"), short: [b' '; 11], attr: ATTR_DIRECTORY, cluster: self.root_cluster, size: 0, date: 0, time: 0 }
+  }
+
+  fn parse_short(&self, pos: u64, raw: &[u8]) -> Entry {
+    let hi = LittleEndian::read_u16(&raw[20..]) as u32;
+    let lo = LittleEndian::read_u16(&raw[26..]) as u32;
+    Entry {
+      pos: pos,
+      first_pos: pos,
+      name: short_to_string(&raw[..11], raw[12]),
+      short: {
+        let mut short = [0u8; 11];
+        short.clone_from_slice(&raw[..11]);
+        short
+      },
+      attr: raw[11],
+      cluster: (hi << 16) | lo,
+      size: LittleEndian::read_u32(&raw[28..]),
+      date: LittleEndian::read_u16(&raw[24..]),
+      time: LittleEndian::read_u16(&raw[22..]),
+    }
+  }
+
+  // The inode number of the short entry at `pos`, a new one if it hasn't
+  // been given one yet.
+  fn ino(&mut self, pos: u64) -> Ino {
+    if let Some(&ino) = self.positions.get(&pos) {
+      return ino;
+    }
+    let ino = self.next_ino;
+    self.next_ino += 1;
+    self.inos.insert(ino, pos);
+    self.positions.insert(pos, ino);
+    ino
+  }
+
+  // The short entry at `from` was rewritten at `to`.
+  fn moved(&mut self, from: u64, to: u64) {
+    if let Some(ino) = self.positions.remove(&from) {
+      self.inos.insert(ino, to);
+      self.positions.insert(to, ino);
+    }
+  }
+
+  fn forget(&mut self, pos: u64) {
+    if let Some(ino) = self.positions.remove(&pos) {
+      self.inos.remove(&ino);
+    }
+  }
+
+  // The entry that inode number `ino` stands for
+  fn entry(&self, ino: Ino) -> Result<Entry, Error> {
+    if ino == ROOT {
+      return Ok(self.root_entry());
+    }
+    let pos = try!(self.inos.get(&ino).cloned().ok_or(Error::NotFound));
+    let mut raw = [0u8; 32];
+    try!(self.read_at(pos, &mut raw));
+    if raw[0] == 0 || raw[0] == DELETED || raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+      return Err(Error::NotFound);
+    }
+    Ok(self.parse_short(pos, &raw))
+  }
+
+  fn dir(&self, ino: Ino) -> Result<Entry, Error> {
+    let e = try!(self.entry(ino));
+    if e.kind() != FileType::Directory {
+      return Err(Error::NotADirectory);
+    }
+    Ok(e)
+  }
+
+  // The position of every 32-byte slot in a directory
+  fn slots(&self, dir: &Entry) -> Result<Vec<u64>, Error> {
+    let per_cluster = self.cluster_size / DIRENT_SIZE;
+    let mut v = vec![];
+    for c in try!(self.chain(dir.cluster)) {
+      let base = self.cluster_pos(c);
+      v.extend((0..per_cluster).map(|i| base + i * DIRENT_SIZE));
+    }
+    Ok(v)
+  }
+
+  // Everything in a directory, including "." and "..", but not the volume label.
+  fn entries(&self, dir: &Entry) -> Result<Vec<Entry>, Error> {
+    let mut v = vec![];
+    // Long name pieces collected so far: (checksum, first position, chars)
+    let mut long: Option<(u8, u64, Vec<u16>)> = None;
+
+    for pos in try!(self.slots(dir)) {
+      let mut raw = [0u8; 32];
+      try!(self.read_at(pos, &mut raw));
+      if raw[0] == 0 {
+        break;
+      }
+      if raw[0] == DELETED {
+        long = None;
+        continue;
+      }
+
+      if raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+        let ord = (raw[0] & !LAST_LONG_ENTRY) as usize;
+        let chars: Vec<u16> = LONG_NAME_OFFSETS.iter().map(|&o| LittleEndian::read_u16(&raw[o..])).collect();
+        if ord == 0 || ord * CHARS_PER_LONG_ENTRY > MAX_NAME + CHARS_PER_LONG_ENTRY {
+          long = None;
+        } else if raw[0] & LAST_LONG_ENTRY != 0 {
+          // The pieces come last one first.
+          let mut all = vec![0xffffu16; ord * CHARS_PER_LONG_ENTRY];
+          all[(ord - 1) * CHARS_PER_LONG_ENTRY..].clone_from_slice(&chars);
+          long = Some((raw[13], pos, all));
+        } else {
+          let fits = match long {
+            Some((sum, _, ref all)) => sum == raw[13] && ord * CHARS_PER_LONG_ENTRY <= all.len(),
+            None => false,
+          };
+          if fits {
+            if let Some((_, _, ref mut all)) = long {
+              all[(ord - 1) * CHARS_PER_LONG_ENTRY..ord * CHARS_PER_LONG_ENTRY].clone_from_slice(&chars);
+            }
+          } else {
+            long = None;
+          }
+        }
+        continue;
+      }
+
+      let mut e = self.parse_short(pos, &raw);
+      if let Some((sum, first, all)) = long.take() {
+        if sum == short_checksum(&raw[..11]) {
+          let units: Vec<u16> = all.into_iter().take_while(|&c| c != 0 && c != 0xffff).collect();
+          e.name = String::from_utf16_lossy(&units);
+          e.first_pos = first;
+        }
+      }
+      if e.attr & ATTR_VOLUME_ID != 0 {
+        continue;
+      }
+      v.push(e);
+    }
+    Ok(v)
+  }
+
+  fn find(&self, dir: &Entry, name: &str) -> Result<Entry, Error> {
+    // FAT names are case-insensitive, and the short name works, too.
+    let wanted = name.to_lowercase();
+    try!(self.entries(dir)).into_iter()
+      .find(|e| e.name.to_lowercase() == wanted || short_to_string(&e.short, LOWERCASE_BASE | LOWERCASE_EXT) == wanted)
+      .ok_or(Error::NotFound)
+  }
+
+  // `count` free slots in a row, growing the directory if there aren't any.
+  fn free_slots(&mut self, dir: &Entry, count: usize) -> Result<Vec<u64>, Error> {
+    let slots = try!(self.slots(dir));
+    let mut run = vec![];
+    let mut end_seen = false;
+    for &pos in slots.iter() {
+      let mut first = [0u8; 1];
+      if !end_seen {
+        try!(self.read_at(pos, &mut first));
+      }
+      // Everything after the end marker is free, too.
+      if end_seen || first[0] == 0 || first[0] == DELETED {
+        end_seen = end_seen || first[0] == 0;
+        run.push(pos);
+        if run.len() == count {
+          return Ok(run);
+        }
+      } else {
+        run.clear();
+      }
+    }
+
+    let mut last = try!(self.chain(dir.cluster)).last().cloned();
+    let per_cluster = (self.cluster_size / DIRENT_SIZE) as usize;
+    while run.len() < count {
+      let c = try!(self.alloc_cluster(last));
+      last = Some(c);
+      let base = self.cluster_pos(c);
+      for i in 0..per_cluster {
+        run.push(base + i as u64 * DIRENT_SIZE);
+        if run.len() == count {
+          break;
+        }
+      }
+    }
+    Ok(run)
+  }
+
+  // Write the entries for a new name in `dir`; returns the short entry's position.
+  fn add_entry(&mut self, dir: &Entry, name: &str, attr: u8, cluster: u32, size: u32) -> Result<u64, Error> {
+    if name.len() > MAX_NAME {
+      return Err(Error::NameTooLong);
+    }
+    if name.len() == 0 || name.contains(|c| "\"*/:<>?\\|".contains(c) || (c as u32) < 0x20) {
+      return Err(Error::InvalidArgument);
+    }
+
+    let taken: Vec<[u8; 11]> = try!(self.entries(dir)).into_iter().map(|e| e.short).collect();
+    let (short, long) = match exact_short_name(name) {
+      Some(s) if !taken.contains(&s) => (s, vec![]),
+      _ => {
+        let mut n = 1;
+        let mut short = numbered_short_name(name, n);
+        while taken.contains(&short) {
+          n += 1;
+          if n > 999999 {
+            return Err(Error::NoSpace);
+          }
+          short = numbered_short_name(name, n);
+        }
+        (short, name.utf16_units().collect::<Vec<u16>>())
+      },
+    };
+
+    let pieces = (long.len() + CHARS_PER_LONG_ENTRY - 1) / CHARS_PER_LONG_ENTRY;
+    let slots = try!(self.free_slots(dir, pieces + 1));
+    let sum = short_checksum(&short);
+
+    for (i, &pos) in slots[..pieces].iter().enumerate() {
+      let ord = pieces - i;
+      let mut raw = [0u8; 32];
+      raw[0] = ord as u8 | if i == 0 { LAST_LONG_ENTRY } else { 0 };
+      raw[11] = ATTR_LONG_NAME;
+      raw[13] = sum;
+      for (j, &o) in LONG_NAME_OFFSETS.iter().enumerate() {
+        let k = (ord - 1) * CHARS_PER_LONG_ENTRY + j;
+        // NUL-terminated, then padded with 0xffff
+        let c = if k < long.len() { long[k] } else if k == long.len() { 0 } else { 0xffff };
+        LittleEndian::write_u16(&mut raw[o..], c);
+      }
+      try!(self.write_at(pos, &raw));
+    }
+
+    let pos = slots[pieces];
+    let mut raw = [0u8; 32];
+    raw[..11].clone_from_slice(&short);
+    raw[11] = attr;
+    LittleEndian::write_u16(&mut raw[20..], (cluster >> 16) as u16);
+    LittleEndian::write_u16(&mut raw[26..], cluster as u16);
+    LittleEndian::write_u32(&mut raw[28..], size);
+    try!(self.write_at(pos, &raw));
+    Ok(pos)
+  }
+
+  // Mark all of an entry's slots in `dir` deleted. Its clusters are left alone.
+  fn remove_entry(&self, dir: &Entry, e: &Entry) -> Result<(), Error> {
+    let slots = try!(self.slots(dir));
+    let first = try!(slots.iter().position(|&p| p == e.first_pos).ok_or(Error::InvalidDiskFormat));
+    for &pos in slots[first..].iter() {
+      try!(self.write_at(pos, &[DELETED]));
+      if pos == e.pos {
+        break;
+      }
+    }
+    Ok(())
+  }
+
+  fn set_cluster_and_size(&self, e: &mut Entry, cluster: u32, size: u32) -> Result<(), Error> {
+    let mut raw = [0u8; 32];
+    try!(self.read_at(e.pos, &mut raw));
+    LittleEndian::write_u16(&mut raw[20..], (cluster >> 16) as u16);
+    LittleEndian::write_u16(&mut raw[26..], cluster as u16);
+    LittleEndian::write_u32(&mut raw[28..], size);
+    raw[11] |= if e.kind() == FileType::Regular { ATTR_ARCHIVE } else { 0 };
+    try!(self.write_at(e.pos, &raw));
+    e.cluster = cluster;
+    e.size = size;
+    Ok(())
+  }
+
+  fn read_data(&self, e: &Entry, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    let size = e.size as u64;
+    if offset >= size {
+      return Ok(0);
+    }
+    let len = cmp::min(buf.len() as u64, size - offset) as usize;
+    let chain = try!(self.chain(e.cluster));
+    let mut done = 0;
+    while done < len {
+      let pos = offset + done as u64;
+      let index = (pos / self.cluster_size) as usize;
+      let in_cluster = pos % self.cluster_size;
+      let n = cmp::min((self.cluster_size - in_cluster) as usize, len - done);
+      let c = try!(chain.get(index).cloned().ok_or(Error::InvalidDiskFormat));
+      try!(self.read_at(self.cluster_pos(c) + in_cluster, &mut buf[done..done+n]));
+      done += n;
+    }
+    Ok(len)
+  }
+
+  // Make the chain long enough for `size` bytes, or cut it down to that.
+  fn resize(&mut self, e: &mut Entry, size: u64) -> Result<(), Error> {
+    if size > u32::max_value() as u64 {
+      return Err(Error::NoSpace);
+    }
+    let needed = ((size + self.cluster_size - 1) / self.cluster_size) as usize;
+    let mut chain = try!(self.chain(e.cluster));
+    let mut first = e.cluster;
+
+    while chain.len() < needed {
+      let c = try!(self.alloc_cluster(chain.last().cloned()));
+      if chain.len() == 0 {
+        first = c;
+      }
+      chain.push(c);
+    }
+    if chain.len() > needed {
+      if needed == 0 {
+        first = 0;
+      } else {
+        try!(self.set_fat_entry(chain[needed - 1], CLUSTER_MASK));
+      }
+      for &c in chain[needed..].iter() {
+        try!(self.set_fat_entry(c, 0));
+      }
+    }
+
+    // The old tail may have junk from before a shrink.
+    let old = e.size as u64;
+    if size > old && old % self.cluster_size != 0 {
+      let c = chain[(old / self.cluster_size) as usize];
+      let in_cluster = old % self.cluster_size;
+      let n = cmp::min(self.cluster_size - in_cluster, size - old);
+      try!(self.write_at(self.cluster_pos(c) + in_cluster, &vec![0u8; n as usize]));
+    }
+    self.set_cluster_and_size(e, first, size as u32)
+  }
+
+  fn write_data(&mut self, e: &mut Entry, offset: u64, data: &[u8]) -> Result<usize, Error> {
+    let end = offset + data.len() as u64;
+    if end > e.size as u64 {
+      try!(self.resize(e, end));
+    }
+    let chain = try!(self.chain(e.cluster));
+    let mut done = 0;
+    while done < data.len() {
+      let pos = offset + done as u64;
+      let in_cluster = pos % self.cluster_size;
+      let n = cmp::min((self.cluster_size - in_cluster) as usize, data.len() - done);
+      let c = chain[(pos / self.cluster_size) as usize];
+      try!(self.write_at(self.cluster_pos(c) + in_cluster, &data[done..done+n]));
+      done += n;
+    }
+    Ok(done)
+  }
+
+  fn is_empty(&self, dir: &Entry) -> Result<bool, Error> {
+    Ok(try!(self.entries(dir)).iter().all(|e| e.name == "." || e.name == ".."))
+  }
+
+  // Whether directory `dir` is `ancestor` or somewhere below it.
+  fn is_within(&self, dir: &Entry, ancestor: &Entry) -> Result<bool, Error> {
+    let mut cluster = dir.cluster;
+    let mut hops = 0;
+    loop {
+      if cluster == ancestor.cluster {
+        return Ok(true);
+      }
+      // ".." says 0 for the root.
+      if cluster == self.root_cluster || cluster == 0 || hops > self.clusters {
+        return Ok(false);
+      }
+      let here = Entry { cluster: cluster, ..self.root_entry() };
+      cluster = try!(self.find(&here, "..")).cluster;
+      hops += 1;
+    }
+  }
+
+  // What ".." in a subdirectory of `dir` says
+  fn parent_cluster(&self, dir: &Entry) -> u32 {
+    if dir.cluster == self.root_cluster { 0 } else { dir.cluster }
+  }
+
+  fn remove(&mut self, dir: &Entry, e: &Entry) -> Result<(), Error> {
+    if e.kind() == FileType::Directory && !try!(self.is_empty(e)) {
+      return Err(Error::NotEmpty);
+    }
+    try!(self.remove_entry(dir, e));
+    self.forget(e.pos);
+    if e.cluster != 0 {
+      try!(self.free_chain(e.cluster));
+    }
+    Ok(())
+  }
+}
+
+impl Fat {
+  pub fn new(dev: Arc<block::Cache>) -> Result<Self, Error> {
+    let mut bpb = [0u8; 512];
+    try!(super::read_bytes(&*dev, 0, &mut bpb).map_err(io_error));
+    if bpb[510] != 0x55 || bpb[511] != 0xaa {
+      return Err(Error::InvalidDiskFormat);
+    }
+
+    let bytes_per_sector = LittleEndian::read_u16(&bpb[11..]) as u64;
+    let sectors_per_cluster = bpb[13] as u64;
+    let reserved = LittleEndian::read_u16(&bpb[14..]) as u64;
+    let fats = bpb[16] as u64;
+    let root_entries = LittleEndian::read_u16(&bpb[17..]);
+    let total16 = LittleEndian::read_u16(&bpb[19..]) as u64;
+    let fat_size16 = LittleEndian::read_u16(&bpb[22..]);
+    let total32 = LittleEndian::read_u32(&bpb[32..]) as u64;
+    let fat_size32 = LittleEndian::read_u32(&bpb[36..]) as u64;
+    let root_cluster = LittleEndian::read_u32(&bpb[44..]);
+    let fsinfo = LittleEndian::read_u16(&bpb[48..]) as u64;
+
+    if bytes_per_sector != SECTOR_SIZE || sectors_per_cluster == 0 || fats == 0 || fat_size32 == 0 {
+      return Err(Error::InvalidDiskFormat);
+    }
+    // FAT12 and FAT16 have a fixed root directory and smaller FAT entries.
+    if root_entries != 0 || fat_size16 != 0 {
+      println!("fat: only FAT32 is supported");
+      return Err(Error::Unsupported);
+    }
+
+    let total = if total16 != 0 { total16 } else { total32 };
+    let data_start = (reserved + fats * fat_size32) * SECTOR_SIZE;
+    let clusters = ((total * SECTOR_SIZE).saturating_sub(data_start) / (sectors_per_cluster * SECTOR_SIZE)) as u32;
+    // More entries than the FAT has room for would be a lie.
+    let clusters = cmp::min(clusters, (fat_size32 * SECTOR_SIZE / 4) as u32 - 2);
+
+    let inner = Inner {
+      dev: dev,
+      cluster_size: sectors_per_cluster * SECTOR_SIZE,
+      fat_start: reserved * SECTOR_SIZE,
+      fat_size: fat_size32 * SECTOR_SIZE,
+      fats: fats,
+      data_start: data_start,
+      clusters: clusters,
+      root_cluster: root_cluster,
+      fsinfo: fsinfo * SECTOR_SIZE,
+      next_free: 2,
+      fsinfo_valid: fsinfo != 0 && fsinfo != 0xffff,
+      inos: BTreeMap::new(),
+      positions: BTreeMap::new(),
+      next_ino: ROOT + 1,
+    };
+    if !inner.valid_cluster(root_cluster) {
+      return Err(Error::InvalidDiskFormat);
+    }
+    println!("fat: {} clusters of {} bytes", clusters, inner.cluster_size);
+    Ok(Fat { inner: GlobalMutex::new(inner) })
+  }
+}
+
+impl Filesystem for Fat {
+  fn root(&self) -> Ino {
+    ROOT
+  }
+
+  fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, Error> {
+    let mut fs = self.inner.lock();
+    let dir = try!(fs.dir(dir));
+    let pos = try!(fs.find(&dir, name)).pos;
+    Ok(fs.ino(pos))
+  }
+
+  fn stat(&self, ino: Ino) -> Result<Stat, Error> {
+    let e = try!(self.inner.lock().entry(ino));
+    let perm = if e.kind() == FileType::Directory { 0o755 } else { 0o644 };
+    Ok(Stat {
+      ino: ino,
+      kind: e.kind(),
+      mode: if e.attr & ATTR_READ_ONLY != 0 { perm & !0o222 } else { perm },
+      nlink: if e.kind() == FileType::Directory { 2 } else { 1 },
+      uid: 0,
+      gid: 0,
+      size: e.size as u64,
+      rdev: 0,
+      mtime: dos_time(e.date, e.time),
+    })
+  }
+
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    let fs = self.inner.lock();
+    let e = try!(fs.entry(ino));
+    if e.kind() == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    fs.read_data(&e, offset, buf)
+  }
+
+  fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, Error> {
+    let mut fs = self.inner.lock();
+    let dir = try!(fs.dir(dir));
+    let entries = try!(fs.entries(&dir));
+    Ok(entries.into_iter()
+       .filter(|e| e.name != "." && e.name != "..")
+       .map(|e| DirEntry { ino: fs.ino(e.pos), kind: e.kind(), name: e.name })
+       .collect())
+  }
+
+  fn readlink(&self, _ino: Ino) -> Result<String, Error> {
+    Err(Error::InvalidArgument)
+  }
+
+  fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+    let mut fs = self.inner.lock();
+    let mut e = try!(fs.entry(ino));
+    if e.kind() == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    fs.write_data(&mut e, offset, buf)
+  }
+
+  fn truncate(&self, ino: Ino, size: u64) -> Result<(), Error> {
+    let mut fs = self.inner.lock();
+    let mut e = try!(fs.entry(ino));
+    if e.kind() == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    fs.resize(&mut e, size)
+  }
+
+  fn create(&self, dir: Ino, name: &str, kind: FileType, _mode: u32, _rdev: u64) -> Result<Ino, Error> {
+    let mut fs = self.inner.lock();
+    let parent = try!(fs.dir(dir));
+    match fs.find(&parent, name) {
+      Ok(_) => return Err(Error::Exists),
+      Err(Error::NotFound) => {},
+      Err(e) => return Err(e),
+    }
+
+    match kind {
+      FileType::Regular => {
+        let pos = try!(fs.add_entry(&parent, name, ATTR_ARCHIVE, 0, 0));
+        Ok(fs.ino(pos))
+      },
+      FileType::Directory => {
+        let c = try!(fs.alloc_cluster(None));
+        let dotdot = fs.parent_cluster(&parent);
+        let base = fs.cluster_pos(c);
+        for (i, &(short, cluster)) in [(*b".          ", c), (*b"..         ", dotdot)].iter().enumerate() {
+          let mut raw = [0u8; 32];
+          raw[..11].clone_from_slice(&short);
+          raw[11] = ATTR_DIRECTORY;
+          LittleEndian::write_u16(&mut raw[20..], (cluster >> 16) as u16);
+          LittleEndian::write_u16(&mut raw[26..], cluster as u16);
+          try!(fs.write_at(base + i as u64 * DIRENT_SIZE, &raw));
+        }
+        match fs.add_entry(&parent, name, ATTR_DIRECTORY, c, 0) {
+          Ok(pos) => Ok(fs.ino(pos)),
+          Err(e) => {
+            try!(fs.free_chain(c));
+            Err(e)
+          },
+        }
+      },
+      _ => Err(Error::Unsupported),
+    }
+  }
+
+  fn symlink(&self, _dir: Ino, _name: &str, _target: &str) -> Result<Ino, Error> {
+    Err(Error::Unsupported)
+  }
+
+  fn link(&self, _dir: Ino, _name: &str, _ino: Ino) -> Result<(), Error> {
+    Err(Error::Unsupported)
+  }
+
+  fn unlink(&self, dir: Ino, name: &str) -> Result<(), Error> {
+    let mut fs = self.inner.lock();
+    let parent = try!(fs.dir(dir));
+    let e = try!(fs.find(&parent, name));
+    if e.kind() == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    fs.remove(&parent, &e)
+  }
+
+  fn rmdir(&self, dir: Ino, name: &str) -> Result<(), Error> {
+    let mut fs = self.inner.lock();
+    let parent = try!(fs.dir(dir));
+    let e = try!(fs.find(&parent, name));
+    if e.kind() != FileType::Directory {
+      return Err(Error::NotADirectory);
+    }
+    fs.remove(&parent, &e)
+  }
+
+  fn rename(&self, from_dir: Ino, from: &str, to_dir: Ino, to: &str) -> Result<(), Error> {
+    let mut fs = self.inner.lock();
+    let src_dir = try!(fs.dir(from_dir));
+    let e = try!(fs.find(&src_dir, from));
+    let dst_dir = try!(fs.dir(to_dir));
+    let is_dir = e.kind() == FileType::Directory;
+
+    if is_dir && try!(fs.is_within(&dst_dir, &e)) {
+      return Err(Error::InvalidArgument);
+    }
+
+    match fs.find(&dst_dir, to) {
+      // Same file: maybe just a change of case
+      Ok(ref existing) if existing.pos == e.pos && existing.name == to => return Ok(()),
+      Ok(ref existing) if existing.pos == e.pos => {},
+      Ok(existing) => {
+        match (is_dir, existing.kind() == FileType::Directory) {
+          (true, false) => return Err(Error::NotADirectory),
+          (false, true) => return Err(Error::IsADirectory),
+          _ => try!(fs.remove(&dst_dir, &existing)),
+        }
+      },
+      Err(Error::NotFound) => {},
+      Err(err) => return Err(err),
+    }
+
+    // The new entry first, so a crash leaves two names rather than none.
+    let pos = try!(fs.add_entry(&dst_dir, to, e.attr, e.cluster, e.size));
+    try!(fs.remove_entry(&src_dir, &e));
+    fs.moved(e.pos, pos);
+
+    if is_dir && src_dir.cluster != dst_dir.cluster {
+      let mut dotdot = try!(fs.find(&e, ".."));
+      let parent = fs.parent_cluster(&dst_dir);
+      try!(fs.set_cluster_and_size(&mut dotdot, parent, 0));
+    }
+    Ok(())
+  }
+
+  fn set_mode(&self, ino: Ino, mode: u32) -> Result<(), Error> {
+    let fs = self.inner.lock();
+    let e = try!(fs.entry(ino));
+    if ino == ROOT {
+      return Err(Error::InvalidArgument);
+    }
+    let attr = if mode & 0o200 == 0 { e.attr | ATTR_READ_ONLY } else { e.attr & !ATTR_READ_ONLY };
+    fs.write_at(e.pos + 11, &[attr])
+  }
+
+  fn sync(&self) -> Result<(), Error> {
+    let fs = self.inner.lock();
+    fs.dev.flush().map_err(io_error)
+  }
+}
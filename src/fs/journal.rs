@@ -0,0 +1,365 @@
+use prelude::*;
+use core::cmp;
+use collections::btree_map::BTreeMap;
+
+use block;
+use byteorder::{ByteOrder,BigEndian};
+use super::{io_error, Error};
+
+// A write-ahead log in the format of ext3's jbd (and jbd2 without 64-bit
+// block numbers or v2/v3 checksums), kept in blocks of the filesystem it
+// protects. Everything in it is big-endian.
+//
+// A transaction is a descriptor block listing where each logged block
+// belongs, the blocks themselves, and a commit block with a crc32 over all
+// of that. Only once the commit block is on the device are the blocks
+// written to their real place ("checkpointed"). We checkpoint right after
+// every commit, so the log never holds more than one transaction, and it
+// always starts at the first log block.
+
+const MAGIC: u32 = 0xc03b3998;
+
+const DESCRIPTOR_BLOCK: u32 = 1;
+const COMMIT_BLOCK: u32 = 2;
+const SUPERBLOCK_V1: u32 = 3;
+const SUPERBLOCK_V2: u32 = 4;
+const REVOKE_BLOCK: u32 = 5;
+
+const HEADER_SIZE: usize = 12;
+const TAG_SIZE: usize = 8;
+const UUID_SIZE: usize = 16;
+const REVOKE_HEADER_SIZE: usize = 16;
+
+// Tag flags
+const FLAG_ESCAPE: u16 = 1;
+const FLAG_SAME_UUID: u16 = 2;
+const FLAG_LAST_TAG: u16 = 8;
+
+const COMPAT_CHECKSUM: u32 = 0x1;
+const INCOMPAT_REVOKE: u32 = 0x1;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_REVOKE;
+
+const CRC32_CHKSUM: u8 = 1;
+const CRC32_CHKSUM_SIZE: u8 = 4;
+
+// The big-endian crc32 that jbd uses, without the final inversion.
+fn crc32_be(mut crc: u32, data: &[u8]) -> u32 {
+  for &b in data {
+    crc ^= (b as u32) << 24;
+    for _ in 0..8 {
+      crc = if crc & 0x80000000 != 0 { (crc << 1) ^ 0x04c11db7 } else { crc << 1 };
+    }
+  }
+  crc
+}
+
+fn header(buf: &mut [u8], blocktype: u32, sequence: u32) {
+  BigEndian::write_u32(&mut buf[0..], MAGIC);
+  BigEndian::write_u32(&mut buf[4..], blocktype);
+  BigEndian::write_u32(&mut buf[8..], sequence);
+}
+
+// A transaction found in the log during recovery
+struct Logged {
+  sequence: u32,
+  // (where it belongs, log block, whether it was escaped)
+  blocks: Vec<(u32, u32, bool)>,
+  revoked: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+  dev: Arc<block::Cache>,
+  block_size: usize,
+  // The filesystem block of each journal block, including the superblock
+  map: Vec<u32>,
+  first: u32,
+  maxlen: u32,
+  // Of the next transaction
+  sequence: u32,
+  // Where the log starts, or 0 if there's nothing to replay
+  start: u32,
+  uuid: [u8; 16],
+  v2: bool,
+  compat: u32,
+}
+
+impl Journal {
+  // `map` lists the filesystem blocks of the journal, in order.
+  pub fn load(dev: Arc<block::Cache>, block_size: u64, map: Vec<u32>) -> Result<Journal, Error> {
+    if map.len() < 2 {
+      return Err(Error::InvalidDiskFormat);
+    }
+    let mut j = Journal {
+      dev: dev, block_size: block_size as usize, map: map, first: 0, maxlen: 0,
+      sequence: 0, start: 0, uuid: [0; 16], v2: false, compat: 0,
+    };
+
+    let sb = try!(j.read(0));
+    let blocktype = BigEndian::read_u32(&sb[4..]);
+    if BigEndian::read_u32(&sb[0..]) != MAGIC || (blocktype != SUPERBLOCK_V1 && blocktype != SUPERBLOCK_V2) {
+      println!("journal: bad superblock");
+      return Err(Error::InvalidDiskFormat);
+    }
+    if BigEndian::read_u32(&sb[12..]) as u64 != block_size {
+      return Err(Error::InvalidDiskFormat);
+    }
+    j.maxlen = BigEndian::read_u32(&sb[16..]);
+    j.first = BigEndian::read_u32(&sb[20..]);
+    j.sequence = BigEndian::read_u32(&sb[24..]);
+    j.start = BigEndian::read_u32(&sb[28..]);
+    if j.maxlen as usize > j.map.len() || j.first == 0 || j.first >= j.maxlen || j.start >= j.maxlen {
+      return Err(Error::InvalidDiskFormat);
+    }
+
+    j.v2 = blocktype == SUPERBLOCK_V2;
+    if j.v2 {
+      j.compat = BigEndian::read_u32(&sb[36..]);
+      let incompat = BigEndian::read_u32(&sb[40..]);
+      if incompat & !SUPPORTED_INCOMPAT != 0 {
+        println!("journal: unsupported features {:x}", incompat & !SUPPORTED_INCOMPAT);
+        return Err(Error::Unsupported);
+      }
+      j.uuid.clone_from_slice(&sb[48..64]);
+    }
+    Ok(j)
+  }
+
+  pub fn needs_recovery(&self) -> bool {
+    self.start != 0
+  }
+
+  fn read(&self, n: u32) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; self.block_size];
+    try!(self.read_home(self.map[n as usize], &mut buf));
+    Ok(buf)
+  }
+
+  fn write(&self, n: u32, data: &[u8]) -> Result<(), Error> {
+    self.write_home(self.map[n as usize], data)
+  }
+
+  fn read_home(&self, block: u32, buf: &mut [u8]) -> Result<(), Error> {
+    super::read_bytes(&*self.dev, block as u64 * self.block_size as u64, buf).map_err(io_error)
+  }
+
+  fn write_home(&self, block: u32, data: &[u8]) -> Result<(), Error> {
+    super::write_bytes(&*self.dev, block as u64 * self.block_size as u64, data).map_err(io_error)
+  }
+
+  fn flush(&self) -> Result<(), Error> {
+    self.dev.flush().map_err(io_error)
+  }
+
+  // The log block after `n`. The log wraps around, although ours never does.
+  fn next(&self, n: u32) -> u32 {
+    if n + 1 >= self.maxlen { self.first } else { n + 1 }
+  }
+
+  fn write_superblock(&self) -> Result<(), Error> {
+    let mut sb = try!(self.read(0));
+    BigEndian::write_u32(&mut sb[24..], self.sequence);
+    BigEndian::write_u32(&mut sb[28..], self.start);
+    if self.v2 {
+      BigEndian::write_u32(&mut sb[36..], self.compat);
+    }
+    self.write(0, &sb)
+  }
+
+  // Tags that fit in a descriptor block, leaving room for one UUID
+  fn tags_per_descriptor(&self) -> usize {
+    (self.block_size - HEADER_SIZE - UUID_SIZE) / TAG_SIZE
+  }
+
+  // The most blocks a single transaction can hold
+  pub fn capacity(&self) -> usize {
+    let space = (self.maxlen - self.first) as usize - 1;
+    let per = self.tags_per_descriptor();
+    space * per / (per + 1)
+  }
+
+  // Walk the log and collect the transactions that were committed in full.
+  fn scan(&self) -> Result<Vec<Logged>, Error> {
+    let mut done = vec![];
+    let mut n = self.start;
+    let mut current = Logged { sequence: self.sequence, blocks: vec![], revoked: vec![] };
+    let mut crc = !0;
+    let mut seen = 0;
+
+    while seen < self.maxlen {
+      let block = try!(self.read(n));
+      if BigEndian::read_u32(&block[0..]) != MAGIC || BigEndian::read_u32(&block[8..]) != current.sequence {
+        break;
+      }
+      match BigEndian::read_u32(&block[4..]) {
+        DESCRIPTOR_BLOCK => {
+          crc = crc32_be(crc, &block);
+          let mut pos = HEADER_SIZE;
+          loop {
+            if pos + TAG_SIZE > self.block_size {
+              break;
+            }
+            let home = BigEndian::read_u32(&block[pos..]);
+            let flags = BigEndian::read_u16(&block[pos+6..]);
+            n = self.next(n);
+            seen += 1;
+            crc = crc32_be(crc, &try!(self.read(n)));
+            current.blocks.push((home, n, flags & FLAG_ESCAPE != 0));
+            pos += TAG_SIZE;
+            if flags & FLAG_SAME_UUID == 0 {
+              pos += UUID_SIZE;
+            }
+            if flags & FLAG_LAST_TAG != 0 {
+              break;
+            }
+          }
+        },
+        REVOKE_BLOCK => {
+          let count = cmp::min(BigEndian::read_u32(&block[12..]) as usize, self.block_size);
+          let mut pos = REVOKE_HEADER_SIZE;
+          while pos + 4 <= count {
+            current.revoked.push(BigEndian::read_u32(&block[pos..]));
+            pos += 4;
+          }
+        },
+        COMMIT_BLOCK => {
+          if self.v2 && self.compat & COMPAT_CHECKSUM != 0 && block[12] == CRC32_CHKSUM && block[13] == CRC32_CHKSUM_SIZE {
+            if BigEndian::read_u32(&block[16..]) != crc {
+              println!("journal: transaction {} has a bad checksum, ignoring it", current.sequence);
+              break;
+            }
+          }
+          let sequence = current.sequence.wrapping_add(1);
+          done.push(current);
+          current = Logged { sequence: sequence, blocks: vec![], revoked: vec![] };
+          crc = !0;
+        },
+        _ => break,
+      }
+      n = self.next(n);
+      seen += 1;
+    }
+    Ok(done)
+  }
+
+  // Write everything that made it into the log to its real place. Done at
+  // mount time, before anything else touches the filesystem.
+  pub fn recover(&mut self) -> Result<(), Error> {
+    if !self.needs_recovery() {
+      return Ok(());
+    }
+    let logged = try!(self.scan());
+
+    // A revoke record keeps a block from being replayed from its own
+    // transaction and all earlier ones.
+    let mut revoked = BTreeMap::new();
+    for t in logged.iter() {
+      for &b in t.revoked.iter() {
+        revoked.insert(b, t.sequence);
+      }
+    }
+
+    for t in logged.iter() {
+      for &(home, n, escaped) in t.blocks.iter() {
+        match revoked.get(&home) {
+          Some(&s) if s.wrapping_sub(t.sequence) as i32 >= 0 => continue,
+          _ => {},
+        }
+        let mut data = try!(self.read(n));
+        if escaped {
+          BigEndian::write_u32(&mut data[0..], MAGIC);
+        }
+        try!(self.write_home(home, &data));
+      }
+    }
+    try!(self.flush());
+
+    if let Some(last) = logged.last() {
+      self.sequence = last.sequence.wrapping_add(1);
+    }
+    println!("journal: replayed {} transactions", logged.len());
+    self.start = 0;
+    try!(self.write_superblock());
+    self.flush()
+  }
+
+  // Log `blocks` (filesystem block number to new contents) as one
+  // transaction, then write them to their place. When this returns Ok, the
+  // change is on the device; if we crash anywhere before that, recovery
+  // makes it look like all of it happened or none.
+  pub fn commit(&mut self, blocks: &BTreeMap<u32, Vec<u8>>) -> Result<(), Error> {
+    if blocks.len() == 0 {
+      return Ok(());
+    }
+    if blocks.len() > self.capacity() {
+      println!("journal: transaction of {} blocks is too big", blocks.len());
+      return Err(Error::NoSpace);
+    }
+
+    // Only this transaction is in the log from here on. We also turn on
+    // commit checksums, which older readers can ignore.
+    self.start = self.first;
+    if self.v2 {
+      self.compat |= COMPAT_CHECKSUM;
+    }
+    try!(self.write_superblock());
+
+    let mut n = self.first;
+    let mut crc = !0;
+    let all: Vec<(&u32, &Vec<u8>)> = blocks.iter().collect();
+    for chunk in all.chunks(self.tags_per_descriptor()) {
+      let mut descriptor = vec![0u8; self.block_size];
+      header(&mut descriptor, DESCRIPTOR_BLOCK, self.sequence);
+      let mut pos = HEADER_SIZE;
+      let mut copies = vec![];
+      for (i, &(&home, data)) in chunk.iter().enumerate() {
+        let mut flags = if i == 0 { 0 } else { FLAG_SAME_UUID };
+        if i == chunk.len() - 1 {
+          flags |= FLAG_LAST_TAG;
+        }
+        // A block that looks like a journal block would confuse recovery.
+        let mut copy = data.clone();
+        if BigEndian::read_u32(&copy[0..]) == MAGIC {
+          BigEndian::write_u32(&mut copy[0..], 0);
+          flags |= FLAG_ESCAPE;
+        }
+        BigEndian::write_u32(&mut descriptor[pos..], home);
+        BigEndian::write_u16(&mut descriptor[pos+6..], flags);
+        pos += TAG_SIZE;
+        if i == 0 {
+          descriptor[pos..pos+UUID_SIZE].clone_from_slice(&self.uuid);
+          pos += UUID_SIZE;
+        }
+        copies.push(copy);
+      }
+
+      crc = crc32_be(crc, &descriptor);
+      try!(self.write(n, &descriptor));
+      n += 1;
+      for copy in copies {
+        crc = crc32_be(crc, &copy);
+        try!(self.write(n, &copy));
+        n += 1;
+      }
+    }
+    try!(self.flush());
+
+    let mut commit = vec![0u8; self.block_size];
+    header(&mut commit, COMMIT_BLOCK, self.sequence);
+    commit[12] = CRC32_CHKSUM;
+    commit[13] = CRC32_CHKSUM_SIZE;
+    BigEndian::write_u32(&mut commit[16..], crc);
+    try!(self.write(n, &commit));
+    try!(self.flush());
+
+    for (&home, data) in blocks.iter() {
+      try!(self.write_home(home, data));
+    }
+    try!(self.flush());
+
+    self.sequence = self.sequence.wrapping_add(1);
+    self.start = 0;
+    try!(self.write_superblock());
+    self.flush()
+  }
+}
@@ -1,30 +1,212 @@
 mod cpio;
+mod ext2;
+mod fat;
+mod journal;
+mod tmpfs;
+pub mod vfs;
 
 use block;
-use alloc::arc::Arc;
-
-use alloc::boxed::Box;
-use mem;
-use core::str;
-use self::cpio::{Cursor,Entry};
-use collections::vec::Vec;
 use collections::string::String;
+use collections::vec::Vec;
+use core::{cmp,fmt};
 
-#[derive(Debug)]
+pub use self::cpio::Cpiofs;
+pub use self::ext2::Ext2;
+pub use self::fat::Fat;
+pub use self::tmpfs::Tmpfs;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum Error {
   ReadFailed(block::Error),
   InvalidDiskFormat,
   Unknown,
   NotFound,
+  NotADirectory,
+  IsADirectory,
+  NotEmpty,
+  Exists,
+  ReadOnly,
+  NoSpace,
+  NameTooLong,
+  TooManySymlinks,
+  InvalidArgument,
+  CrossDevice,
+  Unsupported,
+}
+
+pub type Ino = u64;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum FileType {
+  Regular,
+  Directory,
+  Symlink,
+  CharDevice,
+  BlockDevice,
+  Fifo,
+  Socket,
+}
+
+#[derive(Debug,Clone)]
+pub struct Stat {
+  pub ino: Ino,
+  pub kind: FileType,
+  // Permission bits only, the type lives in `kind`.
+  pub mode: u32,
+  pub nlink: u32,
+  pub uid: u32,
+  pub gid: u32,
+  pub size: u64,
+  // For device files: which device (see usertask::devfs)
+  pub rdev: u64,
+  // Seconds since the epoch, 0 where the filesystem doesn't know.
+  pub mtime: u64,
+}
+
+// The file type bits of a Unix mode, as used by cpio, ext2 and stat(2).
+pub const S_IFMT: u32 = 0o170000;
+
+pub fn file_type(mode: u32) -> Option<FileType> {
+  match mode & S_IFMT {
+    0o100000 => Some(FileType::Regular),
+    0o040000 => Some(FileType::Directory),
+    0o120000 => Some(FileType::Symlink),
+    0o020000 => Some(FileType::CharDevice),
+    0o060000 => Some(FileType::BlockDevice),
+    0o010000 => Some(FileType::Fifo),
+    0o140000 => Some(FileType::Socket),
+    _ => None,
+  }
+}
+
+pub fn type_bits(kind: FileType) -> u32 {
+  match kind {
+    FileType::Regular => 0o100000,
+    FileType::Directory => 0o040000,
+    FileType::Symlink => 0o120000,
+    FileType::CharDevice => 0o020000,
+    FileType::BlockDevice => 0o060000,
+    FileType::Fifo => 0o010000,
+    FileType::Socket => 0o140000,
+  }
+}
+
+const SECTOR_SIZE: u64 = 512;
+
+fn io_error(e: block::Error) -> Error {
+  match e {
+    block::Error::ReadOnly => Error::ReadOnly,
+    e => Error::ReadFailed(e),
+  }
 }
 
+// Copy the bytes at device position `pos` into buf, however many sectors
+// that takes. Whole sectors in the middle are read in one go.
+pub fn read_bytes(dev: &block::Cache, pos: u64, buf: &mut [u8]) -> Result<(), block::Error> {
+  let mut done = 0;
+  while done < buf.len() {
+    let at = pos + done as u64;
+    let start = (at % SECTOR_SIZE) as usize;
+    let whole = (buf.len() - done) / SECTOR_SIZE as usize * SECTOR_SIZE as usize;
+    if start == 0 && whole > 0 {
+      try!(dev.read(at / SECTOR_SIZE, &mut buf[done..done+whole]));
+      done += whole;
+      continue;
+    }
+    let sector = try!(dev.get(at / SECTOR_SIZE));
+    let n = cmp::min(buf.len() - done, SECTOR_SIZE as usize - start);
+    buf[done..done+n].clone_from_slice(&sector[start..start+n]);
+    done += n;
+  }
+  Ok(())
+}
 
-pub trait Fs<'t> {
-  fn stat(&mut self, name: &str) -> Result<usize, Error>;
-  fn slurp(&mut self, name: &str, buf: &mut[u8]) -> Result<usize, Error>;
+// The other way around. Sectors that are only partly overwritten are read
+// first.
+fn write_bytes(dev: &block::Cache, pos: u64, data: &[u8]) -> Result<(), block::Error> {
+  let mut done = 0;
+  while done < data.len() {
+    let at = pos + done as u64;
+    let start = (at % SECTOR_SIZE) as usize;
+    let n = cmp::min(data.len() - done, SECTOR_SIZE as usize - start);
+    let mut sector = [0u8; 512];
+    if n < SECTOR_SIZE as usize {
+      sector.clone_from_slice(&try!(dev.get(at / SECTOR_SIZE)));
+    }
+    sector[start..start+n].clone_from_slice(&data[done..done+n]);
+    try!(dev.write(at / SECTOR_SIZE, &sector));
+    done += n;
+  }
+  Ok(())
+}
+
+#[derive(Debug,Clone)]
+pub struct DirEntry {
+  pub name: String,
+  pub ino: Ino,
+  pub kind: FileType,
+}
+
+// What every filesystem implements. Files and directories are named by inode
+// numbers that are only meaningful to the filesystem that handed them out;
+// paths and mount points are the VFS's business (see vfs.rs).
+//
+// All methods take &self, since the same filesystem is used by every process
+// at once; implementations lock internally. Everything that changes the
+// filesystem defaults to failing with ReadOnly.
+pub trait Filesystem: Send + Sync + fmt::Debug {
+  fn root(&self) -> Ino;
+
+  // Find `name` in directory `dir`. Never asked for "." or "..".
+  fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, Error>;
+  fn stat(&self, ino: Ino) -> Result<Stat, Error>;
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, Error>;
+  fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, Error>;
+  fn readlink(&self, ino: Ino) -> Result<String, Error>;
+
+  // An open file now refers to `ino`, until the matching release. Only
+  // filesystems that must keep unlinked inodes around for it care.
+  fn open(&self, _ino: Ino) -> Result<(), Error> {
+    Ok(())
+  }
+  fn release(&self, _ino: Ino) {
+  }
+
+  fn write(&self, _ino: Ino, _offset: u64, _buf: &[u8]) -> Result<usize, Error> {
+    Err(Error::ReadOnly)
+  }
+  fn truncate(&self, _ino: Ino, _size: u64) -> Result<(), Error> {
+    Err(Error::ReadOnly)
+  }
+  // Creates a regular file, directory or device node.
+  fn create(&self, _dir: Ino, _name: &str, _kind: FileType, _mode: u32, _rdev: u64) -> Result<Ino, Error> {
+    Err(Error::ReadOnly)
+  }
+  fn symlink(&self, _dir: Ino, _name: &str, _target: &str) -> Result<Ino, Error> {
+    Err(Error::ReadOnly)
+  }
+  fn link(&self, _dir: Ino, _name: &str, _ino: Ino) -> Result<(), Error> {
+    Err(Error::ReadOnly)
+  }
+  // Removes a non-directory entry.
+  fn unlink(&self, _dir: Ino, _name: &str) -> Result<(), Error> {
+    Err(Error::ReadOnly)
+  }
+  // Removes an empty directory.
+  fn rmdir(&self, _dir: Ino, _name: &str) -> Result<(), Error> {
+    Err(Error::ReadOnly)
+  }
+  fn rename(&self, _from_dir: Ino, _from: &str, _to_dir: Ino, _to: &str) -> Result<(), Error> {
+    Err(Error::ReadOnly)
+  }
+  fn set_mode(&self, _ino: Ino, _mode: u32) -> Result<(), Error> {
+    Err(Error::ReadOnly)
+  }
 
-  fn open<'u: 't>(&'u self, name: &str) -> Result<File<'u>, Error>;
-  fn index(&mut self, dirname: &str) -> Result<Vec<String>, Error>;
+  // Write back anything that's only in memory so far.
+  fn sync(&self) -> Result<(), Error> {
+    Ok(())
+  }
 }
 
 /*
@@ -69,79 +251,3 @@ pub trait Fs<'t> {
   (c) File's need not be Sync
   (d) Blockdev's *do* need to be Sync
 */
-
-#[derive(Debug)]
-pub struct Cpiofs {
-  dev: Arc<block::Cache>,
-}
-
-impl Cpiofs {
-  pub fn new(dev: Arc<block::Cache>) -> Self {
-    Cpiofs { dev: dev }
-  }
-
-  fn cursor(&mut self) -> Cursor {
-    Cursor::new(self.dev.clone())
-  }
-}
-
-#[derive(Debug)]
-struct File<'f> {
-  fs: &'f Cpiofs
-}
-
-impl<'t> Fs<'t> for Cpiofs {
-  fn open<'u: 't>(&'u self, name: &str) -> Result<File<'u>, Error> {
-    Ok(File{ fs: &self })
-  }
-
-  fn index(&mut self, dirname: &str) -> Result<Vec<String>, Error> {
-    //let filename_needle = &dirname[1..dirname.len()]; // strip off leading '/'
-
-    Ok(self.cursor().map(|e| e.unwrap().name).collect())
-  }
-
-  fn stat(&mut self, filename: &str) -> Result<usize, Error> {
-    let filename_needle = &filename[1..filename.len()]; // strip off leading '/'
-
-    match self.cursor().map(|e| e.unwrap()).find(|e| e.name.as_bytes() == filename_needle.as_bytes()) {
-      Some(e) => Ok(e.size),
-      None => Err(Error::NotFound),
-    }
-  }
-
-
-  fn slurp(&mut self, filename: &str, buf: &mut[u8]) -> Result<usize, Error> {
-    let filename_needle = &filename[1..filename.len()]; // strip off leading '/'
-    let entry = match self.cursor().map(|e| e.unwrap()).find(|e| e.name.as_bytes() == filename_needle.as_bytes()) {
-      Some(e) => e,
-      None => { return Err(Error::NotFound); }
-    };
-    let (mut next_sector, initial_body_offset) = entry.body_pos;
-
-    let mut written = 0;
-    while written < entry.size {
-      use core::slice::bytes::copy_memory;
-
-      println!("Reading sector {}", next_sector);
-      let sectorbuf = self.dev.get(next_sector as u64).unwrap();
-      next_sector = next_sector + 1;
-
-      let n = {
-        let src = if written == 0 {
-          &sectorbuf[initial_body_offset..]
-        } else {
-          &sectorbuf[..]
-        };
-        println!("copying, src: {:?}", &src[0..10]);
-        let mut dest = &mut buf[written..entry.size];
-        dest.clone_from_slice(src)
-      };
-
-      written = written + n;
-    }
-
-    assert_eq!(written, entry.size);
-    Ok(written)
-  }
-}
@@ -0,0 +1,337 @@
+use prelude::*;
+use core::cmp;
+use collections::btree_map::BTreeMap;
+
+use mem;
+use sync::global_mutex::GlobalMutex;
+use super::{DirEntry, Error, FileType, Filesystem, Ino, Stat};
+
+const ROOT: Ino = 1;
+
+// Heap that growing a file must leave to the rest of the kernel, which
+// panics when it runs out. Freed memory isn't reused, so we can't count on
+// getting any back either.
+const RESERVE: usize = 0x40000;
+
+#[derive(Debug)]
+struct Inode {
+  kind: FileType,
+  mode: u32,
+  nlink: u32,
+  // How many open files refer to it
+  opens: u32,
+  rdev: u64,
+  // File contents, or a symlink's target
+  data: Vec<u8>,
+  // Only for directories
+  children: BTreeMap<String, Ino>,
+  parent: Ino,
+}
+
+#[derive(Debug)]
+struct Inner {
+  inodes: BTreeMap<Ino, Inode>,
+  next_ino: Ino,
+  // Bytes of file data, which is what the limit applies to
+  used: usize,
+}
+
+// A filesystem that only lives in memory, gone at the next boot.
+//
+// Inodes are freed once their last link is gone and nobody has them open.
+#[derive(Debug)]
+pub struct Tmpfs {
+  inner: GlobalMutex<Inner>,
+  limit: usize,
+}
+
+impl Inner {
+  fn get(&self, ino: Ino) -> Result<&Inode, Error> {
+    self.inodes.get(&ino).ok_or(Error::NotFound)
+  }
+
+  fn get_mut(&mut self, ino: Ino) -> Result<&mut Inode, Error> {
+    self.inodes.get_mut(&ino).ok_or(Error::NotFound)
+  }
+
+  fn dir(&self, ino: Ino) -> Result<&Inode, Error> {
+    let i = try!(self.get(ino));
+    if i.kind != FileType::Directory {
+      return Err(Error::NotADirectory);
+    }
+    Ok(i)
+  }
+
+  fn child(&self, dir: Ino, name: &str) -> Result<Ino, Error> {
+    try!(self.dir(dir)).children.get(name).cloned().ok_or(Error::NotFound)
+  }
+
+  fn add(&mut self, dir: Ino, name: &str, kind: FileType, mode: u32, rdev: u64, data: Vec<u8>) -> Result<Ino, Error> {
+    if try!(self.dir(dir)).children.contains_key(name) {
+      return Err(Error::Exists);
+    }
+    let ino = self.next_ino;
+    self.next_ino += 1;
+    let nlink = if kind == FileType::Directory { 2 } else { 1 };
+    self.inodes.insert(ino, Inode { kind: kind, mode: mode, nlink: nlink, opens: 0, rdev: rdev, data: data, children: BTreeMap::new(), parent: dir });
+    let parent = try!(self.get_mut(dir));
+    parent.children.insert(String::from(name), ino);
+    if kind == FileType::Directory {
+      parent.nlink += 1;
+    }
+    Ok(ino)
+  }
+
+  // Take `name` out of `dir`, freeing the inode if that was its last link
+  // and it isn't open.
+  fn remove(&mut self, dir: Ino, name: &str) -> Result<(), Error> {
+    let ino = try!(self.child(dir, name));
+    let is_dir = try!(self.get(ino)).kind == FileType::Directory;
+    {
+      let parent = try!(self.get_mut(dir));
+      parent.children.remove(name);
+      if is_dir {
+        parent.nlink -= 1;
+      }
+    }
+
+    try!(self.get_mut(ino)).nlink -= if is_dir { 2 } else { 1 };
+    self.free_if_unused(ino);
+    Ok(())
+  }
+
+  fn free_if_unused(&mut self, ino: Ino) {
+    let unused = match self.inodes.get(&ino) {
+      Some(i) => i.nlink == 0 && i.opens == 0,
+      None => false,
+    };
+    if unused {
+      let i = self.inodes.remove(&ino).unwrap();
+      self.used -= i.data.len();
+    }
+  }
+
+  // Whether `ino` is `dir` or somewhere below it.
+  fn is_within(&self, mut ino: Ino, dir: Ino) -> bool {
+    loop {
+      if ino == dir {
+        return true;
+      }
+      if ino == ROOT {
+        return false;
+      }
+      ino = match self.get(ino) {
+        Ok(i) => i.parent,
+        Err(_) => return false,
+      };
+    }
+  }
+}
+
+impl Tmpfs {
+  // `limit` is the most file data it will hold, in bytes.
+  pub fn new(limit: usize) -> Self {
+    let mut inodes = BTreeMap::new();
+    inodes.insert(ROOT, Inode { kind: FileType::Directory, mode: 0o1777, nlink: 2, opens: 0, rdev: 0, data: vec![], children: BTreeMap::new(), parent: ROOT });
+    Tmpfs { inner: GlobalMutex::new(Inner { inodes: inodes, next_ino: ROOT + 1, used: 0 }), limit: limit }
+  }
+
+  // Grow or shrink a file's data, within the limit and what the heap has
+  // left. Growing reserves room for more than asked, since every time the
+  // data moves, its old copy is lost.
+  fn resize(&self, inner: &mut Inner, ino: Ino, size: usize) -> Result<(), Error> {
+    let old = try!(inner.get(ino)).data.len();
+    if size > old && inner.used + (size - old) > self.limit {
+      return Err(Error::NoSpace);
+    }
+    {
+      let data = &mut try!(inner.get_mut(ino)).data;
+      if size > data.capacity() {
+        let want = cmp::min(cmp::max(size, data.capacity() * 2), self.limit);
+        let usage = mem::usage();
+        if usage.total - usage.used < want + RESERVE {
+          return Err(Error::NoSpace);
+        }
+        let len = data.len();
+        data.reserve_exact(want - len);
+      }
+      data.resize(size, 0);
+    }
+    inner.used = inner.used + size - old;
+    Ok(())
+  }
+}
+
+impl Filesystem for Tmpfs {
+  fn root(&self) -> Ino {
+    ROOT
+  }
+
+  fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, Error> {
+    self.inner.lock().child(dir, name)
+  }
+
+  fn stat(&self, ino: Ino) -> Result<Stat, Error> {
+    let inner = self.inner.lock();
+    let i = try!(inner.get(ino));
+    Ok(Stat { ino: ino, kind: i.kind, mode: i.mode, nlink: i.nlink, uid: 0, gid: 0, size: i.data.len() as u64, rdev: i.rdev, mtime: 0 })
+  }
+
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    let inner = self.inner.lock();
+    let i = try!(inner.get(ino));
+    if i.kind == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    let offset = offset as usize;
+    if offset >= i.data.len() {
+      return Ok(0);
+    }
+    let n = cmp::min(buf.len(), i.data.len() - offset);
+    buf[..n].clone_from_slice(&i.data[offset..offset+n]);
+    Ok(n)
+  }
+
+  fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, Error> {
+    let inner = self.inner.lock();
+    let d = try!(inner.dir(dir));
+    Ok(d.children.iter().map(|(name, &ino)| DirEntry { name: name.clone(), ino: ino, kind: inner.inodes[&ino].kind }).collect())
+  }
+
+  fn readlink(&self, ino: Ino) -> Result<String, Error> {
+    let inner = self.inner.lock();
+    let i = try!(inner.get(ino));
+    if i.kind != FileType::Symlink {
+      return Err(Error::InvalidArgument);
+    }
+    String::from_utf8(i.data.clone()).map_err(|_| Error::InvalidArgument)
+  }
+
+  fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+    let mut inner = self.inner.lock();
+    if try!(inner.get(ino)).kind == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    let offset = offset as usize;
+    let end = offset + buf.len();
+    if end > try!(inner.get(ino)).data.len() {
+      try!(self.resize(&mut inner, ino, end));
+    }
+    try!(inner.get_mut(ino)).data[offset..end].clone_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn truncate(&self, ino: Ino, size: u64) -> Result<(), Error> {
+    let mut inner = self.inner.lock();
+    if try!(inner.get(ino)).kind != FileType::Regular {
+      return Err(Error::InvalidArgument);
+    }
+    self.resize(&mut inner, ino, size as usize)
+  }
+
+  fn open(&self, ino: Ino) -> Result<(), Error> {
+    try!(self.inner.lock().get_mut(ino)).opens += 1;
+    Ok(())
+  }
+
+  fn release(&self, ino: Ino) {
+    let mut inner = self.inner.lock();
+    if let Ok(i) = inner.get_mut(ino) {
+      i.opens -= 1;
+    }
+    inner.free_if_unused(ino);
+  }
+
+  fn create(&self, dir: Ino, name: &str, kind: FileType, mode: u32, rdev: u64) -> Result<Ino, Error> {
+    if kind == FileType::Symlink {
+      return Err(Error::InvalidArgument);
+    }
+    self.inner.lock().add(dir, name, kind, mode, rdev, vec![])
+  }
+
+  fn symlink(&self, dir: Ino, name: &str, target: &str) -> Result<Ino, Error> {
+    let mut inner = self.inner.lock();
+    if inner.used + target.len() > self.limit {
+      return Err(Error::NoSpace);
+    }
+    let ino = try!(inner.add(dir, name, FileType::Symlink, 0o777, 0, target.as_bytes().to_vec()));
+    inner.used += target.len();
+    Ok(ino)
+  }
+
+  fn link(&self, dir: Ino, name: &str, ino: Ino) -> Result<(), Error> {
+    let mut inner = self.inner.lock();
+    if try!(inner.get(ino)).kind == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    if try!(inner.dir(dir)).children.contains_key(name) {
+      return Err(Error::Exists);
+    }
+    try!(inner.get_mut(dir)).children.insert(String::from(name), ino);
+    try!(inner.get_mut(ino)).nlink += 1;
+    Ok(())
+  }
+
+  fn unlink(&self, dir: Ino, name: &str) -> Result<(), Error> {
+    let mut inner = self.inner.lock();
+    let ino = try!(inner.child(dir, name));
+    if try!(inner.get(ino)).kind == FileType::Directory {
+      return Err(Error::IsADirectory);
+    }
+    inner.remove(dir, name)
+  }
+
+  fn rmdir(&self, dir: Ino, name: &str) -> Result<(), Error> {
+    let mut inner = self.inner.lock();
+    let ino = try!(inner.child(dir, name));
+    if try!(inner.dir(ino)).children.len() > 0 {
+      return Err(Error::NotEmpty);
+    }
+    inner.remove(dir, name)
+  }
+
+  // Like rename(2): replaces `to` if it's there and of a compatible kind.
+  fn rename(&self, from_dir: Ino, from: &str, to_dir: Ino, to: &str) -> Result<(), Error> {
+    let mut inner = self.inner.lock();
+    let ino = try!(inner.child(from_dir, from));
+    let is_dir = try!(inner.get(ino)).kind == FileType::Directory;
+    try!(inner.dir(to_dir));
+
+    if is_dir && inner.is_within(to_dir, ino) {
+      return Err(Error::InvalidArgument);
+    }
+
+    match inner.child(to_dir, to) {
+      Ok(existing) if existing == ino => return Ok(()),
+      Ok(existing) => {
+        let (target_is_dir, target_empty) = {
+          let target = try!(inner.get(existing));
+          (target.kind == FileType::Directory, target.children.len() == 0)
+        };
+        match (is_dir, target_is_dir) {
+          (true, false) => return Err(Error::NotADirectory),
+          (false, true) => return Err(Error::IsADirectory),
+          (true, true) if !target_empty => return Err(Error::NotEmpty),
+          _ => {},
+        }
+        try!(inner.remove(to_dir, to));
+      },
+      Err(Error::NotFound) => {},
+      Err(e) => return Err(e),
+    }
+
+    try!(inner.get_mut(from_dir)).children.remove(from);
+    try!(inner.get_mut(to_dir)).children.insert(String::from(to), ino);
+    if is_dir {
+      try!(inner.get_mut(from_dir)).nlink -= 1;
+      try!(inner.get_mut(to_dir)).nlink += 1;
+      try!(inner.get_mut(ino)).parent = to_dir;
+    }
+    Ok(())
+  }
+
+  fn set_mode(&self, ino: Ino, mode: u32) -> Result<(), Error> {
+    try!(self.inner.lock().get_mut(ino)).mode = mode & 0o7777;
+    Ok(())
+  }
+}
@@ -0,0 +1,247 @@
+use prelude::*;
+
+use sync::global_mutex::GlobalMutex;
+use super::{DirEntry, Error, FileType, Filesystem, Ino, Stat};
+
+// Like Linux, give up after this many symlinks in a single lookup.
+const MAX_SYMLINKS: usize = 40;
+
+const MAX_NAME: usize = 255;
+
+// A file or directory on some filesystem.
+#[derive(Debug,Clone)]
+pub struct Vnode {
+  pub fs: Arc<Filesystem>,
+  pub ino: Ino,
+}
+
+pub fn same_fs(a: &Arc<Filesystem>, b: &Arc<Filesystem>) -> bool {
+  &**a as *const Filesystem as *const u8 == &**b as *const Filesystem as *const u8
+}
+
+impl Vnode {
+  pub fn same(&self, other: &Vnode) -> bool {
+    self.ino == other.ino && same_fs(&self.fs, &other.fs)
+  }
+
+  pub fn stat(&self) -> Result<Stat, Error> {
+    self.fs.stat(self.ino)
+  }
+
+  pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    self.fs.read(self.ino, offset, buf)
+  }
+
+  pub fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+    self.fs.write(self.ino, offset, buf)
+  }
+
+  pub fn truncate(&self, size: u64) -> Result<(), Error> {
+    self.fs.truncate(self.ino, size)
+  }
+
+  pub fn readdir(&self) -> Result<Vec<DirEntry>, Error> {
+    self.fs.readdir(self.ino)
+  }
+
+  pub fn readlink(&self) -> Result<String, Error> {
+    self.fs.readlink(self.ino)
+  }
+
+  // Read the whole file into memory.
+  pub fn slurp(&self) -> Result<Vec<u8>, Error> {
+    let size = try!(self.stat()).size as usize;
+    let mut buf = vec![0u8; size];
+    let mut done = 0;
+    while done < size {
+      let n = try!(self.read(done as u64, &mut buf[done..]));
+      if n == 0 {
+        break;
+      }
+      done += n;
+    }
+    buf.truncate(done);
+    Ok(buf)
+  }
+}
+
+// A vnode together with the path we took to reach it, so that ".." can
+// leave a mounted filesystem again.
+#[derive(Debug)]
+pub struct Dentry {
+  pub name: String,
+  pub vnode: Vnode,
+  parent: Option<Arc<Dentry>>,
+}
+
+impl Dentry {
+  // The root's parent is the root itself.
+  pub fn parent(me: &Arc<Dentry>) -> Arc<Dentry> {
+    match me.parent {
+      Some(ref p) => p.clone(),
+      None => me.clone(),
+    }
+  }
+
+  pub fn path(&self) -> String {
+    let mut names = vec![];
+    let mut d = self;
+    while let Some(ref p) = d.parent {
+      names.push(&d.name[..]);
+      d = &**p;
+    }
+
+    if names.len() == 0 {
+      return String::from("/");
+    }
+    let mut path = String::new();
+    for name in names.iter().rev() {
+      path.push('/');
+      path.push_str(name);
+    }
+    path
+  }
+}
+
+struct Mount {
+  path: String,
+  covered: Vnode,
+  root: Vnode,
+}
+
+struct Mounts {
+  root: Option<Arc<Dentry>>,
+  // In the order they were mounted
+  mounts: Vec<Mount>,
+}
+
+unsafe_lazy_static! {
+  static ref MOUNTS: GlobalMutex<Mounts> = { GlobalMutex::new(Mounts { root: None, mounts: vec![] }) };
+}
+
+pub fn mount_root(fs: Arc<Filesystem>) {
+  let ino = fs.root();
+  let root = Dentry { name: String::from("/"), vnode: Vnode { fs: fs, ino: ino }, parent: None };
+  MOUNTS.lock().root = Some(Arc::new(root));
+}
+
+// Mount `fs` on top of the directory at `path`, hiding what's there.
+pub fn mount(path: &str, fs: Arc<Filesystem>) -> Result<(), Error> {
+  let at = try!(resolve(&root(), path, true));
+  if try!(at.vnode.stat()).kind != FileType::Directory {
+    return Err(Error::NotADirectory);
+  }
+
+  let ino = fs.root();
+  println!("vfs: mounting {:?} on {}", fs, at.path());
+  MOUNTS.lock().mounts.push(Mount { path: at.path(), covered: at.vnode.clone(), root: Vnode { fs: fs, ino: ino } });
+  Ok(())
+}
+
+// Mount points and the filesystems mounted there.
+pub fn mounts() -> Vec<(String, Arc<Filesystem>)> {
+  let m = MOUNTS.lock();
+  let mut v = vec![(String::from("/"), m.root.as_ref().unwrap().vnode.fs.clone())];
+  v.extend(m.mounts.iter().map(|m| (m.path.clone(), m.root.fs.clone())));
+  v
+}
+
+// A small number that tells mounted filesystems apart (st_dev, basically).
+pub fn dev_id(fs: &Arc<Filesystem>) -> u64 {
+  match mounts().iter().position(|m| same_fs(&m.1, fs)) {
+    Some(i) => i as u64 + 1,
+    None => 0,
+  }
+}
+
+pub fn root() -> Arc<Dentry> {
+  MOUNTS.lock().root.clone().expect("no root filesystem mounted")
+}
+
+// What's mounted on top of `v`, if anything.
+fn covering(v: &Vnode) -> Option<Vnode> {
+  MOUNTS.lock().mounts.iter().rev().find(|m| m.covered.same(v)).map(|m| m.root.clone())
+}
+
+// Walk `path`, starting at `start` if it's relative. The last component is
+// only followed if it's a symlink and `follow` is set, like stat vs. lstat.
+pub fn resolve(start: &Arc<Dentry>, path: &str, follow: bool) -> Result<Arc<Dentry>, Error> {
+  let mut links = 0;
+  walk(start, path, follow, &mut links)
+}
+
+// For operations that create or remove `path`: resolve everything but the
+// last component, which is returned as is.
+pub fn resolve_parent(start: &Arc<Dentry>, path: &str) -> Result<(Arc<Dentry>, String), Error> {
+  let trimmed = path.trim_right_matches('/');
+  let (dir, name) = match trimmed.rfind('/') {
+    Some(i) => (&trimmed[..i+1], &trimmed[i+1..]),
+    None => ("", trimmed),
+  };
+  if name.len() == 0 || name == "." || name == ".." {
+    return Err(Error::InvalidArgument);
+  }
+  if name.len() > MAX_NAME {
+    return Err(Error::NameTooLong);
+  }
+
+  let parent = if dir.len() == 0 { start.clone() } else { try!(resolve(start, dir, true)) };
+  if try!(parent.vnode.stat()).kind != FileType::Directory {
+    return Err(Error::NotADirectory);
+  }
+  Ok((parent, String::from(name)))
+}
+
+fn walk(start: &Arc<Dentry>, path: &str, follow: bool, links: &mut usize) -> Result<Arc<Dentry>, Error> {
+  if path.len() == 0 {
+    return Err(Error::NotFound);
+  }
+
+  let mut cur = if path.starts_with('/') { root() } else { start.clone() };
+  let components: Vec<&str> = path.split('/').filter(|c| c.len() > 0).collect();
+  // "dir/" always means the directory, even if dir is a symlink
+  let follow = follow || path.ends_with('/');
+
+  for (i, name) in components.iter().enumerate() {
+    let last = i + 1 == components.len();
+    cur = try!(step(&cur, name, !last || follow, links));
+  }
+
+  if path.ends_with('/') && try!(cur.vnode.stat()).kind != FileType::Directory {
+    return Err(Error::NotADirectory);
+  }
+  Ok(cur)
+}
+
+fn step(dir: &Arc<Dentry>, name: &str, follow: bool, links: &mut usize) -> Result<Arc<Dentry>, Error> {
+  if name.len() > MAX_NAME {
+    return Err(Error::NameTooLong);
+  }
+  if try!(dir.vnode.stat()).kind != FileType::Directory {
+    return Err(Error::NotADirectory);
+  }
+
+  match name {
+    "." => return Ok(dir.clone()),
+    ".." => return Ok(Dentry::parent(dir)),
+    _ => {},
+  }
+
+  let ino = try!(dir.vnode.fs.lookup(dir.vnode.ino, name));
+  let mut vnode = Vnode { fs: dir.vnode.fs.clone(), ino: ino };
+  while let Some(root) = covering(&vnode) {
+    vnode = root;
+  }
+
+  if follow && try!(vnode.stat()).kind == FileType::Symlink {
+    *links += 1;
+    if *links > MAX_SYMLINKS {
+      return Err(Error::TooManySymlinks);
+    }
+    let target = try!(vnode.readlink());
+    // Relative targets are relative to the directory containing the link.
+    return walk(dir, &target, true, links);
+  }
+
+  Ok(Arc::new(Dentry { name: String::from(name), vnode: vnode, parent: Some(dir.clone()) }))
+}
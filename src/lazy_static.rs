@@ -0,0 +1,69 @@
+// On first access to the global, initialize it using the given expression.
+// You *must* ensure that until the first access returns, no further accesses occur.
+macro_rules! unsafe_lazy_static {
+    ($(#[$attr:meta])* static ref $N:ident : $T:ty = $e:expr; $($t:tt)*) => {
+        unsafe_lazy_static!(PRIV, $(#[$attr])* static ref $N : $T = $e; $($t)*);
+    };
+    ($(#[$attr:meta])* pub static ref $N:ident : $T:ty = $e:expr; $($t:tt)*) => {
+        unsafe_lazy_static!(PUB, $(#[$attr])* static ref $N : $T = $e; $($t)*);
+    };
+    ($VIS:ident, $(#[$attr:meta])* static ref $N:ident : $T:ty = $e:expr; $($t:tt)*) => {
+        unsafe_lazy_static!(MAKE TY, $VIS, $(#[$attr])*, $N);
+        impl ::core::ops::Deref for $N {
+            type Target = $T;
+            fn deref<'a>(&'a self) -> &'a $T {
+                #[inline(always)]
+                fn __static_ref_initialize() -> $T { $e }
+
+                unsafe {
+                    #[inline(always)]
+                    fn require_sync<T: Sync>(_: &T) { }
+
+                    #[inline(always)]
+                    unsafe fn __stability() -> &'static $T {
+                        use core::cell::UnsafeCell;
+
+                        struct SyncCell(UnsafeCell<Option<$T>>);
+                        unsafe impl Sync for SyncCell {}
+
+                        static mut DONE: bool = false;
+
+                        static DATA: SyncCell = SyncCell(UnsafeCell::new(None));
+                        if !DONE {
+                          *DATA.0.get() = Some(__static_ref_initialize());
+                          DONE = true;
+                        }
+                        match *DATA.0.get() {
+                            Some(ref x) => x,
+                            None => core::intrinsics::unreachable(),
+                        }
+                    }
+
+                    let static_ref = __stability();
+                    require_sync(static_ref);
+                    static_ref
+                }
+            }
+        }
+        unsafe_lazy_static!($($t)*);
+    };
+    (MAKE TY, PUB, $(#[$attr:meta])*, $N:ident) => {
+        #[allow(missing_copy_implementations)]
+        #[allow(non_camel_case_types)]
+        #[allow(dead_code)]
+        $(#[$attr])*
+        pub struct $N {__private_field: ()}
+        #[doc(hidden)]
+        pub static $N: $N = $N {__private_field: ()};
+    };
+    (MAKE TY, PRIV, $(#[$attr:meta])*, $N:ident) => {
+        #[allow(missing_copy_implementations)]
+        #[allow(non_camel_case_types)]
+        #[allow(dead_code)]
+        $(#[$attr])*
+        struct $N {__private_field: ()}
+        #[doc(hidden)]
+        static $N: $N = $N {__private_field: ()};
+    };
+    () => ()
+}
@@ -9,3 +9,42 @@ pub fn align_up(address: usize, granularity: usize) -> usize {
 pub fn physical_from_kernel(kernel: usize) -> usize {
   kernel & (0x0000008000000000-1)
 }
+
+pub fn kernel_from_physical(physical: usize) -> usize {
+  physical | 0x0000008000000000
+}
+
+// Map device memory (see mm.c) and return where the kernel sees it.
+// Unsafe because nothing stops two drivers from mapping the same registers.
+pub unsafe fn map_mmio(physical: u64, len: usize) -> Result<usize, ()> {
+  if mm_map_mmio(physical, len as u64) != 0 {
+    return Err(());
+  }
+  Ok(kernel_from_physical(physical as usize))
+}
+
+extern {
+  fn mm_stats(limit: *mut usize, used: *mut usize);
+  fn mm_map_mmio(physical: u64, len: u64) -> i32;
+  static rust_allocd: usize;
+}
+
+// How much of the kernel heap is in use, in bytes. Only whole pages (user
+// memory, page tables) are ever reused, so `used` is everything else handed
+// out since boot plus the pages in use, while `live` only counts what Rust
+// hasn't freed yet.
+#[derive(Debug,Clone,Copy)]
+pub struct Usage {
+  pub total: usize,
+  pub used: usize,
+  pub live: usize,
+}
+
+pub fn usage() -> Usage {
+  let mut total = 0;
+  let mut used = 0;
+  unsafe {
+    mm_stats(&mut total, &mut used);
+    Usage { total: total, used: used, live: rust_allocd }
+  }
+}
@@ -0,0 +1,52 @@
+use core::intrinsics::{volatile_load,volatile_store};
+use mem;
+
+// A range of memory-mapped device registers, the counterpart of
+// cpuio::IoPort for devices that live in the physical address space.
+#[derive(Debug)]
+pub struct Mmio {
+  base: usize,
+  len: usize,
+}
+
+impl Mmio {
+  // Unsafe because you could map the same registers multiple times.
+  pub unsafe fn map(physical: u64, len: usize) -> Result<Mmio, ()> {
+    let base = try!(mem::map_mmio(physical, len));
+    Ok(Mmio { base: base, len: len })
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  fn at(&self, offset: usize, size: usize) -> usize {
+    assert!(offset + size <= self.len);
+    self.base + offset
+  }
+
+  pub fn read8(&self, offset: usize) -> u8 {
+    unsafe { volatile_load(self.at(offset, 1) as *const u8) }
+  }
+  pub fn read16(&self, offset: usize) -> u16 {
+    unsafe { volatile_load(self.at(offset, 2) as *const u16) }
+  }
+  pub fn read32(&self, offset: usize) -> u32 {
+    unsafe { volatile_load(self.at(offset, 4) as *const u32) }
+  }
+
+  pub fn write8(&mut self, offset: usize, val: u8) {
+    unsafe { volatile_store(self.at(offset, 1) as *mut u8, val) }
+  }
+  pub fn write16(&mut self, offset: usize, val: u16) {
+    unsafe { volatile_store(self.at(offset, 2) as *mut u16, val) }
+  }
+  pub fn write32(&mut self, offset: usize, val: u32) {
+    unsafe { volatile_store(self.at(offset, 4) as *mut u32, val) }
+  }
+  // As two 32-bit writes, low half first, which is what virtio wants.
+  pub fn write64(&mut self, offset: usize, val: u64) {
+    self.write32(offset, val as u32);
+    self.write32(offset + 4, (val >> 32) as u32);
+  }
+}
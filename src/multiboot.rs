@@ -0,0 +1,21 @@
+use core::slice;
+use mem;
+
+extern {
+  fn multiboot_module(i: usize, start: *mut u64, end: *mut u64) -> i32;
+}
+
+// The memory that the boot loader loaded the i-th module into, if there is
+// such a module (see multiboot.c). Nothing else uses that memory, the heap
+// starts behind the modules.
+pub fn module(i: usize) -> Option<&'static mut [u8]> {
+  let mut start = 0;
+  let mut end = 0;
+  unsafe {
+    if multiboot_module(i, &mut start, &mut end) == 0 || end < start {
+      return None;
+    }
+    let kernel = mem::kernel_from_physical(start as usize) as *mut u8;
+    Some(slice::from_raw_parts_mut(kernel, (end - start) as usize))
+  }
+}
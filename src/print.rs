@@ -1,6 +1,7 @@
 use core::prelude::*;
 use core::fmt;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 extern {
   fn rust_writek(txt : &[u8], len: usize) -> ();
@@ -36,13 +37,36 @@ macro_rules! writeln {
   );
 }
 
+// From `loglevel=` on the kernel command line. At 0, print! and println!
+// don't print anything. debug! only prints from 2 on, it's for output that
+// comes with every interrupt or task switch.
+static LOGLEVEL: AtomicUsize = AtomicUsize::new(1);
+
+pub fn set_loglevel(level: usize) {
+  LOGLEVEL.store(level, Ordering::SeqCst);
+}
+
+fn quiet() -> bool {
+  LOGLEVEL.load(Ordering::SeqCst) == 0
+}
+
+pub fn debugging() -> bool {
+  LOGLEVEL.load(Ordering::SeqCst) >= 2
+}
+
 pub fn myprint_args(fmt: fmt::Arguments) -> Result<(), fmt::Error>  {
+  if quiet() {
+    return Ok(());
+  }
   let kio = &mut Kio;
   let io = kio as &mut Write;
   write!(io, "{}", fmt)
 }
 
 pub fn myprintln_args(fmt: fmt::Arguments) -> Result<(), fmt::Error>  {
+  if quiet() {
+    return Ok(());
+  }
   let kio = &mut Kio;
   let io = kio as &mut Write;
   writeln!(io, "{}", fmt)
@@ -55,3 +79,7 @@ macro_rules! print {
 macro_rules! println {
     ($($arg:tt)*) => (::print::myprintln_args(format_args!($($arg)*)).unwrap())
 }
+
+macro_rules! debug {
+    ($($arg:tt)*) => (if ::print::debugging() { println!($($arg)*) })
+}
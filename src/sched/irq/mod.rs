@@ -10,7 +10,7 @@ use sync::global_mutex::GlobalMutex;
 // locking a sleeping lock sync borrows ownership of the process context
 // -> enforces that sleeping mutexes can only be acquired in process context
 
-use core::sync::atomic::{AtomicBool,Ordering};
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
 
 pub trait InterruptHandler: Send + Debug {
   fn critical(&mut self); // will be executed with interrupts disabled
@@ -23,6 +23,9 @@ pub trait InterruptHandler: Send + Debug {
 struct UnsafeTableEntry {
   again: AtomicBool,
   handlers: GlobalMutex<Vec<Box<InterruptHandler>>>,
+  // For /proc/interrupts, so that nobody has to take the handler lock
+  count: AtomicUsize,
+  nhandlers: AtomicUsize,
 }
 
 impl UnsafeTableEntry {
@@ -30,6 +33,8 @@ impl UnsafeTableEntry {
     UnsafeTableEntry {
       again: AtomicBool::new(false),
       handlers: GlobalMutex::new(vec![]),
+      count: AtomicUsize::new(0),
+      nhandlers: AtomicUsize::new(0),
     }
   }
   pub fn trigger(&mut self, num: u8) {
@@ -38,6 +43,7 @@ impl UnsafeTableEntry {
 
     // I think we can do Relaxed since other reads/writes are protected by the mutex.
     self.again.store(true, Ordering::Relaxed);
+    self.count.fetch_add(1, Ordering::Relaxed);
 
     let mut handlers = match self.handlers.try_lock() {
       None => {
@@ -46,7 +52,7 @@ impl UnsafeTableEntry {
       },
       Some(e) => e
     };
-    println!("Triggering handlers: {:?}", *handlers);
+    debug!("Triggering handlers: {:?}", *handlers);
     if handlers.len() == 0 {
       if num == 0x30 {
         println!("Is early test interrupt, OK.");
@@ -71,4 +77,4 @@ pub fn handle_irq(num: u8) {
   table::handle_irq(num);
 }
 
-pub use self::table::add_handler;
+pub use self::table::{add_handler,stats};
@@ -2,6 +2,7 @@ use super::UnsafeTableEntry;
 use alloc::boxed::Box;
 use collections::vec::Vec;
 use core::fmt;
+use core::sync::atomic::Ordering;
 
 use sync::global_mutex::GlobalMutex;
 
@@ -36,18 +37,27 @@ pub fn init() {
 // TODO: should we even be able to print from IRQ-land?
 // this is like __do_IRQ in Linux
 pub fn handle_irq(num: u8) {
-  print!("\x1B[;31m");
-  println!("[[[Bang! IRQ 0x{:x} handled by sched::irq",num);
+  debug!("\x1B[;31m[[[Bang! IRQ 0x{:x} handled by sched::irq",num);
 
   let mut entry = unsafe { &mut (*(TABLE.unwrap()))[num as usize] };
 
   entry.trigger(num);
-  println!("sched::irq is done with interrupt 0x{:x}]]]",num);
-  print!("\x1B[0m");
+  debug!("sched::irq is done with interrupt 0x{:x}]]]\x1B[0m",num);
 }
 
 pub fn add_handler(num: u8, handler: Box<super::InterruptHandler>) {
   let mut entry = unsafe { &mut (*(TABLE.unwrap()))[num as usize] };
   let mut list = entry.handlers.lock();
   list.push(handler);
+  entry.nhandlers.store(list.len(), Ordering::Relaxed);
+}
+
+// (vector, times it fired, number of handlers) for every vector that has
+// either fired or has a handler.
+pub fn stats() -> Vec<(u8, usize, usize)> {
+  let table = unsafe { &*(TABLE.unwrap()) };
+  table.iter().enumerate()
+    .map(|(num, e)| (num as u8, e.count.load(Ordering::Relaxed), e.nhandlers.load(Ordering::Relaxed)))
+    .filter(|&(_, count, handlers)| count > 0 || handlers > 0)
+    .collect()
 }
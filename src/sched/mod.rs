@@ -1,3 +1,4 @@
+use cmdline;
 use kbuf;
 
 use alloc::boxed::{Box,FnBox};
@@ -5,80 +6,10 @@ use core::prelude::*;
 use core::mem;
 use core;
 use collections::linked_list::LinkedList;
+use collections::vec::Vec;
 
 
 
-// On first access to the global, initialize it using the given expression.
-// You *must* ensure that until the first access returns, no further accesses occur.
-macro_rules! unsafe_lazy_static {
-    ($(#[$attr:meta])* static ref $N:ident : $T:ty = $e:expr; $($t:tt)*) => {
-        unsafe_lazy_static!(PRIV, $(#[$attr])* static ref $N : $T = $e; $($t)*);
-    };
-    ($(#[$attr:meta])* pub static ref $N:ident : $T:ty = $e:expr; $($t:tt)*) => {
-        unsafe_lazy_static!(PUB, $(#[$attr])* static ref $N : $T = $e; $($t)*);
-    };
-    ($VIS:ident, $(#[$attr:meta])* static ref $N:ident : $T:ty = $e:expr; $($t:tt)*) => {
-        unsafe_lazy_static!(MAKE TY, $VIS, $(#[$attr])*, $N);
-        impl ::core::ops::Deref for $N {
-            type Target = $T;
-            fn deref<'a>(&'a self) -> &'a $T {
-                #[inline(always)]
-                fn __static_ref_initialize() -> $T { $e }
-
-                unsafe {
-                    #[inline(always)]
-                    fn require_sync<T: Sync>(_: &T) { }
-
-                    #[inline(always)]
-                    unsafe fn __stability() -> &'static $T {
-                        use core::cell::UnsafeCell;
-
-                        struct SyncCell(UnsafeCell<Option<$T>>);
-                        unsafe impl Sync for SyncCell {}
-
-                        static mut DONE: bool = false;
-
-                        static DATA: SyncCell = SyncCell(UnsafeCell::new(None));
-                        if !DONE {
-                          *DATA.0.get() = Some(__static_ref_initialize());
-                          DONE = true;
-                        }
-                        match *DATA.0.get() {
-                            Some(ref x) => x,
-                            None => core::intrinsics::unreachable(),
-                        }
-                    }
-
-                    let static_ref = __stability();
-                    require_sync(static_ref);
-                    static_ref
-                }
-            }
-        }
-        unsafe_lazy_static!($($t)*);
-    };
-    (MAKE TY, PUB, $(#[$attr:meta])*, $N:ident) => {
-        #[allow(missing_copy_implementations)]
-        #[allow(non_camel_case_types)]
-        #[allow(dead_code)]
-        $(#[$attr])*
-        pub struct $N {__private_field: ()}
-        #[doc(hidden)]
-        pub static $N: $N = $N {__private_field: ()};
-    };
-    (MAKE TY, PRIV, $(#[$attr:meta])*, $N:ident) => {
-        #[allow(missing_copy_implementations)]
-        #[allow(non_camel_case_types)]
-        #[allow(dead_code)]
-        $(#[$attr])*
-        struct $N {__private_field: ()}
-        #[doc(hidden)]
-        static $N: $N = $N {__private_field: ()};
-    };
-    () => ()
-}
-
-
 mod context;
 pub mod blocking;
 pub mod irq;
@@ -117,6 +48,8 @@ impl core::fmt::Debug for Entrypoint {
 struct PerCoreState {
   runnable : LinkedList<Box<Task>>,
   current: Option<Box<Task>>,
+  // Tasks that have exited, kept so that add_task can reuse their stacks
+  exited: Vec<Box<Task>>,
 }
 // FIXME(smp): this should be per-core as well, but we have to access it from C-land, sooo...
 extern {
@@ -125,6 +58,9 @@ extern {
   static mut context_switch_jumpto : u64;
 
   fn context_switch();
+
+  fn task_current_memspace() -> u64;
+  fn task_restore_memspace(cr3: u64);
 }
 // Okay, this should not be a static and Rust rightly slaps us in the face for
 // trying to use a mutable static thingie. However, we don't even have
@@ -135,7 +71,7 @@ extern {
 use sync::global_mutex::GlobalMutex;
 
 unsafe_lazy_static! {
-  static ref theState: GlobalMutex<PerCoreState> = { GlobalMutex::new(PerCoreState{runnable: LinkedList::new(), current: None}) };
+  static ref theState: GlobalMutex<PerCoreState> = { GlobalMutex::new(PerCoreState{runnable: LinkedList::new(), current: None, exited: Vec::new()}) };
 }
 
 use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
@@ -144,8 +80,14 @@ use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 static NEXT_TASK_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
 pub fn kyield() {
+  // Each task comes back in the address space it left in, so that the task
+  // running a process can go on touching its user memory.
+  let memspace = unsafe { task_current_memspace() };
   if reschedule() {
-    unsafe { context_switch(); }
+    unsafe {
+      context_switch();
+      task_restore_memspace(memspace);
+    }
   }
 }
 
@@ -190,10 +132,10 @@ fn reschedule() -> bool {
 
   let mut cur = theState.lock();
 
-  println!("Task {:p} called for a reschedule.", &cur.current);
-  println!("Info: {:?}", &cur.current);
+  debug!("Task {:p} called for a reschedule.", &cur.current);
+  debug!("Info: {:?}", &cur.current);
 
-  println!("LOLZERZ");
+  debug!("LOLZERZ");
 
   // eep
   unsafe { context_switch_oldrsp_dst = 0 };
@@ -201,13 +143,13 @@ fn reschedule() -> bool {
   unsafe { context_switch_jumpto = 0 };
 
 
-  println!("yielding, state={:?}", *cur);
+  debug!("yielding, state={:?}", *cur);
 
-  println!("again: {:?}", *cur);
+  debug!("again: {:?}", *cur);
 
   let nextval = cur.runnable.pop_front();
 
-  println!("next: {:?}", nextval);
+  debug!("next: {:?}", nextval);
 
   let next = match nextval {
     None => {
@@ -233,13 +175,13 @@ fn reschedule() -> bool {
     }
     Some(mut boxt) => {
       unsafe { context_switch_newrsp = *boxt.rsp }; // pointer size..
-      println!("loading sp=0x{:x}", unsafe{*boxt.rsp});
+      debug!("loading sp=0x{:x}", unsafe{*boxt.rsp});
 
       if !boxt.started {
         boxt.started = true;
         unsafe { context_switch_jumpto = starttask as u64 };
       }
-      println!("yielding to {:?}", boxt.desc);
+      debug!("yielding to {:?}", boxt.desc);
       Some(boxt)
     }
   };
@@ -249,6 +191,9 @@ fn reschedule() -> bool {
     Some(mut old_t) => {
       if old_t.exited {
         println!("task marked as exited, not rescheduling");
+        // We're still on its stack until context_switch, but nobody can
+        // hand it out again before then.
+        cur.exited.push(old_t);
       } else {
         unsafe { context_switch_oldrsp_dst = old_t.rsp as u64; }
         cur.runnable.push_back(old_t); // TODO(perf): this allocates!!! LinkedList sucks, apparently
@@ -259,7 +204,7 @@ fn reschedule() -> bool {
     }
   }
 
-  println!("Leaving state: {:?}", *cur);
+  debug!("Leaving state: {:?}", *cur);
 
   true
 }
@@ -269,22 +214,43 @@ pub fn add_task<F, T>(entrypoint: F, desc: &'static str)
   where F: FnOnce() -> T, F: Send + 'static, T: Send + 'static {
   let id = NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst);
 
-  // FIXME: Stack protection is still *totally* needed...
-  let stack = kbuf::new("task stack");
-  let rsp = unsafe { (stack.original_mem as u64) } +0xfff0;
-  println!("Task RSP: 0x{:x}", rsp);
   let main = move || {
     entrypoint();
   };
 
-  let t = box Task{id: id, desc: desc, entrypoint: Some(Entrypoint(box main)),
-    stack: stack,
-    rsp: Box::into_raw(box rsp), // FIXME: leak!
-    started: false,
-    exited: false,
-    parked_for_irq: 0};
+  let mut s = theState.lock();
+  let t = match s.exited.pop() {
+    Some(mut t) => {
+      t.id = id;
+      t.desc = desc;
+      t.entrypoint = Some(Entrypoint(box main));
+      t.started = false;
+      t.exited = false;
+      t.parked_for_irq = 0;
+      unsafe { *t.rsp = (t.stack.original_mem as u64) + 0xfff0; }
+      t
+    },
+    None => {
+      // FIXME: Stack protection is still *totally* needed...
+      let stack = kbuf::new("task stack");
+      let rsp = unsafe { (stack.original_mem as u64) } +0xfff0;
+      box Task{id: id, desc: desc, entrypoint: Some(Entrypoint(box main)),
+        stack: stack,
+        rsp: Box::into_raw(box rsp), // reused along with the stack
+        started: false,
+        exited: false,
+        parked_for_irq: 0}
+    },
+  };
+  println!("Task RSP: 0x{:x}", unsafe { *t.rsp });
+
+  s.runnable.push_back(t);
+}
 
-  theState.lock().runnable.push_back(t);
+// How many steps a user process gets to run before it has to yield, from
+// `sched.timeslice=` on the kernel command line.
+pub fn timeslice() -> usize {
+  cmdline::OPTIONS.timeslice
 }
 
 // Start the scheduler loop, consuming the active thread as the 'boot thread'.
@@ -0,0 +1,333 @@
+use prelude::*;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use collections::btree_map::BTreeMap;
+
+use block;
+use fs::{self,DirEntry,FileType,Filesystem,Ino,Stat};
+use sync::global_mutex::GlobalMutex;
+use super::errno::Errno;
+use super::fd::File;
+use super::files::{SEEK_SET,SEEK_CUR,SEEK_END};
+use super::process::{Handle,Process};
+
+// Opens a device on behalf of a process. Whether every open gets its own
+// File (e.g. to keep a position) or they share one is up to the driver.
+pub type Opener = Box<Fn(&Handle) -> Result<Arc<File>, Errno> + Send + Sync>;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Kind {
+  Char,
+  Block,
+}
+
+impl Kind {
+  fn file_type(self) -> FileType {
+    match self {
+      Kind::Char => FileType::CharDevice,
+      Kind::Block => FileType::BlockDevice,
+    }
+  }
+}
+
+struct Entry {
+  kind: Kind,
+  // The device number that device nodes refer to, see Stat::rdev.
+  rdev: u64,
+  open: Arc<Opener>,
+  // For block devices, so that filesystems can be mounted from them
+  cache: Option<Arc<block::Cache>>,
+}
+
+struct Devices {
+  by_name: BTreeMap<String, Entry>,
+  next_rdev: u64,
+}
+
+unsafe_lazy_static! {
+  static ref DEVICES: GlobalMutex<Devices> = { GlobalMutex::new(Devices { by_name: BTreeMap::new(), next_rdev: 1 }) };
+}
+
+// Make a device show up as /dev/<name>.
+pub fn register(name: &str, kind: Kind, open: Opener) -> Result<(), Errno> {
+  add(name, kind, open, None)
+}
+
+// Every open of a block device gets its own position.
+pub fn register_block(name: &str, cache: Arc<block::Cache>) -> Result<(), Errno> {
+  let c = cache.clone();
+  let n = String::from(name);
+  let open = box move |_: &Handle| Ok(Arc::new(BlockFile::new(&n, c.clone())) as Arc<File>);
+  add(name, Kind::Block, open, Some(cache))
+}
+
+fn add(name: &str, kind: Kind, open: Opener, cache: Option<Arc<block::Cache>>) -> Result<(), Errno> {
+  let mut devices = DEVICES.lock();
+  if devices.by_name.contains_key(name) {
+    return Err(Errno::EEXIST);
+  }
+  let rdev = devices.next_rdev;
+  devices.next_rdev += 1;
+  println!("devfs: registered /dev/{} ({:?}, {})", name, kind, rdev);
+  devices.by_name.insert(String::from(name), Entry { kind: kind, rdev: rdev, open: Arc::new(open), cache: cache });
+  Ok(())
+}
+
+// All registered devices, sorted by name.
+pub fn list() -> Vec<(String, Kind)> {
+  DEVICES.lock().by_name.iter().map(|(name, e)| (name.clone(), e.kind)).collect()
+}
+
+// Open the device that a device node with the given rdev refers to.
+pub fn open(rdev: u64, p: &Handle) -> Result<Arc<File>, Errno> {
+  // Drivers may block in open, so don't hold the lock while calling them.
+  let open = match DEVICES.lock().by_name.values().find(|e| e.rdev == rdev) {
+    Some(e) => e.open.clone(),
+    None => return Err(Errno::ENXIO),
+  };
+  (*open)(p)
+}
+
+// The block device behind a device node, to mount a filesystem from.
+pub fn block_device(rdev: u64) -> Result<Arc<block::Cache>, Errno> {
+  match DEVICES.lock().by_name.values().find(|e| e.rdev == rdev) {
+    Some(&Entry { cache: Some(ref c), .. }) => Ok(c.clone()),
+    Some(_) => Err(Errno::ENOTBLK),
+    None => Err(Errno::ENXIO),
+  }
+}
+
+// The filesystem mounted at /dev: one device node per registered device.
+// A device's inode number is its rdev plus one, the root directory is 1.
+#[derive(Debug)]
+pub struct Devfs;
+
+const ROOT: Ino = 1;
+
+impl Devfs {
+  fn find(ino: Ino) -> Result<(String, Kind, u64), fs::Error> {
+    DEVICES.lock().by_name.iter()
+      .find(|&(_, e)| e.rdev + 1 == ino)
+      .map(|(name, e)| (name.clone(), e.kind, e.rdev))
+      .ok_or(fs::Error::NotFound)
+  }
+}
+
+impl Filesystem for Devfs {
+  fn root(&self) -> Ino {
+    ROOT
+  }
+
+  fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, fs::Error> {
+    if dir != ROOT {
+      return Err(fs::Error::NotADirectory);
+    }
+    DEVICES.lock().by_name.get(name).map(|e| e.rdev + 1).ok_or(fs::Error::NotFound)
+  }
+
+  fn stat(&self, ino: Ino) -> Result<Stat, fs::Error> {
+    if ino == ROOT {
+      return Ok(Stat { ino: ROOT, kind: FileType::Directory, mode: 0o755, nlink: 2, uid: 0, gid: 0, size: 0, rdev: 0, mtime: 0 });
+    }
+    let (_, kind, rdev) = try!(Devfs::find(ino));
+    Ok(Stat { ino: ino, kind: kind.file_type(), mode: 0o666, nlink: 1, uid: 0, gid: 0, size: 0, rdev: rdev, mtime: 0 })
+  }
+
+  // Device nodes are opened through their driver, never read directly.
+  fn read(&self, ino: Ino, _offset: u64, _buf: &mut [u8]) -> Result<usize, fs::Error> {
+    if ino == ROOT { Err(fs::Error::IsADirectory) } else { Err(fs::Error::InvalidArgument) }
+  }
+
+  fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, fs::Error> {
+    if dir != ROOT {
+      return Err(fs::Error::NotADirectory);
+    }
+    Ok(DEVICES.lock().by_name.iter()
+       .map(|(name, e)| DirEntry { name: name.clone(), ino: e.rdev + 1, kind: e.kind.file_type() })
+       .collect())
+  }
+
+  fn readlink(&self, _ino: Ino) -> Result<String, fs::Error> {
+    Err(fs::Error::InvalidArgument)
+  }
+}
+
+// The devices that don't need any hardware.
+pub fn register_builtin() {
+  register("null", Kind::Char, box |_: &Handle| Ok(Arc::new(Null) as Arc<File>)).unwrap();
+  register("zero", Kind::Char, box |_: &Handle| Ok(Arc::new(Zero) as Arc<File>)).unwrap();
+  register("urandom", Kind::Char, box |_: &Handle| Ok(Arc::new(Urandom) as Arc<File>)).unwrap();
+}
+
+#[derive(Debug)]
+struct Null;
+
+impl File for Null {
+  fn read(&self, _p: &Process, _buf: &mut [u8]) -> Result<usize, Errno> {
+    Ok(0)
+  }
+
+  fn write(&self, _p: &Process, buf: &[u8]) -> Result<usize, Errno> {
+    Ok(buf.len())
+  }
+
+  fn path(&self) -> String {
+    String::from("/dev/null")
+  }
+}
+
+#[derive(Debug)]
+struct Zero;
+
+impl File for Zero {
+  fn read(&self, _p: &Process, buf: &mut [u8]) -> Result<usize, Errno> {
+    for b in buf.iter_mut() {
+      *b = 0;
+    }
+    Ok(buf.len())
+  }
+
+  fn write(&self, _p: &Process, buf: &[u8]) -> Result<usize, Errno> {
+    Ok(buf.len())
+  }
+
+  fn path(&self) -> String {
+    String::from("/dev/zero")
+  }
+}
+
+// A xorshift64* generator seeded from the time stamp counter. Good enough to
+// get different numbers on every boot, but nothing to build crypto on.
+#[derive(Debug)]
+struct Urandom;
+
+unsafe_lazy_static! {
+  static ref RANDOM_STATE: GlobalMutex<u64> = { GlobalMutex::new(rdtsc() | 1) };
+}
+
+fn rdtsc() -> u64 {
+  let lo: u32;
+  let hi: u32;
+  unsafe {
+    asm!("rdtsc" : "={eax}" (lo), "={edx}" (hi) : : : "volatile");
+  }
+  ((hi as u64) << 32) | lo as u64
+}
+
+impl File for Urandom {
+  fn read(&self, _p: &Process, buf: &mut [u8]) -> Result<usize, Errno> {
+    let mut x = RANDOM_STATE.lock();
+    for chunk in buf.chunks_mut(8) {
+      *x = *x ^ (*x >> 12);
+      *x = *x ^ (*x << 25);
+      *x = *x ^ (*x >> 27);
+      let v = x.wrapping_mul(0x2545f4914f6cdd1d);
+      for (i, b) in chunk.iter_mut().enumerate() {
+        *b = (v >> (8 * i)) as u8;
+      }
+    }
+    Ok(buf.len())
+  }
+
+  // Like Linux, writing doesn't credit any entropy.
+  fn write(&self, _p: &Process, buf: &[u8]) -> Result<usize, Errno> {
+    Ok(buf.len())
+  }
+
+  fn path(&self) -> String {
+    String::from("/dev/urandom")
+  }
+}
+
+const SECTOR_SIZE: usize = 512;
+
+// A block device opened as a file: reads and writes go through the cache,
+// starting at the position that the previous one left off at.
+#[derive(Debug)]
+pub struct BlockFile {
+  name: String,
+  cache: Arc<block::Cache>,
+  pos: AtomicUsize,
+}
+
+impl BlockFile {
+  pub fn new(name: &str, cache: Arc<block::Cache>) -> Self {
+    BlockFile { name: String::from(name), cache: cache, pos: AtomicUsize::new(0) }
+  }
+}
+
+impl File for BlockFile {
+  // Up to the end of the device. Whole sectors in the middle are fetched
+  // in as few requests as the cache can manage.
+  fn read(&self, _p: &Process, buf: &mut [u8]) -> Result<usize, Errno> {
+    let start = self.pos.load(Ordering::SeqCst);
+    let size = self.cache.sectors() as usize * SECTOR_SIZE;
+    if start >= size {
+      return Ok(0);
+    }
+    let n = cmp::min(buf.len(), size - start);
+    try!(fs::read_bytes(&*self.cache, start as u64, &mut buf[..n]).map_err(|_| Errno::EIO));
+
+    self.pos.store(start + n, Ordering::SeqCst);
+    Ok(n)
+  }
+
+  // Partial sectors are read first and written back with the new bytes.
+  fn write(&self, _p: &Process, buf: &[u8]) -> Result<usize, Errno> {
+    let start = self.pos.load(Ordering::SeqCst);
+    let mut done = 0;
+
+    while done < buf.len() {
+      let pos = start + done;
+      let sector = (pos / SECTOR_SIZE) as u64;
+      if sector >= self.cache.sectors() {
+        if done > 0 {
+          break;
+        }
+        return Err(Errno::ENOSPC);
+      }
+      let offset = pos % SECTOR_SIZE;
+      let n = cmp::min(buf.len() - done, SECTOR_SIZE - offset);
+
+      let mut data = [0u8; SECTOR_SIZE];
+      if n < SECTOR_SIZE {
+        let old = try!(self.cache.get(sector).map_err(|_| Errno::EIO));
+        data.clone_from_slice(&old[..]);
+      }
+      data[offset..offset+n].clone_from_slice(&buf[done..done+n]);
+      match self.cache.write(sector, &data) {
+        Ok(()) => {},
+        Err(_) if done > 0 => break,
+        Err(block::Error::ReadOnly) => return Err(Errno::EROFS),
+        Err(_) => return Err(Errno::EIO),
+      }
+      done += n;
+    }
+
+    self.pos.store(start + done, Ordering::SeqCst);
+    Ok(done)
+  }
+
+  // Anywhere from the start of the device up to its end.
+  fn seek(&self, offset: i64, whence: u32) -> Result<u64, Errno> {
+    let size = (self.cache.sectors() * SECTOR_SIZE as u64) as i64;
+    let base = match whence {
+      SEEK_SET => 0,
+      SEEK_CUR => self.pos.load(Ordering::SeqCst) as i64,
+      SEEK_END => size,
+      _ => return Err(Errno::EINVAL),
+    };
+    let pos = base + offset;
+    if pos < 0 || pos > size {
+      return Err(Errno::EINVAL);
+    }
+    self.pos.store(pos as usize, Ordering::SeqCst);
+    Ok(pos as u64)
+  }
+
+  fn path(&self) -> String {
+    let mut path = String::from("/dev/");
+    path.push_str(&self.name);
+    path
+  }
+}
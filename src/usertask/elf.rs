@@ -2,7 +2,10 @@ use core::{mem,slice};
 use core::ptr::copy;
 use core::iter::{Iterator,IntoIterator};
 
+use prelude::*;
 use super::super::mem::*;
+use super::errno::Errno;
+use super::mm::{AddressSpace,RegionKind,PROT_READ,PROT_WRITE,PROT_EXEC};
 
 #[derive(Debug)]
 pub struct Image {
@@ -12,25 +15,20 @@ pub struct Image {
 
 #[derive(Debug)]
 pub enum Error {
-  InvalidElf
+  InvalidElf,
+  MapFailed(Errno),
 }
 
 macro_rules! ensure {
   ($x:expr) => (if !$x { return Err(Error::InvalidElf)} );
 }
 
-type task = u8; // sigh
-type page = u8; // ...
+const SHT_NOBITS: u32 = 0x8;
+const SHF_WRITE: u64 = 0x1;
+const SHF_EXECINSTR: u64 = 0x4;
 
-extern {
-  fn task_new() -> *mut task;
-  fn task_addpage(t: *mut task, page: *const page);
-  fn task_enter_memspace(t: *mut task);
-}
-
-// Unsafe because we don't respect the address space at all.
-// TODO: actually store which segments and pages we allocated.
-pub unsafe fn load(elf: &[u8]) -> Result<Image, Error> {
+// Unsafe because we switch into the new address space and copy into it.
+pub unsafe fn load(elf: &[u8], mm: &mut AddressSpace) -> Result<Image, Error> {
   println!("Loading Elf from {:p}, len {}", elf.as_ptr(), elf.len());
 
   assert_eq!(64, mem::size_of::<Elf64Header>());
@@ -53,8 +51,6 @@ pub unsafe fn load(elf: &[u8]) -> Result<Image, Error> {
   ensure!(0 < n_sections);
   ensure!(n_sections < 30);
 
-  let task = task_new();
-
   ensure!(elf.len() >= first_section_off
             + n_sections * mem::size_of::<Elf64SectionHeader>());
 
@@ -62,42 +58,53 @@ pub unsafe fn load(elf: &[u8]) -> Result<Image, Error> {
     slice::from_raw_parts(mem::transmute(elf.as_ptr().offset(first_section_off as isize)), n_sections)
   };
 
+  // Sections routinely share pages (think .text and .rodata), so first collect
+  // the page ranges we need, then merge overlapping ones before mapping them.
+  let mut ranges: Vec<(usize, usize, u64)> = vec![];
   for s in sections.iter().filter(|s| s.addr != 0 && s.size != 0) {
     println!("Found a nonempty section: [{:x}; {:x}] {:?}", s.addr, s.size, s);
 
-    let startpage = align_down(s.addr as usize, 0x1000); // align down
-    let endpage = align_up((s.addr+s.size) as usize, 0x1000); // align up
+    let startpage = align_down(s.addr as usize, 0x1000);
+    let endpage = align_up((s.addr+s.size) as usize, 0x1000);
     println!("It needs these pages: {:x} - {:x}", startpage, endpage);
 
     assert!(endpage > startpage); // empty sections are disallowed
 
-    let mut page = startpage;
-    while page < endpage {
-      println!("Adding page at {:x}", page);
+    let mut prot = PROT_READ;
+    if s.flags & SHF_WRITE != 0 { prot = prot | PROT_WRITE; }
+    if s.flags & SHF_EXECINSTR != 0 { prot = prot | PROT_EXEC; }
+    ranges.push((startpage, endpage, prot));
+  }
 
-      // TODO: I believe task_addpage filters duplicates, but unsure
-      task_addpage(task, page as *const u8);
-      page += 0x1000;
+  ranges.sort_by(|a, b| a.0.cmp(&b.0));
+  let mut merged: Vec<(usize, usize, u64)> = vec![];
+  for (start, end, prot) in ranges {
+    if let Some(last) = merged.last_mut() {
+      if start <= last.1 {
+        if end > last.1 { last.1 = end; }
+        last.2 = last.2 | prot;
+        continue;
+      }
     }
+    merged.push((start, end, prot));
   }
 
-  task_addpage(task, 0x50000 as *const u8); // add a dummy page for malloc
-
-  task_enter_memspace(task); // This is super unsafe!
+  let mut image_end = 0;
+  for &(start, end, prot) in merged.iter() {
+    // Map writable for now, so that we can copy the section data in.
+    if let Err(e) = mm.map(start, end, prot | PROT_WRITE, RegionKind::Image) {
+      return Err(Error::MapFailed(e));
+    }
+    image_end = end;
+  }
+  ensure!(image_end != 0);
 
-  let mut brk = 0;
+  mm.enter(); // This is super unsafe!
 
   for section in sections.iter().filter(|s| s.addr != 0 && s.size != 0) {
-    if section._type == 0x8 && section.flags == 0x3 {
-      // This seems to indicate the .data section, i.e. in the traditional memory layout,
-      // the program break will be at the end of this section.
-      // Our current, fairly ridiculous setup is to use this break location as the initial stack.
-      // This means we will inevitably write into our own data. But we'll get to that later.
-
-      // The least we can do is round up to the nearest page bound to cut us some slack.
-      // (We allocated the entire page anyway in the above loop)
-      brk = align_up((section.addr + section.size) as usize, 0x1000);
-      println!("Found data section at {:x}, setting brk to {:x}", section.addr, brk);
+    // .bss and friends have no data in the file, and our pages are already zeroed.
+    if section._type == SHT_NOBITS {
+      continue;
     }
 
     ensure!(elf.len() >= (section.offset as usize) + (section.size as usize));
@@ -107,15 +114,27 @@ pub unsafe fn load(elf: &[u8]) -> Result<Image, Error> {
     copy(data_source, data_dest, section.size as usize);
   }
 
-  // We should have found the break.
-  ensure!(brk != 0);
+  for &(start, end, prot) in merged.iter() {
+    if let Err(e) = mm.mprotect(start, end - start, prot) {
+      return Err(Error::MapFailed(e));
+    }
+  }
+
+  // The program break starts right after the highest loaded section.
+  mm.set_brk_start(image_end);
+  println!("Image ends at {:x}, setting brk to {:x}", image_end, mm.brk(0));
+
+  let rsp = match mm.map_stack() {
+    Ok(rsp) => rsp,
+    Err(e) => return Err(Error::MapFailed(e)),
+  };
 
   // Memory sanity check: This should be the opcode for "push %rbp", the first instruction
   // in _start. Actually a horrible way to check this, but meh.
   let firstword = hdr.entrypoint as *const u16;
   ensure!(*firstword == 0x4855);
 
-  Ok(Image{initial_rip: hdr.entrypoint as usize, initial_rsp: brk})
+  Ok(Image{initial_rip: hdr.entrypoint as usize, initial_rsp: rsp})
 }
 
 #[derive(Debug)]
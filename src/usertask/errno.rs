@@ -0,0 +1,73 @@
+use fs;
+
+// Error numbers as seen by userspace. The values are the same as Linux's, so
+// that an unmodified libc (dietlibc, musl) interprets them correctly.
+// A failing syscall returns the negated error number in %rax.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Errno {
+  EPERM = 1,
+  ENOENT = 2,
+  ESRCH = 3,
+  EINTR = 4,
+  EIO = 5,
+  ENXIO = 6,
+  E2BIG = 7,
+  ENOEXEC = 8,
+  EBADF = 9,
+  ECHILD = 10,
+  ENOMEM = 12,
+  EACCES = 13,
+  EFAULT = 14,
+  ENOTBLK = 15,
+  EEXIST = 17,
+  EXDEV = 18,
+  ENODEV = 19,
+  ENOTDIR = 20,
+  EISDIR = 21,
+  EINVAL = 22,
+  EMFILE = 24,
+  ENOTTY = 25,
+  ENOSPC = 28,
+  ESPIPE = 29,
+  EROFS = 30,
+  EPIPE = 32,
+  ERANGE = 34,
+  ENAMETOOLONG = 36,
+  ENOSYS = 38,
+  ENOTEMPTY = 39,
+  ELOOP = 40,
+}
+
+impl Errno {
+  pub fn as_retval(self) -> u64 {
+    (-(self as i64)) as u64
+  }
+}
+
+impl From<fs::Error> for Errno {
+  fn from(e: fs::Error) -> Errno {
+    match e {
+      fs::Error::ReadFailed(_) | fs::Error::InvalidDiskFormat | fs::Error::Unknown => Errno::EIO,
+      fs::Error::NotFound => Errno::ENOENT,
+      fs::Error::NotADirectory => Errno::ENOTDIR,
+      fs::Error::IsADirectory => Errno::EISDIR,
+      fs::Error::NotEmpty => Errno::ENOTEMPTY,
+      fs::Error::Exists => Errno::EEXIST,
+      fs::Error::ReadOnly => Errno::EROFS,
+      fs::Error::NoSpace => Errno::ENOSPC,
+      fs::Error::NameTooLong => Errno::ENAMETOOLONG,
+      fs::Error::TooManySymlinks => Errno::ELOOP,
+      fs::Error::InvalidArgument => Errno::EINVAL,
+      fs::Error::CrossDevice => Errno::EXDEV,
+      fs::Error::Unsupported => Errno::EINVAL,
+    }
+  }
+}
+
+pub fn retval(r: Result<usize, Errno>) -> u64 {
+  match r {
+    Ok(v) => v as u64,
+    Err(e) => e.as_retval(),
+  }
+}
@@ -0,0 +1,117 @@
+use prelude::*;
+
+use fs::FileType;
+use fs::vfs::{self,Dentry};
+use super::elf;
+use super::errno::Errno;
+use super::files::PATH_MAX;
+use super::mm::AddressSpace;
+use super::process::Process;
+use super::state::UsermodeState;
+
+// Limits on what execve copies out of the old image
+const MAX_ARGS: usize = 1024;
+const MAX_ARG_BYTES: usize = 0x8000;
+
+// Load the program at `path` into a fresh address space, and lay out argv and
+// envp on its stack the way the SysV ABI wants them at process entry:
+// argc, argv[], NULL, envp[], NULL, an empty auxv, then the strings.
+//
+// Leaves the CPU in the new address space, even if that fails half-way.
+pub fn load(cwd: &Arc<Dentry>, path: &str, argv: &[String], envp: &[String]) -> Result<(AddressSpace, UsermodeState), Errno> {
+  let d = try!(vfs::resolve(cwd, path, true));
+  if try!(d.vnode.stat()).kind != FileType::Regular {
+    return Err(Errno::EACCES);
+  }
+  let image = try!(d.vnode.slurp());
+
+  let mut mm = AddressSpace::new();
+  let loaded = match unsafe { elf::load(&image[..], &mut mm) } {
+    Ok(i) => i,
+    Err(elf::Error::MapFailed(e)) => return Err(e),
+    Err(elf::Error::InvalidElf) => return Err(Errno::ENOEXEC),
+  };
+
+  let rsp = try!(push_args(&mm, loaded.initial_rsp, argv, envp));
+  Ok((mm, UsermodeState::new(loaded.initial_rip as u64, rsp as u64)))
+}
+
+fn push_args(mm: &AddressSpace, top: usize, argv: &[String], envp: &[String]) -> Result<usize, Errno> {
+  let bytes = argv.iter().chain(envp.iter()).fold(0, |sum, s| sum + s.len() + 1);
+  if bytes > MAX_ARG_BYTES {
+    return Err(Errno::E2BIG);
+  }
+
+  let mut sp = top;
+  let mut pointers: Vec<u64> = vec![argv.len() as u64];
+  for strings in [argv, envp].iter() {
+    for s in strings.iter() {
+      sp -= s.len() + 1;
+      try!(mm.check_range(sp, s.len() + 1, true));
+      let dest = unsafe { slice::from_raw_parts_mut(sp as *mut u8, s.len() + 1) };
+      dest[..s.len()].clone_from_slice(s.as_bytes());
+      dest[s.len()] = 0;
+      pointers.push(sp as u64);
+    }
+    // Can't push the NULLs right away, the argv pointers come first.
+  }
+  let envp_start = 1 + argv.len();
+  pointers.insert(envp_start, 0); // end of argv
+  pointers.push(0); // end of envp
+  pointers.push(0); // AT_NULL
+  pointers.push(0);
+
+  // %rsp has to be 16-byte aligned at argc.
+  sp = sp & !0xf;
+  if pointers.len() % 2 != 0 {
+    sp -= 8;
+  }
+  sp -= pointers.len() * 8;
+
+  try!(mm.check_range(sp, pointers.len() * 8, true));
+  let dest = unsafe { slice::from_raw_parts_mut(sp as *mut u64, pointers.len()) };
+  dest.clone_from_slice(&pointers[..]);
+  Ok(sp)
+}
+
+// Copy a NULL-terminated array of strings (argv, envp) out of user memory.
+fn read_strings(p: &Process, addr: usize) -> Result<Vec<String>, Errno> {
+  let mut v = vec![];
+  if addr == 0 {
+    return Ok(v);
+  }
+  loop {
+    if v.len() >= MAX_ARGS {
+      return Err(Errno::E2BIG);
+    }
+    let slot = addr + v.len() * 8;
+    try!(p.mm.check_range(slot, 8, false));
+    let ptr = unsafe { *(slot as *const u64) } as usize;
+    if ptr == 0 {
+      return Ok(v);
+    }
+    v.push(try!(p.mm.read_cstr(ptr, MAX_ARG_BYTES)));
+  }
+}
+
+pub fn sys_execve(p: &mut Process, path: usize, argv: usize, envp: usize) -> Result<usize, Errno> {
+  let path = try!(p.mm.read_cstr(path, PATH_MAX));
+  let argv = try!(read_strings(p, argv));
+  let envp = try!(read_strings(p, envp));
+
+  match load(&p.cwd, &path, &argv[..], &envp[..]) {
+    Ok((mm, state)) => {
+      println!("pid {} executing {}", p.pid(), path);
+      p.mm = mm;
+      p.state = state;
+      p.path = path;
+      p.signals.reset_handlers();
+      Ok(0)
+    },
+    Err(e) => {
+      // Back to the old image, which is still intact.
+      unsafe { p.mm.enter(); }
+      Err(e)
+    },
+  }
+}
@@ -0,0 +1,113 @@
+use prelude::*;
+
+use fs::{FileType,Stat};
+use super::errno::Errno;
+use super::process::Process;
+
+pub type Fd = usize;
+
+const MAX_FDS: usize = 64;
+
+// Anything a file descriptor can point to. Implementations do their own
+// locking, since the same object can be shared between descriptors and
+// processes. Reads and writes may block, so they must not be called with a
+// GlobalMutex held.
+pub trait File: Send + Sync + Debug {
+  // Blocking reads and writes give up with EINTR once `p` has a signal to
+  // deal with.
+  fn read(&self, p: &Process, buf: &mut [u8]) -> Result<usize, Errno>;
+  fn write(&self, p: &Process, buf: &[u8]) -> Result<usize, Errno>;
+
+  // Device-specific requests; `arg` is usually a user pointer.
+  fn ioctl(&self, _p: &Process, _req: u64, _arg: usize) -> Result<usize, Errno> {
+    Err(Errno::ENOTTY)
+  }
+
+  // Only files on a filesystem have a position.
+  fn seek(&self, _offset: i64, _whence: u32) -> Result<u64, Errno> {
+    Err(Errno::ESPIPE)
+  }
+
+  // What fstat reports. Anything that isn't on a filesystem looks like an
+  // anonymous character device.
+  fn stat(&self) -> Result<(u64, Stat), Errno> {
+    Ok((0, Stat { ino: 0, kind: FileType::CharDevice, mode: 0o600, nlink: 1, uid: 0, gid: 0, size: 0, rdev: 0, mtime: 0 }))
+  }
+
+  fn truncate(&self, _size: u64) -> Result<(), Errno> {
+    Err(Errno::EINVAL)
+  }
+
+  // Fill `buf` with linux_dirent64 records, see files.rs.
+  fn getdents(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+    Err(Errno::ENOTDIR)
+  }
+
+  // Where /proc/<pid>/fd/<fd> points to. Like on Linux, that's not
+  // necessarily a path that can be opened again.
+  fn path(&self) -> String {
+    String::from("anon_inode:[file]")
+  }
+}
+
+// Per-process file descriptor table. Closing a descriptor drops our reference
+// to the file; the file itself goes away with its last descriptor, in
+// whichever process that is. A forked child gets a clone.
+#[derive(Debug,Clone)]
+pub struct Fdt {
+  files: Vec<Option<Arc<File>>>,
+}
+
+impl Fdt {
+  pub fn new() -> Self {
+    Fdt { files: vec![] }
+  }
+
+  // Install the file at the lowest free descriptor.
+  pub fn insert(&mut self, f: Arc<File>) -> Result<Fd, Errno> {
+    if let Some(fd) = self.files.iter().position(|x| x.is_none()) {
+      self.files[fd] = Some(f);
+      return Ok(fd);
+    }
+    if self.files.len() >= MAX_FDS {
+      return Err(Errno::EMFILE);
+    }
+    self.files.push(Some(f));
+    Ok(self.files.len() - 1)
+  }
+
+  pub fn get(&self, fd: Fd) -> Result<Arc<File>, Errno> {
+    match self.files.get(fd) {
+      Some(&Some(ref f)) => Ok(f.clone()),
+      _ => Err(Errno::EBADF),
+    }
+  }
+
+  pub fn close(&mut self, fd: Fd) -> Result<(), Errno> {
+    if fd >= self.files.len() || self.files[fd].is_none() {
+      return Err(Errno::EBADF);
+    }
+    self.files[fd] = None;
+    Ok(())
+  }
+
+  // The open descriptors, lowest first.
+  pub fn list(&self) -> Vec<(Fd, Arc<File>)> {
+    self.files.iter().enumerate()
+      .filter_map(|(fd, f)| f.as_ref().map(|f| (fd, f.clone())))
+      .collect()
+  }
+
+  pub fn dup2(&mut self, old: Fd, new: Fd) -> Result<Fd, Errno> {
+    let f = try!(self.get(old));
+    if new >= MAX_FDS {
+      return Err(Errno::EBADF);
+    }
+    while self.files.len() <= new {
+      self.files.push(None);
+    }
+    // Replacing the slot drops (and thereby closes) whatever was there.
+    self.files[new] = Some(f);
+    Ok(new)
+  }
+}
@@ -0,0 +1,401 @@
+use prelude::*;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use fs::{self,FileType,Stat};
+use fs::vfs::{self,Dentry};
+use super::devfs;
+use super::errno::Errno;
+use super::fd::{Fd,File};
+use super::process::Process;
+
+pub const PATH_MAX: usize = 4096;
+
+// How much file data a tmpfs can hold, at most: the kernel heap is only
+// a few MiB, and a tmpfs also stops growing when that runs low.
+pub const TMPFS_SIZE: usize = 1024 * 1024;
+
+// open(2) flags, as on Linux
+const O_ACCMODE: u64 = 0o3;
+const O_RDONLY: u64 = 0o0;
+const O_WRONLY: u64 = 0o1;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const O_DIRECTORY: u64 = 0o200000;
+const O_NOFOLLOW: u64 = 0o400000;
+
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+
+// A file or directory opened through the VFS. Every open() gets its own
+// position, which dup2'd descriptors share.
+#[derive(Debug)]
+pub struct OpenFile {
+  dentry: Arc<Dentry>,
+  flags: u64,
+  pos: AtomicUsize,
+}
+
+impl OpenFile {
+  fn readable(&self) -> bool {
+    self.flags & O_ACCMODE != O_WRONLY
+  }
+
+  fn writable(&self) -> bool {
+    self.flags & O_ACCMODE != O_RDONLY
+  }
+}
+
+// The last descriptor referring to it is gone.
+impl Drop for OpenFile {
+  fn drop(&mut self) {
+    self.dentry.vnode.fs.release(self.dentry.vnode.ino);
+  }
+}
+
+impl File for OpenFile {
+  fn read(&self, _p: &Process, buf: &mut [u8]) -> Result<usize, Errno> {
+    if !self.readable() {
+      return Err(Errno::EBADF);
+    }
+    let pos = self.pos.load(Ordering::SeqCst);
+    let n = try!(self.dentry.vnode.read(pos as u64, buf));
+    self.pos.store(pos + n, Ordering::SeqCst);
+    Ok(n)
+  }
+
+  fn write(&self, _p: &Process, buf: &[u8]) -> Result<usize, Errno> {
+    if !self.writable() {
+      return Err(Errno::EBADF);
+    }
+    let pos = if self.flags & O_APPEND != 0 {
+      try!(self.dentry.vnode.stat()).size as usize
+    } else {
+      self.pos.load(Ordering::SeqCst)
+    };
+    let n = try!(self.dentry.vnode.write(pos as u64, buf));
+    self.pos.store(pos + n, Ordering::SeqCst);
+    Ok(n)
+  }
+
+  fn seek(&self, offset: i64, whence: u32) -> Result<u64, Errno> {
+    let base = match whence {
+      SEEK_SET => 0,
+      SEEK_CUR => self.pos.load(Ordering::SeqCst) as i64,
+      SEEK_END => try!(self.dentry.vnode.stat()).size as i64,
+      _ => return Err(Errno::EINVAL),
+    };
+    let pos = base + offset;
+    if pos < 0 {
+      return Err(Errno::EINVAL);
+    }
+    self.pos.store(pos as usize, Ordering::SeqCst);
+    Ok(pos as u64)
+  }
+
+  fn stat(&self) -> Result<(u64, Stat), Errno> {
+    let st = try!(self.dentry.vnode.stat());
+    Ok((vfs::dev_id(&self.dentry.vnode.fs), st))
+  }
+
+  fn truncate(&self, size: u64) -> Result<(), Errno> {
+    if !self.writable() {
+      return Err(Errno::EINVAL);
+    }
+    if try!(self.dentry.vnode.stat()).kind != FileType::Regular {
+      return Err(Errno::EINVAL);
+    }
+    Ok(try!(self.dentry.vnode.truncate(size)))
+  }
+
+  // For directories, the position counts entries, including "." and "..".
+  fn getdents(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+    let v = &self.dentry.vnode;
+    let mut entries = vec![
+      fs::DirEntry { name: String::from("."), ino: v.ino, kind: FileType::Directory },
+      fs::DirEntry { name: String::from(".."), ino: Dentry::parent(&self.dentry).vnode.ino, kind: FileType::Directory },
+    ];
+    entries.extend(try!(v.readdir()).into_iter());
+
+    let mut index = self.pos.load(Ordering::SeqCst);
+    let mut written = 0;
+    while index < entries.len() {
+      match put_dirent(&mut buf[written..], &entries[index], index as i64 + 1) {
+        Some(n) => written += n,
+        None if written == 0 => return Err(Errno::EINVAL), // buffer too small
+        None => break,
+      }
+      index += 1;
+    }
+    self.pos.store(index, Ordering::SeqCst);
+    Ok(written)
+  }
+
+  fn path(&self) -> String {
+    self.dentry.path()
+  }
+}
+
+// Append a struct linux_dirent64 to buf, if it fits.
+fn put_dirent(buf: &mut [u8], e: &fs::DirEntry, next: i64) -> Option<usize> {
+  let header = 8 + 8 + 2 + 1;
+  let reclen = (header + e.name.len() + 1 + 7) & !7;
+  if reclen > buf.len() {
+    return None;
+  }
+
+  let d_type: u8 = match e.kind {
+    FileType::Fifo => 1,
+    FileType::CharDevice => 2,
+    FileType::Directory => 4,
+    FileType::BlockDevice => 6,
+    FileType::Regular => 8,
+    FileType::Symlink => 10,
+    FileType::Socket => 12,
+  };
+
+  for b in buf[..reclen].iter_mut() {
+    *b = 0;
+  }
+  unsafe {
+    *(buf.as_mut_ptr() as *mut u64) = e.ino;
+    *(buf.as_mut_ptr().offset(8) as *mut i64) = next;
+    *(buf.as_mut_ptr().offset(16) as *mut u16) = reclen as u16;
+  }
+  buf[18] = d_type;
+  buf[header..header + e.name.len()].clone_from_slice(e.name.as_bytes());
+  Some(reclen)
+}
+
+// struct stat on x86_64 Linux
+#[repr(C)]
+struct UserStat {
+  dev: u64,
+  ino: u64,
+  nlink: u64,
+  mode: u32,
+  uid: u32,
+  gid: u32,
+  pad0: u32,
+  rdev: u64,
+  size: i64,
+  blksize: i64,
+  blocks: i64,
+  atime: u64,
+  atime_nsec: u64,
+  mtime: u64,
+  mtime_nsec: u64,
+  ctime: u64,
+  ctime_nsec: u64,
+  unused: [i64; 3],
+}
+
+fn put_stat(p: &Process, addr: usize, dev: u64, st: &Stat) -> Result<usize, Errno> {
+  try!(p.mm.check_range(addr, mem::size_of::<UserStat>(), true));
+  let u = UserStat {
+    dev: dev,
+    ino: st.ino,
+    nlink: st.nlink as u64,
+    mode: fs::type_bits(st.kind) | st.mode,
+    uid: st.uid,
+    gid: st.gid,
+    pad0: 0,
+    rdev: st.rdev,
+    size: st.size as i64,
+    blksize: 512,
+    blocks: ((st.size + 511) / 512) as i64,
+    atime: st.mtime,
+    atime_nsec: 0,
+    mtime: st.mtime,
+    mtime_nsec: 0,
+    ctime: st.mtime,
+    ctime_nsec: 0,
+    unused: [0; 3],
+  };
+  unsafe { *(addr as *mut UserStat) = u; }
+  Ok(0)
+}
+
+pub fn sys_open(p: &mut Process, path: usize, flags: u64, mode: u64) -> Result<usize, Errno> {
+  let path = try!(p.mm.read_cstr(path, PATH_MAX));
+
+  let dentry = match vfs::resolve(&p.cwd, &path, flags & O_NOFOLLOW == 0) {
+    Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(Errno::EEXIST),
+    Ok(d) => d,
+    Err(fs::Error::NotFound) if flags & O_CREAT != 0 => {
+      let (dir, name) = try!(vfs::resolve_parent(&p.cwd, &path));
+      try!(dir.vnode.fs.create(dir.vnode.ino, &name, FileType::Regular, (mode & 0o7777) as u32, 0));
+      try!(vfs::resolve(&dir, &name, false))
+    },
+    Err(e) => return Err(Errno::from(e)),
+  };
+
+  let st = try!(dentry.vnode.stat());
+  match st.kind {
+    FileType::CharDevice | FileType::BlockDevice => {
+      let f = try!(devfs::open(st.rdev, &p.handle));
+      return p.fds.insert(f);
+    },
+    FileType::Directory if flags & O_ACCMODE != O_RDONLY => return Err(Errno::EISDIR),
+    FileType::Symlink => return Err(Errno::ELOOP), // O_NOFOLLOW
+    FileType::Directory => {},
+    _ if flags & O_DIRECTORY != 0 => return Err(Errno::ENOTDIR),
+    _ => {},
+  }
+
+  if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY && st.kind == FileType::Regular {
+    try!(dentry.vnode.truncate(0));
+  }
+
+  try!(dentry.vnode.fs.open(dentry.vnode.ino));
+  p.fds.insert(Arc::new(OpenFile { dentry: dentry, flags: flags, pos: AtomicUsize::new(0) }))
+}
+
+pub fn sys_stat(p: &mut Process, path: usize, buf: usize, follow: bool) -> Result<usize, Errno> {
+  let path = try!(p.mm.read_cstr(path, PATH_MAX));
+  let d = try!(vfs::resolve(&p.cwd, &path, follow));
+  let st = try!(d.vnode.stat());
+  put_stat(p, buf, vfs::dev_id(&d.vnode.fs), &st)
+}
+
+pub fn sys_fstat(p: &mut Process, fd: Fd, buf: usize) -> Result<usize, Errno> {
+  let (dev, st) = try!(try!(p.fds.get(fd)).stat());
+  put_stat(p, buf, dev, &st)
+}
+
+pub fn sys_lseek(p: &mut Process, fd: Fd, offset: i64, whence: u64) -> Result<usize, Errno> {
+  let f = try!(p.fds.get(fd));
+  f.seek(offset, whence as u32).map(|pos| pos as usize)
+}
+
+pub fn sys_getdents(p: &mut Process, fd: Fd, buf: usize, len: usize) -> Result<usize, Errno> {
+  let f = try!(p.fds.get(fd));
+  try!(p.mm.check_range(buf, len, true));
+  f.getdents(unsafe { slice::from_raw_parts_mut(buf as *mut u8, len) })
+}
+
+pub fn sys_chdir(p: &mut Process, path: usize) -> Result<usize, Errno> {
+  let path = try!(p.mm.read_cstr(path, PATH_MAX));
+  let d = try!(vfs::resolve(&p.cwd, &path, true));
+  if try!(d.vnode.stat()).kind != FileType::Directory {
+    return Err(Errno::ENOTDIR);
+  }
+  p.cwd = d;
+  Ok(0)
+}
+
+// Like the Linux syscall, returns the length including the NUL.
+pub fn sys_getcwd(p: &mut Process, buf: usize, size: usize) -> Result<usize, Errno> {
+  let path = p.cwd.path();
+  if path.len() + 1 > size {
+    return Err(Errno::ERANGE);
+  }
+  try!(p.mm.check_range(buf, path.len() + 1, true));
+  let out = unsafe { slice::from_raw_parts_mut(buf as *mut u8, path.len() + 1) };
+  out[..path.len()].clone_from_slice(path.as_bytes());
+  out[path.len()] = 0;
+  Ok(path.len() + 1)
+}
+
+pub fn sys_readlink(p: &mut Process, path: usize, buf: usize, size: usize) -> Result<usize, Errno> {
+  let path = try!(p.mm.read_cstr(path, PATH_MAX));
+  let d = try!(vfs::resolve(&p.cwd, &path, false));
+  if try!(d.vnode.stat()).kind != FileType::Symlink {
+    return Err(Errno::EINVAL);
+  }
+  let target = try!(d.vnode.readlink());
+
+  // No NUL terminator, and silently truncated
+  let n = if target.len() < size { target.len() } else { size };
+  try!(p.mm.check_range(buf, n, true));
+  let out = unsafe { slice::from_raw_parts_mut(buf as *mut u8, n) };
+  out.clone_from_slice(&target.as_bytes()[..n]);
+  Ok(n)
+}
+
+pub fn sys_mkdir(p: &mut Process, path: usize, mode: u64) -> Result<usize, Errno> {
+  let path = try!(p.mm.read_cstr(path, PATH_MAX));
+  let (dir, name) = try!(vfs::resolve_parent(&p.cwd, &path));
+  try!(dir.vnode.fs.create(dir.vnode.ino, &name, FileType::Directory, (mode & 0o7777) as u32, 0));
+  Ok(0)
+}
+
+pub fn sys_rmdir(p: &mut Process, path: usize) -> Result<usize, Errno> {
+  let path = try!(p.mm.read_cstr(path, PATH_MAX));
+  let (dir, name) = try!(vfs::resolve_parent(&p.cwd, &path));
+  try!(dir.vnode.fs.rmdir(dir.vnode.ino, &name));
+  Ok(0)
+}
+
+pub fn sys_unlink(p: &mut Process, path: usize) -> Result<usize, Errno> {
+  let path = try!(p.mm.read_cstr(path, PATH_MAX));
+  let (dir, name) = try!(vfs::resolve_parent(&p.cwd, &path));
+  try!(dir.vnode.fs.unlink(dir.vnode.ino, &name));
+  Ok(0)
+}
+
+pub fn sys_rename(p: &mut Process, from: usize, to: usize) -> Result<usize, Errno> {
+  let from = try!(p.mm.read_cstr(from, PATH_MAX));
+  let to = try!(p.mm.read_cstr(to, PATH_MAX));
+  let (from_dir, from_name) = try!(vfs::resolve_parent(&p.cwd, &from));
+  let (to_dir, to_name) = try!(vfs::resolve_parent(&p.cwd, &to));
+  if !vfs::same_fs(&from_dir.vnode.fs, &to_dir.vnode.fs) {
+    return Err(Errno::EXDEV);
+  }
+  try!(from_dir.vnode.fs.rename(from_dir.vnode.ino, &from_name, to_dir.vnode.ino, &to_name));
+  Ok(0)
+}
+
+pub fn sys_symlink(p: &mut Process, target: usize, path: usize) -> Result<usize, Errno> {
+  let target = try!(p.mm.read_cstr(target, PATH_MAX));
+  let path = try!(p.mm.read_cstr(path, PATH_MAX));
+  let (dir, name) = try!(vfs::resolve_parent(&p.cwd, &path));
+  try!(dir.vnode.fs.symlink(dir.vnode.ino, &name, &target));
+  Ok(0)
+}
+
+pub fn sys_ftruncate(p: &mut Process, fd: Fd, size: u64) -> Result<usize, Errno> {
+  try!(try!(p.fds.get(fd)).truncate(size));
+  Ok(0)
+}
+
+// Like mount(2) without the flags. Only tmpfs works without a source, the
+// others need a block device.
+pub fn sys_mount(p: &mut Process, source: usize, target: usize, fstype: usize) -> Result<usize, Errno> {
+  let fstype = try!(p.mm.read_cstr(fstype, 32));
+  let target = try!(vfs::resolve(&p.cwd, &try!(p.mm.read_cstr(target, PATH_MAX)), true));
+
+  let fs = match &fstype[..] {
+    "tmpfs" => Arc::new(fs::Tmpfs::new(TMPFS_SIZE)) as Arc<fs::Filesystem>,
+    "ext2" | "vfat" | "cpio" => {
+      let source = try!(p.mm.read_cstr(source, PATH_MAX));
+      let st = try!(try!(vfs::resolve(&p.cwd, &source, true)).vnode.stat());
+      if st.kind != FileType::BlockDevice {
+        return Err(Errno::ENOTBLK);
+      }
+      let dev = try!(devfs::block_device(st.rdev));
+      match &fstype[..] {
+        "ext2" => Arc::new(try!(fs::Ext2::new(dev))) as Arc<fs::Filesystem>,
+        "vfat" => Arc::new(try!(fs::Fat::new(dev))) as Arc<fs::Filesystem>,
+        _ => Arc::new(try!(fs::Cpiofs::new(dev))) as Arc<fs::Filesystem>,
+      }
+    },
+    _ => return Err(Errno::ENODEV),
+  };
+
+  try!(vfs::mount(&target.path(), fs));
+  Ok(0)
+}
+
+// Write back what every mounted filesystem has in memory.
+pub fn sys_sync(_p: &mut Process) -> Result<usize, Errno> {
+  for (path, fs) in vfs::mounts() {
+    if let Err(e) = fs.sync() {
+      println!("sync: {} failed: {:?}", path, e);
+    }
+  }
+  Ok(0)
+}
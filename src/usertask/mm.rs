@@ -0,0 +1,393 @@
+use prelude::*;
+use core::cmp;
+
+use mem;
+use super::errno::Errno;
+
+// The user part of a task's address space. Everything we hand out has to fit
+// into the first PDPT entry that task.c sets up, i.e. below 1GB.
+const USER_END: usize = 0x40000000;
+
+const PAGE: usize = 0x1000;
+
+// Round up to a whole page. Only for addresses we already know are below
+// USER_END; lengths from userspace go through user_range_end instead.
+fn page_up(address: usize) -> usize {
+  (address + PAGE - 1) & !(PAGE - 1)
+}
+
+// The end of [addr; addr+len) rounded up to a whole page, or None if that
+// overflows or ends above USER_END.
+fn user_range_end(addr: usize, len: usize) -> Option<usize> {
+  len.checked_add(PAGE - 1)
+    .and_then(|len| addr.checked_add(len & !(PAGE - 1)))
+    .and_then(|end| if end <= USER_END { Some(end) } else { None })
+}
+
+// Memory that mapping user pages must leave to the rest of the kernel. The
+// heap panics when it runs out, so we'd rather fail the mapping.
+const RESERVE: usize = 0x20000;
+
+// Whether there's enough heap left for `len` bytes of user pages, and the
+// page tables that might come with them.
+fn fits_in_memory(len: usize) -> bool {
+  let usage = mem::usage();
+  let pages = len / PAGE;
+  let needed = (pages + pages / 512 + 2) * PAGE + RESERVE;
+  usage.total - usage.used >= needed
+}
+
+// Non-fixed anonymous mappings are placed at the first gap above this address.
+const MMAP_BASE: usize = 0x10000000;
+
+const STACK_TOP: usize = USER_END;
+const STACK_SIZE: usize = 0x10000;
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+// Page table entry bits, see task_addpage
+const PTE_WRITABLE: u64 = 2;
+const PTE_USER: u64 = 4;
+
+type task = u8; // sigh
+
+extern {
+  fn task_new() -> *mut task;
+  fn task_free(t: *mut task);
+  fn task_copy(src: *mut task) -> *mut task;
+  fn task_addpage(t: *mut task, page: *const u8, flags: u64) -> i32;
+  fn task_removepage(t: *mut task, page: *const u8) -> i32;
+  fn task_protectpage(t: *mut task, page: *const u8, flags: u64) -> i32;
+  fn task_enter_memspace(t: *mut task);
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum RegionKind {
+  Image,
+  Heap,
+  Stack,
+  Anonymous,
+}
+
+// A contiguous, page-aligned range [start; end) of mapped user memory.
+#[derive(Debug,Clone)]
+pub struct Region {
+  pub start: usize,
+  pub end: usize,
+  pub prot: u64,
+  pub kind: RegionKind,
+}
+
+// We don't have NX enabled, so anything that's readable is also executable.
+// PROT_NONE pages stay present, but aren't accessible from ring 3.
+fn pte_flags(prot: u64) -> u64 {
+  let mut flags = 0;
+  if prot & (PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+    flags = flags | PTE_USER;
+  }
+  if prot & PROT_WRITE != 0 {
+    flags = flags | PTE_WRITABLE;
+  }
+  flags
+}
+
+#[derive(Debug)]
+pub struct AddressSpace {
+  task: *mut task,
+
+  // sorted by start address, never overlapping
+  regions: Vec<Region>,
+
+  brk_start: usize,
+  brk: usize,
+}
+
+// The task pointer is only ever dereferenced by the C side while we hold
+// the AddressSpace mutably.
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+  pub fn new() -> Self {
+    AddressSpace { task: unsafe { task_new() }, regions: vec![], brk_start: 0, brk: 0 }
+  }
+
+  // A copy of every mapped page, for fork.
+  pub fn fork(&self) -> Result<AddressSpace, Errno> {
+    let size = self.regions.iter().fold(0, |sum, r| sum + (r.end - r.start));
+    if !fits_in_memory(size) {
+      return Err(Errno::ENOMEM);
+    }
+    Ok(AddressSpace {
+      task: unsafe { task_copy(self.task) },
+      regions: self.regions.clone(),
+      brk_start: self.brk_start,
+      brk: self.brk,
+    })
+  }
+
+  pub fn regions(&self) -> &[Region] {
+    &self.regions[..]
+  }
+
+  // Switch the CPU over to this address space.
+  // Unsafe because every user pointer we're holding changes its meaning.
+  pub unsafe fn enter(&self) {
+    task_enter_memspace(self.task);
+  }
+
+  fn is_free(&self, start: usize, end: usize) -> bool {
+    start < end && end <= USER_END &&
+      !self.regions.iter().any(|r| r.start < end && start < r.end)
+  }
+
+  // Make sure that userspace is allowed to access [addr; addr+len) before we
+  // touch it on its behalf.
+  pub fn check_range(&self, addr: usize, len: usize, write: bool) -> Result<(), Errno> {
+    if addr.checked_add(len).map_or(true, |end| end > USER_END) {
+      return Err(Errno::EFAULT);
+    }
+
+    let mut pos = addr;
+    let end = addr + len;
+    while pos < end {
+      match self.regions.iter().find(|r| r.start <= pos && pos < r.end) {
+        Some(r) => {
+          let needed = if write { PROT_WRITE } else { PROT_READ };
+          if r.prot & needed == 0 {
+            return Err(Errno::EFAULT);
+          }
+          pos = r.end;
+        },
+        None => return Err(Errno::EFAULT),
+      }
+    }
+    Ok(())
+  }
+
+  // Copy a NUL-terminated string out of user memory.
+  pub fn read_cstr(&self, addr: usize, max: usize) -> Result<String, Errno> {
+    let mut bytes = vec![];
+    while bytes.len() < max {
+      try!(self.check_range(addr + bytes.len(), 1, false));
+      let c = unsafe { *((addr + bytes.len()) as *const u8) };
+      if c == 0 {
+        return String::from_utf8(bytes).map_err(|_| Errno::EINVAL);
+      }
+      bytes.push(c);
+    }
+    Err(Errno::ENAMETOOLONG)
+  }
+
+  // Map fresh zeroed pages for [start; end), which must be page-aligned and unused.
+  pub fn map(&mut self, start: usize, end: usize, prot: u64, kind: RegionKind) -> Result<(), Errno> {
+    if start & (PAGE-1) != 0 || end & (PAGE-1) != 0 {
+      return Err(Errno::EINVAL);
+    }
+    if !self.is_free(start, end) || !fits_in_memory(end - start) {
+      return Err(Errno::ENOMEM);
+    }
+
+    let mut page = start;
+    while page < end {
+      if unsafe { task_addpage(self.task, page as *const u8, pte_flags(prot)) } != 0 {
+        // roll back what we have mapped so far
+        let mut p = start;
+        while p < page {
+          unsafe { task_removepage(self.task, p as *const u8); }
+          p += PAGE;
+        }
+        return Err(Errno::ENOMEM);
+      }
+      page += PAGE;
+    }
+
+    self.insert(Region { start: start, end: end, prot: prot, kind: kind });
+    Ok(())
+  }
+
+  fn insert(&mut self, r: Region) {
+    let pos = self.regions.iter().position(|x| x.start > r.start).unwrap_or(self.regions.len());
+    self.regions.insert(pos, r);
+  }
+
+  // Remove [start; end) from the region list, splitting regions that only
+  // partially overlap. Returns the removed parts. Doesn't touch page tables.
+  fn carve(&mut self, start: usize, end: usize) -> Vec<Region> {
+    let mut removed = vec![];
+    let mut kept = vec![];
+
+    for r in self.regions.drain(..) {
+      if r.end <= start || end <= r.start {
+        kept.push(r);
+        continue;
+      }
+      if r.start < start {
+        kept.push(Region { start: r.start, end: start, prot: r.prot, kind: r.kind });
+      }
+      if end < r.end {
+        kept.push(Region { start: end, end: r.end, prot: r.prot, kind: r.kind });
+      }
+      removed.push(Region { start: cmp::max(r.start, start), end: cmp::min(r.end, end), prot: r.prot, kind: r.kind });
+    }
+
+    kept.sort_by(|a, b| a.start.cmp(&b.start));
+    self.regions = kept;
+    removed
+  }
+
+  fn unmap(&mut self, start: usize, end: usize) {
+    for r in self.carve(start, end) {
+      let mut page = r.start;
+      while page < r.end {
+        unsafe { task_removepage(self.task, page as *const u8); }
+        page += PAGE;
+      }
+    }
+  }
+
+  // Set up the initial user stack and return the initial stack pointer.
+  pub fn map_stack(&mut self) -> Result<usize, Errno> {
+    try!(self.map(STACK_TOP - STACK_SIZE, STACK_TOP, PROT_READ | PROT_WRITE, RegionKind::Stack));
+    Ok(STACK_TOP)
+  }
+
+  // Called by the ELF loader once the image is mapped; the heap starts
+  // on the page after the image.
+  pub fn set_brk_start(&mut self, start: usize) {
+    self.brk_start = page_up(start);
+    self.brk = self.brk_start;
+  }
+
+  // Like Linux's brk(2): returns the new program break on success, and the
+  // current one if `new` is out of range or the memory couldn't be mapped.
+  // Passing 0 queries the current break.
+  pub fn brk(&mut self, new: usize) -> usize {
+    if new < self.brk_start || new >= USER_END {
+      return self.brk;
+    }
+
+    let old_end = page_up(self.brk);
+    let new_end = page_up(new);
+
+    if new_end > old_end {
+      if self.map(old_end, new_end, PROT_READ | PROT_WRITE, RegionKind::Heap).is_err() {
+        return self.brk;
+      }
+    } else if new_end < old_end {
+      self.unmap(new_end, old_end);
+    }
+
+    self.brk = new;
+    self.brk
+  }
+
+  fn find_gap(&self, hint: usize, len: usize) -> Option<usize> {
+    if hint != 0 && hint.checked_add(len).map_or(false, |end| self.is_free(hint, end)) {
+      return Some(hint);
+    }
+
+    let mut candidate = MMAP_BASE;
+    for r in self.regions.iter() {
+      if r.end <= candidate {
+        continue;
+      }
+      if candidate + len <= r.start {
+        break;
+      }
+      candidate = r.end;
+    }
+
+    if candidate + len <= STACK_TOP - STACK_SIZE {
+      Some(candidate)
+    } else {
+      None
+    }
+  }
+
+  // Only private anonymous mappings are supported for now; there is nothing
+  // file-backed that we could map yet.
+  pub fn mmap(&mut self, addr: usize, len: usize, prot: u64, flags: u64, _fd: i64, off: u64) -> Result<usize, Errno> {
+    if len == 0 || addr & (PAGE-1) != 0 || off & (PAGE as u64 - 1) != 0 {
+      return Err(Errno::EINVAL);
+    }
+    if flags & MAP_ANONYMOUS == 0 || flags & MAP_SHARED != 0 || flags & MAP_PRIVATE == 0 {
+      return Err(Errno::EINVAL);
+    }
+
+    let len = match user_range_end(0, len) {
+      Some(len) if len < USER_END => len,
+      _ => return Err(Errno::ENOMEM),
+    };
+
+    let start = if flags & MAP_FIXED != 0 {
+      if addr == 0 || user_range_end(addr, len).is_none() {
+        return Err(Errno::EINVAL);
+      }
+      // MAP_FIXED replaces whatever was mapped there before, but only once
+      // we know that the new mapping is going to work.
+      if !fits_in_memory(len) {
+        return Err(Errno::ENOMEM);
+      }
+      self.unmap(addr, addr + len);
+      addr
+    } else {
+      match self.find_gap(addr, len) {
+        Some(a) => a,
+        None => return Err(Errno::ENOMEM),
+      }
+    };
+
+    try!(self.map(start, start + len, prot, RegionKind::Anonymous));
+    Ok(start)
+  }
+
+  pub fn munmap(&mut self, addr: usize, len: usize) -> Result<(), Errno> {
+    let end = match user_range_end(addr, len) {
+      Some(end) if len != 0 && addr & (PAGE-1) == 0 => end,
+      _ => return Err(Errno::EINVAL),
+    };
+    self.unmap(addr, end);
+    Ok(())
+  }
+
+  pub fn mprotect(&mut self, addr: usize, len: usize, prot: u64) -> Result<(), Errno> {
+    let end = match user_range_end(addr, len) {
+      Some(end) if addr & (PAGE-1) == 0 => end,
+      _ => return Err(Errno::EINVAL),
+    };
+
+    let parts = self.carve(addr, end);
+    let covered = parts.iter().fold(0, |sum, r| sum + (r.end - r.start));
+    let complete = covered == end - addr;
+
+    for mut r in parts {
+      if complete {
+        r.prot = prot;
+        let mut page = r.start;
+        while page < r.end {
+          unsafe { task_protectpage(self.task, page as *const u8, pte_flags(prot)); }
+          page += PAGE;
+        }
+      }
+      self.insert(r);
+    }
+
+    // Like Linux, fail if part of the range isn't mapped at all.
+    if complete { Ok(()) } else { Err(Errno::ENOMEM) }
+  }
+}
+
+impl Drop for AddressSpace {
+  fn drop(&mut self) {
+    // Gives back every page, so it doesn't matter that the regions aren't
+    // unmapped one by one.
+    unsafe { task_free(self.task); }
+  }
+}
@@ -2,119 +2,475 @@ use core::{slice, str};
 
 mod state;
 mod elf;
+mod mm;
+mod errno;
+mod fd;
+mod pipe;
+mod process;
+mod signal;
+mod tty;
+mod devfs;
+mod procfs;
+mod files;
+mod exec;
 
-use drivers::virtio;
-use super::{cpuio,fs};
-use fs::Fs;
+use drivers::{uart,virtio};
+use super::fs;
+use fs::vfs;
 
 use block;
+use cmdline::OPTIONS;
+use multiboot;
 use sched;
 use core::cell::UnsafeCell;
 use self::state::StepResult::*;
 use self::state::SyscallType::*;
 use alloc::arc::Arc;
+use collections::string::String;
+use collections::vec::Vec;
+use core::fmt::Write;
 
-//type Fd = u64;
-//type Fdt = Map<Fd, Arc<GlobalMutex<RefCell<File>>>>;
+use self::errno::Errno;
+use self::fd::Fdt;
+
+// How much of the boot disk, and of each partition on it, to keep in memory,
+// in sectors
+const CACHE_SECTORS: usize = 128;
 
 pub fn exec_init() {
   println!("Starting init task! Or at least I hope so.");
 
-  let serport = unsafe { cpuio::alloc(0xc080, 24, "XXXXXXXXXXXXXXXXXXXXXXXX").unwrap() };
-  let mut serdev = virtio::serial::Serialdev::new(serport).unwrap();
-  // serdev.putc('.');
-  // serdev.putc('\n');
-  // panic!("done");
+  // Without a virtio console, the console is the UART the kernel log goes to.
+  let uart = Arc::new(uart::Uart::new(uart::COM1));
+  let serdev = virtio::serial::take().map(Arc::new);
 
-  // TODO: request this from somewhere
-  let port = unsafe { cpuio::alloc(0xc040, 24, "XXXXXXXXXXXXXXXXXXXXXXXX").unwrap() };
+  let disks = match multiboot::module(0) {
+    Some(initrd) => initrd_disks(initrd),
+    None => virtio_disks(),
+  };
 
-  let blockdev = virtio::block::Blockdev::new(port).unwrap();
-  println!("result of blockdevice init: {:?}", blockdev);
+  // The root filesystem is on the disk that `root=` names, or else on the
+  // first disk that holds one: the initrd, or the whole virtio disk, or one
+  // of its partitions.
+  let rootfs = match OPTIONS.root {
+    Some(ref root) => match disks.iter().find(|&&(ref name, _)| name == root) {
+      Some(&(_, ref c)) => match fs::Cpiofs::new(c.clone()) {
+        Ok(fs) => fs,
+        Err(e) => panic!("can't mount the root filesystem on {}: {:?}", root, e),
+      },
+      None => panic!("root disk {} not found", root),
+    },
+    None => match disks.iter().filter_map(|&(_, ref c)| fs::Cpiofs::new(c.clone()).ok()).next() {
+      Some(fs) => fs,
+      None => panic!("can't find a root filesystem on any disk"),
+    },
+  };
+  println!("fs: {:?}", rootfs);
+  vfs::mount_root(Arc::new(rootfs) as Arc<fs::Filesystem>);
 
-  let cache = block::cached::NoopCache::new(blockdev);
+  println!("||\n||  $ ls");
+  for x in vfs::root().vnode.readdir().unwrap() {
+    println!("||  {}", x.name);
+  }
 
-  let mut fs = fs::Cpiofs::new(Arc::new(cache) as Arc<block::Cache>);
-  println!("fs: {:?}", fs);
+  let console = match serdev {
+    Some(ref serdev) => {
+      serdev.putc('*');
+      Arc::new(tty::Tty::new("/dev/console", serdev.clone()))
+    },
+    None => {
+      println!("No virtio console, using the UART for /dev/console");
+      Arc::new(tty::Tty::new("/dev/console", uart.clone()))
+    },
+  };
+  let uart = Arc::new(tty::Tty::new("/dev/ttyS0", uart));
+  register_devices(console.clone(), uart.clone(), &disks);
+  if let Err(e) = vfs::mount("/dev", Arc::new(devfs::Devfs) as Arc<fs::Filesystem>) {
+    println!("Couldn't mount /dev: {:?}", e);
+  }
+  if let Err(e) = vfs::mount("/proc", Arc::new(procfs::Procfs) as Arc<fs::Filesystem>) {
+    println!("Couldn't mount /proc: {:?}", e);
+  }
+  if let Err(e) = vfs::mount("/tmp", Arc::new(fs::Tmpfs::new(files::TMPFS_SIZE)) as Arc<fs::Filesystem>) {
+    println!("Couldn't mount /tmp: {:?}", e);
+  }
 
+  let argv = [OPTIONS.init.clone()];
+  let mut envp = vec![];
+  if let Some(ref test) = OPTIONS.test {
+    let mut var = String::from("TEST=");
+    var.push_str(test);
+    envp.push(var);
+  }
+  let (mm, s) = exec::load(&vfs::root(), &OPTIONS.init, &argv, &envp).unwrap();
+  println!("Succesfully loaded init from disk.");
 
-  println!("||\n||  $ ls");
-  for x in fs.index("/").unwrap() {
-    println!("||  {}", x);
+  // stdin, stdout and stderr all go to the console (or whatever `console=`
+  // says), which becomes init's controlling terminal.
+  let tty = match &OPTIONS.console[..] {
+    "console" => console,
+    "ttyS0" => uart,
+    other => {
+      println!("No console called {}, using /dev/console", other);
+      console
+    },
+  };
+  let mut fds = Fdt::new();
+  for _ in 0..3 {
+    fds.insert(tty.clone() as Arc<fd::File>).unwrap();
   }
 
-  let size = fs.stat("/init").unwrap();
-  println!("||\n||  $ stat /init");
-  println!("||  size {}", size);
+  let mut p = process::Process::new(&OPTIONS.init, mm, fds, s);
+  tty::Tty::open(&tty, &p.handle);
 
-  serdev.putc('*');
+  let status = run(&mut p);
+  process::exit(p, status);
 
-  let mut buf = vec!(0u8; size);
-  let read = fs.slurp("/init", &mut buf);
+  println!("User process exited normally or due to crash.");
+}
 
-  {
-    let file1 = fs.open("/init");
-    let file2 = fs.open("/README.md");
+// The kernel task of a forked process, which starts out returning 0 from
+// fork.
+fn run_forked(mut p: process::Process) {
+  unsafe { p.mm.enter(); }
+  let status = run(&mut p);
+  process::exit(p, status);
+}
 
-    println!("Files 1 and 2: {:?} {:?}",file1, file2);
+// GRUB loaded the root filesystem for us, as the first Multiboot module.
+fn initrd_disks(initrd: &'static mut [u8]) -> Vec<(String, Arc<block::Cache>)> {
+  println!("Using the {} byte initrd as the root disk", initrd.len());
+  let ram = block::ramdev::Ramdev::from_memory(initrd);
+  // It's in memory already, no need to cache it.
+  vec![(String::from("ram0"), Arc::new(block::cached::NoopCache::new(ram)) as Arc<block::Cache>)]
+}
+
+// The virtio disks that the PCI scan found, vda, vdb and so on, each followed
+// by the partitions on it.
+fn virtio_disks() -> Vec<(String, Arc<block::Cache>)> {
+  let mut disks = vec![];
+  for (i, blockdev) in virtio::block::take_all().into_iter().enumerate() {
+    let mut disk = String::from("vd");
+    disk.push((b'a' + i as u8) as char);
+
+    let whole = block::partition::Partition::whole(blockdev);
+    let partitions = match whole.scan() {
+      Ok(ps) => ps,
+      Err(e) => {
+        println!("Couldn't read the partition table on {}: {:?}", disk, e);
+        vec![]
+      },
+    };
+
+    // One cache for the whole disk; partitions go through it too.
+    let cache = Arc::new(block::lru::LruCache::new(whole, CACHE_SECTORS)) as Arc<block::Cache>;
+    disks.push((disk.clone(), cache.clone()));
+    for p in partitions {
+      let mut name = disk.clone();
+      let _ = write!(name, "{}", p.number());
+      disks.push((name, Arc::new(block::partition::CachedPartition::new(cache.clone(), &p)) as Arc<block::Cache>));
+    }
   }
+  disks
+}
 
-  println!("Read result: {:?}",read);
-  let n = read.unwrap();
-  println!("Succesfully read init from disk.");
+fn register_devices(console: Arc<tty::Tty>, uart: Arc<tty::Tty>, disks: &[(String, Arc<block::Cache>)]) {
+  devfs::register_builtin();
 
-  let loaded_elf = &buf[0..n];
+  devfs::register("console", devfs::Kind::Char, box move |h: &process::Handle| {
+    tty::Tty::open(&console, h);
+    Ok(console.clone() as Arc<fd::File>)
+  }).unwrap();
 
-  let loaded = unsafe { elf::load(loaded_elf) };
-  println!("Load result: {:?}", loaded);
+  devfs::register("ttyS0", devfs::Kind::Char, box move |h: &process::Handle| {
+    tty::Tty::open(&uart, h);
+    Ok(uart.clone() as Arc<fd::File>)
+  }).unwrap();
 
-  let image = loaded.unwrap();
-  let mut s = state::UsermodeState::new(image.initial_rip as u64, image.initial_rsp as u64);
+  for &(ref name, ref cache) in disks.iter() {
+    devfs::register_block(name, cache.clone()).unwrap();
+  }
+}
 
+// Run the process until it's gone, returning its wait status.
+fn run(p: &mut process::Process) -> i32 {
   let mut last_syscall_retval = 0;
+  let mut steps = 0;
   loop {
-    let r = s.step(last_syscall_retval);
-    println!("Step result: {:?}", r);
+    if let signal::Delivery::Terminate(sig) = signal::deliver(p, last_syscall_retval) {
+      println!("pid {} was killed by signal {}", p.pid(), sig);
+      return sig as i32;
+    }
+
+    let r = p.state.step(last_syscall_retval);
+    debug!("Step result: {:?}", r);
+
+    // What /proc shows about us only changes with these.
+    let republish = match r {
+      Syscall(Open(..)) | Syscall(Close(..)) | Syscall(Dup2(..)) | Syscall(Pipe(..)) |
+      Syscall(Brk(..)) | Syscall(Mmap(..)) | Syscall(Munmap(..)) | Syscall(Mprotect(..)) |
+      Syscall(Execve(..)) => true,
+      _ => false,
+    };
 
     match r {
       Syscall(Write(fd, buf, len)) => {
-        let data = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) }; // copy_from_user
-        let text = str::from_utf8(data).unwrap();
-
-        for c in data {
-          serdev.putc(*c as char);
-        }
-        //print!("    | {}", text);
+        let r = sys_write(p, fd as usize, buf as usize, len);
+        last_syscall_retval = restart_if_interrupted(p, r);
       },
       Syscall(Exit(ret)) => {
-        println!("Init exited with 0x{:x}!", ret);
-        break;
+        println!("pid {} exited with 0x{:x}!", p.pid(), ret);
+        return ((ret & 0xff) << 8) as i32;
       },
-      Syscall(Open(name, flags)) => {
-        //let data = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) }; // name_from_user
-        //let text = str::from_utf8(data).unwrap();
-        //print!("    | {}", text);
+      Syscall(Open(name, flags, mode)) => {
+        last_syscall_retval = errno::retval(files::sys_open(p, name as usize, flags, mode));
       },
       Syscall(Read(fd, buf, len)) => {
-        let mut data = unsafe { slice::from_raw_parts_mut(buf as *mut u8, len as usize) }; // copy_from_user
-        print!("before read: {:?}", data);
-        last_syscall_retval = serdev.read(&mut data) as u64;
-        print!("after read: {:?}", data);
-        //print!("    | {}", text);
+        let r = sys_read(p, fd as usize, buf as usize, len);
+        last_syscall_retval = restart_if_interrupted(p, r);
+      },
+      Syscall(Pipe(fildes)) => {
+        last_syscall_retval = errno::retval(sys_pipe(p, fildes as usize));
+      },
+      Syscall(Close(fd)) => {
+        last_syscall_retval = errno::retval(p.fds.close(fd as usize).map(|_| 0));
+      },
+      Syscall(Dup2(old, new)) => {
+        last_syscall_retval = errno::retval(p.fds.dup2(old as usize, new as usize));
+      },
+      Syscall(Brk(addr)) => {
+        last_syscall_retval = p.mm.brk(addr as usize) as u64;
+      },
+      Syscall(Mmap(addr, len, prot, flags, fd, off)) => {
+        last_syscall_retval = errno::retval(p.mm.mmap(addr as usize, len, prot, flags, fd, off));
+      },
+      Syscall(Munmap(addr, len)) => {
+        last_syscall_retval = errno::retval(p.mm.munmap(addr as usize, len).map(|_| 0));
+      },
+      Syscall(Mprotect(addr, len, prot)) => {
+        last_syscall_retval = errno::retval(p.mm.mprotect(addr as usize, len, prot).map(|_| 0));
+      },
+      Syscall(Kill(pid, sig)) => {
+        last_syscall_retval = errno::retval(process::kill(&p.handle, pid, sig as usize).map(|_| 0));
+      },
+      Syscall(Sigaction(sig, act, oldact)) => {
+        last_syscall_retval = errno::retval(sys_sigaction(p, sig as usize, act as usize, oldact as usize));
+      },
+      Syscall(Sigprocmask(how, set, oldset)) => {
+        last_syscall_retval = errno::retval(sys_sigprocmask(p, how, set as usize, oldset as usize));
+      },
+      Syscall(Sigreturn) => {
+        match signal::sigreturn(p) {
+          Ok(rax) => last_syscall_retval = rax,
+          Err(_) => signal::force(p, signal::SIGSEGV),
+        }
+      },
+      Syscall(Getpid) => {
+        last_syscall_retval = p.pid() as u64;
+      },
+      Syscall(Ioctl(fd, req, arg)) => {
+        last_syscall_retval = errno::retval(sys_ioctl(p, fd as usize, req, arg as usize));
+      },
+      Syscall(Setpgid(pid, pgid)) => {
+        last_syscall_retval = errno::retval(process::setpgid(&p.handle, pid as usize, pgid as usize).map(|_| 0));
+      },
+      Syscall(Getpgid(pid)) => {
+        last_syscall_retval = errno::retval(process::getpgid(&p.handle, pid as usize));
+      },
+      Syscall(Setsid) => {
+        last_syscall_retval = errno::retval(process::setsid(&p.handle));
+      },
+      Syscall(Stat(path, buf)) => {
+        last_syscall_retval = errno::retval(files::sys_stat(p, path as usize, buf as usize, true));
+      },
+      Syscall(Lstat(path, buf)) => {
+        last_syscall_retval = errno::retval(files::sys_stat(p, path as usize, buf as usize, false));
+      },
+      Syscall(Fstat(fd, buf)) => {
+        last_syscall_retval = errno::retval(files::sys_fstat(p, fd as usize, buf as usize));
+      },
+      Syscall(Lseek(fd, offset, whence)) => {
+        last_syscall_retval = errno::retval(files::sys_lseek(p, fd as usize, offset, whence));
+      },
+      Syscall(Getdents(fd, buf, len)) => {
+        last_syscall_retval = errno::retval(files::sys_getdents(p, fd as usize, buf as usize, len));
+      },
+      Syscall(Chdir(path)) => {
+        last_syscall_retval = errno::retval(files::sys_chdir(p, path as usize));
       },
+      Syscall(Getcwd(buf, size)) => {
+        last_syscall_retval = errno::retval(files::sys_getcwd(p, buf as usize, size));
+      },
+      Syscall(Readlink(path, buf, size)) => {
+        last_syscall_retval = errno::retval(files::sys_readlink(p, path as usize, buf as usize, size));
+      },
+      Syscall(Execve(path, argv, envp)) => {
+        last_syscall_retval = errno::retval(exec::sys_execve(p, path as usize, argv as usize, envp as usize));
+      },
+      Syscall(Mkdir(path, mode)) => {
+        last_syscall_retval = errno::retval(files::sys_mkdir(p, path as usize, mode));
+      },
+      Syscall(Rmdir(path)) => {
+        last_syscall_retval = errno::retval(files::sys_rmdir(p, path as usize));
+      },
+      Syscall(Unlink(path)) => {
+        last_syscall_retval = errno::retval(files::sys_unlink(p, path as usize));
+      },
+      Syscall(Rename(from, to)) => {
+        last_syscall_retval = errno::retval(files::sys_rename(p, from as usize, to as usize));
+      },
+      Syscall(Symlink(target, path)) => {
+        last_syscall_retval = errno::retval(files::sys_symlink(p, target as usize, path as usize));
+      },
+      Syscall(Ftruncate(fd, size)) => {
+        last_syscall_retval = errno::retval(files::sys_ftruncate(p, fd as usize, size));
+      },
+      Syscall(Mount(source, target, fstype)) => {
+        last_syscall_retval = errno::retval(files::sys_mount(p, source as usize, target as usize, fstype as usize));
+      },
+      Syscall(Sync) => {
+        last_syscall_retval = errno::retval(files::sys_sync(p));
+      },
+      Syscall(Fork) => {
+        last_syscall_retval = errno::retval(sys_fork(p));
+      },
+      Syscall(Wait4(pid, status, options)) => {
+        let r = sys_wait4(p, pid, status as usize, options);
+        last_syscall_retval = restart_if_interrupted(p, r);
+      },
+      Syscall(Getppid) => {
+        last_syscall_retval = p.handle.ppid() as u64;
+      },
+      Fault(vector, addr, code) => {
+        println!("pid {} faulted: exception {} at rip {:x}, address {:x}, code {:x}", p.pid(), vector, p.state.rip, addr, code);
+        let sig = match vector {
+          0 => signal::SIGFPE,
+          6 => signal::SIGILL,
+          _ => signal::SIGSEGV,
+        };
+        signal::force(p, sig);
+      },
+      // Nothing to do, but it's a chance to run other tasks and to deliver
+      // signals that arrived in the meantime.
+      Preempted => {},
       // Syscall(s) => {
       //   println!("syscall: {:?}",s);
       // },
       _ => {
         println!("unknown syscall, crashing process: {:?}", r);
-        break
+        return signal::SIGKILL as i32;
       }
     }
-    sched::kyield();
+
+    if republish {
+      p.publish();
+    }
+
+    steps += 1;
+    if steps >= sched::timeslice() {
+      steps = 0;
+      sched::kyield();
+    }
   }
+}
 
-  println!("User process exited normally or due to crash.");
+// A blocking syscall was interrupted by a signal: unless a handler without
+// SA_RESTART is about to run, do it again once deliver() has dealt with it.
+fn restart_if_interrupted(p: &mut process::Process, r: Result<usize, Errno>) -> u64 {
+  if r == Err(Errno::EINTR) && signal::restarts(p) {
+    p.state.restart_syscall();
+  }
+  errno::retval(r)
+}
+
+fn sys_read(p: &mut process::Process, fd: fd::Fd, buf: usize, len: usize) -> Result<usize, Errno> {
+  let file = try!(p.fds.get(fd));
+  try!(p.mm.check_range(buf, len, true));
+  let data = unsafe { slice::from_raw_parts_mut(buf as *mut u8, len) };
+  file.read(p, data)
+}
 
-  // FIXME FIXME: this is superbad; better than overwriting kernel code, but still bad
-  //uint64_t rsp = (uint64_t)t->brk;
+fn sys_write(p: &mut process::Process, fd: fd::Fd, buf: usize, len: usize) -> Result<usize, Errno> {
+  let file = try!(p.fds.get(fd));
+  try!(p.mm.check_range(buf, len, false));
+  let data = unsafe { slice::from_raw_parts(buf as *const u8, len) };
+  let r = file.write(p, data);
+  if let Err(Errno::EPIPE) = r {
+    p.handle.post(signal::SIGPIPE);
+  }
+  r
+}
+
+fn sys_ioctl(p: &mut process::Process, fd: fd::Fd, req: u64, arg: usize) -> Result<usize, Errno> {
+  let file = try!(p.fds.get(fd));
+  file.ioctl(p, req, arg)
+}
+
+fn sys_pipe(p: &mut process::Process, fildes: usize) -> Result<usize, Errno> {
+  try!(p.mm.check_range(fildes, 8, true));
+
+  let (reader, writer) = pipe::new();
+  let rfd = try!(p.fds.insert(Arc::new(reader)));
+  let wfd = match p.fds.insert(Arc::new(writer)) {
+    Ok(fd) => fd,
+    Err(e) => { p.fds.close(rfd).unwrap(); return Err(e); }
+  };
+
+  let out = unsafe { slice::from_raw_parts_mut(fildes as *mut i32, 2) };
+  out[0] = rfd as i32;
+  out[1] = wfd as i32;
+  Ok(0)
+}
+
+fn sys_fork(p: &mut process::Process) -> Result<usize, Errno> {
+  let child = try!(p.fork());
+  let pid = child.pid();
+  sched::add_task(move || run_forked(child), "user process");
+  Ok(pid)
+}
+
+fn sys_wait4(p: &mut process::Process, pid: i64, status: usize, options: u64) -> Result<usize, Errno> {
+  if status != 0 {
+    try!(p.mm.check_range(status, 4, true));
+  }
+  match try!(process::wait(p, pid, options)) {
+    Some((child, s)) => {
+      if status != 0 {
+        unsafe { *(status as *mut i32) = s; }
+      }
+      Ok(child)
+    },
+    None => Ok(0),
+  }
+}
+
+fn sys_sigaction(p: &mut process::Process, sig: usize, act: usize, oldact: usize) -> Result<usize, Errno> {
+  let size = core::mem::size_of::<signal::Action>();
+  let old = try!(p.signals.action(sig));
+
+  if act != 0 {
+    try!(p.mm.check_range(act, size, false));
+    let new = unsafe { *(act as *const signal::Action) };
+    try!(p.signals.set_action(sig, new));
+  }
+  if oldact != 0 {
+    try!(p.mm.check_range(oldact, size, true));
+    unsafe { *(oldact as *mut signal::Action) = old; }
+  }
+  Ok(0)
+}
+
+fn sys_sigprocmask(p: &mut process::Process, how: u64, set: usize, oldset: usize) -> Result<usize, Errno> {
+  let old = p.signals.blocked() as u64;
+
+  if set != 0 {
+    try!(p.mm.check_range(set, 8, false));
+    let mask = unsafe { *(set as *const u64) };
+    try!(p.signals.procmask(how, mask as usize));
+  }
+  if oldset != 0 {
+    try!(p.mm.check_range(oldset, 8, true));
+    unsafe { *(oldset as *mut u64) = old; }
+  }
+  Ok(0)
 }
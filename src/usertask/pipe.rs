@@ -0,0 +1,191 @@
+use prelude::*;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use fs::{FileType,Stat};
+use sched::blocking::{self,SignalToken};
+use sync::global_mutex::GlobalMutex;
+use super::errno::Errno;
+use super::fd::File;
+use super::process::Process;
+use super::signal;
+
+static NEXT_INO: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Like Linux's PIPE_BUF, writes up to this size are atomic.
+const PIPE_SIZE: usize = 4096;
+
+#[derive(Debug)]
+struct Inner {
+  ino: u64,
+  buf: VecDeque<u8>,
+
+  // Whether each end is still open
+  reader: bool,
+  writer: bool,
+
+  // Tasks sleeping until there is something to read, or room to write.
+  // Everybody gets woken on any change and re-checks.
+  read_waiters: Vec<SignalToken>,
+  write_waiters: Vec<SignalToken>,
+}
+
+impl Inner {
+  fn wake(waiters: &mut Vec<SignalToken>) {
+    for w in waiters.drain(..) {
+      w.signal();
+    }
+  }
+}
+
+// The two ends of a pipe. Descriptors, in one process or several, share
+// them, and dropping the last reference to an end closes it.
+#[derive(Debug)]
+pub struct Reader {
+  inner: Arc<GlobalMutex<Inner>>,
+}
+
+#[derive(Debug)]
+pub struct Writer {
+  inner: Arc<GlobalMutex<Inner>>,
+}
+
+pub fn new() -> (Reader, Writer) {
+  let inner = Arc::new(GlobalMutex::new(Inner {
+    ino: NEXT_INO.fetch_add(1, Ordering::SeqCst) as u64 + 1,
+    buf: VecDeque::with_capacity(PIPE_SIZE),
+    reader: true,
+    writer: true,
+    read_waiters: vec![],
+    write_waiters: vec![],
+  }));
+  (Reader { inner: inner.clone() }, Writer { inner: inner })
+}
+
+// Both ends report the same inode number, so that they can be matched up.
+fn fifo_stat(inner: &Arc<GlobalMutex<Inner>>) -> Stat {
+  let p = inner.lock();
+  Stat { ino: p.ino, kind: FileType::Fifo, mode: 0o600, nlink: 1, uid: 0, gid: 0, size: p.buf.len() as u64, rdev: 0, mtime: 0 }
+}
+
+fn fifo_path(inner: &Arc<GlobalMutex<Inner>>) -> String {
+  let mut path = String::new();
+  let _ = write!(path, "pipe:[{}]", inner.lock().ino);
+  path
+}
+
+impl File for Reader {
+  // Blocks until at least one byte is available. Returns 0 (EOF) once the
+  // pipe is empty and all writers are gone.
+  fn read(&self, caller: &Process, buf: &mut [u8]) -> Result<usize, Errno> {
+    if buf.len() == 0 {
+      return Ok(0);
+    }
+
+    loop {
+      let (wait, wake) = {
+        let mut p = self.inner.lock();
+        if p.buf.len() > 0 {
+          let n = cmp::min(buf.len(), p.buf.len());
+          for (i, b) in p.buf.drain(..n).enumerate() {
+            buf[i] = b;
+          }
+          Inner::wake(&mut p.write_waiters);
+          return Ok(n);
+        }
+        if !p.writer {
+          return Ok(0);
+        }
+        if signal::interrupting(caller) {
+          return Err(Errno::EINTR);
+        }
+
+        // Register while still holding the lock, so that we can't miss a wakeup.
+        let (wait, wake) = blocking::tokens(String::from("pipe read"));
+        p.read_waiters.push(wake.clone());
+        (wait, wake)
+      };
+      caller.handle.sleep(&wait, wake);
+    }
+  }
+
+  fn write(&self, _p: &Process, _buf: &[u8]) -> Result<usize, Errno> {
+    Err(Errno::EBADF)
+  }
+
+  fn stat(&self) -> Result<(u64, Stat), Errno> {
+    Ok((0, fifo_stat(&self.inner)))
+  }
+
+  fn path(&self) -> String {
+    fifo_path(&self.inner)
+  }
+}
+
+impl File for Writer {
+  // Blocks until everything is written, or fails with EPIPE once all readers
+  // are gone. Writes of up to PIPE_SIZE bytes are never interleaved with
+  // other writers. A signal interrupts the write, which then returns what
+  // it has written so far.
+  fn write(&self, caller: &Process, buf: &[u8]) -> Result<usize, Errno> {
+    let mut written = 0;
+
+    while written < buf.len() {
+      let (wait, wake) = {
+        let mut p = self.inner.lock();
+        if !p.reader {
+          return if written > 0 { Ok(written) } else { Err(Errno::EPIPE) };
+        }
+
+        let room = PIPE_SIZE - p.buf.len();
+        let remaining = buf.len() - written;
+        if room > 0 && (remaining > PIPE_SIZE || room >= remaining) {
+          let n = cmp::min(room, remaining);
+          p.buf.extend(buf[written..written+n].iter().cloned());
+          written += n;
+          Inner::wake(&mut p.read_waiters);
+          continue;
+        }
+
+        if signal::interrupting(caller) {
+          return if written > 0 { Ok(written) } else { Err(Errno::EINTR) };
+        }
+
+        let (wait, wake) = blocking::tokens(String::from("pipe write"));
+        p.write_waiters.push(wake.clone());
+        (wait, wake)
+      };
+      caller.handle.sleep(&wait, wake);
+    }
+
+    Ok(written)
+  }
+
+  fn read(&self, _p: &Process, _buf: &mut [u8]) -> Result<usize, Errno> {
+    Err(Errno::EBADF)
+  }
+
+  fn stat(&self) -> Result<(u64, Stat), Errno> {
+    Ok((0, fifo_stat(&self.inner)))
+  }
+
+  fn path(&self) -> String {
+    fifo_path(&self.inner)
+  }
+}
+
+impl Drop for Reader {
+  fn drop(&mut self) {
+    let mut p = self.inner.lock();
+    p.reader = false;
+    Inner::wake(&mut p.write_waiters);
+  }
+}
+
+impl Drop for Writer {
+  fn drop(&mut self) {
+    let mut p = self.inner.lock();
+    p.writer = false;
+    Inner::wake(&mut p.read_waiters);
+  }
+}
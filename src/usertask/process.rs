@@ -0,0 +1,402 @@
+use prelude::*;
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use collections::btree_map::BTreeMap;
+use fs::vfs::{self,Dentry};
+use sched::blocking::{self,SignalToken,WaitToken};
+use sync::global_mutex::GlobalMutex;
+
+use super::errno::Errno;
+use super::fd::{Fd,Fdt};
+use super::mm::{AddressSpace,Region};
+use super::signal::{self,Signals};
+use super::state::UsermodeState;
+
+pub type Pid = usize;
+
+// What /proc shows about a process. Nobody but the process itself can look
+// at its Process, so it copies this over whenever it changes, see
+// Process::publish.
+#[derive(Debug,Clone)]
+pub struct Info {
+  // The path it was executed from
+  pub path: String,
+  pub regions: Vec<Region>,
+  pub fds: Vec<(Fd, String)>,
+}
+
+// Something about a child that its parent hasn't collected with wait4 yet.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Change {
+  // The process is gone, with this wait status, see sys/wait.h
+  Exited(i32),
+  // The process was stopped by this signal
+  Stopped(usize),
+}
+
+// wait4 options
+pub const WNOHANG: u64 = 1;
+pub const WUNTRACED: u64 = 2;
+
+// The parts of a process that other tasks (and IRQ handlers) may touch while
+// the process itself is running: its identity, the pending signal set, and
+// what it last published about itself. Once the process has exited, this is
+// all that's left of it until the parent reaps it.
+#[derive(Debug)]
+pub struct Handle {
+  pub pid: Pid,
+  ppid: AtomicUsize,
+  pgid: AtomicUsize,
+  sid: AtomicUsize,
+  pending: AtomicUsize,
+  info: GlobalMutex<Info>,
+  change: GlobalMutex<Option<Change>>,
+
+  // Whatever the process is blocked on, so that a signal can wake it up.
+  waker: GlobalMutex<Option<SignalToken>>,
+}
+
+impl Handle {
+  // The parent, or 0 for init.
+  pub fn ppid(&self) -> Pid {
+    self.ppid.load(Ordering::SeqCst)
+  }
+
+  pub fn exited(&self) -> bool {
+    match *self.change.lock() {
+      Some(Change::Exited(_)) => true,
+      _ => false,
+    }
+  }
+
+  pub fn sid(&self) -> Pid {
+    self.sid.load(Ordering::SeqCst)
+  }
+
+  pub fn is_session_leader(&self) -> bool {
+    self.sid() == self.pid
+  }
+
+  pub fn pgid(&self) -> Pid {
+    self.pgid.load(Ordering::SeqCst)
+  }
+
+  pub fn set_pgid(&self, pgid: Pid) {
+    self.pgid.store(pgid, Ordering::SeqCst);
+  }
+
+  pub fn post(&self, sig: usize) {
+    self.pending.fetch_or(signal::bit(sig), Ordering::SeqCst);
+    if let Some(w) = self.waker.lock().take() {
+      w.signal();
+    }
+  }
+
+  // Block until `signal` is signalled, or until a signal is posted to us.
+  // Callers check for signals themselves before they go to sleep; nothing
+  // else runs in between, since we never yield before this.
+  pub fn sleep(&self, wait: &WaitToken, signal: SignalToken) {
+    *self.waker.lock() = Some(signal);
+    wait.wait();
+    *self.waker.lock() = None;
+  }
+
+  pub fn pending(&self) -> usize {
+    self.pending.load(Ordering::SeqCst)
+  }
+
+  // Atomically clear `sig`, returning whether it was pending.
+  pub fn take(&self, sig: usize) -> bool {
+    self.pending.fetch_and(!signal::bit(sig), Ordering::SeqCst) & signal::bit(sig) != 0
+  }
+
+  pub fn info(&self) -> Info {
+    self.info.lock().clone()
+  }
+}
+
+unsafe_lazy_static! {
+  static ref TABLE: GlobalMutex<BTreeMap<Pid, Arc<Handle>>> = { GlobalMutex::new(BTreeMap::new()) };
+}
+
+static NEXT_PID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Everything that makes up a user process. This is owned by the kernel task
+// running the process; only the Handle is shared.
+#[derive(Debug)]
+pub struct Process {
+  pub handle: Arc<Handle>,
+  pub path: String,
+  pub mm: AddressSpace,
+  pub fds: Fdt,
+  pub cwd: Arc<Dentry>,
+  pub signals: Signals,
+  pub state: UsermodeState,
+}
+
+fn register(ppid: Pid, pgid: Option<Pid>, sid: Option<Pid>, info: Info) -> Arc<Handle> {
+  let pid = NEXT_PID.fetch_add(1, Ordering::SeqCst) + 1; // init is pid 1
+  let handle = Arc::new(Handle {
+    pid: pid,
+    ppid: AtomicUsize::new(ppid),
+    pgid: AtomicUsize::new(pgid.unwrap_or(pid)),
+    sid: AtomicUsize::new(sid.unwrap_or(pid)),
+    pending: AtomicUsize::new(0),
+    info: GlobalMutex::new(info),
+    change: GlobalMutex::new(None),
+    waker: GlobalMutex::new(None),
+  });
+  TABLE.lock().insert(pid, handle.clone());
+  handle
+}
+
+impl Process {
+  // Registers a new process, leading its own session and process group and
+  // starting out in the root directory. `path` is the executable it runs.
+  pub fn new(path: &str, mm: AddressSpace, fds: Fdt, state: UsermodeState) -> Self {
+    let handle = register(0, None, None, Info { path: String::from(path), regions: vec![], fds: vec![] });
+    let p = Process { handle: handle, path: String::from(path), mm: mm, fds: fds, cwd: vfs::root(), signals: Signals::new(), state: state };
+    p.publish();
+    p
+  }
+
+  // fork(2): a child in our session and process group, with a copy of our
+  // memory, our open files, signal actions and mask, but nothing pending.
+  // It resumes from the same syscall.
+  pub fn fork(&self) -> Result<Process, Errno> {
+    let mm = try!(self.mm.fork());
+    let handle = register(self.pid(), Some(self.handle.pgid()), Some(self.handle.sid()), self.handle.info());
+    Ok(Process {
+      handle: handle,
+      path: self.path.clone(),
+      mm: mm,
+      fds: self.fds.clone(),
+      cwd: self.cwd.clone(),
+      signals: self.signals.clone(),
+      state: self.state.fork(),
+    })
+  }
+
+  pub fn pid(&self) -> Pid {
+    self.handle.pid
+  }
+
+  // Update what /proc shows about us.
+  pub fn publish(&self) {
+    let info = Info {
+      path: self.path.clone(),
+      regions: self.mm.regions().to_vec(),
+      fds: self.fds.list().into_iter().map(|(fd, f)| (fd, f.path())).collect(),
+    };
+    *self.handle.info.lock() = info;
+  }
+}
+
+// The end of a process, with `status` for the parent's wait4. Its memory
+// and files go away right here. Nobody waits for orphans, so init adopts the
+// running ones, and zombies without a parent are reaped right away.
+pub fn exit(p: Process, status: i32) {
+  let handle = p.handle.clone();
+  drop(p);
+
+  let parent = {
+    let mut table = TABLE.lock();
+    let children: Vec<Arc<Handle>> = table.values().filter(|h| h.ppid() == handle.pid).cloned().collect();
+    for c in children {
+      if c.exited() {
+        table.remove(&c.pid);
+      } else {
+        c.ppid.store(1, Ordering::SeqCst);
+      }
+    }
+
+    *handle.change.lock() = Some(Change::Exited(status));
+    match table.get(&handle.ppid()).cloned() {
+      Some(parent) => Some(parent),
+      None => {
+        table.remove(&handle.pid);
+        None
+      },
+    }
+  };
+
+  if let Some(parent) = parent {
+    parent.post(signal::SIGCHLD);
+  }
+}
+
+// A stop signal took effect: tell the parent, for wait4 with WUNTRACED.
+pub fn stopped(p: &Process, sig: usize) {
+  *p.handle.change.lock() = Some(Change::Stopped(sig));
+  if let Some(parent) = lookup(p.handle.ppid()) {
+    parent.post(signal::SIGCHLD);
+  }
+}
+
+// SIGCONT: a stop the parent hasn't collected yet is no news anymore.
+pub fn continued(p: &Process) {
+  let mut change = p.handle.change.lock();
+  if let Some(Change::Stopped(_)) = *change {
+    *change = None;
+  }
+}
+
+// Whether `pid` as passed to wait4 selects `child` of `caller`.
+fn selects(caller: &Handle, pid: i64, child: &Handle) -> bool {
+  if pid > 0 {
+    child.pid == pid as Pid
+  } else if pid == 0 {
+    child.pgid() == caller.pgid()
+  } else if pid == -1 {
+    true
+  } else {
+    child.pgid() == (-pid) as Pid
+  }
+}
+
+// wait4(2), without resource usage: pid > 0 waits for that child, -1 for
+// any, 0 for any in our process group and < -1 for any in group -pid.
+// With WUNTRACED, stopped children are reported too, once per stop.
+// Returns the child and its wait status, or None with WNOHANG if there's
+// nothing to report yet.
+pub fn wait(p: &Process, pid: i64, options: u64) -> Result<Option<(Pid, i32)>, Errno> {
+  loop {
+    {
+      let mut table = TABLE.lock();
+      let children: Vec<Arc<Handle>> = table.values()
+        .filter(|h| h.ppid() == p.pid() && selects(&p.handle, pid, h))
+        .cloned().collect();
+      if children.len() == 0 {
+        return Err(Errno::ECHILD);
+      }
+
+      for c in children {
+        let change = *c.change.lock();
+        match change {
+          Some(Change::Exited(status)) => {
+            table.remove(&c.pid);
+            return Ok(Some((c.pid, status)));
+          },
+          Some(Change::Stopped(sig)) if options & WUNTRACED != 0 => {
+            *c.change.lock() = None;
+            return Ok(Some((c.pid, (sig << 8 | 0x7f) as i32)));
+          },
+          _ => {},
+        }
+      }
+    }
+
+    if options & WNOHANG != 0 {
+      return Ok(None);
+    }
+    if signal::interrupting(p) {
+      return Err(Errno::EINTR);
+    }
+    // A child's exit posts SIGCHLD to us, which wakes us up.
+    let (wait, signal) = blocking::tokens(String::from("wait4"));
+    p.handle.sleep(&wait, signal);
+  }
+}
+
+pub fn lookup(pid: Pid) -> Option<Arc<Handle>> {
+  TABLE.lock().get(&pid).cloned()
+}
+
+// Every process there is, lowest pid first.
+pub fn pids() -> Vec<Pid> {
+  TABLE.lock().keys().cloned().collect()
+}
+
+// Send `sig` to every process in the given group.
+pub fn signal_group(pgid: Pid, sig: usize) -> Result<(), Errno> {
+  let targets: Vec<Arc<Handle>> = TABLE.lock().values().filter(|h| h.pgid() == pgid).cloned().collect();
+  if targets.len() == 0 {
+    return Err(Errno::ESRCH);
+  }
+  if sig != 0 {
+    for h in targets {
+      h.post(sig);
+    }
+  }
+  Ok(())
+}
+
+// kill(2) semantics: pid > 0 is a single process, 0 the sender's process
+// group, -1 everybody but init, and < -1 the process group -pid.
+// Signal 0 only checks whether the target exists.
+pub fn kill(sender: &Handle, pid: i64, sig: usize) -> Result<(), Errno> {
+  if sig >= signal::NSIG {
+    return Err(Errno::EINVAL);
+  }
+
+  if pid > 0 {
+    match lookup(pid as Pid) {
+      Some(h) => { if sig != 0 { h.post(sig); } Ok(()) },
+      None => Err(Errno::ESRCH),
+    }
+  } else if pid == 0 {
+    signal_group(sender.pgid(), sig)
+  } else if pid == -1 {
+    let targets: Vec<Arc<Handle>> = TABLE.lock().values().filter(|h| h.pid != 1).cloned().collect();
+    if targets.len() == 0 {
+      return Err(Errno::ESRCH);
+    }
+    if sig != 0 {
+      for h in targets {
+        h.post(sig);
+      }
+    }
+    Ok(())
+  } else {
+    signal_group((-pid) as Pid, sig)
+  }
+}
+
+// The session that process group `pgid` belongs to, if it exists.
+pub fn group_session(pgid: Pid) -> Option<Pid> {
+  TABLE.lock().values().find(|h| h.pgid() == pgid).map(|h| h.sid())
+}
+
+pub fn getpgid(caller: &Handle, pid: Pid) -> Result<Pid, Errno> {
+  if pid == 0 {
+    return Ok(caller.pgid());
+  }
+  lookup(pid).map(|h| h.pgid()).ok_or(Errno::ESRCH)
+}
+
+// setpgid(2); 0 for either argument means the target's pid. A process can
+// move itself or its children, as long as they're in its session, into a
+// new group or an existing one in that session.
+pub fn setpgid(caller: &Handle, pid: Pid, pgid: Pid) -> Result<(), Errno> {
+  let target = if pid == 0 || pid == caller.pid {
+    match lookup(caller.pid) {
+      Some(h) => h,
+      None => return Err(Errno::ESRCH),
+    }
+  } else {
+    match lookup(pid) {
+      Some(ref h) if h.ppid() == caller.pid && !h.exited() => h.clone(),
+      _ => return Err(Errno::ESRCH),
+    }
+  };
+  let pgid = if pgid == 0 { target.pid } else { pgid };
+
+  if target.is_session_leader() || target.sid() != caller.sid() {
+    return Err(Errno::EPERM);
+  }
+  if pgid != target.pid && group_session(pgid) != Some(caller.sid()) {
+    return Err(Errno::EPERM);
+  }
+  target.set_pgid(pgid);
+  Ok(())
+}
+
+// setsid(2): start a new session without a controlling terminal.
+pub fn setsid(caller: &Handle) -> Result<Pid, Errno> {
+  if group_session(caller.pid).is_some() {
+    return Err(Errno::EPERM); // already a process group leader
+  }
+  caller.sid.store(caller.pid, Ordering::SeqCst);
+  caller.set_pgid(caller.pid);
+  Ok(caller.pid)
+}
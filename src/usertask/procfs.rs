@@ -0,0 +1,260 @@
+use prelude::*;
+use core::cmp;
+
+use drivers;
+use fs::{self,DirEntry,FileType,Filesystem,Ino,Stat};
+use mem;
+use sched;
+use super::fd::Fd;
+use super::mm::{self,RegionKind};
+use super::process::{self,Pid};
+
+// The filesystem mounted at /proc. Nothing in here is stored anywhere: every
+// read renders the file from the kernel's current state.
+//
+// Inode numbers are pid << 16 plus what the inode is within the process's
+// directory; the global files and the root directory live at pid 0.
+#[derive(Debug)]
+pub struct Procfs;
+
+const ROOT: Ino = 1;
+const MEMINFO: Ino = 2;
+const INTERRUPTS: Ino = 3;
+const DEVICES: Ino = 4;
+
+const GLOBAL: [(&'static str, Ino); 3] = [("meminfo", MEMINFO), ("interrupts", INTERRUPTS), ("devices", DEVICES)];
+
+// Within a process's directory
+const PID_DIR: Ino = 0;
+const STATUS: Ino = 1;
+const MAPS: Ino = 2;
+const FD_DIR: Ino = 3;
+const FD_BASE: Ino = 0x100;
+
+const PER_PID: [(&'static str, Ino, FileType); 3] = [("status", STATUS, FileType::Regular), ("maps", MAPS, FileType::Regular), ("fd", FD_DIR, FileType::Directory)];
+
+fn pid_ino(pid: Pid, which: Ino) -> Ino {
+  ((pid as Ino) << 16) | which
+}
+
+fn split(ino: Ino) -> (Pid, Ino) {
+  ((ino >> 16) as Pid, ino & 0xffff)
+}
+
+fn parse_number(name: &str) -> Option<usize> {
+  // No leading zeroes, so that every number has only one name.
+  if name.len() == 0 || (name.len() > 1 && name.as_bytes()[0] == b'0') {
+    return None;
+  }
+  name.parse().ok()
+}
+
+fn info(pid: Pid) -> Result<process::Info, fs::Error> {
+  process::lookup(pid).map(|h| h.info()).ok_or(fs::Error::NotFound)
+}
+
+// Like the Name: line on Linux, just the last part of the executable's path.
+fn basename(path: &str) -> &str {
+  match path.rfind('/') {
+    Some(i) => &path[i+1..],
+    None => path,
+  }
+}
+
+fn status(pid: Pid) -> Result<String, fs::Error> {
+  let h = try!(process::lookup(pid).ok_or(fs::Error::NotFound));
+  let info = h.info();
+  let vm = info.regions.iter().fold(0, |n, r| n + r.end - r.start);
+
+  let mut s = String::new();
+  let _ = write!(s, "Name:\t{}\n", basename(&info.path));
+  let _ = write!(s, "Pid:\t{}\n", pid);
+  let _ = write!(s, "PPid:\t0\n");
+  let _ = write!(s, "Pgid:\t{}\n", h.pgid());
+  let _ = write!(s, "Sid:\t{}\n", h.sid());
+  let _ = write!(s, "SigPnd:\t{:016x}\n", h.pending());
+  let _ = write!(s, "VmSize:\t{:8} kB\n", vm / 1024);
+  Ok(s)
+}
+
+// In the format of Linux's /proc/<pid>/maps. There are no file mappings but
+// the image, so offsets, devices and inodes are always 0.
+fn maps(pid: Pid) -> Result<String, fs::Error> {
+  let info = try!(info(pid));
+  let mut s = String::new();
+  for r in info.regions.iter() {
+    let _ = write!(s, "{:08x}-{:08x} {}{}{}p 00000000 00:00 0",
+                   r.start, r.end,
+                   if r.prot & mm::PROT_READ != 0 { 'r' } else { '-' },
+                   if r.prot & mm::PROT_WRITE != 0 { 'w' } else { '-' },
+                   if r.prot & mm::PROT_EXEC != 0 { 'x' } else { '-' });
+    let _ = match r.kind {
+      RegionKind::Image => write!(s, "          {}\n", info.path),
+      RegionKind::Heap => write!(s, "          [heap]\n"),
+      RegionKind::Stack => write!(s, "          [stack]\n"),
+      RegionKind::Anonymous => write!(s, "\n"),
+    };
+  }
+  Ok(s)
+}
+
+fn meminfo() -> String {
+  let usage = mem::usage();
+  let mut s = String::new();
+  let _ = write!(s, "MemTotal:  {:8} kB\n", usage.total / 1024);
+  let _ = write!(s, "MemFree:   {:8} kB\n", (usage.total - usage.used) / 1024);
+  let _ = write!(s, "Allocated: {:8} kB\n", usage.live / 1024);
+  s
+}
+
+fn interrupts() -> String {
+  let mut s = String::from("IRQ       COUNT  HANDLERS\n");
+  for (num, count, handlers) in sched::irq::stats() {
+    let _ = write!(s, "{:#04x}: {:10}  {}\n", num, count, handlers);
+  }
+  s
+}
+
+fn devices() -> String {
+  let mut s = String::new();
+  for d in drivers::list() {
+    let _ = write!(s, "{} {} {}\n", d.bus, d.address, d.description);
+  }
+  s
+}
+
+fn fd_target(pid: Pid, fd: Fd) -> Result<String, fs::Error> {
+  let info = try!(info(pid));
+  info.fds.into_iter().find(|&(n, _)| n == fd).map(|(_, path)| path).ok_or(fs::Error::NotFound)
+}
+
+impl Procfs {
+  // The contents of a regular file.
+  fn render(&self, ino: Ino) -> Result<String, fs::Error> {
+    match split(ino) {
+      (0, MEMINFO) => Ok(meminfo()),
+      (0, INTERRUPTS) => Ok(interrupts()),
+      (0, DEVICES) => Ok(devices()),
+      (0, _) => Err(fs::Error::NotFound),
+      (pid, STATUS) => status(pid),
+      (pid, MAPS) => maps(pid),
+      _ => Err(fs::Error::NotFound),
+    }
+  }
+
+  fn kind(&self, ino: Ino) -> Result<FileType, fs::Error> {
+    match split(ino) {
+      (0, ROOT) => Ok(FileType::Directory),
+      (0, MEMINFO) | (0, INTERRUPTS) | (0, DEVICES) => Ok(FileType::Regular),
+      (0, _) => Err(fs::Error::NotFound),
+      (pid, which) => {
+        let info = try!(info(pid));
+        match which {
+          PID_DIR | FD_DIR => Ok(FileType::Directory),
+          STATUS | MAPS => Ok(FileType::Regular),
+          n if n >= FD_BASE && info.fds.iter().any(|&(fd, _)| fd as Ino == n - FD_BASE) => Ok(FileType::Symlink),
+          _ => Err(fs::Error::NotFound),
+        }
+      },
+    }
+  }
+}
+
+impl Filesystem for Procfs {
+  fn root(&self) -> Ino {
+    ROOT
+  }
+
+  fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, fs::Error> {
+    if try!(self.kind(dir)) != FileType::Directory {
+      return Err(fs::Error::NotADirectory);
+    }
+    match split(dir) {
+      (0, _) => {
+        if let Some(&(_, ino)) = GLOBAL.iter().find(|&&(n, _)| n == name) {
+          return Ok(ino);
+        }
+        match parse_number(name) {
+          Some(pid) if pid > 0 && process::lookup(pid).is_some() => Ok(pid_ino(pid, PID_DIR)),
+          _ => Err(fs::Error::NotFound),
+        }
+      },
+      (pid, PID_DIR) => {
+        PER_PID.iter().find(|&&(n, _, _)| n == name).map(|&(_, which, _)| pid_ino(pid, which)).ok_or(fs::Error::NotFound)
+      },
+      (pid, _) => {
+        let fd = try!(parse_number(name).ok_or(fs::Error::NotFound));
+        try!(fd_target(pid, fd));
+        Ok(pid_ino(pid, FD_BASE + fd as Ino))
+      },
+    }
+  }
+
+  fn stat(&self, ino: Ino) -> Result<Stat, fs::Error> {
+    let kind = try!(self.kind(ino));
+    let (mode, nlink, size) = match kind {
+      FileType::Directory => (0o555, 2, 0),
+      FileType::Symlink => {
+        let (pid, which) = split(ino);
+        (0o700, 1, try!(fd_target(pid, (which - FD_BASE) as Fd)).len())
+      },
+      _ => (0o444, 1, try!(self.render(ino)).len()),
+    };
+    Ok(Stat { ino: ino, kind: kind, mode: mode, nlink: nlink, uid: 0, gid: 0, size: size as u64, rdev: 0, mtime: 0 })
+  }
+
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, fs::Error> {
+    if try!(self.kind(ino)) == FileType::Directory {
+      return Err(fs::Error::IsADirectory);
+    }
+    let data = try!(self.render(ino));
+    let data = data.as_bytes();
+    let offset = offset as usize;
+    if offset >= data.len() {
+      return Ok(0);
+    }
+    let n = cmp::min(buf.len(), data.len() - offset);
+    buf[..n].clone_from_slice(&data[offset..offset+n]);
+    Ok(n)
+  }
+
+  fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, fs::Error> {
+    if try!(self.kind(dir)) != FileType::Directory {
+      return Err(fs::Error::NotADirectory);
+    }
+    let mut entries = vec![];
+    match split(dir) {
+      (0, _) => {
+        for &(name, ino) in GLOBAL.iter() {
+          entries.push(DirEntry { name: String::from(name), ino: ino, kind: FileType::Regular });
+        }
+        for pid in process::pids() {
+          let mut name = String::new();
+          let _ = write!(name, "{}", pid);
+          entries.push(DirEntry { name: name, ino: pid_ino(pid, PID_DIR), kind: FileType::Directory });
+        }
+      },
+      (pid, PID_DIR) => {
+        for &(name, which, kind) in PER_PID.iter() {
+          entries.push(DirEntry { name: String::from(name), ino: pid_ino(pid, which), kind: kind });
+        }
+      },
+      (pid, _) => {
+        for (fd, _) in try!(info(pid)).fds {
+          let mut name = String::new();
+          let _ = write!(name, "{}", fd);
+          entries.push(DirEntry { name: name, ino: pid_ino(pid, FD_BASE + fd as Ino), kind: FileType::Symlink });
+        }
+      },
+    }
+    Ok(entries)
+  }
+
+  fn readlink(&self, ino: Ino) -> Result<String, fs::Error> {
+    if try!(self.kind(ino)) != FileType::Symlink {
+      return Err(fs::Error::InvalidArgument);
+    }
+    let (pid, which) = split(ino);
+    fd_target(pid, (which - FD_BASE) as Fd)
+  }
+}
@@ -0,0 +1,320 @@
+use prelude::*;
+
+use sched::blocking;
+use super::errno::Errno;
+use super::process::{self,Process};
+use super::state::Registers;
+
+// Signal numbers are the same as Linux's on x86_64.
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+pub const NSIG: usize = 32;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+pub const SA_NODEFER: u64 = 0x40000000;
+pub const SA_RESETHAND: u64 = 0x80000000;
+pub const SA_RESTORER: u64 = 0x04000000;
+pub const SA_RESTART: u64 = 0x10000000;
+
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+pub fn bit(sig: usize) -> usize {
+  1 << sig
+}
+
+// These can neither be caught, ignored nor blocked.
+const UNCATCHABLE: usize = (1 << SIGKILL) | (1 << SIGSTOP);
+
+#[derive(Debug,PartialEq)]
+enum DefaultAction {
+  Terminate,
+  Ignore,
+  Stop,
+  Continue,
+}
+
+fn default_action(sig: usize) -> DefaultAction {
+  match sig {
+    SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+    SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+    SIGCONT => DefaultAction::Continue,
+    _ => DefaultAction::Terminate,
+  }
+}
+
+// Matches the kernel's `struct sigaction` layout on x86_64.
+#[derive(Debug,Clone,Copy)]
+#[repr(C)]
+pub struct Action {
+  pub handler: u64,
+  pub flags: u64,
+  pub restorer: u64,
+  pub mask: u64,
+}
+
+#[derive(Debug,Clone)]
+pub struct Signals {
+  actions: [Action; NSIG],
+  blocked: usize,
+}
+
+impl Signals {
+  pub fn new() -> Self {
+    Signals {
+      actions: [Action { handler: SIG_DFL, flags: 0, restorer: 0, mask: 0 }; NSIG],
+      blocked: 0,
+    }
+  }
+
+  pub fn action(&self, sig: usize) -> Result<Action, Errno> {
+    if sig == 0 || sig >= NSIG {
+      return Err(Errno::EINVAL);
+    }
+    Ok(self.actions[sig])
+  }
+
+  pub fn set_action(&mut self, sig: usize, act: Action) -> Result<(), Errno> {
+    if sig == 0 || sig >= NSIG || bit(sig) & UNCATCHABLE != 0 {
+      return Err(Errno::EINVAL);
+    }
+    self.actions[sig] = act;
+    Ok(())
+  }
+
+  // What execve does: caught signals go back to their default action, since
+  // the handlers are gone with the old image. Ignored ones stay ignored.
+  pub fn reset_handlers(&mut self) {
+    for act in self.actions.iter_mut() {
+      if act.handler != SIG_IGN {
+        *act = Action { handler: SIG_DFL, flags: 0, restorer: 0, mask: 0 };
+      }
+    }
+  }
+
+  pub fn blocked(&self) -> usize {
+    self.blocked
+  }
+
+  pub fn set_blocked(&mut self, mask: usize) {
+    self.blocked = mask & !UNCATCHABLE & !1;
+  }
+
+  pub fn procmask(&mut self, how: u64, set: usize) -> Result<(), Errno> {
+    let new = match how {
+      SIG_BLOCK => self.blocked | set,
+      SIG_UNBLOCK => self.blocked & !set,
+      SIG_SETMASK => set,
+      _ => return Err(Errno::EINVAL),
+    };
+    self.set_blocked(new);
+    Ok(())
+  }
+}
+
+// What we push onto the user stack before running a handler. The handler
+// "returns" into the restorer, which calls sigreturn with %rsp pointing at
+// `signo`.
+#[repr(C)]
+struct Frame {
+  restorer: u64,
+  signo: u64,
+  rip: u64,
+  rsp: u64,
+  rax: u64,
+  blocked: u64,
+  // Non-zero if the process is to be resumed with all of `regs`, see
+  // UsermodeState::saved_regs.
+  saved: u64,
+  regs: Registers,
+}
+
+#[derive(Debug)]
+pub enum Delivery {
+  // Go on running the process, possibly in a handler now.
+  Resume,
+  Terminate(usize),
+}
+
+// The lowest-numbered pending, unblocked signal that deliver() wouldn't just
+// throw away, and its action.
+fn interrupting_signal(p: &Process) -> Option<(usize, Action)> {
+  let deliverable = p.handle.pending() & !p.signals.blocked;
+  (1..NSIG).filter(|s| deliverable & bit(*s) != 0)
+    .map(|s| (s, p.signals.actions[s]))
+    .find(|&(s, act)| match act.handler {
+      SIG_IGN => false,
+      SIG_DFL => match default_action(s) {
+        DefaultAction::Ignore | DefaultAction::Continue => false,
+        _ => true,
+      },
+      _ => true,
+    })
+}
+
+// Whether `p` has a signal to deal with, so that blocking syscalls should give
+// up with EINTR. Whether they're then restarted is up to restarts().
+pub fn interrupting(p: &Process) -> bool {
+  interrupting_signal(p).is_some()
+}
+
+// After a syscall failed with EINTR: whether to run it again once the signal
+// has been dealt with. That's the case unless a handler without SA_RESTART
+// runs in between; default actions either kill us or stop and continue us.
+pub fn restarts(p: &Process) -> bool {
+  match interrupting_signal(p) {
+    Some((_, act)) => act.handler == SIG_DFL || act.flags & SA_RESTART != 0,
+    None => true,
+  }
+}
+
+// A synchronous fault: the signal can't be ignored or blocked away, since the
+// faulting instruction would just run again.
+pub fn force(p: &mut Process, sig: usize) {
+  let act = p.signals.actions[sig];
+  if act.handler == SIG_IGN || p.signals.blocked & bit(sig) != 0 {
+    p.signals.actions[sig].handler = SIG_DFL;
+    p.signals.blocked = p.signals.blocked & !bit(sig);
+  }
+  p.handle.post(sig);
+}
+
+// Called right before we return to user mode with `retval` in %rax.
+// Picks the lowest-numbered pending, unblocked signal (repeatedly, until one
+// needs a handler to run or nothing is left) and applies its action.
+pub fn deliver(p: &mut Process, retval: u64) -> Delivery {
+  loop {
+    let deliverable = p.handle.pending() & !p.signals.blocked;
+    if deliverable == 0 {
+      return Delivery::Resume;
+    }
+
+    let sig = (1..NSIG).find(|s| deliverable & bit(*s) != 0).unwrap();
+    if !p.handle.take(sig) {
+      continue; // somebody else took it in the meantime
+    }
+
+    let act = p.signals.actions[sig];
+    println!("Delivering signal {} to pid {}, action {:?}", sig, p.pid(), act);
+
+    if act.handler == SIG_IGN {
+      continue;
+    }
+
+    if act.handler == SIG_DFL {
+      match default_action(sig) {
+        DefaultAction::Ignore | DefaultAction::Continue => continue,
+        DefaultAction::Terminate => return Delivery::Terminate(sig),
+        DefaultAction::Stop => {
+          if let Some(sig) = stop(p, sig) {
+            return Delivery::Terminate(sig);
+          }
+          continue;
+        },
+      }
+    }
+
+    return match push_frame(p, sig, act, retval) {
+      Ok(()) => Delivery::Resume,
+      Err(_) => Delivery::Terminate(SIGSEGV),
+    };
+  }
+}
+
+// Sleep until SIGCONT (or SIGKILL) arrives. Posting any signal wakes us up
+// to check.
+fn stop(p: &mut Process, sig: usize) -> Option<usize> {
+  println!("pid {} stopped", p.pid());
+  process::stopped(p, sig);
+  loop {
+    if p.handle.take(SIGKILL) {
+      return Some(SIGKILL);
+    }
+    if p.handle.take(SIGCONT) {
+      println!("pid {} continued", p.pid());
+      process::continued(p);
+      return None;
+    }
+    let (wait, wake) = blocking::tokens(String::from("stopped"));
+    p.handle.sleep(&wait, wake);
+  }
+}
+
+fn push_frame(p: &mut Process, sig: usize, act: Action, retval: u64) -> Result<(), Errno> {
+  if act.restorer == 0 {
+    return Err(Errno::EFAULT);
+  }
+
+  // Skip the red zone, and align so that the handler sees %rsp+8 aligned
+  // to 16 bytes, just like after a call instruction.
+  let size = core::mem::size_of::<Frame>();
+  // %rsp is whatever userspace left there, so it may not leave room at all.
+  let frame_addr = try!((p.state.rsp as usize).checked_sub(128 + size)
+    .and_then(|a| (a & !0xf).checked_sub(8))
+    .ok_or(Errno::EFAULT));
+  try!(p.mm.check_range(frame_addr, size, true));
+
+  let frame = Frame {
+    restorer: act.restorer,
+    signo: sig as u64,
+    rip: p.state.rip,
+    rsp: p.state.rsp,
+    rax: retval,
+    blocked: p.signals.blocked as u64,
+    saved: p.state.saved_regs.is_some() as u64,
+    regs: p.state.saved_regs.unwrap_or([0; 16]),
+  };
+  unsafe { *(frame_addr as *mut Frame) = frame; }
+
+  let mut blocked = p.signals.blocked | act.mask as usize;
+  if act.flags & SA_NODEFER == 0 {
+    blocked = blocked | bit(sig);
+  }
+  p.signals.set_blocked(blocked);
+
+  if act.flags & SA_RESETHAND != 0 {
+    p.signals.actions[sig].handler = SIG_DFL;
+  }
+
+  p.state.enter_handler(act.handler, frame_addr as u64, sig as u64);
+  Ok(())
+}
+
+// Undo push_frame. The restorer has already popped the return address, so
+// %rsp points at `signo`. Returns the %rax value of the interrupted context.
+pub fn sigreturn(p: &mut Process) -> Result<u64, Errno> {
+  let frame_addr = p.state.rsp as usize - 8;
+  try!(p.mm.check_range(frame_addr, core::mem::size_of::<Frame>(), false));
+  let frame = unsafe { &*(frame_addr as *const Frame) };
+
+  p.state.rip = frame.rip;
+  p.state.rsp = frame.rsp;
+  p.state.saved_regs = if frame.saved != 0 { Some(frame.regs) } else { None };
+  p.signals.set_blocked(frame.blocked as usize);
+  Ok(frame.rax)
+}
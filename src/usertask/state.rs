@@ -9,19 +9,50 @@ extern {
   static mut trampoline_from_user_arg2 : u64;
   static mut trampoline_from_user_arg3 : u64;
   static mut trampoline_from_user_arg4 : u64;
+  static mut trampoline_from_user_arg5 : u64;
+  static mut trampoline_from_user_arg6 : u64;
+  static mut trampoline_from_user_arg7 : u64;
 
   static mut trampoline_from_user_rip : u64;
   static mut trampoline_from_user_rsp : u64;
   static mut trampoline_from_user_codeseg : u64;
   static mut trampoline_to_user_raxval : u64;
+  static mut trampoline_to_user_rdival : u64;
+  static mut trampoline_to_user_rbpval : u64;
+  static mut trampoline_from_user_rbp : u64;
+
+  static mut trampoline_user_regs : Registers;
+  static mut trampoline_to_user_resume : u64;
 }
 
 type uptr = u64;
 
+// All general purpose registers and rflags, in the order of
+// trampoline_user_regs: rax, rbx, rcx, rdx, rsi, rdi, rbp, r8 to r15, rflags.
+pub type Registers = [u64; 16];
+
+// The flags userspace gets to keep (CF, PF, AF, ZF, SF, TF, DF and OF), and
+// the ones it always has: interrupts on, and bit 1, which is always set.
+const RFLAGS_USER: u64 = 0xdd5;
+const RFLAGS_FIXED: u64 = 0x202;
+
 #[derive(Debug)]
 pub struct UsermodeState {
-  rip: uptr,
-  rsp: uptr,
+  pub rip: uptr,
+  pub rsp: uptr,
+
+  // Only used to pass the signal number to a signal handler
+  rdi: u64,
+  // Kept across syscalls, since compiled code expects its frame pointer back
+  rbp: u64,
+
+  // Registers that the next step() puts back in full, because the timer
+  // interrupted the process or because its syscall is to be restarted.
+  pub saved_regs: Option<Registers>,
+
+  // The registers of the last syscall: rax (the number) and the arguments
+  // in rbx, rcx, rdx, rsi, rdi and r8.
+  syscall: [u64; 7],
 }
 
 #[derive(Debug)]
@@ -29,19 +60,86 @@ pub enum SyscallType {
   Exit(i64),
   Write(u64, uptr, usize),
   Read(u64, uptr, usize),
-  Open(uptr, u64),
+  Open(uptr, u64, u64),
+  Brk(uptr),
+  Mmap(uptr, usize, u64, u64, i64, u64),
+  Munmap(uptr, usize),
+  Mprotect(uptr, usize, u64),
+  Pipe(uptr),
+  Close(u64),
+  Dup2(u64, u64),
+  Kill(i64, u64),
+  Sigaction(u64, uptr, uptr),
+  Sigprocmask(u64, uptr, uptr),
+  Sigreturn,
+  Getpid,
+  Ioctl(u64, u64, uptr),
+  Setpgid(u64, u64),
+  Getpgid(u64),
+  Setsid,
+  Stat(uptr, uptr),
+  Lstat(uptr, uptr),
+  Fstat(u64, uptr),
+  Lseek(u64, i64, u64),
+  Getdents(u64, uptr, usize),
+  Chdir(uptr),
+  Getcwd(uptr, usize),
+  Readlink(uptr, uptr, usize),
+  Execve(uptr, uptr, uptr),
+  Mkdir(uptr, u64),
+  Rmdir(uptr),
+  Unlink(uptr),
+  Rename(uptr, uptr),
+  Symlink(uptr, uptr),
+  Ftruncate(u64, u64),
+  Mount(uptr, uptr, uptr),
+  Sync,
+  Fork,
+  Wait4(i64, uptr, u64),
+  Getppid,
 }
 
 #[derive(Debug)]
 pub enum StepResult {
   Syscall(SyscallType),
+  // CPU exception number, faulting address (for page faults) and error code
+  Fault(u8, u64, u64),
+  // The timer interrupted the process; it can just go on running.
+  Preempted,
   Crash,
 }
 
 // TODO(safety): per-cpu storage of the statics
 impl UsermodeState {
   pub fn new(entrypoint: u64, initial_stack: u64) -> Self {
-    UsermodeState { rip: entrypoint, rsp: initial_stack }
+    UsermodeState { rip: entrypoint, rsp: initial_stack, rdi: 0, rbp: 0, saved_regs: None, syscall: [0; 7] }
+  }
+
+  // For fork: the child returns from the same syscall as the parent.
+  pub fn fork(&self) -> Self {
+    UsermodeState { rip: self.rip, rsp: self.rsp, rdi: 0, rbp: self.rbp, saved_regs: self.saved_regs, syscall: self.syscall }
+  }
+
+  // Make the next step() call `handler(signo)` on the given stack.
+  pub fn enter_handler(&mut self, handler: uptr, rsp: uptr, signo: u64) {
+    self.rip = handler;
+    self.rsp = rsp;
+    self.rdi = signo;
+    self.saved_regs = None;
+  }
+
+  // Make the next step() run the last syscall again, with the same
+  // arguments: back up over `int $49`, which is two bytes long.
+  pub fn restart_syscall(&mut self) {
+    let mut regs = [0; 16];
+    for (i, r) in self.syscall[..6].iter().enumerate() {
+      regs[i] = *r;
+    }
+    regs[6] = self.rbp;
+    regs[7] = self.syscall[6]; // r8
+    regs[15] = RFLAGS_FIXED;
+    self.rip -= 2;
+    self.saved_regs = Some(regs);
   }
 
   pub fn step(&mut self, raxval: u64) -> StepResult {
@@ -50,6 +148,18 @@ impl UsermodeState {
       trampoline_to_user_rsp = self.rsp;
       trampoline_to_user_rip = self.rip;
       trampoline_to_user_raxval = raxval;
+      trampoline_to_user_rdival = self.rdi;
+      trampoline_to_user_rbpval = self.rbp;
+      self.rdi = 0;
+      trampoline_to_user_resume = match self.saved_regs.take() {
+        Some(mut regs) => {
+          // The flags may have come from a signal frame, i.e. from userspace.
+          regs[15] = (regs[15] & RFLAGS_USER) | RFLAGS_FIXED;
+          trampoline_user_regs = regs;
+          1
+        },
+        None => 0,
+      };
 
       println!("Trampolining to userspace: rip@{:x} codeseg@{:x} rsp@{:x}", trampoline_to_user_rip, trampoline_to_user_codeseg, trampoline_to_user_rsp);
 
@@ -59,12 +169,58 @@ impl UsermodeState {
 
       self.rsp = trampoline_from_user_rsp;
       self.rip = trampoline_from_user_rip;
+      self.rbp = trampoline_from_user_rbp;
+      self.syscall = [trampoline_from_user_arg1, trampoline_from_user_arg2, trampoline_from_user_arg3,
+        trampoline_from_user_arg4, trampoline_from_user_arg5, trampoline_from_user_arg6, trampoline_from_user_arg7];
 
       match trampoline_from_user_arg1 {
         1 => StepResult::Syscall(SyscallType::Exit(trampoline_from_user_arg2 as i64)),
         2 => StepResult::Syscall(SyscallType::Write(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as uptr, trampoline_from_user_arg4 as usize)),
         3 => StepResult::Syscall(SyscallType::Read(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as uptr, trampoline_from_user_arg4 as usize)),
-        4 => StepResult::Syscall(SyscallType::Open(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as u64)),
+        4 => StepResult::Syscall(SyscallType::Open(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as u64, trampoline_from_user_arg4 as u64)),
+        5 => StepResult::Syscall(SyscallType::Brk(trampoline_from_user_arg2 as uptr)),
+        6 => StepResult::Syscall(SyscallType::Mmap(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as usize, trampoline_from_user_arg4 as u64, trampoline_from_user_arg5 as u64, trampoline_from_user_arg6 as i64, trampoline_from_user_arg7 as u64)),
+        7 => StepResult::Syscall(SyscallType::Munmap(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as usize)),
+        8 => StepResult::Syscall(SyscallType::Mprotect(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as usize, trampoline_from_user_arg4 as u64)),
+        9 => StepResult::Syscall(SyscallType::Pipe(trampoline_from_user_arg2 as uptr)),
+        10 => StepResult::Syscall(SyscallType::Close(trampoline_from_user_arg2 as u64)),
+        11 => StepResult::Syscall(SyscallType::Dup2(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as u64)),
+        12 => StepResult::Syscall(SyscallType::Kill(trampoline_from_user_arg2 as i64, trampoline_from_user_arg3 as u64)),
+        13 => StepResult::Syscall(SyscallType::Sigaction(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as uptr, trampoline_from_user_arg4 as uptr)),
+        14 => StepResult::Syscall(SyscallType::Sigprocmask(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as uptr, trampoline_from_user_arg4 as uptr)),
+        15 => StepResult::Syscall(SyscallType::Sigreturn),
+        16 => StepResult::Syscall(SyscallType::Getpid),
+        17 => StepResult::Syscall(SyscallType::Ioctl(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as u64, trampoline_from_user_arg4 as uptr)),
+        18 => StepResult::Syscall(SyscallType::Setpgid(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as u64)),
+        19 => StepResult::Syscall(SyscallType::Getpgid(trampoline_from_user_arg2 as u64)),
+        20 => StepResult::Syscall(SyscallType::Setsid),
+        21 => StepResult::Syscall(SyscallType::Stat(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as uptr)),
+        22 => StepResult::Syscall(SyscallType::Lstat(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as uptr)),
+        23 => StepResult::Syscall(SyscallType::Fstat(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as uptr)),
+        24 => StepResult::Syscall(SyscallType::Lseek(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as i64, trampoline_from_user_arg4 as u64)),
+        25 => StepResult::Syscall(SyscallType::Getdents(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as uptr, trampoline_from_user_arg4 as usize)),
+        26 => StepResult::Syscall(SyscallType::Chdir(trampoline_from_user_arg2 as uptr)),
+        27 => StepResult::Syscall(SyscallType::Getcwd(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as usize)),
+        28 => StepResult::Syscall(SyscallType::Readlink(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as uptr, trampoline_from_user_arg4 as usize)),
+        29 => StepResult::Syscall(SyscallType::Execve(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as uptr, trampoline_from_user_arg4 as uptr)),
+        30 => StepResult::Syscall(SyscallType::Mkdir(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as u64)),
+        31 => StepResult::Syscall(SyscallType::Rmdir(trampoline_from_user_arg2 as uptr)),
+        32 => StepResult::Syscall(SyscallType::Unlink(trampoline_from_user_arg2 as uptr)),
+        33 => StepResult::Syscall(SyscallType::Rename(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as uptr)),
+        34 => StepResult::Syscall(SyscallType::Symlink(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as uptr)),
+        35 => StepResult::Syscall(SyscallType::Ftruncate(trampoline_from_user_arg2 as u64, trampoline_from_user_arg3 as u64)),
+        36 => StepResult::Syscall(SyscallType::Mount(trampoline_from_user_arg2 as uptr, trampoline_from_user_arg3 as uptr, trampoline_from_user_arg4 as uptr)),
+        37 => StepResult::Syscall(SyscallType::Sync),
+        38 => StepResult::Syscall(SyscallType::Fork),
+        39 => StepResult::Syscall(SyscallType::Wait4(trampoline_from_user_arg2 as i64, trampoline_from_user_arg3 as uptr, trampoline_from_user_arg4 as u64)),
+        40 => StepResult::Syscall(SyscallType::Getppid),
+        // see isr_dispatcher in interrupthandler.s
+        0x100...0x1ff => StepResult::Fault((trampoline_from_user_arg1 - 0x100) as u8, trampoline_from_user_arg2, trampoline_from_user_arg3),
+        // see timer_isr
+        0x200 => {
+          self.saved_regs = Some(trampoline_user_regs);
+          StepResult::Preempted
+        },
         _ => StepResult::Crash,
       }
     }
@@ -0,0 +1,566 @@
+use prelude::*;
+use core::{cmp,mem};
+
+use drivers::uart::Uart;
+use drivers::virtio::serial::{self,Serialdev};
+use sched;
+use sched::blocking::{self,SignalToken};
+use sync::global_mutex::GlobalMutex;
+use super::errno::Errno;
+use super::fd::File;
+use super::process::{self,Handle,Pid,Process};
+use super::signal;
+
+// What a terminal needs from the device underneath it.
+pub trait Serial: Send + Sync + Debug {
+  // Blocks until there is input.
+  fn read(&self, buf: &mut [u8]) -> usize;
+  fn write(&self, data: &[u8]);
+}
+
+impl Serial for Serialdev {
+  fn read(&self, buf: &mut [u8]) -> usize { Serialdev::read(self, buf) }
+  fn write(&self, data: &[u8]) { Serialdev::write(self, data) }
+}
+
+impl Serial for Uart {
+  fn read(&self, buf: &mut [u8]) -> usize { Uart::read(self, buf) }
+  fn write(&self, data: &[u8]) { Uart::write(self, data) }
+}
+
+// ioctl requests, as on Linux
+const TCGETS: u64 = 0x5401;
+const TCSETS: u64 = 0x5402;
+const TCSETSW: u64 = 0x5403;
+const TCSETSF: u64 = 0x5404;
+const TIOCSCTTY: u64 = 0x540e;
+const TIOCGPGRP: u64 = 0x540f;
+const TIOCSPGRP: u64 = 0x5410;
+const TIOCGWINSZ: u64 = 0x5413;
+const TIOCSWINSZ: u64 = 0x5414;
+const FIONREAD: u64 = 0x541b;
+const TIOCNOTTY: u64 = 0x5422;
+const TIOCGSID: u64 = 0x5429;
+
+// c_iflag
+const INLCR: u32 = 0o100;
+const IGNCR: u32 = 0o200;
+const ICRNL: u32 = 0o400;
+
+// c_oflag
+const OPOST: u32 = 0o1;
+const ONLCR: u32 = 0o4;
+
+// c_lflag
+const ISIG: u32 = 0o1;
+const ICANON: u32 = 0o2;
+const ECHO: u32 = 0o10;
+const ECHOE: u32 = 0o20;
+const ECHOK: u32 = 0o40;
+const ECHONL: u32 = 0o100;
+const NOFLSH: u32 = 0o200;
+const ECHOCTL: u32 = 0o1000;
+const ECHOKE: u32 = 0o4000;
+const IEXTEN: u32 = 0o100000;
+
+// indices into c_cc
+const VINTR: usize = 0;
+const VQUIT: usize = 1;
+const VERASE: usize = 2;
+const VKILL: usize = 3;
+const VEOF: usize = 4;
+const VMIN: usize = 6;
+const VSUSP: usize = 10;
+const VEOL: usize = 11;
+const VWERASE: usize = 14;
+
+const NCCS: usize = 19;
+
+// Longest line we buffer in canonical mode; anything beyond is dropped.
+const MAX_LINE: usize = 4095;
+
+// The kernel's `struct termios`, which is what TCGETS and TCSETS take.
+#[derive(Debug,Clone,Copy)]
+#[repr(C)]
+pub struct Termios {
+  iflag: u32,
+  oflag: u32,
+  cflag: u32,
+  lflag: u32,
+  line: u8,
+  cc: [u8; NCCS],
+}
+
+impl Termios {
+  // What Linux gives a freshly opened terminal.
+  fn new() -> Self {
+    let mut cc = [0u8; NCCS];
+    cc[VINTR] = 0x03; // ^C
+    cc[VQUIT] = 0x1c; // ^\
+    cc[VERASE] = 0x7f; // DEL
+    cc[VKILL] = 0x15; // ^U
+    cc[VEOF] = 0x04; // ^D
+    cc[VMIN] = 1;
+    cc[VSUSP] = 0x1a; // ^Z
+    cc[VWERASE] = 0x17; // ^W
+
+    Termios {
+      iflag: ICRNL,
+      oflag: OPOST | ONLCR,
+      cflag: 0o2277, // B38400 | CS8 | CREAD | HUPCL
+      lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN,
+      line: 0,
+      cc: cc,
+    }
+  }
+
+  fn is(&self, c: u8, idx: usize) -> bool {
+    // A _POSIX_VDISABLE (0) entry matches nothing
+    self.cc[idx] != 0 && self.cc[idx] == c
+  }
+}
+
+#[derive(Debug,Clone,Copy)]
+#[repr(C)]
+struct Winsize {
+  rows: u16,
+  cols: u16,
+  xpixel: u16,
+  ypixel: u16,
+}
+
+#[derive(Debug)]
+struct Inner {
+  termios: Termios,
+  winsize: Winsize,
+
+  // The line currently being edited (canonical mode only).
+  line: Vec<u8>,
+
+  // Input that read() can return. In canonical mode every entry is one line,
+  // and an empty one is an end-of-file from ^D.
+  ready: VecDeque<Vec<u8>>,
+
+  // Readers waiting for input (or a signal), woken by the input task.
+  read_waiters: Vec<SignalToken>,
+  // Whether the input task is running, see Tty::open
+  listening: bool,
+
+  // The session this is the controlling terminal of (0 for none), and its
+  // foreground process group.
+  session: Pid,
+  pgrp: Pid,
+}
+
+impl Inner {
+  fn ready_bytes(&self) -> usize {
+    self.ready.iter().fold(0, |sum, l| sum + l.len())
+  }
+
+  fn flush_input(&mut self) {
+    self.line.clear();
+    self.ready.clear();
+  }
+
+  fn signal(&mut self, sig: usize) {
+    if self.termios.lflag & NOFLSH == 0 {
+      self.flush_input();
+    }
+    if self.pgrp != 0 {
+      let _ = process::signal_group(self.pgrp, sig);
+    }
+  }
+
+  // Echo `c` the way ECHOCTL wants control characters shown.
+  fn echo_char(&self, c: u8, out: &mut Vec<u8>) {
+    if self.termios.lflag & ECHOCTL != 0 && c < 0x20 && c != b'\n' && c != b'\t' {
+      out.push(b'^');
+      out.push(c + 0x40);
+    } else {
+      out.push(c);
+    }
+  }
+
+  fn erase(&mut self, out: &mut Vec<u8>) -> bool {
+    match self.line.pop() {
+      Some(c) => {
+        if self.termios.lflag & (ECHO | ECHOE) == ECHO | ECHOE {
+          let width = if self.termios.lflag & ECHOCTL != 0 && c < 0x20 && c != b'\t' { 2 } else { 1 };
+          for _ in 0..width {
+            out.extend_from_slice(b"\x08 \x08");
+          }
+        }
+        true
+      },
+      None => false,
+    }
+  }
+
+  // Run one input character through the line discipline. Anything that
+  // should be echoed is appended to `out`.
+  fn input(&mut self, mut c: u8, out: &mut Vec<u8>) {
+    let t = self.termios;
+
+    if c == b'\r' {
+      if t.iflag & IGNCR != 0 {
+        return;
+      }
+      if t.iflag & ICRNL != 0 {
+        c = b'\n';
+      }
+    } else if c == b'\n' && t.iflag & INLCR != 0 {
+      c = b'\r';
+    }
+
+    if t.lflag & ISIG != 0 {
+      let sig = if t.is(c, VINTR) {
+        Some(signal::SIGINT)
+      } else if t.is(c, VQUIT) {
+        Some(signal::SIGQUIT)
+      } else if t.is(c, VSUSP) {
+        Some(signal::SIGTSTP)
+      } else {
+        None
+      };
+      if let Some(sig) = sig {
+        if t.lflag & ECHO != 0 {
+          self.echo_char(c, out);
+          out.push(b'\n');
+        }
+        self.signal(sig);
+        return;
+      }
+    }
+
+    if t.lflag & ICANON == 0 {
+      if t.lflag & ECHO != 0 {
+        out.push(c);
+      }
+      match self.ready.back_mut() {
+        Some(l) => { l.push(c); return; },
+        None => {},
+      }
+      self.ready.push_back(vec![c]);
+      return;
+    }
+
+    if t.is(c, VERASE) {
+      self.erase(out);
+    } else if t.is(c, VKILL) {
+      if t.lflag & ECHOKE != 0 {
+        while self.erase(out) {}
+      } else {
+        self.line.clear();
+        if t.lflag & ECHOK != 0 {
+          out.push(b'\n');
+        }
+      }
+    } else if t.is(c, VWERASE) && t.lflag & IEXTEN != 0 {
+      while self.line.last() == Some(&b' ') {
+        self.erase(out);
+      }
+      while self.line.len() > 0 && self.line.last() != Some(&b' ') {
+        self.erase(out);
+      }
+    } else if t.is(c, VEOF) {
+      // Hands over what we have so far, or signals EOF on an empty line.
+      let line = self.line.drain(..).collect();
+      self.ready.push_back(line);
+    } else if c == b'\n' || t.is(c, VEOL) {
+      if t.lflag & (ECHO | ECHONL) != 0 {
+        out.push(c);
+      }
+      self.line.push(c);
+      let line = self.line.drain(..).collect();
+      self.ready.push_back(line);
+    } else if self.line.len() < MAX_LINE {
+      if t.lflag & ECHO != 0 {
+        self.echo_char(c, out);
+      }
+      self.line.push(c);
+    }
+  }
+
+  // Take up to buf.len() bytes of input: at most one line in canonical mode,
+  // as much as there is otherwise.
+  fn take(&mut self, buf: &mut [u8]) -> usize {
+    let canonical = self.termios.lflag & ICANON != 0;
+    let mut n = 0;
+
+    while n < buf.len() {
+      let mut line = match self.ready.pop_front() {
+        Some(l) => l,
+        None => break,
+      };
+      let count = cmp::min(buf.len() - n, line.len());
+      for (i, b) in line.drain(..count).enumerate() {
+        buf[n + i] = b;
+      }
+      n += count;
+
+      if line.len() > 0 {
+        self.ready.push_front(line);
+      }
+      if canonical {
+        break;
+      }
+    }
+    n
+  }
+
+  // Switching between canonical and raw mode keeps whatever input we have.
+  fn set_termios(&mut self, t: Termios) {
+    let was_canonical = self.termios.lflag & ICANON != 0;
+    self.termios = t;
+
+    if was_canonical && t.lflag & ICANON == 0 {
+      let line = self.line.drain(..).collect();
+      self.ready.push_back(line);
+      let all = self.ready.drain(..).flat_map(|l| l.into_iter()).collect();
+      self.ready.push_back(all);
+    } else if !was_canonical && t.lflag & ICANON != 0 {
+      for l in self.ready.drain(..) {
+        self.line.extend_from_slice(&l[..]);
+      }
+    }
+  }
+}
+
+// A terminal on top of a serial device. Once it's opened, a kernel task
+// feeds all input through the line discipline as it arrives, so that ^C
+// takes effect while the foreground process is busy with something else.
+#[derive(Debug)]
+pub struct Tty {
+  // The device node it's registered as
+  path: &'static str,
+  dev: Arc<Serial>,
+  inner: GlobalMutex<Inner>,
+}
+
+impl Tty {
+  pub fn new(path: &'static str, dev: Arc<Serial>) -> Self {
+    Tty {
+      path: path,
+      dev: dev,
+      inner: GlobalMutex::new(Inner {
+        termios: Termios::new(),
+        winsize: Winsize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+        line: vec![],
+        ready: VecDeque::new(),
+        read_waiters: vec![],
+        listening: false,
+        session: 0,
+        pgrp: 0,
+      }),
+    }
+  }
+
+  // Like opening a terminal on Linux: a session leader without a controlling
+  // terminal acquires this one. The first open starts taking input.
+  pub fn open(tty: &Arc<Tty>, p: &Handle) {
+    let mut t = tty.inner.lock();
+    if t.session == 0 && p.is_session_leader() {
+      t.session = p.sid();
+      t.pgrp = p.pgid();
+    }
+    if !t.listening {
+      t.listening = true;
+      let listener = tty.clone();
+      sched::add_task(move || listener.listen(), "tty input");
+    }
+  }
+
+  // The input task: never blocks on the device with our lock held.
+  fn listen(&self) {
+    let mut raw = [0u8; serial::RX_BUF_SIZE];
+    loop {
+      let n = self.dev.read(&mut raw);
+      self.feed(&raw[..n]);
+    }
+  }
+
+  fn feed(&self, data: &[u8]) {
+    let mut echo = vec![];
+    {
+      let mut t = self.inner.lock();
+      for c in data {
+        t.input(*c, &mut echo);
+      }
+      for w in t.read_waiters.drain(..) {
+        w.signal();
+      }
+    }
+    if echo.len() > 0 {
+      self.output(&echo[..]);
+    }
+  }
+
+  fn output(&self, data: &[u8]) {
+    let oflag = self.inner.lock().termios.oflag;
+    if oflag & (OPOST | ONLCR) != OPOST | ONLCR {
+      self.dev.write(data);
+      return;
+    }
+
+    for (i, chunk) in data.split(|c| *c == b'\n').enumerate() {
+      if i > 0 {
+        self.dev.write(b"\r\n");
+      }
+      self.dev.write(chunk);
+    }
+  }
+
+  fn read_user<T: Copy>(p: &Process, arg: usize) -> Result<T, Errno> {
+    try!(p.mm.check_range(arg, mem::size_of::<T>(), false));
+    Ok(unsafe { *(arg as *const T) })
+  }
+
+  fn write_user<T: Copy>(p: &Process, arg: usize, v: T) -> Result<usize, Errno> {
+    try!(p.mm.check_range(arg, mem::size_of::<T>(), true));
+    unsafe { *(arg as *mut T) = v; }
+    Ok(0)
+  }
+}
+
+impl File for Tty {
+  fn read(&self, p: &Process, buf: &mut [u8]) -> Result<usize, Errno> {
+    if buf.len() == 0 {
+      return Ok(0);
+    }
+
+    loop {
+      let (wait, wake) = {
+        let mut t = self.inner.lock();
+        let canonical = t.termios.lflag & ICANON != 0;
+        let min = t.termios.cc[VMIN] as usize;
+        if (canonical && t.ready.len() > 0) || (!canonical && t.ready_bytes() >= cmp::max(min, 1)) {
+          return Ok(t.take(buf));
+        }
+        // VMIN == 0 means polling, so take whatever the input task has fed
+        // us so far. VTIME isn't supported, we have no timers.
+        if !canonical && min == 0 {
+          return Ok(t.take(buf));
+        }
+        // Only give up if the signal went to us: a ^C for another process
+        // group doesn't concern this reader. Whether the read then fails
+        // with EINTR or is restarted is up to the signal code.
+        if signal::interrupting(p) {
+          return Err(Errno::EINTR);
+        }
+
+        // Register while still holding the lock, so that we can't miss a
+        // wakeup. A signal for us wakes us up as well.
+        let (wait, wake) = blocking::tokens(String::from("tty read"));
+        t.read_waiters.push(wake.clone());
+        (wait, wake)
+      };
+      p.handle.sleep(&wait, wake);
+    }
+  }
+
+  fn write(&self, _p: &Process, buf: &[u8]) -> Result<usize, Errno> {
+    self.output(buf);
+    Ok(buf.len())
+  }
+
+  fn ioctl(&self, p: &Process, req: u64, arg: usize) -> Result<usize, Errno> {
+    match req {
+      TCGETS => {
+        let t = self.inner.lock().termios;
+        Tty::write_user(p, arg, t)
+      },
+      TCSETS | TCSETSW | TCSETSF => {
+        let new: Termios = try!(Tty::read_user(p, arg));
+        let mut t = self.inner.lock();
+        if req == TCSETSF {
+          t.flush_input();
+        }
+        t.set_termios(new);
+        Ok(0)
+      },
+      TIOCGWINSZ => {
+        let w = self.inner.lock().winsize;
+        Tty::write_user(p, arg, w)
+      },
+      TIOCSWINSZ => {
+        let w: Winsize = try!(Tty::read_user(p, arg));
+        let pgrp = {
+          let mut t = self.inner.lock();
+          t.winsize = w;
+          t.pgrp
+        };
+        if pgrp != 0 {
+          let _ = process::signal_group(pgrp, signal::SIGWINCH);
+        }
+        Ok(0)
+      },
+      FIONREAD => {
+        let n = self.inner.lock().ready_bytes() as i32;
+        Tty::write_user(p, arg, n)
+      },
+      TIOCGPGRP => {
+        let t = self.inner.lock();
+        if t.session != p.handle.sid() {
+          return Err(Errno::ENOTTY);
+        }
+        let pgrp = t.pgrp as i32;
+        drop(t);
+        Tty::write_user(p, arg, pgrp)
+      },
+      TIOCSPGRP => {
+        let pgrp: i32 = try!(Tty::read_user(p, arg));
+        if pgrp <= 0 {
+          return Err(Errno::EINVAL);
+        }
+        let mut t = self.inner.lock();
+        if t.session != p.handle.sid() {
+          return Err(Errno::ENOTTY);
+        }
+        if process::group_session(pgrp as Pid) != Some(t.session) {
+          return Err(Errno::EPERM);
+        }
+        t.pgrp = pgrp as Pid;
+        Ok(0)
+      },
+      TIOCGSID => {
+        let session = self.inner.lock().session;
+        if session == 0 || session != p.handle.sid() {
+          return Err(Errno::ENOTTY);
+        }
+        Tty::write_user(p, arg, session as i32)
+      },
+      TIOCSCTTY => {
+        let mut t = self.inner.lock();
+        if !p.handle.is_session_leader() {
+          return Err(Errno::EPERM);
+        }
+        if t.session == p.handle.sid() {
+          return Ok(0);
+        }
+        // Stealing a terminal from another session isn't supported.
+        if t.session != 0 {
+          return Err(Errno::EPERM);
+        }
+        t.session = p.handle.sid();
+        t.pgrp = p.handle.pgid();
+        Ok(0)
+      },
+      TIOCNOTTY => {
+        let mut t = self.inner.lock();
+        if t.session != p.handle.sid() {
+          return Err(Errno::ENOTTY);
+        }
+        if p.handle.is_session_leader() {
+          t.session = 0;
+          t.pgrp = 0;
+        }
+        Ok(0)
+      },
+      _ => Err(Errno::ENOTTY),
+    }
+  }
+
+  fn path(&self) -> String {
+    String::from(self.path)
+  }
+}
@@ -1,28 +1,33 @@
 #include <stdio.h>
-
-int open(const char *path, int flags);
-int read(int fd, const void *buf, size_t count);
-int write(int fd, const void *buf, size_t count);
+#include <unistd.h>
 
 int main() {
-  int fd = open("/dev/console", 0);
-
+  // The console's line discipline does the editing, so every read gives us
+  // one complete line.
   while(1) {
-    write(fd, "$ ", 2);
+    write(1, "$ ", 2);
 
     char buf[256] = {0};
-    read(fd, buf, 256);
+    int n = read(0, buf, 255);
+    if(n == 0) {
+      break; // ^D
+    }
+    if(n < 0) {
+      write(1, "\n", 1); // ^C
+      continue;
+    }
 
     int pos = 0;
     while(buf[pos] && buf[pos] != ' ' && buf[pos] != '\n') {
       pos++;
     }
-    buf[pos] = 0; // set space to \0
+    char sep = buf[pos];
+    buf[pos] = 0;
 
     if(pos == 0) {
-      printf("");
+      continue;
     } else if(buf[0] == 'e' && buf[1] == 'c' && buf[2] == 'h' && buf[3] == 'o' && buf[4] == 0) {
-      printf("%s", buf+pos+1);
+      printf("%s", sep == ' ' ? buf+pos+1 : "\n");
     } else {
       printf("sh: %s: command not found\n", buf);
     }
@@ -1,61 +1,358 @@
 #include <cor/syscall.h>
+#include <sys/mman.h>
+#include <signal.h>
+#include <sys/ioctl.h>
+#include <termios.h>
+#include <fcntl.h>
+#include <sys/stat.h>
+#include <dirent.h>
+#include <sys/mount.h>
+#include <sys/wait.h>
 #include <vendor/stdarg.h>
 #include <stdint.h>
 
-int exit(int ret) {
-  __asm__ ( "movq %0, %%rax\n"
-            "movq %1, %%rbx\n"
-            "int $49"
+// Arguments go into %rbx, %rcx, %rdx, %rsi, %rdi and %r8, the return value
+// comes back in %rax. The kernel only preserves %rbp for us.
+static uint64_t cor_syscall(uint64_t num, uint64_t a1, uint64_t a2, uint64_t a3,
+                            uint64_t a4, uint64_t a5, uint64_t a6) {
+  register uint64_t r8 __asm__("r8") = a6;
+  __asm__ volatile ( "int $49"
+          : "+a"(num), "+b"(a1), "+c"(a2), "+d"(a3), "+S"(a4), "+D"(a5), "+r"(r8)
           :
-          : "r"((uint64_t)SYSCALL_EXIT), "r"((uint64_t)ret)
-          : "rax", "rbx", "rcx", "rdx", "r12", "r13", "r14", "r15"
+          : "r9", "r10", "r11", "r12", "r13", "r14", "r15", "memory"
           );
-  return 0;
+  return num;
 }
 
-int open(const char *path, int flags) {
-  __asm__ ( "movq %0, %%rax\n"
-            "movq %1, %%rbx\n"
-            "movq %2, %%rcx\n"
-            "int $49"
-          :
-          : "r"((uint64_t)SYSCALL_OPEN), "r"((uint64_t)path), "r"((uint64_t)flags)
-          : "rax", "rbx", "rcx", "rdx", "r12", "r13", "r14", "r15"
-          );
+int exit(int ret) {
+  cor_syscall(SYSCALL_EXIT, (uint64_t)ret, 0, 0, 0, 0, 0);
   return 0;
 }
 
+// The mode is only there with O_CREAT.
+int open(const char *path, int flags, ...) {
+  int mode = 0;
+  if(flags & O_CREAT) {
+    va_list ap;
+    va_start(ap, flags);
+    mode = va_arg(ap, int);
+    va_end(ap);
+  }
+  return (int)cor_syscall(SYSCALL_OPEN, (uint64_t)path, (uint64_t)flags, (uint64_t)mode, 0, 0, 0);
+}
+
+int stat(const char *path, struct stat *buf) {
+  return (int)cor_syscall(SYSCALL_STAT, (uint64_t)path, (uint64_t)buf, 0, 0, 0, 0);
+}
+
+int lstat(const char *path, struct stat *buf) {
+  return (int)cor_syscall(SYSCALL_LSTAT, (uint64_t)path, (uint64_t)buf, 0, 0, 0, 0);
+}
+
+int fstat(int fd, struct stat *buf) {
+  return (int)cor_syscall(SYSCALL_FSTAT, (uint64_t)fd, (uint64_t)buf, 0, 0, 0, 0);
+}
+
+long lseek(int fd, long offset, int whence) {
+  return (long)cor_syscall(SYSCALL_LSEEK, (uint64_t)fd, (uint64_t)offset, (uint64_t)whence, 0, 0, 0);
+}
+
+int mkdir(const char *path, int mode) {
+  return (int)cor_syscall(SYSCALL_MKDIR, (uint64_t)path, (uint64_t)mode, 0, 0, 0, 0);
+}
+
+int rmdir(const char *path) {
+  return (int)cor_syscall(SYSCALL_RMDIR, (uint64_t)path, 0, 0, 0, 0, 0);
+}
+
+int unlink(const char *path) {
+  return (int)cor_syscall(SYSCALL_UNLINK, (uint64_t)path, 0, 0, 0, 0, 0);
+}
+
+int rename(const char *from, const char *to) {
+  return (int)cor_syscall(SYSCALL_RENAME, (uint64_t)from, (uint64_t)to, 0, 0, 0, 0);
+}
+
+int symlink(const char *target, const char *path) {
+  return (int)cor_syscall(SYSCALL_SYMLINK, (uint64_t)target, (uint64_t)path, 0, 0, 0, 0);
+}
+
+int ftruncate(int fd, long length) {
+  return (int)cor_syscall(SYSCALL_FTRUNCATE, (uint64_t)fd, (uint64_t)length, 0, 0, 0, 0);
+}
+
+int mount(const char *source, const char *target, const char *fstype) {
+  return (int)cor_syscall(SYSCALL_MOUNT, (uint64_t)source, (uint64_t)target, (uint64_t)fstype, 0, 0, 0);
+}
+
+void sync() {
+  cor_syscall(SYSCALL_SYNC, 0, 0, 0, 0, 0, 0);
+}
+
+int chdir(const char *path) {
+  return (int)cor_syscall(SYSCALL_CHDIR, (uint64_t)path, 0, 0, 0, 0, 0);
+}
+
+char *getcwd(char *buf, size_t size) {
+  if((long)cor_syscall(SYSCALL_GETCWD, (uint64_t)buf, (uint64_t)size, 0, 0, 0, 0) < 0) {
+    return 0;
+  }
+  return buf;
+}
+
+long readlink(const char *path, char *buf, size_t size) {
+  return (long)cor_syscall(SYSCALL_READLINK, (uint64_t)path, (uint64_t)buf, (uint64_t)size, 0, 0, 0);
+}
+
+int execve(const char *path, char *const argv[], char *const envp[]) {
+  return (int)cor_syscall(SYSCALL_EXECVE, (uint64_t)path, (uint64_t)argv, (uint64_t)envp, 0, 0, 0);
+}
+
+#define EXECL_MAX_ARGS 32
+int execl(const char *path, const char *arg, ...) {
+  char *argv[EXECL_MAX_ARGS + 1];
+  int i = 0;
+  va_list ap;
+  va_start(ap, arg);
+  while(arg && i < EXECL_MAX_ARGS) {
+    argv[i++] = (char *)arg;
+    arg = va_arg(ap, const char *);
+  }
+  va_end(ap);
+  argv[i] = 0;
+  char *envp[] = {0};
+  return execve(path, argv, envp);
+}
+
+struct DIR {
+  int fd;
+  int pos, len;
+  char buf[1024];
+};
+
+DIR *opendir(const char *name) {
+  int fd = open(name, O_RDONLY | O_DIRECTORY);
+  if(fd < 0) {
+    return 0;
+  }
+  DIR *d = malloc(sizeof(DIR));
+  d->fd = fd;
+  d->pos = d->len = 0;
+  return d;
+}
+
+struct dirent *readdir(DIR *d) {
+  if(d->pos >= d->len) {
+    int n = (int)cor_syscall(SYSCALL_GETDENTS, (uint64_t)d->fd, (uint64_t)d->buf, sizeof(d->buf), 0, 0, 0);
+    if(n <= 0) {
+      return 0;
+    }
+    d->pos = 0;
+    d->len = n;
+  }
+  struct dirent *e = (struct dirent *)(d->buf + d->pos);
+  d->pos += e->d_reclen;
+  return e;
+}
+
+// There's no free() yet, so the DIR itself leaks.
+int closedir(DIR *d) {
+  return close(d->fd);
+}
+
 int read(int fd, const void *buf, size_t count) {
-  __asm__ ( "movq %0, %%rax\n"
-            "movq %1, %%rbx\n"
-            "movq %2, %%rcx\n"
-            "movq %3, %%rdx\n"
-            "int $49"
-          :
-          : "r"((uint64_t)SYSCALL_READ), "r"((uint64_t)fd), "r"((uint64_t)buf), "r"((uint64_t)count)
-          : "rax", "rbx", "rcx", "rdx", "r12", "r13", "r14", "r15"
-          );
-  return 0;
+  return (int)cor_syscall(SYSCALL_READ, (uint64_t)fd, (uint64_t)buf, (uint64_t)count, 0, 0, 0);
 }
 
 int write(int fd, const void *buf, size_t count) {
-  __asm__ ( "movq %0, %%rax\n"
-            "movq %1, %%rbx\n"
-            "movq %2, %%rcx\n"
-            "movq %3, %%rdx\n"
-            "int $49"
-          :
-          : "r"((uint64_t)SYSCALL_WRITE), "r"((uint64_t)fd), "r"((uint64_t)buf), "r"((uint64_t)count)
-          : "rax", "rbx", "rcx", "rdx", "r12", "r13", "r14", "r15"
-          );
-  return 0;
+  return (int)cor_syscall(SYSCALL_WRITE, (uint64_t)fd, (uint64_t)buf, (uint64_t)count, 0, 0, 0);
+}
+
+int pipe(int fildes[2]) {
+  return (int)cor_syscall(SYSCALL_PIPE, (uint64_t)fildes, 0, 0, 0, 0, 0);
+}
+
+int close(int fd) {
+  return (int)cor_syscall(SYSCALL_CLOSE, (uint64_t)fd, 0, 0, 0, 0, 0);
+}
+
+int dup2(int oldfd, int newfd) {
+  return (int)cor_syscall(SYSCALL_DUP2, (uint64_t)oldfd, (uint64_t)newfd, 0, 0, 0, 0);
 }
 
-void *mallocstart = (void*)0x50000;
+int getpid(void) {
+  return (int)cor_syscall(SYSCALL_GETPID, 0, 0, 0, 0, 0, 0);
+}
 
+int getppid(void) {
+  return (int)cor_syscall(SYSCALL_GETPPID, 0, 0, 0, 0, 0, 0);
+}
+
+int fork(void) {
+  return (int)cor_syscall(SYSCALL_FORK, 0, 0, 0, 0, 0, 0);
+}
+
+void _exit(int status) {
+  exit(status);
+}
+
+int waitpid(int pid, int *status, int options) {
+  return (int)cor_syscall(SYSCALL_WAIT4, (uint64_t)(long)pid, (uint64_t)status, (uint64_t)options, 0, 0, 0);
+}
+
+int wait(int *status) {
+  return waitpid(-1, status, 0);
+}
+
+// What ash uses. We don't keep resource usage, so `rusage` is left alone.
+int wait3(int *status, int options, void *rusage) {
+  rusage = rusage;
+  return waitpid(-1, status, options);
+}
+
+int kill(int pid, int sig) {
+  return (int)cor_syscall(SYSCALL_KILL, (uint64_t)(long)pid, (uint64_t)sig, 0, 0, 0, 0);
+}
+
+int raise(int sig) {
+  return kill(getpid(), sig);
+}
+
+#define STR_(x) #x
+#define STR(x) STR_(x)
+
+// Handlers return here. The kernel finds its saved state right above %rsp.
+void __cor_sigreturn(void);
+__asm__ (
+  ".text\n"
+  "__cor_sigreturn:\n"
+  "  mov $" STR(SYSCALL_SIGRETURN) ", %rax\n"
+  "  int $49\n"
+);
+
+int sigaction(int sig, const struct sigaction *act, struct sigaction *oldact) {
+  struct sigaction kact;
+  if(act) {
+    kact = *act;
+    kact.sa_flags |= SA_RESTORER;
+    kact.sa_restorer = __cor_sigreturn;
+    act = &kact;
+  }
+  return (int)cor_syscall(SYSCALL_SIGACTION, (uint64_t)sig, (uint64_t)act, (uint64_t)oldact, 0, 0, 0);
+}
+
+int sigprocmask(int how, const sigset_t *set, sigset_t *oldset) {
+  return (int)cor_syscall(SYSCALL_SIGPROCMASK, (uint64_t)how, (uint64_t)set, (uint64_t)oldset, 0, 0, 0);
+}
+
+int killpg(int pgrp, int sig) {
+  return kill(-pgrp, sig);
+}
+
+int ioctl(int fd, unsigned long request, ...) {
+  va_list ap;
+  va_start(ap, request);
+  void *arg = va_arg(ap, void *);
+  va_end(ap);
+  return (int)cor_syscall(SYSCALL_IOCTL, (uint64_t)fd, (uint64_t)request, (uint64_t)arg, 0, 0, 0);
+}
+
+int isatty(int fd) {
+  struct termios t;
+  return ioctl(fd, TCGETS, &t) == 0;
+}
+
+int tcgetattr(int fd, struct termios *t) {
+  return ioctl(fd, TCGETS, t);
+}
+
+int tcsetattr(int fd, int action, const struct termios *t) {
+  return ioctl(fd, TCSETS + action, t);
+}
+
+int tcgetpgrp(int fd) {
+  int pgrp;
+  if(ioctl(fd, TIOCGPGRP, &pgrp) != 0) {
+    return -1;
+  }
+  return pgrp;
+}
+
+int tcsetpgrp(int fd, int pgrp) {
+  return ioctl(fd, TIOCSPGRP, &pgrp);
+}
+
+int setpgid(int pid, int pgid) {
+  return (int)cor_syscall(SYSCALL_SETPGID, (uint64_t)pid, (uint64_t)pgid, 0, 0, 0, 0);
+}
+
+int getpgid(int pid) {
+  return (int)cor_syscall(SYSCALL_GETPGID, (uint64_t)pid, 0, 0, 0, 0, 0);
+}
+
+int getpgrp(void) {
+  return getpgid(0);
+}
+
+int setpgrp(void) {
+  return setpgid(0, 0);
+}
+
+int setsid(void) {
+  return (int)cor_syscall(SYSCALL_SETSID, 0, 0, 0, 0, 0, 0);
+}
+
+sighandler_t signal(int sig, sighandler_t handler) {
+  struct sigaction act = {0}, old;
+  act.sa_handler = handler;
+  act.sa_flags = SA_RESTART; // BSD semantics, like glibc
+  if(sigaction(sig, &act, &old) != 0) {
+    return SIG_ERR;
+  }
+  return old.sa_handler;
+}
+
+void *brk(void *addr) {
+  return (void *)cor_syscall(SYSCALL_BRK, (uint64_t)addr, 0, 0, 0, 0, 0);
+}
+
+void *sbrk(long increment) {
+  void *old = brk(0);
+  if(increment == 0) {
+    return old;
+  }
+  if(brk(old + increment) != old + increment) {
+    return (void *)-1;
+  }
+  return old;
+}
+
+// Negative return values in [-4095; -1] are error numbers.
+void *mmap(void *addr, size_t length, int prot, int flags, int fd, long offset) {
+  uint64_t ret = cor_syscall(SYSCALL_MMAP, (uint64_t)addr, (uint64_t)length, (uint64_t)prot,
+                             (uint64_t)flags, (uint64_t)fd, (uint64_t)offset);
+  if(ret > (uint64_t)-4096) {
+    return MAP_FAILED;
+  }
+  return (void *)ret;
+}
+
+int munmap(void *addr, size_t length) {
+  return (int)cor_syscall(SYSCALL_MUNMAP, (uint64_t)addr, (uint64_t)length, 0, 0, 0, 0);
+}
+
+int mprotect(void *addr, size_t length, int prot) {
+  return (int)cor_syscall(SYSCALL_MPROTECT, (uint64_t)addr, (uint64_t)length, (uint64_t)prot, 0, 0, 0);
+}
+
+// Still just a bump allocator, but on top of a real heap now.
 void *malloc(size_t size) {
-  mallocstart += size;
-  return mallocstart - size;
+  size = (size + 15) & ~((size_t)15);
+  void *p = sbrk(size);
+  if(p == (void *)-1) {
+    return 0;
+  }
+  return p;
 }
 
 size_t strlen(const char *str) {
@@ -182,13 +479,19 @@ int printf(const char *format, ...) {
   return 0;
 }
 
-void main();
+int main();
 
 // TODO: .data and .bss sections break (probably anything besides .text)
 void _start() {
+  // We're entered without a return address, so argc sits right above the
+  // saved %rbp, followed by argv and envp.
+  uint64_t *sp = (uint64_t *)__builtin_frame_address(0) + 1;
+  int argc = (int)sp[0];
+  char **argv = (char **)(sp + 1);
+  char **envp = argv + argc + 1;
+
   printf("Hello from _start.\n");
-  main();
-  exit(0xBABE);
+  exit(main(argc, argv, envp));
 
   while(1);
 }
@@ -202,38 +505,18 @@ int stdout = 2;
 int *__errno_location;
 
 stub(abort);
-stub(closedir);
-stub(execve);
-stub(fork);
 stub(getenv);
-stub(getpgrp);
-stub(getpid);
 stub(init);
 stub(initshellproc);
-stub(ioctl);
-stub(kill);
-stub(killpg);
 stub(_longjmp);
-stub(raise);
-stub(readdir);
 stub(reset);
 stub(_setjmp);
-stub(setpgrp);
 stub(sigsetmask);
-stub(wait3);
-stub(execl);
-stub(pipe);
 stub(fcntl);
 stub(realloc);
-stub(chdir);
 stub(strcat);
-stub(readlink);
-stub(lstat);
 stub(free);
-stub(signal);
 stub(atoi);
-stub(opendir);
-stub(_exit);
 stub(strcpy);
 stub(fgets);
 stub(putc);
@@ -243,12 +526,9 @@ stub(putchar);
 stub(fwrite);
 stub(atol);
 stub(sprintf);
-stub(stat);
 stub(puts);
 stub(geteuid);
 stub(getegid);
 stub(fprintf);
-stub(close);
-stub(isatty);
 stub(fputs);
 stub(umask);